@@ -0,0 +1,268 @@
+// certificates from an ACME CA like Let's Encrypt, enabled with the `acme` cargo feature
+//
+// with `acme` configured, the certificate in the top level `tls` files is
+// issued for `domains` and renewed `renew_before_days` before it expires:
+//
+//   "tls": { "cert": "certs/cert.pem", "key": "certs/key.pem" },
+//   "acme": { "domains": ["example.com"], "contact": ["mailto:admin@example.com"] }
+//
+// control of each domain is proven with the HTTP-01 challenge, the CA fetches
+// /.well-known/acme-challenge/<token> over plain HTTP on port 80, so a
+// listener has to be reachable there. it is answered ahead of redirects and
+// routes. what the CA signs is written to the files and swapped into the
+// listeners serving them, no restart needed. until the first certificate is
+// issued those listeners fail every handshake. the account registered with
+// the CA, agreeing to its terms, is kept in `account_file` for the renewals.
+
+use crate::config::Config;
+use crate::http::{Response, StatusCode};
+use crate::log;
+use crate::tls::{self, TlsFiles};
+use chrono::{DateTime, Utc};
+use instant_acme::{
+    Account, AccountCredentials, AuthorizationStatus, ChallengeType, Identifier, LetsEncrypt, NewAccount, NewOrder,
+    OrderStatus,
+};
+use rcgen::{CertificateParams, DistinguishedName, KeyPair};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::process;
+use std::sync::{LazyLock, Mutex, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+// where the CA looks for the answer to a challenge, followed by its token
+pub(crate) const CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
+// how often the certificate's expiry is looked at
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+// wait before ordering again after an order failed
+const RETRY_AFTER: Duration = Duration::from_secs(60 * 60);
+// times an order is polled while the CA validates or signs it, waiting
+// twice as long each time from a second
+const POLLS: u32 = 10;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct AcmeConfig {
+    /// Names the certificate is issued for, each one resolving to this server.
+    pub(crate) domains: Vec<String>,
+    /// Contact URLs given to the CA, like "mailto:admin@example.com".
+    pub(crate) contact: Vec<String>,
+    /// Directory URL of the CA, Let's Encrypt's production one by default.
+    pub(crate) directory: String,
+    /// File the credentials of the account with the CA are kept in.
+    pub(crate) account_file: String,
+    /// Days before it expires that the certificate is renewed.
+    pub(crate) renew_before_days: u32,
+}
+
+impl Default for AcmeConfig {
+    fn default() -> Self {
+        AcmeConfig {
+            domains: Vec::new(),
+            contact: Vec::new(),
+            directory: LetsEncrypt::Production.url().to_string(),
+            account_file: "acme-account.json".to_string(),
+            renew_before_days: 30,
+        }
+    }
+}
+
+// key authorization of every challenge the CA may fetch, by token
+static CHALLENGES: LazyLock<Mutex<HashMap<String, String>>> = LazyLock::new(Mutex::default);
+
+// the challenges of an order, answered until it is validated
+#[derive(Default)]
+pub(crate) struct Answering(Vec<String>);
+
+impl Answering {
+    pub(crate) fn add(&mut self, token: &str, key_authorization: &str) {
+        let mut challenges = CHALLENGES.lock().unwrap_or_else(PoisonError::into_inner);
+        challenges.insert(token.to_string(), key_authorization.to_string());
+        self.0.push(token.to_string());
+    }
+}
+
+impl Drop for Answering {
+    fn drop(&mut self) {
+        let mut challenges = CHALLENGES.lock().unwrap_or_else(PoisonError::into_inner);
+        for token in &self.0 {
+            challenges.remove(token);
+        }
+    }
+}
+
+// whether the certificate in `files` comes from the CA
+pub(crate) fn issues(config: &Config, files: &TlsFiles) -> bool {
+    config.acme.is_some() && config.tls.as_ref() == Some(files)
+}
+
+// the answer to the CA fetching a challenge, None for any other request
+pub(crate) fn challenge(method: &str, uri: &str) -> Option<Response> {
+    let token = uri.strip_prefix(CHALLENGE_PATH).filter(|_| matches!(method, "GET" | "HEAD"))?;
+    let challenges = CHALLENGES.lock().unwrap_or_else(PoisonError::into_inner);
+    Some(match challenges.get(token) {
+        Some(key_authorization) => Response::text(StatusCode::Ok, key_authorization.as_str()),
+        None => Response::problem(StatusCode::NotFound, "No challenge with this token"),
+    })
+}
+
+// keeps the certificate issued and renewed on a thread of its own, exiting
+// when `acme` is set without the files to write it to
+pub(crate) fn spawn_renewer(config: &'static Config) {
+    let Some(acme) = &config.acme else {
+        return;
+    };
+    let Some(files) = &config.tls else {
+        eprintln!("acme needs the top level tls cert and key files to write the certificate to");
+        process::exit(1);
+    };
+    if acme.domains.is_empty() {
+        eprintln!("acme needs the domains to issue the certificate for");
+        process::exit(1);
+    }
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("Failed to start the ACME runtime");
+        runtime.block_on(renew(acme, files));
+    });
+}
+
+async fn renew(acme: &AcmeConfig, files: &TlsFiles) {
+    loop {
+        let wait = match until_renewal(acme, files) {
+            Some(wait) => wait.min(CHECK_INTERVAL),
+            None => match issue(acme, files).await {
+                Ok(()) => CHECK_INTERVAL,
+                Err(e) => {
+                    log::error!("Failed to obtain a certificate for {}: {}", acme.domains.join(", "), e);
+                    RETRY_AFTER
+                }
+            },
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
+
+// how long until the certificate in `files` is due for renewal, None when it
+// is now or there is no certificate
+fn until_renewal(acme: &AcmeConfig, files: &TlsFiles) -> Option<Duration> {
+    let renew_at = expiry(&files.cert)? - chrono::Duration::days(acme.renew_before_days.into());
+    (renew_at - Utc::now()).to_std().ok()
+}
+
+// when the first certificate in the PEM file at `path` expires
+fn expiry(path: &str) -> Option<DateTime<Utc>> {
+    let pem = fs::read(path).ok()?;
+    let (_, pem) = x509_parser::pem::parse_x509_pem(&pem).ok()?;
+    let certificate = pem.parse_x509().ok()?;
+    DateTime::from_timestamp(certificate.validity().not_after.timestamp(), 0)
+}
+
+// orders a certificate for the domains, answers the CA's challenges, then
+// writes what it signs to `files` and serves it
+async fn issue(acme: &AcmeConfig, files: &TlsFiles) -> Result<(), String> {
+    let account = account(acme).await?;
+    let identifiers: Vec<Identifier> = acme.domains.iter().cloned().map(Identifier::Dns).collect();
+    let mut order = account
+        .new_order(&NewOrder { identifiers: &identifiers })
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let mut answering = Answering::default();
+    let authorizations = order.authorizations().await.map_err(|e| e.to_string())?;
+    // domains validated for an earlier order don't need it again
+    for authorization in authorizations.iter().filter(|a| a.status == AuthorizationStatus::Pending) {
+        let challenge = authorization
+            .challenges
+            .iter()
+            .find(|challenge| challenge.r#type == ChallengeType::Http01)
+            .ok_or_else(|| format!("the CA offers no http-01 challenge for {:?}", authorization.identifier))?;
+        answering.add(&challenge.token, order.key_authorization(challenge).as_str());
+        order.set_challenge_ready(&challenge.url).await.map_err(|e| e.to_string())?;
+    }
+    let mut delay = Duration::from_secs(1);
+    for poll in 1.. {
+        let state = order.refresh().await.map_err(|e| e.to_string())?;
+        match state.status {
+            OrderStatus::Ready => break,
+            OrderStatus::Invalid => {
+                let reason = state.error.as_ref().map_or("no reason given".to_string(), ToString::to_string);
+                return Err(format!("the CA refused the order: {reason}"));
+            }
+            _ if poll == POLLS => return Err("the CA took too long to validate the domains".to_string()),
+            _ => {}
+        }
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+    }
+    drop(answering);
+
+    let key = KeyPair::generate().map_err(|e| e.to_string())?;
+    let mut params = CertificateParams::new(acme.domains.clone()).map_err(|e| e.to_string())?;
+    params.distinguished_name = DistinguishedName::new();
+    let request = params.serialize_request(&key).map_err(|e| e.to_string())?;
+    order.finalize(request.der()).await.map_err(|e| e.to_string())?;
+    let mut delay = Duration::from_secs(1);
+    let mut poll = 1;
+    let chain = loop {
+        if let Some(chain) = order.certificate().await.map_err(|e| e.to_string())? {
+            break chain;
+        }
+        if poll == POLLS {
+            return Err("the CA took too long to sign the certificate".to_string());
+        }
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+        poll += 1;
+    };
+
+    write(&files.key, &key.serialize_pem(), true)?;
+    write(&files.cert, &chain, false)?;
+    tls::reload(files)?;
+    log::info!("Obtained a certificate for {}", acme.domains.join(", "));
+    Ok(())
+}
+
+// the account kept in `account_file`, registered with the CA the first time
+async fn account(acme: &AcmeConfig) -> Result<Account, String> {
+    if let Ok(contents) = fs::read_to_string(&acme.account_file) {
+        let credentials: AccountCredentials =
+            serde_json::from_str(&contents).map_err(|e| format!("{}: {e}", acme.account_file))?;
+        return Account::from_credentials(credentials).await.map_err(|e| e.to_string());
+    }
+    let contact: Vec<&str> = acme.contact.iter().map(String::as_str).collect();
+    let new_account = NewAccount { contact: &contact, terms_of_service_agreed: true, only_return_existing: false };
+    let (account, credentials) = Account::create(&new_account, &acme.directory, None)
+        .await
+        .map_err(|e| e.to_string())?;
+    let credentials = serde_json::to_string_pretty(&credentials).map_err(|e| e.to_string())?;
+    write(&acme.account_file, &credentials, true)?;
+    log::info!("Registered an account with {}", acme.directory);
+    Ok(account)
+}
+
+// replaces the file at `path` with `contents` at once, readable only by the
+// server's user when it holds a key
+fn write(path: &str, contents: &str, private: bool) -> Result<(), String> {
+    let temporary = format!("{path}.tmp");
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+    options
+        .open(&temporary)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .map_err(|e| format!("{temporary}: {e}"))?;
+    fs::rename(&temporary, path).map_err(|e| format!("{path}: {e}"))
+}
@@ -0,0 +1,275 @@
+// API keys for the machine-to-machine clients of the entry routes
+//
+// with `api_keys` configured, keys are issued, listed and revoked under
+// /admin/api-keys:
+//
+//   POST /admin/api-keys {"name": "importer", "scopes": ["read", "write"], "rate_limit": {"requests": 100, "window_secs": 60}}
+//
+// the key is in that answer only. `file` keeps the SHA-256 of each one, never
+// the key itself. a client sends its key in X-Api-Key. a request to the
+// entry routes is answered 401 when its key is unknown or revoked, and 403
+// when the key's scopes don't cover the method: "read" for GET, HEAD and
+// OPTIONS, "write" for the rest. it gets 429 over the key's own rate limit,
+// which counts the requests of every client using the key. a request without
+// a key goes through unless `required` is set. a revoked key stays listed,
+// with the time it was revoked.
+
+use crate::config::{self, Config};
+use crate::extract::Json;
+use crate::http::{HeaderMap, Request, Response, StatusCode};
+use crate::log;
+use crate::problem::ApiError;
+use crate::rate_limit::{self, RateLimit};
+use crate::router::Next;
+use chrono::{SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::sync::{Mutex, PoisonError};
+
+pub(crate) const HEADER: &str = "X-Api-Key";
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ApiKeysConfig {
+    /// File the issued keys are kept in, hashed.
+    pub(crate) file: String,
+    /// Refuse the requests to the entry routes that send no key.
+    pub(crate) required: bool,
+}
+
+impl Default for ApiKeysConfig {
+    fn default() -> Self {
+        ApiKeysConfig { file: "api-keys.json".to_string(), required: false }
+    }
+}
+
+// what a key allows, "read" the safe methods and "write" the others
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Scope {
+    Read,
+    Write,
+}
+
+// a key as GET /admin/api-keys lists it
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub(crate) struct ApiKey {
+    pub(crate) id: String,
+    pub(crate) name: String,
+    pub(crate) scopes: Vec<Scope>,
+    pub(crate) rate_limit: Option<RateLimit>,
+    pub(crate) created: String,
+    pub(crate) revoked: Option<String>,
+}
+
+// a key as the file keeps it
+#[derive(Deserialize, Serialize, Debug, Clone)]
+struct Stored {
+    #[serde(flatten)]
+    key: ApiKey,
+    // SHA-256 of the key, in hex
+    hash: String,
+}
+
+// the body of POST /admin/api-keys
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct NewKey {
+    name: String,
+    #[serde(default = "read_only")]
+    scopes: Vec<Scope>,
+    #[serde(default)]
+    rate_limit: Option<RateLimit>,
+}
+
+fn read_only() -> Vec<Scope> {
+    vec![Scope::Read]
+}
+
+// the keys in the file, read on first use and kept with the file's name
+static KEYS: Mutex<Option<(String, Vec<Stored>)>> = Mutex::new(None);
+
+// runs `f` on the keys in the file of `config`
+fn with_keys<T>(config: &ApiKeysConfig, f: impl FnOnce(&mut Vec<Stored>) -> T) -> io::Result<T> {
+    let mut keys = KEYS.lock().unwrap_or_else(PoisonError::into_inner);
+    if keys.as_ref().is_none_or(|(file, _)| *file != config.file) {
+        let stored = match fs::read(&config.file) {
+            Ok(contents) => serde_json::from_slice(&contents)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        *keys = Some((config.file.clone(), stored));
+    }
+    let (_, stored) = keys.as_mut().expect("the keys were just read");
+    Ok(f(stored))
+}
+
+// writes the keys to a temporary file renamed over the old one
+fn save(config: &ApiKeysConfig, keys: &[Stored]) -> io::Result<()> {
+    let temporary = format!("{}.tmp", config.file);
+    fs::write(&temporary, serde_json::to_vec_pretty(keys)?)?;
+    fs::rename(&temporary, &config.file)
+}
+
+fn hash(key: &str) -> String {
+    Sha256::digest(key.as_bytes()).iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+// stores a new key, returning it along with the key itself
+pub(crate) fn issue(config: &ApiKeysConfig, new: NewKey) -> Result<(ApiKey, String), ApiError> {
+    if new.name.trim().is_empty() {
+        return Err((StatusCode::BadRequest, "The key needs a name".to_string()).into());
+    }
+    if new.scopes.is_empty() {
+        return Err((StatusCode::BadRequest, "The key needs at least one scope".to_string()).into());
+    }
+    let random = |bytes| {
+        crate::random_hex(bytes)
+            .map_err(|e| ApiError::from((StatusCode::InternalServerError, format!("Failed to generate a key: {e}"))))
+    };
+    let (id, secret) = (random(8)?, random(32)?);
+    let secret = format!("ak_{secret}");
+    let key = ApiKey {
+        id,
+        name: new.name,
+        scopes: new.scopes,
+        rate_limit: new.rate_limit,
+        created: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        revoked: None,
+    };
+    let stored = Stored { key: key.clone(), hash: hash(&secret) };
+    with_keys(config, |keys| {
+        keys.push(stored);
+        save(config, keys).inspect_err(|_| {
+            keys.pop();
+        })
+    })
+    .and_then(|saved| saved)
+    .map_err(|e| ApiError::from((StatusCode::InternalServerError, format!("Failed to store the key: {e}"))))?;
+    log::info!("Issued API key {} ({})", key.id, key.name);
+    Ok((key, secret))
+}
+
+pub(crate) fn list(config: &ApiKeysConfig) -> io::Result<Vec<ApiKey>> {
+    with_keys(config, |keys| keys.iter().map(|stored| stored.key.clone()).collect())
+}
+
+// marks the key `id` revoked, None when there is no such key. revoking it
+// again keeps the first time
+pub(crate) fn revoke(config: &ApiKeysConfig, id: &str) -> io::Result<Option<ApiKey>> {
+    with_keys(config, |keys| {
+        let Some(stored) = keys.iter_mut().find(|stored| stored.key.id == id) else {
+            return Ok(None);
+        };
+        if stored.key.revoked.is_some() {
+            return Ok(Some(stored.key.clone()));
+        }
+        stored.key.revoked = Some(Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true));
+        let revoked = stored.key.clone();
+        if let Err(e) = save(config, keys) {
+            keys.iter_mut().filter(|stored| stored.key.id == id).for_each(|stored| stored.key.revoked = None);
+            return Err(e);
+        }
+        log::info!("Revoked API key {} ({})", revoked.id, revoked.name);
+        Ok(Some(revoked))
+    })?
+}
+
+// the key a request sends, None when it sends none, or the response refusing it
+pub(crate) fn authenticate(config: &ApiKeysConfig, method: &str, headers: &HeaderMap) -> Result<Option<ApiKey>, Response> {
+    let Some(given) = headers.get(HEADER).map(str::trim) else {
+        return match config.required {
+            true => Err(Response::problem(StatusCode::Unauthorized, format!("An API key is required in {HEADER}"))),
+            false => Ok(None),
+        };
+    };
+    let hash = hash(given);
+    let found = with_keys(config, |keys| keys.iter().find(|stored| stored.hash == hash).map(|stored| stored.key.clone()));
+    let key = match found {
+        Ok(Some(key)) if key.revoked.is_none() => key,
+        Ok(_) => return Err(Response::problem(StatusCode::Unauthorized, "Unknown or revoked API key")),
+        Err(e) => {
+            log::error!("Failed to read {}: {}", config.file, e);
+            return Err(Response::problem(StatusCode::InternalServerError, "Failed to read the API keys"));
+        }
+    };
+    let needed = match method {
+        "GET" | "HEAD" | "OPTIONS" => Scope::Read,
+        _ => Scope::Write,
+    };
+    if !key.scopes.contains(&needed) {
+        let scope = if needed == Scope::Read { "read" } else { "write" };
+        return Err(Response::problem(StatusCode::Forbidden, format!("The API key lacks the {scope} scope")));
+    }
+    if let Some(limit) = key.rate_limit {
+        rate_limit::check_key(&key.id, limit)?;
+    }
+    Ok(Some(key))
+}
+
+// the live key a request sends, whatever route it goes to
+pub(crate) fn sent(config: &Config, headers: &HeaderMap) -> Option<ApiKey> {
+    let api_keys = config.api_keys.as_ref()?;
+    let hash = hash(headers.get(HEADER)?.trim());
+    let found = with_keys(api_keys, |keys| {
+        keys.iter().find(|stored| stored.hash == hash && stored.key.revoked.is_none()).map(|stored| stored.key.clone())
+    });
+    found.ok().flatten()
+}
+
+// the principal a request authenticated as with its key, for the audit log
+pub(crate) fn principal(config: &Config, headers: &HeaderMap) -> Option<String> {
+    sent(config, headers).map(|key| format!("api-key:{}", key.name))
+}
+
+// router middleware checking the key of requests to the entry routes, the
+// handlers find the key in the request's extensions
+pub(crate) fn middleware(request: &mut Request, next: Next) -> Response {
+    let Some(api_keys) = &config::get().api_keys else {
+        return next(request);
+    };
+    match authenticate(api_keys, &request.method, &request.headers) {
+        Ok(key) => {
+            if let Some(key) = key {
+                request.extensions.insert(key);
+            }
+            next(request)
+        }
+        Err(response) => response,
+    }
+}
+
+// the handlers of /admin/api-keys, 404 while `api_keys` isn't configured
+
+fn configured() -> Result<&'static ApiKeysConfig, ApiError> {
+    config::get()
+        .api_keys
+        .as_ref()
+        .ok_or_else(|| (StatusCode::NotFound, "API keys are not configured".to_string()).into())
+}
+
+pub(crate) fn create(Json(new): Json<NewKey>) -> Result<Response, ApiError> {
+    let (key, secret) = issue(configured()?, new)?;
+    let mut body = serde_json::to_value(key).expect("a key serializes");
+    body["key"] = secret.into();
+    Ok(Response::json(StatusCode::Created, body.to_string()))
+}
+
+pub(crate) fn index(_request: &Request) -> Result<Json<Vec<ApiKey>>, ApiError> {
+    let api_keys = configured()?;
+    list(api_keys)
+        .map(Json)
+        .map_err(|e| (StatusCode::InternalServerError, format!("Failed to read {}: {e}", api_keys.file)).into())
+}
+
+pub(crate) fn destroy(request: &Request) -> Result<Json<ApiKey>, ApiError> {
+    let api_keys = configured()?;
+    let id = request.params.get("id").map_or("", String::as_str);
+    match revoke(api_keys, id) {
+        Ok(Some(key)) => Ok(Json(key)),
+        Ok(None) => Err((StatusCode::NotFound, format!("No API key with id {id}")).into()),
+        Err(e) => Err((StatusCode::InternalServerError, format!("Failed to store {}: {e}", api_keys.file)).into()),
+    }
+}
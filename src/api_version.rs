@@ -0,0 +1,97 @@
+// versions of the entries API
+//
+// the unversioned routes are frozen as they are. /api/v1 serves the same
+// handlers, for clients that want to pin them, and /api/v2 is where the API
+// changes: it leaves out the routes named after actions (/submit, /put_entry,
+// /patch_entry_name and /delete_entry) for POST /entries and PUT /entries/{id},
+// and its entries carry _links, see hypermedia.rs.
+//
+// `api_versions` can announce that a version is going away, keyed by its name:
+//
+//   "api_versions": { "v1": { "deprecation": "2025-01-01T00:00:00Z",
+//                             "sunset": "2026-01-01T00:00:00Z",
+//                             "successor": "/api/v2/entries" } }
+//
+// its responses then carry Deprecation (RFC 9745), Sunset (RFC 8594) and a
+// Link to the successor. the unversioned routes count as v1.
+
+use crate::config;
+use crate::http::{self, Request, Response};
+use crate::router::Next;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ApiVersion {
+    V1,
+    V2,
+}
+
+impl ApiVersion {
+    pub(crate) const ALL: [ApiVersion; 2] = [ApiVersion::V1, ApiVersion::V2];
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            ApiVersion::V1 => "v1",
+            ApiVersion::V2 => "v2",
+        }
+    }
+
+    // where its routes are mounted
+    pub(crate) fn prefix(self) -> String {
+        format!("/api/{}", self.name())
+    }
+
+    // the version a path is served by, the unversioned ones being v1's
+    fn of(path: &str) -> ApiVersion {
+        ApiVersion::ALL
+            .into_iter()
+            .find(|version| path.strip_prefix(&version.prefix()).is_some_and(|rest| rest.is_empty() || rest.starts_with('/')))
+            .unwrap_or(ApiVersion::V1)
+    }
+}
+
+// what the responses of a version announce about its retirement
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Lifecycle {
+    /// When the version was, or will be, deprecated, RFC 3339.
+    #[serde(deserialize_with = "rfc3339")]
+    pub(crate) deprecation: Option<DateTime<Utc>>,
+    /// When the version stops being served, RFC 3339.
+    #[serde(deserialize_with = "rfc3339")]
+    pub(crate) sunset: Option<DateTime<Utc>>,
+    /// URL of what replaces it.
+    pub(crate) successor: Option<String>,
+}
+
+fn rfc3339<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error> {
+    let Some(text) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    DateTime::parse_from_rfc3339(&text)
+        .map(|time| Some(time.with_timezone(&Utc)))
+        .map_err(|e| serde::de::Error::custom(format!("invalid RFC 3339 time {text:?}: {e}")))
+}
+
+// adds the headers configured for the version the request was routed to
+pub(crate) fn middleware(request: &mut Request, next: Next) -> Response {
+    let response = next(request);
+    match config::get().api_versions.get(ApiVersion::of(&request.path).name()) {
+        Some(lifecycle) => announce(lifecycle, response),
+        None => response,
+    }
+}
+
+pub(crate) fn announce(lifecycle: &Lifecycle, mut response: Response) -> Response {
+    if let Some(deprecation) = lifecycle.deprecation {
+        response = response.header("Deprecation", format!("@{}", deprecation.timestamp()));
+    }
+    if let Some(sunset) = lifecycle.sunset {
+        response = response.header("Sunset", http::http_date(sunset));
+    }
+    if let Some(successor) = &lifecycle.successor {
+        response = response.header("Link", format!("<{successor}>; rel=\"successor-version\""));
+    }
+    response
+}
@@ -0,0 +1,307 @@
+// tokio based accept loop, enabled with the `tokio` cargo feature
+//
+// every connection becomes its own task instead of taking one of the 5 pool
+// threads, so slow or idle clients, kept alive ones among them, no longer
+// block everyone else. requests are read asynchronously, then answered by the
+// same code as the blocking server on tokio's blocking pool since the
+// handlers do file io.
+//
+// the handlers themselves stay sync: the feature makes the connections async,
+// not the router. a route is still a plain function, registered the same way
+// with the feature on or off, and one answering holds a blocking pool thread
+// (512 by default) rather than one of the 5 workers. there is no async
+// handler type, a handler needing to await something would block on it.
+
+use crate::chunked::StreamBody;
+use crate::config;
+use crate::connection::ConnectionInfo;
+use crate::http::{HeaderMap, Response, Serialized, StatusCode};
+use crate::listener::{Bound, Routes, SocketOptions, Tls};
+use crate::parser::{self, HeadParser};
+use crate::slow_log::Timing;
+use crate::{answer, connections, events, expect_continue, ip_filter, keep_alive, log, rate_limit, reload, stats};
+use std::io::{self, BufReader, Cursor};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+// starts a multi-threaded runtime and serves every listener until they are shut down
+pub(crate) fn run(listeners: Vec<Bound>) {
+    let runtime = tokio::runtime::Runtime::new().expect("Failed to start tokio runtime");
+    runtime.block_on(async {
+        let mut tasks = Vec::new();
+        for bound in listeners {
+            bound
+                .listener
+                .set_nonblocking(true)
+                .expect("Failed to make listener non-blocking");
+            let listener = TcpListener::from_std(bound.listener).expect("Failed to register listener");
+            tasks.push(tokio::spawn(serve(listener, bound.socket, bound.routes, bound.tls)));
+        }
+        for task in tasks {
+            let _ = task.await;
+        }
+    });
+    // dropping the runtime would cancel the connections still being answered
+    reload::drain(config::get());
+}
+
+pub(crate) async fn serve(listener: TcpListener, socket: SocketOptions, routes: Routes, tls: Tls) {
+    loop {
+        while connections::must_wait(config::get().connection_limit) && !reload::stopping() {
+            tokio::time::sleep(connections::POLL).await;
+        }
+        let (stream, peer) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(_) if reload::stopping() => return,
+            Err(e) => {
+                log::error!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        log::debug!("New Connection from {}", peer);
+        if let Err(e) = stream.set_nodelay(socket.tcp_nodelay) {
+            log::warning!("Failed to set TCP_NODELAY: {}", e);
+        }
+        let Some(slot) = connections::try_open(config::get().connection_limit) else {
+            reject(stream, tls.clone(), connections::rejection());
+            continue;
+        };
+        let admitted = ip_filter::check(config::get(), peer.ip())
+            .and_then(|()| rate_limit::check(config::get(), peer.ip()));
+        if let Err(response) = admitted {
+            reject(stream, tls.clone(), response);
+            continue;
+        }
+        let tls = tls.clone();
+        tokio::spawn(async move {
+            let _slot = slot;
+            match tls {
+                #[cfg(feature = "tls")]
+                Some(tls) => crate::tls::handle_connection(stream, tls, routes).await,
+                None => handle_connection(stream, routes).await,
+            }
+        });
+    }
+}
+
+// answers a connection that won't be read from, without holding up the accept
+// loop. on a TLS listener that takes the handshake first
+fn reject(mut stream: TcpStream, tls: Tls, response: Response) {
+    tokio::spawn(async move {
+        match tls {
+            #[cfg(feature = "tls")]
+            Some(tls) => {
+                if let Some(mut stream) = crate::tls::accept(stream, tls).await {
+                    let _ = stream.write_all(&response.to_bytes(false)).await;
+                    let _ = stream.shutdown().await;
+                }
+            }
+            None => {
+                let _ = stream.write_all(&response.to_bytes(false)).await;
+            }
+        }
+    });
+}
+
+// what a connection is read from and written to, a socket or TLS over one
+pub(crate) trait Transport: AsyncRead + AsyncWrite + Unpin + Send + Sized + 'static {
+    // the addresses at both ends, and the TLS details of an encrypted one
+    fn connection(&self) -> ConnectionInfo;
+
+    // writes the file or streamed body of a response after its head, then
+    // hands the connection back for the next request
+    async fn send_body(self, response: Serialized) -> io::Result<Self>;
+
+    // gives the connection to a thread of its own for the event stream
+    fn stream_events(self) -> io::Result<()>;
+}
+
+impl Transport for TcpStream {
+    fn connection(&self) -> ConnectionInfo {
+        ConnectionInfo::plain(self.peer_addr().ok(), self.local_addr().ok())
+    }
+
+    async fn send_body(mut self, response: Serialized) -> io::Result<TcpStream> {
+        if let Some(file) = &response.file {
+            file.send_async(&mut self).await?;
+        } else if let Some(body) = response.stream {
+            return send_stream(self, body).await;
+        }
+        Ok(self)
+    }
+
+    // event streams are long lived and blocking
+    fn stream_events(self) -> io::Result<()> {
+        let stream = self.into_std()?;
+        stream.set_nonblocking(false)?;
+        std::thread::spawn(move || events::stream_events(stream));
+        Ok(())
+    }
+}
+
+pub(crate) async fn handle_connection<S: Transport>(mut stream: S, routes: Routes) {
+    let _in_flight = reload::InFlight::start();
+    stats::connection_accepted();
+    let connection = stream.connection();
+    // what was read past the end of the previous request
+    let mut ahead = Vec::new();
+    for served in 1.. {
+        if served > 1 {
+            if !wait_for_request(&mut stream, &mut ahead).await {
+                break;
+            }
+            // the first request was counted when the connection was accepted
+            if let Some(Err(response)) = connection.peer.map(|peer| rate_limit::check(config::get(), peer.ip())) {
+                let _ = stream.write_all(&response.header("Connection", "close").to_bytes(false)).await;
+                break;
+            }
+        }
+        let mut timing = Timing::start();
+        let raw_request = match read_request(&mut stream, std::mem::take(&mut ahead)).await {
+            Ok((raw_request, rest)) => {
+                ahead = rest;
+                raw_request
+            }
+            Err(e) => {
+                log::warning!("Failed to read request: {}", e);
+                return;
+            }
+        };
+
+        // the request is already in memory and any 100 Continue went out while it
+        // was read, the handlers run on the blocking pool since they do file io
+        let connection = connection.clone();
+        let response = tokio::task::spawn_blocking(move || {
+            let capacity = raw_request.len().max(1);
+            let mut buf_reader = BufReader::with_capacity(capacity, Cursor::new(raw_request));
+            let response = answer(&mut buf_reader, &mut io::sink(), routes, &connection, served, &mut timing);
+            (response, timing)
+        })
+        .await;
+
+        match response {
+            Ok((Some(response), timing)) => {
+                let close = response.close;
+                if let Err(e) = stream.write_all(&response.bytes).await {
+                    log::warning!("Failed to write response: {}", e);
+                    return;
+                }
+                stream = match stream.send_body(response).await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        log::debug!("Failed to send the body: {}", e);
+                        return;
+                    }
+                };
+                if let Err(e) = stream.flush().await {
+                    log::debug!("Failed to send the response: {}", e);
+                    return;
+                }
+                timing.finish();
+                if close {
+                    break;
+                }
+            }
+            Ok((None, _)) => {
+                if let Err(e) = stream.stream_events() {
+                    log::error!("Failed to open event stream: {}", e);
+                }
+                return;
+            }
+            Err(e) => {
+                log::error!("Failed to answer request: {}", e);
+                return;
+            }
+        }
+    }
+    // over TLS the client is told the connection ends on purpose
+    let _ = stream.shutdown().await;
+}
+
+// waits for the next request on a connection kept open, reading what has
+// arrived of it into `ahead`. false when the client closed the connection or
+// left it idle for too long
+async fn wait_for_request<S: Transport>(stream: &mut S, ahead: &mut Vec<u8>) -> bool {
+    if !ahead.is_empty() {
+        return true;
+    }
+    let Some(timeout) = keep_alive::idle_timeout(config::get()) else {
+        return false;
+    };
+    let mut chunk = [0u8; 4096];
+    match tokio::time::timeout(timeout, stream.read(&mut chunk)).await {
+        Ok(Ok(read)) if read > 0 => {
+            ahead.extend_from_slice(&chunk[..read]);
+            true
+        }
+        _ => false,
+    }
+}
+
+// a streamed body is written by blocking code, so the socket moves to the
+// blocking pool for it, like an event stream moves to a thread, and comes
+// back for the next request
+async fn send_stream(stream: TcpStream, body: StreamBody) -> io::Result<TcpStream> {
+    let mut stream = stream.into_std()?;
+    stream.set_nonblocking(false)?;
+    let stream = tokio::task::spawn_blocking(move || body.send(&mut stream).map(|()| stream))
+        .await
+        .map_err(io::Error::other)??;
+    stream.set_nonblocking(true)?;
+    TcpStream::from_std(stream)
+}
+
+// reads until the end of the head plus Content-Length bytes of body, starting
+// with what was read ahead of it. the head is fed to a HeadParser as it
+// arrives to find where it ends. returns the request and whatever came after it
+async fn read_request<S: Transport>(stream: &mut S, mut data: Vec<u8>) -> std::io::Result<(Vec<u8>, Vec<u8>)> {
+    let mut parser = HeadParser::new(config::get().hardened);
+    let mut parsed = 0;
+    let mut expected = None;
+    let mut chunk = [0u8; 4096];
+    loop {
+        if expected.is_none() && parsed < data.len() {
+            match parser.advance(&data[parsed..]) {
+                Ok((used, Some(head))) => {
+                    parsed += used;
+                    match expect_continue(&head) {
+                        Ok(true) => stream.write_all(&Response::new(StatusCode::Continue).to_bytes(false)).await?,
+                        Ok(false) => {}
+                        // answered without the body, which the client holds back
+                        Err(_) => return Ok((data, Vec::new())),
+                    }
+                    let length = content_length(&head.headers);
+                    let limit = if parser::streamed(&head.headers) {
+                        config::get().max_upload_bytes
+                    } else {
+                        config::get().max_body_bytes
+                    };
+                    // answer refuses it before reading any, it isn't buffered first
+                    if length > limit {
+                        return Ok((data, Vec::new()));
+                    }
+                    expected = Some(parsed + length as usize);
+                }
+                Ok((used, None)) => parsed += used,
+                // answer runs into the same error and responds to it
+                Err(_) => return Ok((data, Vec::new())),
+            }
+        }
+        if let Some(expected) = expected.filter(|&expected| data.len() >= expected) {
+            let rest = data.split_off(expected);
+            return Ok((data, rest));
+        }
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Ok((data, Vec::new()));
+        }
+        data.extend_from_slice(&chunk[..read]);
+    }
+}
+
+fn content_length(headers: &HeaderMap) -> u64 {
+    headers
+        .get("Content-Length")
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0)
+}
@@ -0,0 +1,232 @@
+// audit trail of the changes to the entries
+//
+// every write of the entries through store::save appends one JSON line to
+// `audit_log` (audit.log, "" turns it off): when, the request id, who asked
+// for it, what they asked for and each entry it changed, before and after.
+// who is the principal the request authenticated as, if any, and the client's
+// address, the first hop of X-Forwarded-For when one of the `trusted_proxies`
+// sent it. like the request id, who is asking is kept per thread while the
+// request is handled. the log is only appended to, GET /admin/audit reads it
+// back newest first.
+
+use crate::config::Config;
+use crate::endpoints::Character;
+use crate::http::HeaderMap;
+use crate::{config, json, log, request_id};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Mutex, PoisonError};
+
+// entries GET /admin/audit returns when no limit is given, and at most
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+
+// appends from different threads never interleave
+static WRITE_LOCK: Mutex<()> = Mutex::new(());
+
+thread_local! {
+    static CURRENT: RefCell<Option<Actor>> = const { RefCell::new(None) };
+}
+
+// who is changing the entries and how
+#[derive(Debug, Clone)]
+pub(crate) struct Actor {
+    // "PATCH /entries/3", or what the server is doing on its own
+    pub(crate) operation: String,
+    pub(crate) principal: Option<String>,
+    pub(crate) ip: Option<IpAddr>,
+}
+
+impl Actor {
+    // the client sending `headers` from `peer`, asking for `operation`
+    pub(crate) fn client(operation: String, principal: Option<String>, headers: &HeaderMap, peer: Option<SocketAddr>, config: &Config) -> Actor {
+        Actor { operation, principal, ip: client_ip(headers, peer, config) }
+    }
+
+    // the server itself, like the purge of the trash
+    pub(crate) fn server(operation: &str) -> Actor {
+        Actor { operation: operation.to_string(), principal: Some("server".to_string()), ip: None }
+    }
+}
+
+// the address of the client sending `headers` from `peer`, the first hop of
+// X-Forwarded-For when a trusted proxy sent them
+pub(crate) fn client_ip(headers: &HeaderMap, peer: Option<SocketAddr>, config: &Config) -> Option<IpAddr> {
+    let trusted = peer.is_some_and(|peer| config.trusted_proxies.iter().any(|range| range.contains(peer.ip())));
+    let forwarded = headers
+        .get("X-Forwarded-For")
+        .filter(|_| trusted)
+        .and_then(|hops| hops.split(',').next())
+        .and_then(|first| first.trim().parse().ok());
+    forwarded.or(peer.map(|peer| peer.ip()))
+}
+
+// makes `actor` the current one on this thread until the guard is dropped
+pub(crate) fn enter(actor: Actor) -> Entered {
+    let previous = CURRENT.with(|current| current.replace(Some(actor)));
+    Entered { previous }
+}
+
+pub(crate) struct Entered {
+    previous: Option<Actor>,
+}
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        CURRENT.with(|current| *current.borrow_mut() = self.previous.take());
+    }
+}
+
+// the actor of the request this thread is handling
+pub(crate) fn current() -> Option<Actor> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+// one line of the log
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Entry {
+    pub(crate) time: String,
+    pub(crate) request_id: Option<String>,
+    pub(crate) principal: Option<String>,
+    pub(crate) ip: Option<IpAddr>,
+    pub(crate) operation: String,
+    pub(crate) changes: Vec<Change>,
+}
+
+// an entry as it was and as it is now, null when it was added or removed
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Change {
+    pub(crate) id: usize,
+    pub(crate) before: Option<Character>,
+    pub(crate) after: Option<Character>,
+}
+
+// the changes from `before` to `after`
+fn changes(before: &[Character], after: &[Character]) -> Vec<Change> {
+    let mut previous: HashMap<usize, &Character> = before.iter().map(|c| (c.id(), c)).collect();
+    let mut changes = Vec::new();
+    for character in after {
+        match previous.remove(&character.id()) {
+            Some(old) if old == character => {}
+            old => changes.push(Change { id: character.id(), before: old.cloned(), after: Some(character.clone()) }),
+        }
+    }
+    // whatever is left was removed, reported in the order it was in
+    for character in before.iter().filter(|c| previous.contains_key(&c.id())) {
+        changes.push(Change { id: character.id(), before: Some(character.clone()), after: None });
+    }
+    changes
+}
+
+// appends what a write changed to the log, a write changing nothing isn't logged
+pub(crate) fn record(before: &[Character], after: &[Character]) {
+    let path = &config::get().audit_log;
+    if path.is_empty() {
+        return;
+    }
+    let changes = changes(before, after);
+    if changes.is_empty() {
+        return;
+    }
+    let actor = current().unwrap_or_else(|| Actor::server("unknown"));
+    let entry = Entry {
+        time: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        request_id: request_id::current(),
+        principal: actor.principal,
+        ip: actor.ip,
+        operation: actor.operation,
+        changes,
+    };
+    if let Err(e) = append(path, &entry) {
+        // the write already happened, it isn't undone for its audit line
+        log::warning!("Failed to write the audit log: {e}");
+    }
+}
+
+fn append(path: &str, entry: &Entry) -> io::Result<()> {
+    let mut line = json::to_string(entry)?.into_bytes();
+    line.push(b'\n');
+    let _lock = WRITE_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(&line)?;
+    file.sync_data()
+}
+
+// which lines GET /admin/audit returns
+#[derive(Debug, Default)]
+pub(crate) struct Query {
+    // only changes of this entry
+    pub(crate) id: Option<usize>,
+    pub(crate) principal: Option<String>,
+    pub(crate) ip: Option<IpAddr>,
+    pub(crate) since: Option<DateTime<Utc>>,
+    pub(crate) until: Option<DateTime<Utc>>,
+    pub(crate) limit: usize,
+}
+
+impl Query {
+    // reads the parameters from a query string, naming the first invalid one
+    pub(crate) fn parse(params: &HashMap<String, String>) -> Result<Query, String> {
+        fn parsed<T: std::str::FromStr>(params: &HashMap<String, String>, name: &str) -> Result<Option<T>, String> {
+            match params.get(name) {
+                Some(value) => value.parse().map(Some).map_err(|_| format!("Invalid {name} parameter: {value}")),
+                None => Ok(None),
+            }
+        }
+        let time = |name: &str| match params.get(name) {
+            Some(value) => DateTime::parse_from_rfc3339(value)
+                .map(|time| Some(time.with_timezone(&Utc)))
+                .map_err(|_| format!("Invalid {name} parameter: {value}, expected an RFC 3339 time")),
+            None => Ok(None),
+        };
+        let limit = parsed(params, "limit")?.unwrap_or(DEFAULT_LIMIT);
+        if limit == 0 || limit > MAX_LIMIT {
+            return Err(format!("Invalid limit parameter: {limit}, expected 1 to {MAX_LIMIT}"));
+        }
+        Ok(Query {
+            id: parsed(params, "id")?,
+            principal: params.get("principal").cloned(),
+            ip: parsed(params, "ip")?,
+            since: time("since")?,
+            until: time("until")?,
+            limit,
+        })
+    }
+
+    fn matches(&self, entry: &Entry) -> bool {
+        let time = DateTime::parse_from_rfc3339(&entry.time).ok().map(|time| time.with_timezone(&Utc));
+        self.id.is_none_or(|id| entry.changes.iter().any(|change| change.id == id))
+            && self.principal.as_ref().is_none_or(|principal| entry.principal.as_ref() == Some(principal))
+            && self.ip.is_none_or(|ip| entry.ip == Some(ip))
+            && self.since.is_none_or(|since| time.is_some_and(|time| time >= since))
+            && self.until.is_none_or(|until| time.is_some_and(|time| time < until))
+    }
+}
+
+// the latest `query.limit` lines matching it, newest first. a line that can't
+// be read, like one being appended meanwhile, is skipped
+pub(crate) fn search(query: &Query) -> io::Result<Vec<Entry>> {
+    let file = match File::open(&config::get().audit_log) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut latest = VecDeque::with_capacity(query.limit);
+    for line in BufReader::new(file).lines() {
+        let Ok(entry) = serde_json::from_str::<Entry>(&line?) else {
+            continue;
+        };
+        if query.matches(&entry) {
+            if latest.len() == query.limit {
+                latest.pop_front();
+            }
+            latest.push_back(entry);
+        }
+    }
+    Ok(latest.into_iter().rev().collect())
+}
@@ -0,0 +1,113 @@
+// listings of static directories, for mounts with `autoindex` set
+//
+// a directory without an index.html is answered with the names, sizes and
+// modification times of what it holds, directories first. browsers get an
+// HTML page, clients that ask for application/json and not text/html get the
+// same as a JSON array. hidden entries are left out, they can't be fetched.
+
+use crate::formats;
+use crate::http::{http_date, Response, StatusCode};
+use crate::pagination::percent_encode;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::json;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+struct Entry {
+    name: String,
+    directory: bool,
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+// the listing of `directory`, reached through the request path `path`
+pub(crate) fn listing(directory: &Path, path: &str, accept: Option<&str>) -> io::Result<Response> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        if name.starts_with('.') {
+            continue;
+        }
+        // symlinks are listed as what they point to
+        let Ok(metadata) = fs::metadata(entry.path()) else {
+            continue;
+        };
+        entries.push(Entry {
+            name,
+            directory: metadata.is_dir(),
+            size: metadata.len(),
+            modified: metadata.modified().ok(),
+        });
+    }
+    entries.sort_by(|a, b| b.directory.cmp(&a.directory).then_with(|| a.name.cmp(&b.name)));
+
+    let base = if path.ends_with('/') { path.to_string() } else { format!("{path}/") };
+    let response = if wants_json(accept) {
+        Response::json(StatusCode::Ok, to_json(&entries).to_string())
+    } else {
+        Response::new(StatusCode::Ok)
+            .content_type("text/html; charset=utf-8")
+            .body(to_html(&entries, &base).into_bytes())
+    };
+    Ok(response.header("Vary", "Accept"))
+}
+
+// JSON only for clients naming it without naming HTML, browsers send both kinds of */*
+fn wants_json(accept: Option<&str>) -> bool {
+    let names = |media_type: &str| {
+        accept.is_some_and(|accept| {
+            accept.split(',').any(|range| {
+                let mut parts = range.split(';');
+                let named = parts.next().unwrap_or("").trim().eq_ignore_ascii_case(media_type);
+                let refused = parts.any(|param| param.trim().strip_prefix("q=").is_some_and(|q| q.trim().parse() == Ok(0.0)));
+                named && !refused
+            })
+        })
+    };
+    names("application/json") && !names("text/html")
+}
+
+fn to_json(entries: &[Entry]) -> serde_json::Value {
+    entries
+        .iter()
+        .map(|entry| {
+            let modified = entry
+                .modified
+                .map(|modified| DateTime::<Utc>::from(modified).to_rfc3339_opts(SecondsFormat::Secs, true));
+            json!({
+                "name": entry.name,
+                "type": if entry.directory { "directory" } else { "file" },
+                "size": if entry.directory { None } else { Some(entry.size) },
+                "modified": modified,
+            })
+        })
+        .collect()
+}
+
+fn to_html(entries: &[Entry], base: &str) -> String {
+    let title = format!("Index of {}", formats::xml_escape(base));
+    let mut rows = String::new();
+    if base != "/" {
+        rows.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for entry in entries {
+        let slash = if entry.directory { "/" } else { "" };
+        let href = format!("{base}{}{slash}", percent_encode(&entry.name));
+        let size = if entry.directory { String::new() } else { entry.size.to_string() };
+        let modified = entry.modified.map(|modified| http_date(modified.into())).unwrap_or_default();
+        rows.push_str(&format!(
+            "<tr><td><a href=\"{}\">{}{slash}</a></td><td>{size}</td><td>{modified}</td></tr>\n",
+            formats::xml_escape(&href),
+            formats::xml_escape(&entry.name)
+        ));
+    }
+    format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head><meta charset=\"utf-8\"><title>{title}</title></head>\n<body>\n<h1>{title}</h1>\n\
+         <table>\n<tr><th>Name</th><th>Size</th><th>Modified</th></tr>\n{rows}</table>\n</body>\n</html>\n"
+    )
+}
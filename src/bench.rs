@@ -0,0 +1,191 @@
+// a load generator for a running server, `bench --url /entries --connections 50 --duration 30s`
+//
+// every connection is a job on the crate's ThreadPool that sends the same GET
+// over one keep-alive connection as fast as the answers come back, opening a
+// new connection when the server closes one, until the duration is up. the
+// time from writing a request to reading the end of its response is one
+// latency sample. the report has the requests per second and the 50th, 90th
+// and 99th percentile and slowest latency. a response other than 2xx or 3xx
+// and a connection that fails count as errors and aren't sampled.
+
+use crate::client::{Client, Outgoing};
+use crate::config::Config;
+use rust_http_server::ThreadPool;
+use std::fmt;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// a server that doesn't answer in this long fails the request
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+// the wait before trying again after a request failed
+const RETRY: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Options {
+    // host:port of the server
+    pub(crate) address: String,
+    // path and query asked for
+    pub(crate) path: String,
+    pub(crate) connections: usize,
+    pub(crate) duration: Duration,
+}
+
+// the options after `bench`, with the server of `config` unless --url names another
+pub(crate) fn parse_args(args: &[String], config: &Config) -> Result<Options, String> {
+    let mut options = Options {
+        address: local_address(&config.host, config.port),
+        path: "/".to_string(),
+        connections: 10,
+        duration: Duration::from_secs(10),
+    };
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let value = args.next().ok_or_else(|| format!("{flag} needs a value"))?;
+        match flag.as_str() {
+            "--url" => match value.strip_prefix("http://") {
+                Some(rest) => {
+                    let (address, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+                    options.address = if address.contains(':') { address.to_string() } else { format!("{address}:80") };
+                    options.path = if path.is_empty() { "/".to_string() } else { path.to_string() };
+                }
+                None if value.starts_with('/') => options.path = value.clone(),
+                None => return Err(format!("--url {value} is neither a path nor an http:// URL")),
+            },
+            "--connections" => {
+                options.connections = value
+                    .parse()
+                    .ok()
+                    .filter(|&connections| connections > 0)
+                    .ok_or_else(|| format!("--connections {value} is not a positive number"))?;
+            }
+            "--duration" => {
+                options.duration =
+                    parse_duration(value).ok_or_else(|| format!("--duration {value} is not like 30s, 500ms or 2m"))?;
+            }
+            _ => return Err(format!("Unknown option {flag}")),
+        }
+    }
+    Ok(options)
+}
+
+// "30s", "500ms" or "2m", a bare number is seconds
+pub(crate) fn parse_duration(value: &str) -> Option<Duration> {
+    let (number, unit) = value.split_at(value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len()));
+    let number: u64 = number.parse().ok()?;
+    match unit {
+        "ms" => Some(Duration::from_millis(number)),
+        "" | "s" => Some(Duration::from_secs(number)),
+        "m" => Some(Duration::from_secs(number * 60)),
+        _ => None,
+    }
+}
+
+// where a server listening on `host` is reached from this machine
+fn local_address(host: &str, port: u16) -> String {
+    match host {
+        "0.0.0.0" => format!("127.0.0.1:{port}"),
+        "::" | "[::]" => format!("[::1]:{port}"),
+        host if host.contains(':') && !host.starts_with('[') => format!("[{host}]:{port}"),
+        host => format!("{host}:{port}"),
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Report {
+    pub(crate) connections: usize,
+    pub(crate) elapsed: Duration,
+    pub(crate) requests: u64,
+    pub(crate) errors: u64,
+    // of the successful requests, fastest first
+    pub(crate) latencies: Vec<Duration>,
+}
+
+impl Report {
+    pub(crate) fn throughput(&self) -> f64 {
+        self.requests as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    // the latency `percent` of the samples are no slower than, zero without samples
+    pub(crate) fn percentile(&self, percent: f64) -> Duration {
+        let rank = (percent / 100.0 * self.latencies.len() as f64).ceil() as usize;
+        self.latencies.get(rank.saturating_sub(1)).copied().unwrap_or_default()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} connections for {:.1?}", self.connections, self.elapsed)?;
+        writeln!(
+            f,
+            "{} requests, {} errors, {:.1} requests/s",
+            self.requests,
+            self.errors,
+            self.throughput()
+        )?;
+        write!(
+            f,
+            "latency p50 {:.1?}, p90 {:.1?}, p99 {:.1?}, max {:.1?}",
+            self.percentile(50.0),
+            self.percentile(90.0),
+            self.percentile(99.0),
+            self.latencies.last().copied().unwrap_or_default()
+        )
+    }
+}
+
+// what one connection did
+#[derive(Default)]
+struct Tally {
+    requests: u64,
+    errors: u64,
+    latencies: Vec<Duration>,
+}
+
+pub(crate) fn run(options: &Options) -> Report {
+    let request = Outgoing::new("GET", &options.path)
+        .header("User-Agent", "rust-http-server-bench")
+        .header("Accept", "*/*");
+    let started = Instant::now();
+    let deadline = started + options.duration;
+    let (sender, tallies) = mpsc::channel();
+    {
+        let pool = ThreadPool::new(options.connections);
+        for _ in 0..options.connections {
+            let (address, request, sender) = (options.address.clone(), request.clone(), sender.clone());
+            pool.execute(move || {
+                let _ = sender.send(connection(&address, &request, deadline));
+            });
+        }
+        // dropping the pool waits for every job
+    }
+    drop(sender);
+    let mut report = Report { connections: options.connections, elapsed: started.elapsed(), ..Report::default() };
+    for tally in tallies {
+        report.requests += tally.requests;
+        report.errors += tally.errors;
+        report.latencies.extend(tally.latencies);
+    }
+    report.latencies.sort_unstable();
+    report
+}
+
+// sends `request` again and again until `deadline`
+fn connection(address: &str, request: &Outgoing, deadline: Instant) -> Tally {
+    let mut tally = Tally::default();
+    let mut client = Client::new(address).connect_timeout(IO_TIMEOUT).timeout(IO_TIMEOUT);
+    while Instant::now() < deadline {
+        let sent = Instant::now();
+        tally.requests += 1;
+        match client.send(request) {
+            Ok(reply) if (200..400).contains(&reply.status) => tally.latencies.push(sent.elapsed()),
+            Ok(_) => tally.errors += 1,
+            Err(_) => {
+                tally.errors += 1;
+                thread::sleep(RETRY);
+            }
+        }
+    }
+    tally
+}
@@ -0,0 +1,81 @@
+// Cache-Control policies, declared per route and overridable from the config
+//
+// a route declares its default with `.cache(policy)` in the route table, the
+// `cache_control` config map replaces it per path ("/entries") or per prefix
+// ("/uploads/*"). the policy is only added to successful responses, errors
+// are never worth caching.
+
+use crate::config::Config;
+use crate::http::{Response, StatusCode};
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(try_from = "String")]
+pub(crate) enum CachePolicy {
+    // never store the response
+    NoStore,
+    // store it, but revalidate (ETag, Last-Modified) before every reuse
+    NoCache,
+    // reuse it without asking for this many seconds
+    MaxAge(u32),
+}
+
+impl CachePolicy {
+    pub(crate) fn header_value(self) -> String {
+        match self {
+            CachePolicy::NoStore => "no-store".to_string(),
+            CachePolicy::NoCache => "no-cache".to_string(),
+            CachePolicy::MaxAge(seconds) => format!("max-age={seconds}"),
+        }
+    }
+}
+
+// "no-store", "no-cache" or "max-age=<seconds>", as written in config.json
+impl TryFrom<String> for CachePolicy {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<CachePolicy, String> {
+        match value.trim() {
+            "no-store" => Ok(CachePolicy::NoStore),
+            "no-cache" => Ok(CachePolicy::NoCache),
+            other => other
+                .strip_prefix("max-age=")
+                .and_then(|seconds| seconds.parse().ok())
+                .map(CachePolicy::MaxAge)
+                .ok_or_else(|| format!("invalid cache policy {other:?}, expected no-store, no-cache or max-age=<seconds>")),
+        }
+    }
+}
+
+// the configured policy for a path, an exact entry winning over a prefix one
+pub(crate) fn configured(config: &Config, path: &str) -> Option<CachePolicy> {
+    if let Some(policy) = config.cache_control.get(path) {
+        return Some(*policy);
+    }
+    config
+        .cache_control
+        .iter()
+        .filter_map(|(pattern, policy)| Some((pattern.strip_suffix('*')?, policy)))
+        .filter(|(prefix, _)| path.starts_with(prefix))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, policy)| *policy)
+}
+
+// sets Cache-Control on a successful response: the configured policy if
+// there is one, else whatever the handler set, else the route's declared one
+pub(crate) fn apply(config: &Config, path: &str, declared: Option<CachePolicy>, response: Response) -> Response {
+    if !matches!(response.status, StatusCode::Ok | StatusCode::NoContent | StatusCode::PartialContent) {
+        return response;
+    }
+    let policy = match configured(config, path) {
+        Some(policy) => policy,
+        None if response.headers.get("Cache-Control").is_some() => return response,
+        None => match declared {
+            Some(policy) => policy,
+            None => return response,
+        },
+    };
+    let mut response = response;
+    response.headers.insert("Cache-Control", policy.header_value());
+    response
+}
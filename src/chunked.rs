@@ -0,0 +1,151 @@
+// response bodies written while they are sent, with chunked transfer coding
+//
+// a large JSON array isn't serialized into one String first. the response
+// carries what writes it, run when the connection sends the body, every
+// CHUNK bytes going out as one chunk, so memory stays flat however many
+// entries there are. it is run once up front as well, into a hasher, for the
+// ETag and length without keeping any of it. the writer holds its own copy of
+// the data, so what is sent always matches them.
+
+use crate::compression::{self, Encoding};
+use crate::conditional::EtagHasher;
+use crate::json;
+use serde::Serialize;
+use std::io::{self, Write};
+use std::sync::Arc;
+
+// bytes gathered before they go out as a chunk
+const CHUNK: usize = 16 * 1024;
+
+type Writer = dyn Fn(&mut dyn Write) -> io::Result<()> + Send + Sync;
+
+#[derive(Clone)]
+pub(crate) struct StreamBody {
+    measured: Arc<Measured>,
+    encoding: Option<Encoding>,
+}
+
+// the writer with the length and tag of what it writes, before any encoding
+struct Measured {
+    write: Box<Writer>,
+    length: u64,
+    etag: String,
+}
+
+impl StreamBody {
+    // the JSON array of `items`, serialized one at a time
+    pub(crate) fn json_array<T: Serialize + Send + Sync + 'static>(items: Vec<T>) -> io::Result<StreamBody> {
+        // read once, a config reload can't change the bytes after they were measured
+        let canonical = crate::config::get().canonical_json;
+        let write = move |out: &mut dyn Write| -> io::Result<()> {
+            out.write_all(b"[")?;
+            for (index, item) in items.iter().enumerate() {
+                if index > 0 {
+                    out.write_all(b",")?;
+                }
+                let item = if canonical { json::to_canonical_string(item)? } else { serde_json::to_string(item)? };
+                out.write_all(item.as_bytes())?;
+            }
+            out.write_all(b"]")
+        };
+        StreamBody::new(Box::new(write))
+    }
+
+    fn new(write: Box<Writer>) -> io::Result<StreamBody> {
+        let mut measure = Measure { hasher: EtagHasher::default(), length: 0 };
+        write(&mut measure)?;
+        let measured = Measured { write, length: measure.length, etag: measure.hasher.etag() };
+        Ok(StreamBody { measured: Arc::new(measured), encoding: None })
+    }
+
+    pub(crate) fn length(&self) -> u64 {
+        self.measured.length
+    }
+
+    pub(crate) fn etag(&self) -> &str {
+        &self.measured.etag
+    }
+
+    // compresses the body as it is sent
+    pub(crate) fn encode(&mut self, encoding: Encoding) {
+        self.encoding = Some(encoding);
+    }
+
+    // the body without chunked coding, for HTTP/1.0 clients that don't know it
+    pub(crate) fn plain(&self) -> io::Result<Vec<u8>> {
+        match self.encoding {
+            Some(encoding) => compression::compress_into(Vec::new(), encoding, |out| (self.measured.write)(out)),
+            None => {
+                let mut bytes = Vec::new();
+                (self.measured.write)(&mut bytes)?;
+                Ok(bytes)
+            }
+        }
+    }
+
+    // writes the body as chunks and the last, empty one
+    pub(crate) fn send(&self, out: &mut dyn Write) -> io::Result<()> {
+        let mut chunked = Chunked { out, buffer: Vec::with_capacity(CHUNK) };
+        match self.encoding {
+            Some(encoding) => {
+                compression::compress_into(&mut chunked, encoding, |out| (self.measured.write)(out))?;
+            }
+            None => (self.measured.write)(&mut chunked)?,
+        }
+        chunked.flush()?;
+        chunked.out.write_all(b"0\r\n\r\n")?;
+        chunked.out.flush()
+    }
+
+    // the chunked body in memory, for writers that aren't a socket
+    pub(crate) fn read(&self) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        self.send(&mut bytes)?;
+        Ok(bytes)
+    }
+}
+
+// counts and hashes what is written, keeping nothing
+struct Measure {
+    hasher: EtagHasher,
+    length: u64,
+}
+
+impl Write for Measure {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.hasher.update(bytes);
+        self.length += bytes.len() as u64;
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// gathers writes into chunks of about CHUNK bytes
+struct Chunked<'a> {
+    out: &'a mut dyn Write,
+    buffer: Vec<u8>,
+}
+
+impl Write for Chunked<'_> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(bytes);
+        if self.buffer.len() >= CHUNK {
+            self.flush()?;
+        }
+        Ok(bytes.len())
+    }
+
+    // sends what was gathered as one chunk, an empty one would end the body
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let mut chunk = format!("{:x}\r\n", self.buffer.len()).into_bytes();
+        chunk.append(&mut self.buffer);
+        chunk.extend_from_slice(b"\r\n");
+        self.out.write_all(&chunk)
+    }
+}
@@ -0,0 +1,270 @@
+// the HTTP/1.1 client the server talks to other servers with
+//
+// the proxy, the sign-in's calls to the provider, the bench subcommand and
+// the test suite all send their requests and read the answers through here.
+// a request is built like a Response, header by header, and written with a
+// Host and a Content-Length of its own. a response is read whole: interim 1xx
+// answers are skipped and the body is framed by Transfer-Encoding: chunked,
+// Content-Length or the end of the connection. a Client keeps its connection
+// open between requests for as long as the server does, opening a new one
+// when it closed it, and every connection gets its connect and I/O timeouts.
+
+use crate::http::HeaderMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+pub(crate) const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// how long the other server may leave a read or write waiting
+pub(crate) const IO_TIMEOUT: Duration = Duration::from_secs(30);
+// longest status line or header line accepted
+const MAX_LINE: u64 = 16 * 1024;
+
+// a request to send, `Outgoing::new("GET", "/entries").header("Accept", "text/csv")`
+#[derive(Debug, Clone)]
+pub(crate) struct Outgoing {
+    pub(crate) method: String,
+    // the path and query
+    pub(crate) target: String,
+    pub(crate) headers: HeaderMap,
+    pub(crate) body: Vec<u8>,
+}
+
+impl Outgoing {
+    pub(crate) fn new(method: &str, target: &str) -> Outgoing {
+        Outgoing { method: method.to_string(), target: target.to_string(), headers: HeaderMap::new(), body: Vec::new() }
+    }
+
+    pub(crate) fn header(mut self, name: &str, value: impl Into<String>) -> Outgoing {
+        self.headers.append(name, value.into());
+        self
+    }
+
+    #[cfg_attr(not(feature = "oauth"), allow(dead_code))]
+    pub(crate) fn body(mut self, body: impl Into<Vec<u8>>) -> Outgoing {
+        self.body = body.into();
+        self
+    }
+
+    // the bytes sent to `host`, which the Host header names unless set already
+    pub(crate) fn to_bytes(&self, host: &str) -> Vec<u8> {
+        let mut head = format!("{} {} HTTP/1.1\r\n", self.method, self.target);
+        if self.headers.get("Host").is_none() {
+            head.push_str(&format!("Host: {host}\r\n"));
+        }
+        for (name, value) in self.headers.iter() {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        if !self.body.is_empty() || matches!(self.method.as_str(), "POST" | "PUT" | "PATCH") {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        head.push_str("\r\n");
+        let mut bytes = head.into_bytes();
+        bytes.extend_from_slice(&self.body);
+        bytes
+    }
+}
+
+// a response read off a connection
+#[derive(Debug, Clone)]
+pub(crate) struct Reply {
+    pub(crate) status: u16,
+    pub(crate) headers: HeaderMap,
+    pub(crate) body: Vec<u8>,
+    // whether the connection can carry another request
+    pub(crate) keep_alive: bool,
+}
+
+// sends requests to one server, over the same connection while it stays open
+pub(crate) struct Client {
+    // host:port, what is connected to and the Host header
+    address: String,
+    connect_timeout: Duration,
+    timeout: Duration,
+    open: Option<BufReader<TcpStream>>,
+}
+
+impl Client {
+    pub(crate) fn new(address: &str) -> Client {
+        Client {
+            address: address.to_string(),
+            connect_timeout: CONNECT_TIMEOUT,
+            timeout: IO_TIMEOUT,
+            open: None,
+        }
+    }
+
+    pub(crate) fn connect_timeout(mut self, timeout: Duration) -> Client {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    // how long a read or write may wait
+    pub(crate) fn timeout(mut self, timeout: Duration) -> Client {
+        self.timeout = timeout;
+        self
+    }
+
+    // the server's answer to `request`
+    pub(crate) fn send(&mut self, request: &Outgoing) -> io::Result<Reply> {
+        let bytes = request.to_bytes(&self.address);
+        let head_only = request.method == "HEAD";
+        // a request that may have been acted on isn't sent twice
+        let retry = !matches!(request.method.as_str(), "POST" | "PATCH");
+        let result = match self.open.take() {
+            // the server may have closed a connection left open, which is
+            // only found out by using it, the request goes again on a new one
+            Some(mut reader) => match exchange(&mut reader, &bytes, head_only) {
+                Err(e) if retry && closed(&e) => self.exchange_fresh(&bytes, head_only),
+                result => result.map(|reply| (reply, reader)),
+            },
+            None => self.exchange_fresh(&bytes, head_only),
+        };
+        let (reply, reader) = result?;
+        if reply.keep_alive {
+            self.open = Some(reader);
+        }
+        Ok(reply)
+    }
+
+    fn exchange_fresh(&self, bytes: &[u8], head_only: bool) -> io::Result<(Reply, BufReader<TcpStream>)> {
+        let mut reader = BufReader::new(connect(self.address.as_str(), self.connect_timeout, self.timeout)?);
+        let reply = exchange(&mut reader, bytes, head_only)?;
+        Ok((reply, reader))
+    }
+}
+
+fn exchange(reader: &mut BufReader<TcpStream>, bytes: &[u8], head_only: bool) -> io::Result<Reply> {
+    reader.get_mut().write_all(bytes)?;
+    read_response(reader, head_only)
+}
+
+// whether `error` is a connection the server closed before answering
+fn closed(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset | io::ErrorKind::BrokenPipe
+    )
+}
+
+// a connection to the first address `address` resolves to
+pub(crate) fn connect(address: impl ToSocketAddrs, connect_timeout: Duration, timeout: Duration) -> io::Result<TcpStream> {
+    let address = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "the host resolved to no address"))?;
+    let stream = TcpStream::connect_timeout(&address, connect_timeout)?;
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    Ok(stream)
+}
+
+// the final response on `reader`, without a body when it answers a HEAD
+pub(crate) fn read_response<R: Read>(reader: &mut BufReader<R>, head_only: bool) -> io::Result<Reply> {
+    // interim 1xx responses come before the final one
+    let (status, version_1_1, headers) = loop {
+        let head = read_head(reader)?;
+        if !(100..200).contains(&head.0) {
+            break head;
+        }
+    };
+    let mut keep_alive = match headers.get("Connection") {
+        Some(connection) => !connection.eq_ignore_ascii_case("close") && (version_1_1 || connection.eq_ignore_ascii_case("keep-alive")),
+        None => version_1_1,
+    };
+    let mut reply = Reply { status, headers, body: Vec::new(), keep_alive };
+    if head_only || status == 204 || status == 304 {
+        return Ok(reply);
+    }
+    let chunked = reply
+        .headers
+        .get("Transfer-Encoding")
+        .is_some_and(|value| value.to_ascii_lowercase().contains("chunked"));
+    reply.body = if chunked {
+        read_chunked(reader)?
+    } else if let Some(length) = reply.headers.get("Content-Length") {
+        let length = length.trim().parse().map_err(|_| invalid("an invalid Content-Length"))?;
+        let mut body = Vec::new();
+        reader.take(length).read_to_end(&mut body)?;
+        if (body.len() as u64) < length {
+            return Err(invalid("a body shorter than its Content-Length"));
+        }
+        body
+    } else {
+        // the body ends where the connection does
+        keep_alive = false;
+        let mut body = Vec::new();
+        reader.read_to_end(&mut body)?;
+        body
+    };
+    reply.keep_alive = keep_alive;
+    Ok(reply)
+}
+
+// the status code, whether it is HTTP/1.1 and the headers of one response head
+fn read_head<R: Read>(reader: &mut BufReader<R>) -> io::Result<(u16, bool, HeaderMap)> {
+    let status_line = read_line(reader)?;
+    let code = status_line
+        .strip_prefix("HTTP/1.")
+        .and_then(|rest| rest.split(' ').nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| invalid("an invalid status line"))?;
+    let version_1_1 = status_line.starts_with("HTTP/1.1");
+    let mut headers = HeaderMap::new();
+    loop {
+        let line = read_line(reader)?;
+        if line.is_empty() {
+            return Ok((code, version_1_1, headers));
+        }
+        let (name, value) = line.split_once(':').ok_or_else(|| invalid("an invalid header line"))?;
+        headers.append(name.trim(), value.trim());
+    }
+}
+
+fn read_chunked<R: Read>(reader: &mut BufReader<R>) -> io::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let line = read_line(reader)?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = u64::from_str_radix(size, 16).map_err(|_| invalid("an invalid chunk size"))?;
+        if size == 0 {
+            // trailers, up to the blank line ending the body
+            while !read_line(reader)?.is_empty() {}
+            return Ok(body);
+        }
+        let read = reader.take(size).read_to_end(&mut body)?;
+        if (read as u64) < size {
+            return Err(invalid("a chunk cut short"));
+        }
+        read_line(reader)?;
+    }
+}
+
+fn read_line<R: Read>(reader: &mut BufReader<R>) -> io::Result<String> {
+    let mut line = String::new();
+    if reader.take(MAX_LINE).read_line(&mut line)? == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the server closed the connection"));
+    }
+    if !line.ends_with('\n') {
+        return Err(invalid("a line cut short or too long"));
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("the server sent {what}"))
+}
+
+// writes `request` as it is, however malformed, and returns everything the
+// server sends until it closes the connection
+#[cfg(test)]
+pub(crate) fn send_raw(address: &str, request: &[u8]) -> io::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(address)?;
+    stream.write_all(request)?;
+    // nothing follows the request on the connection
+    stream.shutdown(std::net::Shutdown::Write)?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    Ok(response)
+}
@@ -0,0 +1,67 @@
+// wall-clock helpers that tolerate skew between this server and its clients
+//
+// every expiry check goes through is_expired, which allows the configured
+// leeway, so a server whose clock drifts a little doesn't invalidate every
+// session at once. the monitor thread warns when the system time jumps.
+
+use crate::{config, log};
+#[cfg(any(feature = "oauth", feature = "templates"))]
+use chrono::{DateTime, Utc};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+// how often the monitor compares the wall clock with the monotonic clock
+const MONITOR_INTERVAL: Duration = Duration::from_secs(10);
+
+#[cfg(any(feature = "oauth", feature = "templates"))]
+pub(crate) fn leeway() -> chrono::Duration {
+    chrono::Duration::seconds(config::get().clock_skew_leeway_secs as i64)
+}
+
+// true once `expiration` (plus leeway) is in the past
+#[cfg(any(feature = "oauth", feature = "templates"))]
+pub(crate) fn is_expired<Tz: chrono::TimeZone>(expiration: &DateTime<Tz>) -> bool {
+    expiration.with_timezone(&Utc) + leeway() < Utc::now()
+}
+
+// warns whenever the system time moves differently from the monotonic clock
+pub(crate) fn spawn_monitor() {
+    thread::spawn(|| {
+        let threshold = Duration::from_secs(config::get().clock_jump_threshold_secs);
+        let mut last_wall = SystemTime::now();
+        let mut last_instant = Instant::now();
+        loop {
+            thread::sleep(MONITOR_INTERVAL);
+            let (wall, instant) = (SystemTime::now(), Instant::now());
+            if let Some(jump) = clock_jump(last_wall, wall, instant - last_instant, threshold) {
+                log::warning!(
+                    "Warning: system clock jumped {} by {:?}, expiry checks may be off",
+                    if jump > 0 { "forward" } else { "backward" },
+                    Duration::from_millis(jump.unsigned_abs()),
+                );
+            }
+            last_wall = wall;
+            last_instant = instant;
+        }
+    });
+}
+
+// signed difference in milliseconds between how far the wall clock moved and how
+// much time really elapsed, when it exceeds the threshold
+pub(crate) fn clock_jump(
+    previous_wall: SystemTime,
+    current_wall: SystemTime,
+    elapsed: Duration,
+    threshold: Duration,
+) -> Option<i64> {
+    let wall_elapsed = match current_wall.duration_since(previous_wall) {
+        Ok(forward) => forward.as_millis() as i64,
+        Err(backward) => -(backward.duration().as_millis() as i64),
+    };
+    let jump = wall_elapsed - elapsed.as_millis() as i64;
+    if jump.unsigned_abs() > threshold.as_millis() as u64 {
+        Some(jump)
+    } else {
+        None
+    }
+}
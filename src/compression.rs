@@ -0,0 +1,148 @@
+// response compression negotiated from Accept-Encoding, and decoding of
+// request bodies sent with a Content-Encoding
+//
+// response compression is disabled unless `compression` is set in the config.
+// responses smaller than `compression_min_bytes` are sent as-is, compressing
+// them costs more than it saves. request bodies are always decoded, up to
+// `max_decompressed_body_bytes` so a small compressed body can't expand without bound.
+
+use crate::config::Config;
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use flate2::write::{DeflateEncoder, GzEncoder};
+use flate2::Compression;
+use std::io::{self, Read, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    // the Content-Encoding token, also used to tell representations apart in etags
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    // the encoding named by a Content-Encoding token
+    pub(crate) fn from_name(name: &str) -> Option<Encoding> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            "deflate" => Some(Encoding::Deflate),
+            _ => None,
+        }
+    }
+}
+
+// the encoding to apply to a body of `length` bytes, if any
+pub(crate) fn choose(config: &Config, accept_encoding: Option<&str>, length: usize) -> Option<Encoding> {
+    if !config.compression || length < config.compression_min_bytes {
+        return None;
+    }
+    negotiate(accept_encoding?)
+}
+
+// picks the preferred supported encoding from an Accept-Encoding header,
+// skipping codings the client refused with q=0
+pub(crate) fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+    let mut best: Option<(Encoding, f32)> = None;
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let coding = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        let quality = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        let encoding = match coding.as_str() {
+            "gzip" | "x-gzip" | "*" => Encoding::Gzip,
+            "deflate" => Encoding::Deflate,
+            _ => continue,
+        };
+        if quality > 0.0 && best.is_none_or(|(_, best_quality)| quality > best_quality) {
+            best = Some((encoding, quality));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+// whether an Accept-Encoding header takes `coding`, by name or through "*",
+// a coding it names itself isn't taken by a "*" when refused with q=0
+pub(crate) fn accepts(accept_encoding: &str, coding: &str) -> bool {
+    let mut wildcard = false;
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or("").trim();
+        let quality = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if name.eq_ignore_ascii_case(coding) {
+            return quality > 0.0;
+        }
+        if name == "*" {
+            wildcard = quality > 0.0;
+        }
+    }
+    wildcard
+}
+
+pub(crate) fn compress(body: &[u8], encoding: Encoding) -> Vec<u8> {
+    match encoding {
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(body).expect("Writing to a Vec can't fail");
+            encoder.finish().expect("Writing to a Vec can't fail")
+        }
+        Encoding::Deflate => {
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(body).expect("Writing to a Vec can't fail");
+            encoder.finish().expect("Writing to a Vec can't fail")
+        }
+    }
+}
+
+// what `write` produces, compressed on its way to `out`, for bodies never held whole
+pub(crate) fn compress_into<W: Write>(
+    out: W,
+    encoding: Encoding,
+    write: impl FnOnce(&mut dyn Write) -> io::Result<()>,
+) -> io::Result<W> {
+    match encoding {
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(out, Compression::default());
+            write(&mut encoder)?;
+            encoder.finish()
+        }
+        Encoding::Deflate => {
+            let mut encoder = DeflateEncoder::new(out, Compression::default());
+            write(&mut encoder)?;
+            encoder.finish()
+        }
+    }
+}
+
+// decodes a request body, failing with FileTooLarge once it grows past `limit` bytes
+pub(crate) fn decompress(body: &[u8], encoding: Encoding, limit: usize) -> io::Result<Vec<u8>> {
+    let decoder: Box<dyn Read + '_> = match encoding {
+        Encoding::Gzip => Box::new(GzDecoder::new(body)),
+        // "deflate" is zlib-wrapped (RFC 9110 8.4.1.2), but plenty of clients send raw deflate
+        Encoding::Deflate if is_zlib_header(body) => Box::new(ZlibDecoder::new(body)),
+        Encoding::Deflate => Box::new(DeflateDecoder::new(body)),
+    };
+    let mut decoded = Vec::new();
+    decoder.take(limit as u64 + 1).read_to_end(&mut decoded)?;
+    if decoded.len() > limit {
+        return Err(io::Error::new(io::ErrorKind::FileTooLarge, "decompressed body too large"));
+    }
+    Ok(decoded)
+}
+
+fn is_zlib_header(body: &[u8]) -> bool {
+    match body {
+        [cmf, flg, ..] => cmf & 0x0f == 8 && (u16::from(*cmf) << 8 | u16::from(*flg)) % 31 == 0,
+        _ => false,
+    }
+}
@@ -0,0 +1,126 @@
+// conditional requests (RFC 9110 13)
+//
+// handlers only tag their responses with an ETag or Last-Modified, the
+// preconditions are evaluated here once the response is known. a compressed
+// representation gets its own tag (`"<hash>-gzip"`), otherwise a cache could
+// be handed gzip bytes for a tag it stored with the identity body.
+
+use crate::compression::Encoding;
+use chrono::{DateTime, Utc};
+use crate::http::{self, HeaderMap, Response, StatusCode};
+use std::time::SystemTime;
+
+// headers a 304 keeps from the response it replaces (RFC 9110 15.4.5)
+const NOT_MODIFIED_HEADERS: [&str; 6] =
+    ["Cache-Control", "Content-Location", "ETag", "Expires", "Last-Modified", "Vary"];
+
+// a strong entity tag for a body, the quoted FNV-1a hash of its bytes
+pub(crate) fn etag(body: &[u8]) -> String {
+    let mut hasher = EtagHasher::default();
+    hasher.update(body);
+    hasher.etag()
+}
+
+// the hash behind `etag`, fed a piece at a time for bodies never held whole
+pub(crate) struct EtagHasher {
+    hash: u64,
+}
+
+impl Default for EtagHasher {
+    fn default() -> EtagHasher {
+        EtagHasher { hash: 0xcbf2_9ce4_8422_2325 }
+    }
+}
+
+impl EtagHasher {
+    pub(crate) fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.hash ^= u64::from(*byte);
+            self.hash = self.hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    pub(crate) fn etag(&self) -> String {
+        format!("\"{:016x}\"", self.hash)
+    }
+}
+
+// the tag of the same representation after `encoding` was applied
+pub(crate) fn encoded_etag(etag: &str, encoding: Encoding) -> String {
+    let (weak, opaque) = match etag.strip_prefix("W/") {
+        Some(opaque) => ("W/", opaque),
+        None => ("", etag),
+    };
+    let inner = opaque.trim_matches('"');
+    format!("{weak}\"{inner}-{}\"", encoding.name())
+}
+
+// whether an If-None-Match header lists the tag, using the weak comparison
+// the header calls for (RFC 9110 13.1.2)
+pub(crate) fn none_match(if_none_match: &str, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    if_none_match.trim() == "*" || if_none_match.split(',').any(|tag| opaque(tag) == opaque(etag))
+}
+
+// whether an If-Match header names the current tag. If-Match uses the strong
+// comparison, weak tags never match, but a tag handed out for a compressed
+// copy of the same data does
+pub(crate) fn if_match(if_match: &str, etag: &str) -> bool {
+    if if_match.trim() == "*" {
+        return true;
+    }
+    let current = [
+        etag.to_string(),
+        encoded_etag(etag, Encoding::Gzip),
+        encoded_etag(etag, Encoding::Deflate),
+    ];
+    if_match
+        .split(',')
+        .map(str::trim)
+        .any(|tag| !tag.starts_with("W/") && current.iter().any(|current| current == tag))
+}
+
+// the Last-Modified of something changed at `modified`, None while the second
+// it was changed in isn't over yet at `now`: HTTP dates count whole seconds,
+// a client told that second would be answered 304 after another change in it
+pub(crate) fn last_modified(modified: SystemTime, now: SystemTime) -> Option<String> {
+    let (modified, now) = (DateTime::<Utc>::from(modified), DateTime::<Utc>::from(now));
+    (modified.timestamp() < now.timestamp()).then(|| http::http_date(modified))
+}
+
+// whether a Last-Modified date is no later than If-Modified-Since, both
+// being HTTP dates with one second resolution
+pub(crate) fn not_modified_since(if_modified_since: &str, last_modified: &str) -> bool {
+    let parse = |date: &str| DateTime::parse_from_rfc2822(date.trim()).ok();
+    match (parse(if_modified_since), parse(last_modified)) {
+        (Some(since), Some(modified)) => modified <= since,
+        _ => false,
+    }
+}
+
+// replaces a successful GET or HEAD with 304 Not Modified when the client's
+// cached copy is current. If-Modified-Since only counts when the client sent
+// no If-None-Match, an entity tag is the more precise validator (RFC 9110 13.2.2)
+pub(crate) fn evaluate(method: &str, request_headers: &HeaderMap, response: Response) -> Response {
+    let cached = match request_headers.get("If-None-Match") {
+        Some(if_none_match) => response
+            .headers
+            .get("ETag")
+            .is_some_and(|etag| none_match(if_none_match, etag)),
+        None => match (request_headers.get("If-Modified-Since"), response.headers.get("Last-Modified")) {
+            (Some(since), Some(modified)) => not_modified_since(since, modified),
+            _ => false,
+        },
+    };
+    if !cached || !matches!(method, "GET" | "HEAD") || response.status != StatusCode::Ok {
+        return response;
+    }
+
+    let mut not_modified = Response::new(StatusCode::NotModified);
+    for (name, value) in response.headers.iter() {
+        if NOT_MODIFIED_HEADERS.iter().any(|kept| kept.eq_ignore_ascii_case(name)) {
+            not_modified = not_modified.header(name, value);
+        }
+    }
+    not_modified
+}
@@ -0,0 +1,297 @@
+#[cfg(feature = "acme")]
+use crate::acme::AcmeConfig;
+use crate::api_keys::ApiKeysConfig;
+use crate::api_version::Lifecycle;
+use crate::caching::CachePolicy;
+use crate::connections::ConnectionLimit;
+use crate::csrf::CsrfConfig;
+#[cfg(feature = "faults")]
+use crate::faults::FaultRule;
+use crate::ip_filter::{Cidr, IpFilter};
+use crate::listener::{ListenerConfig, SocketOptions};
+use crate::log::Level;
+#[cfg(feature = "oauth")]
+use crate::oauth::OAuthConfig;
+#[cfg(feature = "proxy")]
+use crate::proxy::ProxyRoute;
+use crate::quotas::QuotaConfig;
+use crate::rate_limit::RateLimit;
+use crate::redirects::Redirect;
+use crate::router::TrailingSlash;
+use crate::security_headers::SecurityHeaders;
+use crate::static_files::StaticMount;
+#[cfg(feature = "tls")]
+use crate::tls::TlsFiles;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
+
+pub(crate) const CONFIG_FILE: &str = "config.json";
+
+// settings read once at startup from config.json
+// every field is optional in the file, missing ones fall back to the defaults below
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub(crate) struct Config {
+    /// Address the server listens on.
+    pub(crate) host: String,
+    pub(crate) port: u16,
+    /// Inclusive range of ports tried in order when `port` is already taken.
+    pub(crate) fallback_ports: Option<(u16, u16)>,
+    /// Addresses to listen on instead of `host` and `port`, "[::]" for IPv6.
+    pub(crate) listeners: Vec<ListenerConfig>,
+    /// TCP_NODELAY, SO_REUSEADDR, SO_REUSEPORT and the accept backlog.
+    pub(crate) socket: SocketOptions,
+    /// Certificate and key to serve HTTPS with on `host` and `port`, PEM files.
+    #[cfg(feature = "tls")]
+    pub(crate) tls: Option<TlsFiles>,
+    /// Issue and renew the certificate in `tls` from an ACME CA such as Let's Encrypt.
+    #[cfg(feature = "acme")]
+    pub(crate) acme: Option<AcmeConfig>,
+    /// Reject ambiguous or oversized requests instead of parsing them leniently.
+    pub(crate) hardened: bool,
+    /// Most detailed messages logged: "error", "warn", "info" or "debug".
+    pub(crate) log_level: Level,
+    /// Requests taking longer than this many milliseconds are logged at WARN, 0 for none.
+    pub(crate) slow_request_ms: u64,
+    /// Requests each client address may send per window, unlimited when unset.
+    pub(crate) rate_limit: Option<RateLimit>,
+    /// Daily quotas on the bytes each client sends and is sent, off when unset.
+    pub(crate) quotas: Option<QuotaConfig>,
+    /// Connections answered at once, and whether the ones over it are rejected or wait.
+    pub(crate) connection_limit: Option<ConnectionLimit>,
+    /// Seconds a connection may sit idle between requests, 0 to close it after every response.
+    pub(crate) keep_alive_timeout_secs: u64,
+    /// Requests answered on one connection before it is closed, 0 for no limit.
+    pub(crate) max_requests_per_connection: usize,
+    /// Client address ranges let in or kept out, everywhere or for groups of paths.
+    pub(crate) ip_filter: IpFilter,
+    /// Proxies in front of the server, the X-Request-Id they send is kept and the
+    /// audit log takes the client from their X-Forwarded-For.
+    pub(crate) trusted_proxies: Vec<Cidr>,
+    /// Value of the Server response header, left out when empty.
+    pub(crate) server_name: String,
+    /// Security headers sent with every response, unset ones get a default and "" leaves one out.
+    pub(crate) security_headers: SecurityHeaders,
+    /// Security headers per path ("/docs") or prefix ("/uploads/*"), over
+    /// `security_headers` and the ones a handler sent.
+    pub(crate) route_security_headers: HashMap<String, SecurityHeaders>,
+    /// Double-submit token required on the state-changing requests that carry cookies, off when unset.
+    pub(crate) csrf: Option<CsrfConfig>,
+    /// Keys issued through /admin/api-keys for clients of the entry routes, off when unset.
+    pub(crate) api_keys: Option<ApiKeysConfig>,
+    /// Sign-in through an OAuth2 / OIDC provider for the admin routes and /ui, off when unset.
+    #[cfg(feature = "oauth")]
+    pub(crate) oauth: Option<OAuthConfig>,
+    /// JSON file the characters are stored in.
+    pub(crate) data_file: String,
+    /// Reload the data file when another process changes it.
+    pub(crate) reload_data_file: bool,
+    /// Directory the backups made through POST /admin/backup are written to.
+    pub(crate) backup_dir: String,
+    /// Layout used when the data file is rewritten.
+    pub(crate) data_format: DataFormat,
+    /// How new entries get their id.
+    pub(crate) id_strategy: IdStrategy,
+    /// Paths answered with a redirect before routing.
+    pub(crate) redirects: Vec<Redirect>,
+    /// Old path -> current path, served without a redirect.
+    pub(crate) aliases: HashMap<String, String>,
+    /// Whether "/entries/" reaches "/entries": "strict", "ignore" or "redirect".
+    pub(crate) trailing_slash: TrailingSlash,
+    /// Host name -> directory of static files served for it instead of the API.
+    pub(crate) virtual_hosts: HashMap<String, StaticMount>,
+    /// Paths forwarded to an upstream server, like "/legacy/*" to "http://127.0.0.1:9000".
+    #[cfg(feature = "proxy")]
+    pub(crate) proxy_routes: Vec<ProxyRoute>,
+    /// Latency, errors and broken connections injected on some paths, for testing clients.
+    #[cfg(feature = "faults")]
+    pub(crate) faults: Vec<FaultRule>,
+    /// Bearer token the /admin routes require, they are closed to everyone when unset.
+    pub(crate) admin_token: Option<String>,
+    /// Largest request body a client may declare with Content-Length, refused
+    /// with 413 before any of it is read. Uploads have `max_upload_bytes`.
+    pub(crate) max_body_bytes: u64,
+    /// Largest a gzip or deflate encoded request body may grow to once decoded.
+    pub(crate) max_decompressed_body_bytes: usize,
+    /// Directory uploaded files are stored in and served from.
+    pub(crate) upload_dir: String,
+    /// Largest multipart body accepted by the upload endpoint.
+    pub(crate) max_upload_bytes: u64,
+    /// Cache-Control policy per path ("/entries") or prefix ("/uploads/*"),
+    /// replacing what the route declares.
+    pub(crate) cache_control: HashMap<String, CachePolicy>,
+    /// Most rows a collection endpoint will serialize when no page was requested.
+    pub(crate) max_unpaginated_rows: usize,
+    /// Most bytes a collection endpoint will send when no page was requested.
+    pub(crate) max_unpaginated_bytes: usize,
+    /// JSON collection responses with at least this many entries are written while they are
+    /// sent, with chunked transfer coding, instead of built in memory first. 0 never streams.
+    pub(crate) stream_min_entries: usize,
+    /// Successful JSON and text answers of the entry and admin routes come as
+    /// { "status": "ok", "data": ... }. Off keeps the legacy bodies, like "Success!" as text.
+    pub(crate) success_envelope: bool,
+    /// Entries in the trash longer than this are removed for good. 0 keeps them until restored.
+    pub(crate) purge_deleted_after_secs: u64,
+    /// File every change to the entries is appended to, as JSON lines, empty to keep none.
+    pub(crate) audit_log: String,
+    /// Deprecation and Sunset announced by the responses of each API version, by name.
+    pub(crate) api_versions: HashMap<String, Lifecycle>,
+    /// Serialize JSON responses in canonical form (sorted keys, fixed float formatting).
+    pub(crate) canonical_json: bool,
+    /// Responses to GET requests for the entries kept in memory, 0 to keep none.
+    pub(crate) response_cache_entries: usize,
+    /// Compress responses for clients that send Accept-Encoding.
+    pub(crate) compression: bool,
+    /// Responses smaller than this are never compressed.
+    pub(crate) compression_min_bytes: usize,
+    /// File the server writes its process id to, `--reload` stops the process found there.
+    pub(crate) pid_file: Option<String>,
+    /// Seconds a stopping server waits for the connections it is answering.
+    pub(crate) drain_timeout_secs: u64,
+    /// Seconds the shutdown hooks get together once the connections are drained.
+    pub(crate) shutdown_timeout_secs: u64,
+    /// Seconds a handler may take before its request is answered 503, 0 for no limit.
+    pub(crate) handler_timeout_secs: u64,
+    /// Threads running the handlers that have a deadline. One past it keeps its
+    /// thread until it finishes, a request finding none free is answered 503.
+    pub(crate) handler_threads: usize,
+    /// Seconds of clock skew tolerated when checking expiry times.
+    pub(crate) clock_skew_leeway_secs: u64,
+    /// Warn when the system clock jumps by more than this many seconds.
+    pub(crate) clock_jump_threshold_secs: u64,
+}
+
+// on-disk layout of the data file, "pretty" for humans or "compact" for size
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum DataFormat {
+    Pretty,
+    Compact,
+}
+
+// "sequential" takes the highest id plus one, so the id of a deleted last
+// entry is handed out again. "counter" keeps the next id in a file next to the
+// data file and never reuses one.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum IdStrategy {
+    Sequential,
+    Counter,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            host: "127.0.0.1".to_string(),
+            port: 7878,
+            fallback_ports: None,
+            listeners: Vec::new(),
+            socket: SocketOptions::default(),
+            #[cfg(feature = "tls")]
+            tls: None,
+            #[cfg(feature = "acme")]
+            acme: None,
+            hardened: true,
+            log_level: Level::Debug,
+            slow_request_ms: 500,
+            rate_limit: None,
+            quotas: None,
+            connection_limit: None,
+            keep_alive_timeout_secs: 5,
+            max_requests_per_connection: 100,
+            ip_filter: IpFilter::default(),
+            trusted_proxies: Vec::new(),
+            server_name: concat!("rust-http-server/", env!("CARGO_PKG_VERSION")).to_string(),
+            security_headers: SecurityHeaders::default(),
+            route_security_headers: HashMap::new(),
+            csrf: None,
+            api_keys: None,
+            #[cfg(feature = "oauth")]
+            oauth: None,
+            data_file: "one_piece2.json".to_string(),
+            reload_data_file: true,
+            backup_dir: "backups".to_string(),
+            data_format: DataFormat::Pretty,
+            id_strategy: IdStrategy::Counter,
+            redirects: Vec::new(),
+            aliases: HashMap::new(),
+            trailing_slash: TrailingSlash::Redirect,
+            virtual_hosts: HashMap::new(),
+            #[cfg(feature = "proxy")]
+            proxy_routes: Vec::new(),
+            #[cfg(feature = "faults")]
+            faults: Vec::new(),
+            admin_token: None,
+            max_body_bytes: 8 * 1024 * 1024,
+            max_decompressed_body_bytes: 16 * 1024 * 1024,
+            upload_dir: "uploads".to_string(),
+            max_upload_bytes: 32 * 1024 * 1024,
+            cache_control: HashMap::new(),
+            max_unpaginated_rows: 10_000,
+            max_unpaginated_bytes: 16 * 1024 * 1024,
+            stream_min_entries: 1000,
+            success_envelope: false,
+            purge_deleted_after_secs: 30 * 24 * 60 * 60,
+            audit_log: "audit.log".to_string(),
+            api_versions: HashMap::new(),
+            canonical_json: false,
+            response_cache_entries: 64,
+            compression: false,
+            compression_min_bytes: 1024,
+            pid_file: None,
+            drain_timeout_secs: 30,
+            shutdown_timeout_secs: 10,
+            handler_timeout_secs: 30,
+            handler_threads: 32,
+            clock_skew_leeway_secs: 60,
+            clock_jump_threshold_secs: 5,
+        }
+    }
+}
+
+impl Config {
+    // reads the config file, using the defaults when it is missing or malformed
+    pub(crate) fn load(path: &Path) -> Config {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(_) => return Config::default(),
+        };
+        match serde_json::from_str(&contents) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("Invalid {}: {}, using defaults", path.display(), e);
+                Config::default()
+            }
+        }
+    }
+}
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+// installs the process-wide configuration, only the first call has an effect
+pub(crate) fn init(config: Config) {
+    let _ = CONFIG.set(config);
+}
+
+// returns the process-wide configuration (the defaults if init was never called)
+pub(crate) fn get() -> &'static Config {
+    CONFIG.get_or_init(default)
+}
+
+#[cfg(not(test))]
+fn default() -> Config {
+    Config::default()
+}
+
+// the tests run with the defaults and an admin token, without which they
+// couldn't reach the /admin routes
+#[cfg(test)]
+fn default() -> Config {
+    Config { admin_token: Some(TEST_ADMIN_TOKEN.to_string()), ..Config::default() }
+}
+
+#[cfg(test)]
+pub(crate) const TEST_ADMIN_TOKEN: &str = "test-admin-token";
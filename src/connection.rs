@@ -0,0 +1,70 @@
+// what is known about the connection a request came in on
+//
+// the servers fill it in once per connection and hand it down with every
+// request on it: the client and local addresses, the TLS version, cipher
+// suite and server name of an HTTPS connection, and the protocol ALPN
+// settled on. handlers read it as `request.connection`, the debug log shows
+// it with each request.
+
+use std::fmt;
+use std::net::SocketAddr;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct ConnectionInfo {
+    // the address of the client, None when the request didn't come over a socket
+    pub(crate) peer: Option<SocketAddr>,
+    // the address the client connected to
+    pub(crate) local: Option<SocketAddr>,
+    // None for a cleartext connection
+    pub(crate) tls: Option<TlsInfo>,
+    // the protocol negotiated with ALPN, "h2" or "http/1.1"
+    pub(crate) alpn: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct TlsInfo {
+    // like "TLSv1_3"
+    pub(crate) version: String,
+    // like "TLS13_AES_256_GCM_SHA384"
+    pub(crate) cipher: String,
+    // the host name the client asked for with SNI
+    pub(crate) sni: Option<String>,
+}
+
+impl ConnectionInfo {
+    // a cleartext connection between `peer` and `local`
+    pub(crate) fn plain(peer: Option<SocketAddr>, local: Option<SocketAddr>) -> ConnectionInfo {
+        ConnectionInfo { peer, local, ..ConnectionInfo::default() }
+    }
+
+    // whether it is encrypted, for the headers only sent over HTTPS
+    pub(crate) fn secure(&self) -> bool {
+        self.tls.is_some()
+    }
+}
+
+// "from 203.0.113.5:4000 to 0.0.0.0:8443 over TLSv1_3 TLS13_AES_256_GCM_SHA384 (SNI example.com), ALPN h2"
+impl fmt::Display for ConnectionInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.peer {
+            Some(peer) => write!(f, "from {peer}")?,
+            None => write!(f, "from an unknown client")?,
+        }
+        if let Some(local) = self.local {
+            write!(f, " to {local}")?;
+        }
+        match &self.tls {
+            Some(tls) => {
+                write!(f, " over {} {}", tls.version, tls.cipher)?;
+                if let Some(sni) = &tls.sni {
+                    write!(f, " (SNI {sni})")?;
+                }
+            }
+            None => write!(f, " in cleartext")?,
+        }
+        if let Some(alpn) = &self.alpn {
+            write!(f, ", ALPN {alpn}")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,84 @@
+// a cap on the connections answered at once
+//
+// every accepted connection takes a slot until it is answered, or handed off
+// as an event stream. with `connection_limit` set, a connection over `max`
+// is either answered 503 with Retry-After as soon as it is accepted, before
+// it can take a pool thread, or, with "when_full": "wait", the accept loops
+// stop accepting until a slot frees up and the kernel's backlog holds the
+// rest. the open count and the rejections are in the metrics.
+
+use crate::http::{Response, StatusCode};
+use crate::reload;
+use serde::Deserialize;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub(crate) struct ConnectionLimit {
+    pub(crate) max: usize,
+    #[serde(default)]
+    pub(crate) when_full: WhenFull,
+}
+
+// what happens to connections over the limit
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum WhenFull {
+    #[default]
+    Reject,
+    Wait,
+}
+
+// how often a waiting accept loop looks for a free slot
+pub(crate) const POLL: Duration = Duration::from_millis(10);
+
+static OPEN: AtomicUsize = AtomicUsize::new(0);
+
+static REJECTED: AtomicU64 = AtomicU64::new(0);
+
+// a connection counted as open for as long as it is alive
+pub(crate) struct Slot(());
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        OPEN.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// a slot for a connection just accepted, None when the limit is reached
+pub(crate) fn try_open(limit: Option<ConnectionLimit>) -> Option<Slot> {
+    let max = limit.map_or(usize::MAX, |limit| limit.max);
+    OPEN.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| (open < max).then_some(open + 1))
+        .ok()
+        .map(|_| Slot(()))
+}
+
+// whether the accept loops should hold off accepting until a slot frees up
+pub(crate) fn must_wait(limit: Option<ConnectionLimit>) -> bool {
+    limit.is_some_and(|limit| limit.when_full == WhenFull::Wait && OPEN.load(Ordering::SeqCst) >= limit.max)
+}
+
+// blocks while the accept loops must wait, or until the server is stopping
+pub(crate) fn wait_for_slot(limit: Option<ConnectionLimit>) {
+    while must_wait(limit) && !reload::stopping() {
+        thread::sleep(POLL);
+    }
+}
+
+// the answer to a connection over the limit, counted in the metrics. while
+// waiting that is one another listener's accept loop took the last slot from
+pub(crate) fn rejection() -> Response {
+    REJECTED.fetch_add(1, Ordering::Relaxed);
+    Response::problem(StatusCode::ServiceUnavailable, "The server has too many connections, try again shortly")
+        .header("Retry-After", "1")
+        .header("Connection", "close")
+}
+
+pub(crate) fn open() -> usize {
+    OPEN.load(Ordering::SeqCst)
+}
+
+pub(crate) fn rejected() -> u64 {
+    REJECTED.load(Ordering::Relaxed)
+}
@@ -0,0 +1,154 @@
+// CSRF protection for cookie-authenticated requests, with a double-submit token
+//
+// with `csrf` configured, the response to a client that has no token cookie
+// sets one, holding a random value the page's scripts can read. a POST, PUT,
+// PATCH or DELETE to a protected route that carries cookies must send that
+// value back, in the `header` or, from an HTML form, in the `field` of its
+// urlencoded body. otherwise it is refused with 403. another site can make
+// the browser send the cookies but can't read them to copy the token.
+// requests without cookies aren't authenticated by them, so they aren't
+// checked. `routes` lists the protected paths ("/entries") and prefixes
+// ("/admin/*"), every path is protected when it is empty:
+//
+//   "csrf": { "routes": ["/entries", "/entries/*"] }
+
+use crate::config::Config;
+use crate::http::{self, HeaderMap, Response, StatusCode};
+use crate::log;
+use crate::redirects::{self, Rewrite};
+use serde::Deserialize;
+
+// methods that change state, the ones a forged request is made with
+const UNSAFE_METHODS: [&str; 4] = ["POST", "PUT", "PATCH", "DELETE"];
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct CsrfConfig {
+    /// Name of the cookie the token is issued in.
+    pub(crate) cookie: String,
+    /// Request header the token is sent back in.
+    pub(crate) header: String,
+    /// Field of an urlencoded form body the token may be sent back in instead.
+    pub(crate) field: String,
+    /// Paths ("/entries") and prefixes ("/admin/*") checked, every one when empty.
+    pub(crate) routes: Vec<String>,
+}
+
+impl Default for CsrfConfig {
+    fn default() -> Self {
+        CsrfConfig {
+            cookie: "csrf_token".to_string(),
+            header: "X-CSRF-Token".to_string(),
+            field: "csrf_token".to_string(),
+            routes: Vec::new(),
+        }
+    }
+}
+
+impl CsrfConfig {
+    fn protects(&self, path: &str) -> bool {
+        self.routes.is_empty()
+            || self.routes.iter().any(|route| match route.strip_suffix('*') {
+                Some(prefix) => path.starts_with(prefix),
+                None => path == route,
+            })
+    }
+}
+
+// refuses a state-changing request to a protected route that carries cookies
+// but doesn't send the token of its cookie back
+pub(crate) fn check(config: &Config, method: &str, uri: &str, headers: &HeaderMap, body: &[u8]) -> Result<(), Response> {
+    let Some(csrf) = &config.csrf else {
+        return Ok(());
+    };
+    if !UNSAFE_METHODS.contains(&method) || headers.get("Cookie").is_none() {
+        return Ok(());
+    }
+    // an alias is checked as the route it serves, a redirect changes nothing
+    let uri = match redirects::resolve(config, uri) {
+        Rewrite::Redirect(_) => return Ok(()),
+        Rewrite::Route(uri) => uri,
+    };
+    if !csrf.protects(http::split_uri(&uri).0) {
+        return Ok(());
+    }
+    let cookies = crate::parse_cookies(headers);
+    let Some(expected) = cookies.get(&csrf.cookie).filter(|token| !token.is_empty()) else {
+        return Err(Response::problem(StatusCode::Forbidden, "The CSRF token cookie is missing"));
+    };
+    let given = match headers.get(&csrf.header) {
+        Some(token) => Some(token.trim().to_string()),
+        None => form_field(headers, body, &csrf.field),
+    };
+    match given {
+        Some(given) if same(&given, expected) => Ok(()),
+        Some(_) => Err(Response::problem(StatusCode::Forbidden, "The CSRF token doesn't match its cookie")),
+        None => Err(Response::problem(
+            StatusCode::Forbidden,
+            format!("The CSRF token is missing, send it in {} or the {} form field", csrf.header, csrf.field),
+        )),
+    }
+}
+
+// sets the token cookie on the response to a client that doesn't have one,
+// over TLS only sent back over TLS, unless the handler already set it
+pub(crate) fn issue(config: &Config, headers: &HeaderMap, secure: bool, response: Response) -> Response {
+    let Some(csrf) = &config.csrf else {
+        return response;
+    };
+    let prefix = format!("{}=", csrf.cookie);
+    if response.headers.get_all("Set-Cookie").any(|cookie| cookie.starts_with(&prefix)) {
+        return response;
+    }
+    match form_token(config, headers, secure) {
+        Some(FormToken { set_cookie: Some(cookie), .. }) => response.header("Set-Cookie", cookie),
+        _ => response,
+    }
+}
+
+// what a page puts in its forms: the field, the token of the client's cookie
+// and, when it has none yet, the Set-Cookie giving it a new one
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct FormToken {
+    pub(crate) field: String,
+    pub(crate) token: String,
+    pub(crate) set_cookie: Option<String>,
+}
+
+// the token for the forms of a page answering a request with `headers`, None
+// without `csrf` configured
+pub(crate) fn form_token(config: &Config, headers: &HeaderMap, secure: bool) -> Option<FormToken> {
+    let csrf = config.csrf.as_ref()?;
+    let field = csrf.field.clone();
+    if let Some(token) = crate::parse_cookies(headers).remove(&csrf.cookie).filter(|token| !token.is_empty()) {
+        return Some(FormToken { field, token, set_cookie: None });
+    }
+    let token = match crate::random_hex(32) {
+        Ok(token) => token,
+        Err(e) => {
+            log::error!("Failed to generate a CSRF token: {}", e);
+            return None;
+        }
+    };
+    // readable by scripts, they copy it into the header
+    let mut cookie = format!("{}={}; Path=/; SameSite=Strict", csrf.cookie, token);
+    if secure {
+        cookie.push_str("; Secure");
+    }
+    Some(FormToken { field, token, set_cookie: Some(cookie) })
+}
+
+// the field of an application/x-www-form-urlencoded body
+fn form_field(headers: &HeaderMap, body: &[u8], field: &str) -> Option<String> {
+    let content_type = headers.get("Content-Type")?;
+    if http::media_type(content_type) != "application/x-www-form-urlencoded" {
+        return None;
+    }
+    http::parse_query(&String::from_utf8_lossy(body)).remove(field)
+}
+
+// compared without stopping at the first difference, so the time taken
+// doesn't tell how much of a guess was right
+fn same(given: &str, expected: &str) -> bool {
+    given.len() == expected.len() && given.bytes().zip(expected.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
@@ -0,0 +1,53 @@
+// Swagger UI at /docs, an API explorer reading /openapi.json
+//
+// the Swagger UI 5.17.14 bundle and stylesheet are compiled into the binary
+// from assets/swagger-ui (Apache-2.0, see the LICENSE and NOTICE next to
+// them), so the page works without network access or files next to the
+// server. only the small page bootstrapping it is written here.
+
+use crate::http::{Request, Response, StatusCode};
+
+const BUNDLE: &[u8] = include_bytes!("../assets/swagger-ui/swagger-ui-bundle.js");
+const STYLESHEET: &[u8] = include_bytes!("../assets/swagger-ui/swagger-ui.css");
+
+const PAGE: &str = r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+  <meta charset="utf-8">
+  <meta name="viewport" content="width=device-width, initial-scale=1">
+  <title>API docs</title>
+  <link rel="stylesheet" href="/docs/swagger-ui.css">
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="/docs/swagger-ui-bundle.js"></script>
+  <script>
+    window.ui = SwaggerUIBundle({ url: "/openapi.json", dom_id: "#swagger-ui" });
+  </script>
+</body>
+</html>
+"##;
+
+// what the page needs on top of the default policy: its bootstrapping
+// script, the styles and data: images Swagger UI sets inline
+const CONTENT_SECURITY_POLICY: &str =
+    "default-src 'self'; script-src 'self' 'unsafe-inline'; style-src 'self' 'unsafe-inline'; img-src 'self' data:";
+
+pub(crate) fn page(_request: &Request) -> Response {
+    Response::new(StatusCode::Ok)
+        .content_type("text/html; charset=utf-8")
+        .header("Content-Security-Policy", CONTENT_SECURITY_POLICY)
+        .body(PAGE.as_bytes().to_vec())
+}
+
+// the bundled script and stylesheet, /docs/{asset}
+pub(crate) fn asset(request: &Request) -> Response {
+    let (content_type, body) = match request.params.get("asset").map(String::as_str) {
+        Some("swagger-ui-bundle.js") => ("text/javascript; charset=utf-8", BUNDLE),
+        Some("swagger-ui.css") => ("text/css; charset=utf-8", STYLESHEET),
+        _ => return Response::problem(StatusCode::NotFound, "No such docs asset"),
+    };
+    Response::new(StatusCode::Ok)
+        .content_type(content_type)
+        .body(body.to_vec())
+}
@@ -1,13 +1,23 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
-use std::fs::{write, File};
-use std::io::{BufWriter, Write};
-use std::ops::Add;
+use std::io;
 use std::path::Path;
+use std::str::FromStr;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
-
-#[derive(Debug,Deserialize, Serialize, Clone)]
-struct Character {
+use crate::chunked::StreamBody;
+use crate::formats::{self, Format, COLUMNS};
+use crate::http::StatusCode;
+use crate::json_patch::{self, PatchError};
+use crate::openapi::ApiSchema;
+use crate::pagination::Cursor;
+use crate::search::{self, EntryQuery, SortKey, SortValue};
+use crate::validation::{self, FieldError};
+use crate::{conditional, config, events, json, store};
+use chrono::{DateTime, Datelike, SecondsFormat, Utc};
+
+#[derive(Debug,Deserialize, Serialize, Clone, PartialEq)]
+pub(crate) struct Character {
     id: usize,
     rank: String,
     trend: String,
@@ -16,8 +26,149 @@ struct Character {
     name: String,
     start: u32,
     total_votes: String,
-    average_rating:f32
+    average_rating:f32,
+    // when the entry was moved to the trash, RFC 3339. reads skip it until
+    // it is restored or purged
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    deleted_at: Option<String>,
+}
+impl Character {
+    pub(crate) fn id(&self) -> usize {
+        self.id
+    }
+
+    #[cfg(feature = "templates")]
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn is_deleted(&self) -> bool {
+        self.deleted_at.is_some()
+    }
+
+    // when it was moved to the trash, None for a live entry or an unreadable stamp
+    pub(crate) fn deleted_at(&self) -> Option<DateTime<Utc>> {
+        let deleted_at = DateTime::parse_from_rfc3339(self.deleted_at.as_deref()?).ok()?;
+        Some(deleted_at.with_timezone(&Utc))
+    }
+
+    // every field as text, in the order of formats::COLUMNS
+    pub(crate) fn fields(&self) -> [String; 9] {
+        [
+            self.id.to_string(),
+            self.rank.clone(),
+            self.trend.clone(),
+            self.season.to_string(),
+            self.episode.to_string(),
+            self.name.clone(),
+            self.start.to_string(),
+            self.total_votes.clone(),
+            self.average_rating.to_string(),
+        ]
+    }
+
+    // a character from a CSV record keyed by formats::COLUMNS, its id 0 when
+    // the id column is missing or empty
+    pub(crate) fn from_record(record: &HashMap<String, String>) -> Result<Character, Vec<FieldError>> {
+        let missing: Vec<FieldError> = COLUMNS[1..]
+            .iter()
+            .filter(|column| !record.contains_key(**column))
+            .map(|column| FieldError::new(*column, "is missing"))
+            .collect();
+        if !missing.is_empty() {
+            return Err(missing);
+        }
+
+        let mut errors = Vec::new();
+        let text = |column: &str| record[column].trim().to_string();
+        let character = Character {
+            id: match record.get("id").map(|id| id.trim()) {
+                None | Some("") => 0,
+                Some(_) => parse_field(record, "id", &mut errors),
+            },
+            rank: text("rank"),
+            trend: text("trend"),
+            season: parse_field(record, "season", &mut errors),
+            episode: parse_field(record, "episode", &mut errors),
+            name: text("name"),
+            start: parse_field(record, "start", &mut errors),
+            total_votes: text("total_votes"),
+            average_rating: parse_field(record, "average_rating", &mut errors),
+            deleted_at: None,
+        };
+        if errors.is_empty() { Ok(character) } else { Err(errors) }
+    }
+
+    // every field holding a value the dataset can't, the id is assigned by
+    // the store and isn't checked
+    pub(crate) fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        validation::check(&mut errors, "rank", validation::is_grouped_number(&self.rank), "must be a number like \"32,043\"");
+        validation::check(&mut errors, "trend", validation::is_trend(&self.trend), "must be \"-\" or an integer");
+        validation::check(&mut errors, "season", self.season > 0, "must be greater than 0");
+        validation::check(&mut errors, "episode", self.episode > 0, "must be greater than 0");
+        validation::check(&mut errors, "name", !self.name.trim().is_empty(), "must not be empty");
+        // the anime started airing in 1999
+        let latest = Utc::now().year() as u32 + 1;
+        validation::check(
+            &mut errors,
+            "start",
+            (1999..=latest).contains(&self.start),
+            &format!("must be a year between 1999 and {latest}"),
+        );
+        validation::check(
+            &mut errors,
+            "total_votes",
+            validation::is_grouped_number(&self.total_votes),
+            "must be a number like \"1,234\"",
+        );
+        validation::check(
+            &mut errors,
+            "average_rating",
+            (0.0..=10.0).contains(&self.average_rating),
+            "must be between 0.0 and 10.0",
+        );
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+}
+
+// a numeric CSV field, recording an error and standing in a default when it isn't one
+fn parse_field<T: FromStr + Default>(record: &HashMap<String, String>, column: &str, errors: &mut Vec<FieldError>) -> T {
+    record[column].trim().parse().unwrap_or_else(|_| {
+        errors.push(FieldError::new(column, "must be a number"));
+        T::default()
+    })
 }
+
+impl ApiSchema for Character {
+    const NAME: &'static str = "Character";
+
+    fn schema() -> serde_json::Value {
+        let grouped = serde_json::json!({ "type": "string", "pattern": "^\\d{1,3}(,\\d{3})*$" });
+        serde_json::json!({
+            "type": "object",
+            "required": formats::COLUMNS,
+            "properties": {
+                "id": { "type": "integer", "minimum": 0, "description": "assigned by the server on creation" },
+                "rank": grouped,
+                "trend": { "type": "string", "description": "\"-\" or an integer" },
+                "season": { "type": "integer", "minimum": 1 },
+                "episode": { "type": "integer", "minimum": 1 },
+                "name": { "type": "string", "minLength": 1 },
+                "start": { "type": "integer", "minimum": 1999 },
+                "total_votes": grouped,
+                "average_rating": { "type": "number", "minimum": 0, "maximum": 10 },
+                "deleted_at": {
+                    "type": "string",
+                    "format": "date-time",
+                    "readOnly": true,
+                    "description": "when the entry was moved to the trash, only present while it is there",
+                },
+            },
+        })
+    }
+}
+
 impl fmt::Display for Character{
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{:?}", self)
@@ -26,101 +177,304 @@ impl fmt::Display for Character{
 
 
 
-// returns the entries from 0 to limit
-// returns everything if limit is set to 0
-pub(crate) fn get_entries(limit:usize) -> String {
-    let file_path = Path::new("one_piece2.json");
-    let file = File::open(file_path).expect("Failed to open file");
-    let characters:Vec<Character> = serde_json::from_reader(file)
-        .expect("Error while parsing");
+// a page of entries, serialized or, when large and JSON, left to be written as it is sent
+pub(crate) enum Page {
+    Serialized(String),
+    Streamed(StreamBody),
+}
 
-    let response: String;
+impl Page {
+    pub(crate) fn etag(&self) -> String {
+        match self {
+            Page::Serialized(entries) => conditional::etag(entries.as_bytes()),
+            Page::Streamed(entries) => entries.etag().to_string(),
+        }
+    }
 
-    if(limit == 0){
+    fn len(&self) -> usize {
+        match self {
+            Page::Serialized(entries) => entries.len(),
+            Page::Streamed(entries) => entries.length() as usize,
+        }
+    }
+}
 
-        response = serde_json::to_string(&characters).expect("Error parsing to string")
+// returns `limit` of the entries matching `query` starting at `offset`, serialized in `format`,
+// along with how many entries match in total
+// returns every match if limit is set to 0, as long as the result stays under
+// the configured unpaginated row/byte limits. an offset needs a limit
+pub(crate) fn get_entries(query: &EntryQuery, offset: usize, limit: usize, format: Format) -> Result<(Page, usize), String> {
+    if limit == 0 && offset > 0 {
+        return Err(format!("offset needs limit, e.g. /entries?limit=100&offset={offset}"));
     }
-    else{
-        response = serde_json::to_string(&characters[0..limit]).expect("Error parsing to string");
+    let mut characters = search(store::load(), query);
+    let total = characters.len();
+    let config = config::get();
+
+    if limit == 0 {
+        check_unpaginated_size(total, 0, config.max_unpaginated_rows, usize::MAX)?;
+        let response = page(characters, format)?;
+        check_unpaginated_size(total, response.len(), usize::MAX, config.max_unpaginated_bytes)?;
+        return Ok((response, total));
     }
 
+    let start = offset.min(total);
+    let end = start.saturating_add(limit).min(total);
+    Ok((page(characters.drain(start..end).collect(), format)?, total))
+}
 
-    return response
+// up to `limit` of the entries matching `query` that come after `cursor`, or
+// the first ones without it, along with the cursor of the last one when more
+// follow. the entries are in the query's order, by id unless it sorts them,
+// with ties broken by id so every entry has a place of its own
+pub(crate) fn entries_after(
+    query: &EntryQuery,
+    cursor: Option<&Cursor>,
+    limit: usize,
+) -> Result<(Vec<Character>, Option<Cursor>), String> {
+    let key = query.sort.unwrap_or(SortKey::Id);
+    let order = Cursor::order(key, query.descending);
+    if cursor.is_some_and(|cursor| cursor.order != order) {
+        return Err("The cursor was issued for another sort order".to_string());
+    }
+    let compare = |a: (&SortValue, usize), b: (&SortValue, usize)| {
+        let ordering = a.0.compare(b.0);
+        let ordering = if query.descending { ordering.reverse() } else { ordering };
+        ordering.then(a.1.cmp(&b.1))
+    };
+    let mut places: Vec<(SortValue, Character)> = search(store::load(), query)
+        .into_iter()
+        .map(|c| (sort_value(&c, key), c))
+        .filter(|(value, c)| {
+            cursor.is_none_or(|cursor| compare((value, c.id), (&cursor.value, cursor.id)) == Ordering::Greater)
+        })
+        .collect();
+    places.sort_by(|(a, x), (b, y)| compare((a, x.id), (b, y.id)));
+    let more = places.len() > limit;
+    places.truncate(limit);
+    let next = places
+        .last()
+        .filter(|_| more)
+        .map(|(value, c)| Cursor { order, value: value.clone(), id: c.id });
+    Ok((places.into_iter().map(|(_, c)| c).collect(), next))
 }
 
-//appends a new entry to the end of the .json file
-pub(crate) fn post_entry(req: &str) -> &str{
+fn sort_value(character: &Character, key: SortKey) -> SortValue {
+    match key {
+        SortKey::Id => SortValue::Number(character.id as u64),
+        SortKey::Rank => SortValue::Number(search::grouped_number(&character.rank)),
+        SortKey::Season => SortValue::Number(character.season.into()),
+        SortKey::Episode => SortValue::Number(character.episode.into()),
+        SortKey::Name => SortValue::Text(character.name.clone()),
+        SortKey::Start => SortValue::Number(character.start.into()),
+        SortKey::TotalVotes => SortValue::Number(search::grouped_number(&character.total_votes)),
+        SortKey::AverageRating => SortValue::Rating(character.average_rating),
+    }
+}
 
-    let req:Result<Character, serde_json::Error> = serde_json::from_str(req);
-    match req{
-        Ok(mut new_character) =>{
-            let file_path = Path::new("one_piece2.json");
-            let file = File::open(file_path).expect("Failed to open file");
-            let mut characters:Vec<Character> = serde_json::from_reader(file)
-                .expect("Error while parsing");
-            new_character.id = characters.last().unwrap().id+1;
-            characters.push(new_character);
+// the first `limit` entries matching `query`, every match for 0 as long as
+// they stay under the configured unpaginated row limit
+#[cfg(feature = "graphql")]
+pub(crate) fn find_entries(query: &EntryQuery, limit: usize) -> Result<Vec<Character>, String> {
+    let mut characters = search(store::load(), query);
+    if limit == 0 {
+        check_unpaginated_size(characters.len(), 0, config::get().max_unpaginated_rows, usize::MAX)?;
+    }
+    characters.truncate(if limit == 0 { usize::MAX } else { limit });
+    Ok(characters)
+}
 
-            let new_req = serde_json::to_string_pretty(&characters).unwrap();
-            let file = File::create(file_path).unwrap();
-            let mut writer = BufWriter::new(file);
-            serde_json::to_writer_pretty(&mut writer, &characters).unwrap();
+fn page(characters: Vec<Character>, format: Format) -> Result<Page, String> {
+    let config = config::get();
+    // the success envelope wraps a body built whole
+    let min = if config.success_envelope { 0 } else { config.stream_min_entries };
+    if format == Format::Json && min > 0 && characters.len() >= min {
+        let entries = StreamBody::json_array(characters).map_err(|e| format!("Failed to serialize the entries: {e}"))?;
+        return Ok(Page::Streamed(entries));
+    }
+    Ok(Page::Serialized(format.serialize(&characters).expect("Error parsing to string")))
+}
 
-            // Optionally, add a newline for better formatting
-            writer.write_all(b"\n").unwrap();
+// the entries passing every filter of `query`, in its order
+fn search(mut characters: Vec<Character>, query: &EntryQuery) -> Vec<Character> {
+    characters.retain(|c| {
+        (query.include_deleted || !c.is_deleted())
+            && query.season.is_none_or(|season| c.season == season)
+            && query.episode.is_none_or(|episode| c.episode == episode)
+            && query.start.is_none_or(|start| c.start == start)
+            && query.min_rating.is_none_or(|min| c.average_rating >= min)
+            && query.max_rating.is_none_or(|max| c.average_rating <= max)
+            && query.name_contains.as_ref().is_none_or(|part| c.name.to_lowercase().contains(part))
+    });
+    if let Some(key) = query.sort {
+        characters.sort_by(|a, b| {
+            let ordering = match key {
+                SortKey::Id => a.id.cmp(&b.id),
+                SortKey::Rank => search::grouped_number(&a.rank).cmp(&search::grouped_number(&b.rank)),
+                SortKey::Season => a.season.cmp(&b.season),
+                SortKey::Episode => a.episode.cmp(&b.episode),
+                SortKey::Name => a.name.cmp(&b.name),
+                SortKey::Start => a.start.cmp(&b.start),
+                SortKey::TotalVotes => search::grouped_number(&a.total_votes).cmp(&search::grouped_number(&b.total_votes)),
+                SortKey::AverageRating => a.average_rating.total_cmp(&b.average_rating),
+            };
+            if query.descending { ordering.reverse() } else { ordering }
+        });
+    }
+    characters
+}
 
+#[derive(Serialize)]
+struct Stats {
+    count: usize,
+    min_rating: Option<f32>,
+    max_rating: Option<f32>,
+    average_rating: Option<f64>,
+    total_votes: u64,
+    seasons: Vec<SeasonStats>,
+}
 
-        },
-        Err(e) =>{
-            return "Error"
-        }
+#[derive(Serialize)]
+struct SeasonStats {
+    season: u32,
+    count: usize,
+    average_rating: f64,
+}
+
+// aggregates over the whole store, the ratings ones being null while it's empty
+pub(crate) fn entry_stats() -> String {
+    let characters = live(store::load());
+    let ratings = || characters.iter().map(|c| c.average_rating);
+    // ratings have one decimal, two are plenty for their means
+    let mean = |sum: f64, count: usize| (sum / count as f64 * 100.0).round() / 100.0;
+
+    let mut seasons: BTreeMap<u32, (usize, f64)> = BTreeMap::new();
+    for character in &characters {
+        let (count, sum) = seasons.entry(character.season).or_default();
+        *count += 1;
+        *sum += f64::from(character.average_rating);
     }
-    "Success!"
+    let stats = Stats {
+        count: characters.len(),
+        min_rating: ratings().reduce(f32::min),
+        max_rating: ratings().reduce(f32::max),
+        average_rating: (!characters.is_empty())
+            .then(|| mean(ratings().map(f64::from).sum(), characters.len())),
+        total_votes: characters.iter().map(|c| search::grouped_number(&c.total_votes)).sum(),
+        seasons: seasons
+            .into_iter()
+            .map(|(season, (count, sum))| SeasonStats { season, count, average_rating: mean(sum, count) })
+            .collect(),
+    };
+    json::to_string(&stats).expect("Error parsing to string")
 }
 
-//replaces all the fields of a selected entry filtered by id
-pub(crate) fn put_entry(req: &str) -> &str {
-    
-    let patched_entry:Result<Character, serde_json::Error> = serde_json::from_str(req);
-    match patched_entry{
-        Ok(mut new_character) =>{
-            let file_path = Path::new("one_piece2.json");
-            let file = File::open(file_path).expect("Failed to open file");
-            let mut characters:Vec<Character> = serde_json::from_reader(file)
-                .expect("Error while parsing");
-            let mut flag:bool = false;
-            let mut index:usize = 0;
-            for mut character in characters.clone(){
-                if(character.id == new_character.id){
-                    flag = true;
-                    break;
-                }
-                index+=1;
-            }
+// refuses an unpaginated collection response that exceeds either limit,
+// explaining how to ask for a page instead
+pub(crate) fn check_unpaginated_size(rows: usize, bytes: usize, max_rows: usize, max_bytes: usize) -> Result<(), String> {
+    if rows > max_rows {
+        return Err(format!(
+            "Too many entries to return at once ({rows} > {max_rows}). \
+             Request a page with ?limit=<n>&offset=<n>, e.g. /entries?limit=100&offset=0"
+        ));
+    }
+    if bytes > max_bytes {
+        return Err(format!(
+            "Response too large to return at once ({bytes} > {max_bytes} bytes). \
+             Request a page with ?limit=<n>&offset=<n>, e.g. /entries?limit=100&offset=0"
+        ));
+    }
+    Ok(())
+}
+
+// the ETag of the whole dataset, the one an unpaginated GET /entries carries,
+// which leaves out the entries in the trash
+pub(crate) fn dataset_etag(characters: &[Character]) -> String {
+    let live: Vec<&Character> = characters.iter().filter(|c| !c.is_deleted()).collect();
+    let serialized = json::to_string(&live).expect("Error parsing to string");
+    conditional::etag(serialized.as_bytes())
+}
 
-            if(!flag) { return "Error"; }
-            characters.insert(index, new_character);
-            characters.remove(index+1);
+// the ETag of a single entry, the one GET /entries/{id} carries
+pub(crate) fn entry_etag(character: &Character) -> String {
+    let serialized = json::to_string(character).expect("Error parsing to string");
+    conditional::etag(serialized.as_bytes())
+}
 
-            let file = File::create(file_path).unwrap();
-            let mut writer = BufWriter::new(file);
-            serde_json::to_writer_pretty(&mut writer, &characters).unwrap();
+// an If-Match precondition fails when the data changed since the client read it
+fn check_if_match(if_match: Option<&str>, characters: &[Character]) -> Result<(), (StatusCode, &'static str)> {
+    match if_match {
+        Some(if_match) if !conditional::if_match(if_match, &dataset_etag(characters)) => Err((
+            StatusCode::PreconditionFailed,
+            "The entries changed since your If-Match ETag",
+        )),
+        _ => Ok(()),
+    }
+}
 
-            // Optionally, add a newline for better formatting
-            writer.write_all(b"\n").unwrap();
+// the same for a request on one entry, which only fails when that entry changed
+fn check_entry_match(if_match: Option<&str>, character: &Character) -> Result<(), (StatusCode, &'static str)> {
+    match if_match {
+        Some(if_match) if !conditional::if_match(if_match, &entry_etag(character)) => Err((
+            StatusCode::PreconditionFailed,
+            "The entry changed since your If-Match ETag",
+        )),
+        _ => Ok(()),
+    }
+}
 
+//appends a new entry to the end of the store, returning its id and the stored entry
+pub(crate) fn post_entry(mut new_character: Character) -> Result<(usize, String), (StatusCode, String)> {
+    if let Err(errors) = new_character.validate() {
+        return Err((StatusCode::UnprocessableEntity, validation::to_json(errors)));
+    }
+    let _lock = store::lock();
+    let mut characters = store::load();
+    new_character.deleted_at = None;
+    new_character.id = match store::next_id(&characters) {
+        Ok(id) => id,
+        Err(e) => return Err((StatusCode::InternalServerError, format!("Failed to allocate an id: {e}"))),
+    };
+    let id = new_character.id;
+    let event_data = json::to_string(&new_character).expect("Error parsing to string");
+    characters.push(new_character);
+
+    store::save(&characters);
+
+    events::publish("created", &event_data);
+    Ok((id, event_data))
+}
 
-        },
-        Err(e) =>{
-            return "Error"
-        }
+// a single entry as JSON, None for one in the trash
+pub(crate) fn get_entry(id: usize) -> Option<String> {
+    let character = store::get(id).filter(|c| !c.is_deleted())?;
+    Some(json::to_string(&character).expect("Error parsing to string"))
+}
+
+//replaces all the fields of a selected entry filtered by id
+pub(crate) fn put_entry(mut new_character: Character, if_match: Option<&str>) -> (StatusCode, String) {
+    if let Err(errors) = new_character.validate() {
+        return (StatusCode::UnprocessableEntity, validation::to_json(errors));
     }
-    
-    return "Success!"
+    let _lock = store::lock();
+    let (mut characters, index) = store::load_indexed();
+    let Some(&position) = index.get(&new_character.id).filter(|&&position| !characters[position].is_deleted()) else {
+        return (StatusCode::NotFound, "Character not found".to_string());
+    };
+    if let Err((status, message)) = check_entry_match(if_match, &characters[position]) { return (status, message.to_string()); }
+    new_character.deleted_at = None;
+    let event_data = json::to_string(&new_character).expect("Error parsing to string");
+    characters[position] = new_character;
+
+    store::save(&characters);
+
+    events::publish("updated", &event_data);
+
+    (StatusCode::Ok, "Success!".to_string())
 }
 
 //patches the name field of an entry and replaces it with the name new name field
-pub(crate) fn patch_entry_name(req: &str) -> &str {
+pub(crate) fn patch_entry_name(req: &str, if_match: Option<&str>) -> (StatusCode, String) {
     #[derive(Deserialize, Clone)]
     struct PatchName{
         id: usize,
@@ -130,35 +484,150 @@ pub(crate) fn patch_entry_name(req: &str) -> &str {
     let req: Result<PatchName, serde_json::Error> = serde_json::from_str(req);
     match req{
         Ok(patch) => {
-            let file_path = Path::new("one_piece2.json");
-            let file = File::open(file_path).expect("Failed to open file");
-            let mut characters:Vec<Character> = serde_json::from_reader(file)
-                .expect("Error while parsing");
+            let _lock = store::lock();
+            let (mut characters, index) = store::load_indexed();
             // Find and update the character's name
-            if let Some(character) = characters.iter_mut().find(|c| c.id == patch.id) {
+            let event_data;
+            if let Some(&position) = index.get(&patch.id).filter(|&&position| !characters[position].is_deleted()) {
+                let character = &mut characters[position];
+                if let Err((status, message)) = check_entry_match(if_match, character) {
+                    return (status, message.to_string());
+                }
                 character.name = patch.name.clone();
+                if let Err(errors) = character.validate() {
+                    return (StatusCode::UnprocessableEntity, validation::to_json(errors));
+                }
+                event_data = json::to_string(character).expect("Error parsing to string");
             } else {
-                return "Character not found";
+                return (StatusCode::NotFound, "Character not found".to_string());
             }
 
-            let file = File::create(file_path).unwrap();
-            let mut writer = BufWriter::new(file);
-            serde_json::to_writer_pretty(&mut writer, &characters).unwrap();
-
-            // Optionally, add a newline for better formatting
-            writer.write_all(b"\n").unwrap();
-
+            store::save(&characters);
 
+            events::publish("updated", &event_data);
         },
-        Err(e) => {
-            return "Format not valid";
+        Err(_) => {
+            return (StatusCode::BadRequest, "Expected {\"id\", \"name\"}".to_string());
         }
     }
-    "Success"
+    (StatusCode::Ok, "Success".to_string())
+}
+
+// applies a JSON Patch (RFC 6902) to the entry with the given id, returning the
+// patched entry. the id itself can't be patched.
+pub(crate) fn json_patch_entry(id: usize, patch: &str, if_match: Option<&str>) -> (StatusCode, String) {
+    patch_entry(id, patch, if_match, |document, patch| match json_patch::apply(document, patch) {
+        Ok(()) => Ok(()),
+        Err(e @ PatchError::Invalid(_)) => Err((StatusCode::BadRequest, e.to_string())),
+        // RFC 5789 2.2, the patch is valid but can't be applied to this entry
+        Err(e) => Err((StatusCode::Conflict, e.to_string())),
+    })
+}
+
+// applies a JSON Merge Patch (RFC 7386) to the entry with the given id,
+// returning the patched entry
+pub(crate) fn merge_patch_entry(id: usize, patch: &str, if_match: Option<&str>) -> (StatusCode, String) {
+    patch_entry(id, patch, if_match, |document, patch| {
+        json_patch::merge(document, patch);
+        Ok(())
+    })
+}
+
+// runs `apply` on the JSON form of an entry and stores the result, as long as
+// it still passes validation with the same id
+fn patch_entry(
+    id: usize,
+    patch: &str,
+    if_match: Option<&str>,
+    apply: impl FnOnce(&mut serde_json::Value, &serde_json::Value) -> Result<(), (StatusCode, String)>,
+) -> (StatusCode, String) {
+    let patch: serde_json::Value = match serde_json::from_str(patch) {
+        Ok(patch) => patch,
+        Err(_) => return (StatusCode::BadRequest, "The patch is not valid JSON".to_string()),
+    };
+
+    let _lock = store::lock();
+    let (mut characters, index) = store::load_indexed();
+    let Some(&position) = index.get(&id).filter(|&&position| !characters[position].is_deleted()) else {
+        return (StatusCode::NotFound, "Character not found".to_string());
+    };
+    if let Err((status, message)) = check_entry_match(if_match, &characters[position]) {
+        return (status, message.to_string());
+    }
+    let character = &mut characters[position];
+
+    let mut document = serde_json::to_value(&*character).expect("Character always serializes");
+    if let Err(failed) = apply(&mut document, &patch) {
+        return failed;
+    }
+    let unprocessable = |error: FieldError| (StatusCode::UnprocessableEntity, validation::to_json(vec![error]));
+    let patched: Character = match serde_json::from_value(document) {
+        Ok(patched) => patched,
+        Err(e) => return unprocessable(FieldError::new("entry", format!("is not a valid entry: {e}"))),
+    };
+    if patched.id != id {
+        return unprocessable(FieldError::new("id", "can't be changed"));
+    }
+    if patched.is_deleted() {
+        return unprocessable(FieldError::new("deleted_at", "can't be set, DELETE the entry instead"));
+    }
+    if let Err(errors) = patched.validate() {
+        return (StatusCode::UnprocessableEntity, validation::to_json(errors));
+    }
+
+    *character = patched;
+    let event_data = json::to_string(character).expect("Error parsing to string");
+    store::save(&characters);
+    events::publish("updated", &event_data);
+    (StatusCode::Ok, event_data)
+}
+
+// moves the entry with `id` to the trash, where reads don't see it
+pub(crate) fn trash_entry(id: usize, if_match: Option<&str>) -> (StatusCode, String) {
+    let _lock = store::lock();
+    let (mut characters, index) = store::load_indexed();
+    let Some(&position) = index.get(&id).filter(|&&position| !characters[position].is_deleted()) else {
+        return (StatusCode::NotFound, "Character not found".to_string());
+    };
+    if let Err((status, message)) = check_entry_match(if_match, &characters[position]) {
+        return (status, message.to_string());
+    }
+    characters[position].deleted_at = Some(Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true));
+    store::save(&characters);
+
+    events::publish("deleted", &format!("{{\"id\":{id}}}"));
+    (StatusCode::NoContent, String::new())
+}
+
+// takes the entry with `id` out of the trash, returning it
+pub(crate) fn restore_entry(id: usize, if_match: Option<&str>) -> (StatusCode, String) {
+    let _lock = store::lock();
+    let (mut characters, index) = store::load_indexed();
+    let Some(&position) = index.get(&id) else {
+        return (StatusCode::NotFound, "Character not found".to_string());
+    };
+    if let Err((status, message)) = check_entry_match(if_match, &characters[position]) {
+        return (status, message.to_string());
+    }
+    let character = &mut characters[position];
+    if character.deleted_at.take().is_none() {
+        return (StatusCode::Conflict, "The entry is not in the trash".to_string());
+    }
+    let event_data = json::to_string(character).expect("Error parsing to string");
+    store::save(&characters);
+
+    events::publish("restored", &event_data);
+    (StatusCode::Ok, event_data)
+}
+
+// the entries that aren't in the trash
+fn live(mut characters: Vec<Character>) -> Vec<Character> {
+    characters.retain(|c| !c.is_deleted());
+    characters
 }
 
-//removes an entry from the .json file
-pub(crate) fn delete_entry(req: &str) -> &str {
+//removes an entry from the store
+pub(crate) fn delete_entry(req: &str, if_match: Option<&str>) -> (StatusCode, &'static str) {
     #[derive(Deserialize)]
     struct Delete {
         id: usize,
@@ -167,31 +636,217 @@ pub(crate) fn delete_entry(req: &str) -> &str {
     let req: Result<Delete, serde_json::Error> = serde_json::from_str(req);
     match req {
         Ok(delete_req) => {
-            let file_path = Path::new("one_piece2.json");
-            let file = File::open(file_path).expect("Failed to open file");
-            let mut characters: Vec<Character> = serde_json::from_reader(file)
-                .expect("Error while parsing");
+            let _lock = store::lock();
+            let (mut characters, index) = store::load_indexed();
 
-            let index: Option<usize> = characters.iter().position(|r| r.id == delete_req.id);
-            match index {
+            match index.get(&delete_req.id).copied() {
                 Some(element_index) => {
+                    if let Err(failed) = check_entry_match(if_match, &characters[element_index]) {
+                        return failed;
+                    }
                     characters.remove(element_index);
                 }
                 None => {
-                    return "Error: Character not found";
+                    return (StatusCode::NotFound, "Character not found");
                 }
             }
 
-            let file = File::create(file_path).unwrap();
-            let mut writer = BufWriter::new(file);
-            serde_json::to_writer_pretty(&mut writer, &characters).unwrap();
+            store::save(&characters);
 
-            // Optionally, add a newline for better formatting
-            writer.write_all(b"\n").unwrap();
+            events::publish("deleted", &format!("{{\"id\":{}}}", delete_req.id));
         }
         Err(_) => {
-            return "Error: Invalid request format";
+            return (StatusCode::BadRequest, "Expected {\"id\"}");
+        }
+    }
+    (StatusCode::NoContent, "")
+}
+
+// appends every entry of a JSON array in one write, or none of them when
+// any is invalid, returning the stored entries with their new ids
+pub(crate) fn post_entries(req: &str) -> (StatusCode, String) {
+    let new_characters: Vec<Character> = match serde_json::from_str(req) {
+        Ok(new_characters) => new_characters,
+        Err(_) => return (StatusCode::BadRequest, "Expected an array of entries".to_string()),
+    };
+    if new_characters.is_empty() {
+        return (StatusCode::BadRequest, "Expected at least one entry".to_string());
+    }
+    let errors: Vec<FieldError> = new_characters
+        .iter()
+        .enumerate()
+        .filter_map(|(index, character)| Some((index, character.validate().err()?)))
+        .flat_map(|(index, errors)| errors.into_iter().map(move |error| error.at(index)))
+        .collect();
+    if !errors.is_empty() {
+        return (StatusCode::UnprocessableEntity, validation::to_json(errors));
+    }
+
+    let _lock = store::lock();
+    let mut characters = store::load();
+    let mut created = Vec::with_capacity(new_characters.len());
+    for mut character in new_characters {
+        character.deleted_at = None;
+        character.id = match store::next_id(&characters) {
+            Ok(id) => id,
+            Err(e) => return (StatusCode::InternalServerError, format!("Failed to allocate an id: {e}")),
+        };
+        characters.push(character.clone());
+        created.push(character);
+    }
+    store::save(&characters);
+
+    for character in &created {
+        events::publish("created", &json::to_string(character).expect("Error parsing to string"));
+    }
+    (StatusCode::Created, json::to_string(&created).expect("Error parsing to string"))
+}
+
+// removes every entry whose id is in a JSON array in one write, or none of
+// them when any id doesn't exist
+pub(crate) fn delete_entries(req: &str, if_match: Option<&str>) -> (StatusCode, String) {
+    let mut ids: Vec<usize> = match serde_json::from_str(req) {
+        Ok(ids) => ids,
+        Err(_) => return (StatusCode::BadRequest, "Expected an array of ids".to_string()),
+    };
+    ids.sort_unstable();
+    ids.dedup();
+
+    let _lock = store::lock();
+    let (mut characters, index) = store::load_indexed();
+    if let Err((status, message)) = check_if_match(if_match, &characters) {
+        return (status, message.to_string());
+    }
+    let missing: Vec<String> = ids
+        .iter()
+        .filter(|id| !index.contains_key(id))
+        .map(usize::to_string)
+        .collect();
+    if !missing.is_empty() {
+        return (StatusCode::NotFound, format!("Characters not found: {}", missing.join(", ")));
+    }
+    characters.retain(|c| ids.binary_search(&c.id).is_err());
+    store::save(&characters);
+
+    for id in ids {
+        events::publish("deleted", &format!("{{\"id\":{id}}}"));
+    }
+    (StatusCode::NoContent, String::new())
+}
+
+// every entry as CSV, whatever the unpaginated limits, for spreadsheets
+pub(crate) fn export_entries() -> String {
+    Format::Csv.serialize(&live(store::load())).expect("Error parsing to string")
+}
+
+// merges the rows of a CSV document into the store in one write: a row whose
+// id exists replaces that entry, any other row is appended with a new id.
+// nothing is written when any row is invalid.
+pub(crate) fn import_entries(csv: &str) -> (StatusCode, String) {
+    let records = match formats::parse_csv(csv) {
+        Ok(records) => records,
+        Err(message) => return (StatusCode::BadRequest, message),
+    };
+    let mut errors = Vec::new();
+    let mut rows = Vec::new();
+    for (index, record) in records.iter().enumerate() {
+        match Character::from_record(record).and_then(|character| character.validate().map(|()| character)) {
+            Ok(character) => rows.push(character),
+            Err(row_errors) => errors.extend(row_errors.into_iter().map(|error| error.at(index))),
+        }
+    }
+    if !errors.is_empty() {
+        return (StatusCode::UnprocessableEntity, validation::to_json(errors));
+    }
+
+    let _lock = store::lock();
+    let (mut characters, index) = store::load_indexed();
+    // rows appended here can be replaced by later rows too
+    let mut index = (*index).clone();
+    let (mut created, mut updated) = (0, 0);
+    let mut changes = Vec::new();
+    for mut row in rows {
+        let existing = if row.id == 0 { None } else { index.get(&row.id).copied() };
+        let event = match existing {
+            Some(position) => {
+                characters[position] = row.clone();
+                updated += 1;
+                "updated"
+            }
+            None => {
+                row.id = match store::next_id(&characters) {
+                    Ok(id) => id,
+                    Err(e) => return (StatusCode::InternalServerError, format!("Failed to allocate an id: {e}")),
+                };
+                index.entry(row.id).or_insert(characters.len());
+                characters.push(row.clone());
+                created += 1;
+                "created"
+            }
+        };
+        changes.push((event, json::to_string(&row).expect("Error parsing to string")));
+    }
+    store::save(&characters);
+
+    for (event, data) in changes {
+        events::publish(event, &data);
+    }
+    (StatusCode::Ok, format!("{{\"created\":{created},\"updated\":{updated}}}"))
+}
+
+// the body of POST /admin/restore
+#[derive(Deserialize)]
+pub(crate) struct BackupName {
+    name: String,
+}
+
+// writes a backup of the entries and answers with its name
+pub(crate) fn backup_store() -> (StatusCode, String) {
+    let _lock = store::lock();
+    match store::backup(Path::new(&config::get().backup_dir)) {
+        Ok(name) => (StatusCode::Created, serde_json::json!({ "name": name }).to_string()),
+        Err(e) => (StatusCode::InternalServerError, format!("Backup failed: {e}")),
+    }
+}
+
+// replaces the entries with those of a backup made by backup_store
+pub(crate) fn restore_store(backup: BackupName) -> (StatusCode, String) {
+    let name = backup.name;
+    // a file directly in the backup directory, never a path out of it
+    let valid = name.ends_with(".json")
+        && !name.starts_with('.')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid {
+        return (StatusCode::BadRequest, format!("Not a backup name: {name}"));
+    }
+    let _lock = store::lock();
+    match store::read_backup(Path::new(&config::get().backup_dir), &name) {
+        Ok(characters) => {
+            store::save(&characters);
+            (StatusCode::Ok, serde_json::json!({ "name": name, "entries": characters.len() }).to_string())
         }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (StatusCode::NotFound, format!("No backup named {name}")),
+        Err(e) => (StatusCode::InternalServerError, format!("Backup {name} can't be restored: {e}")),
+    }
+}
+
+// writes the entries held in memory to the data file
+pub(crate) fn flush_store() -> (StatusCode, String) {
+    let _lock = store::lock();
+    match store::flush() {
+        Ok(bytes) => (StatusCode::Ok, format!("{{\"bytes\":{bytes}}}")),
+        Err(e) => (StatusCode::InternalServerError, format!("Flush failed: {e}")),
     }
-    "Success!"
-}
\ No newline at end of file
+}
+
+// rewrites the data file without whitespace and reports how much it shrank
+pub(crate) fn compact_store() -> (StatusCode, String) {
+    let _lock = store::lock();
+    match store::compact() {
+        Ok((before, after)) => (
+            StatusCode::Ok,
+            format!("{{\"bytes_before\":{before},\"bytes_after\":{after}}}"),
+        ),
+        Err(e) => (StatusCode::InternalServerError, format!("Compaction failed: {e}")),
+    }
+}
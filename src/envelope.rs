@@ -0,0 +1,39 @@
+// the { "status": "ok", "data": ... } envelope of successful answers
+//
+// with `success_envelope` on, a 2xx answer of the entry routes, unversioned
+// and under /api, or of the admin routes comes as { "status": "ok", "data":
+// ... } when its body is JSON or plain text: the JSON as it was, the text as
+// a string, so "Success!" becomes { "status": "ok", "data": "Success!" }.
+// errors keep their problem details, CSV, XML, pages and files their format,
+// and a 204 or 304 has nothing to wrap. listings aren't streamed while it is
+// on, the envelope needs the whole body. off, the default, the routes answer
+// with the legacy bodies older clients and the tests expect.
+
+use crate::config;
+use crate::http::{Request, Response};
+use crate::router::Next;
+use serde_json::{json, Value};
+
+pub(crate) fn middleware(request: &mut Request, next: Next) -> Response {
+    let response = next(request);
+    if !config::get().success_envelope {
+        return response;
+    }
+    wrap(response)
+}
+
+pub(crate) fn wrap(response: Response) -> Response {
+    if !(200..300).contains(&response.status.code()) || response.file.is_some() || response.stream.is_some() {
+        return response;
+    }
+    let data = match response.headers.get("Content-Type") {
+        Some(media_type) if media_type.starts_with("application/json") => match serde_json::from_slice(&response.body) {
+            Ok(data) => data,
+            Err(_) => return response,
+        },
+        Some("text/plain; charset=utf-8") => Value::String(String::from_utf8_lossy(&response.body).into_owned()),
+        _ => return response,
+    };
+    let body = crate::json::to_string(&json!({ "status": "ok", "data": data })).expect("Error parsing to string").into_bytes();
+    response.content_type("application/json").body(body)
+}
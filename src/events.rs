@@ -0,0 +1,64 @@
+// server-sent events for data changes
+//
+// GET /events keeps the connection open and receives a text/event-stream
+// message every time an endpoint mutates the dataset. each subscriber gets its
+// own channel, closed subscribers are dropped on the next publish.
+
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::time::Duration;
+
+pub(crate) const EVENTS_PATH: &str = "/events";
+
+// idle connections get a comment line this often so proxies don't time them out
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+static SUBSCRIBERS: Mutex<Vec<Sender<String>>> = Mutex::new(Vec::new());
+static NEXT_EVENT_ID: AtomicU64 = AtomicU64::new(1);
+
+pub(crate) fn subscribe() -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    SUBSCRIBERS.lock().unwrap().push(sender);
+    receiver
+}
+
+// sends an event to every connected subscriber
+pub(crate) fn publish(event: &str, data: &str) {
+    let id = NEXT_EVENT_ID.fetch_add(1, Ordering::Relaxed);
+    let mut message = format!("id: {id}\nevent: {event}\n");
+    for line in data.lines() {
+        message.push_str(&format!("data: {line}\n"));
+    }
+    message.push('\n');
+
+    SUBSCRIBERS
+        .lock()
+        .unwrap()
+        .retain(|subscriber| subscriber.send(message.clone()).is_ok());
+}
+
+// writes the event-stream headers and forwards events until the client goes
+// away, over a socket or TLS on one
+pub(crate) fn stream_events<W: Write>(mut stream: W) {
+    let receiver = subscribe();
+    let headers = "HTTP/1.1 200 OK\r\n\
+                   Content-Type: text/event-stream\r\n\
+                   Cache-Control: no-cache\r\n\
+                   Connection: keep-alive\r\n\r\n";
+    if stream.write_all(headers.as_bytes()).is_err() {
+        return;
+    }
+
+    loop {
+        let message = match receiver.recv_timeout(KEEP_ALIVE_INTERVAL) {
+            Ok(message) => message,
+            Err(RecvTimeoutError::Timeout) => ": keep-alive\n\n".to_string(),
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        if stream.write_all(message.as_bytes()).is_err() || stream.flush().is_err() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,30 @@
+// the Expect request header (RFC 9110 10.1.1)
+//
+// a client about to send a large body can send `Expect: 100-continue` and
+// wait for the server to answer 100 Continue before sending it, or a final
+// status if the request would be refused anyway. 100-continue is the only
+// expectation defined, any other gets 417. HTTP/1.0 has no interim responses,
+// the header is ignored on HTTP/1.0 requests.
+
+use crate::http::{HeaderMap, Version};
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Expectation {
+    // nothing expected, the body follows the head right away
+    None,
+    // the client waits for 100 Continue before sending the body
+    Continue,
+    // an expectation this server can't meet
+    Unsupported(String),
+}
+
+pub(crate) fn expectation(version: Version, headers: &HeaderMap) -> Expectation {
+    let Some(value) = headers.get("Expect").filter(|_| version == Version::Http11) else {
+        return Expectation::None;
+    };
+    if value.trim().eq_ignore_ascii_case("100-continue") {
+        Expectation::Continue
+    } else {
+        Expectation::Unsupported(value.to_string())
+    }
+}
@@ -0,0 +1,148 @@
+// extractors, typed arguments a handler can take instead of the whole request
+//
+// handlers return anything that is IntoResponse: a Response, text, Json<T>
+// for a serialized value, a (StatusCode, _) pair or a Result of those.
+//
+// a handler written as `fn create(Json(character): Json<Character>) -> Response`
+// is registered like any other, the router pulls each argument out of the
+// request before calling it. an argument that can't be extracted answers the
+// request itself: a body that isn't JSON is a 400, JSON of the wrong shape a
+// 422, so handlers only ever see values that made it through.
+
+use crate::http::{HeaderMap, IntoResponse, Request, Response, StatusCode};
+use crate::router::Handler;
+use crate::validation::{self, FieldError};
+use crate::{json, problem};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::sync::Arc;
+
+// a value built from the request, or the response refusing it
+pub(crate) trait FromRequest: Sized {
+    fn from_request(request: &Request) -> Result<Self, Response>;
+}
+
+// the body deserialized from JSON, or a value sent as one
+#[derive(Debug)]
+pub(crate) struct Json<T>(pub(crate) T);
+
+impl<T: DeserializeOwned> FromRequest for Json<T> {
+    fn from_request(request: &Request) -> Result<Self, Response> {
+        request.json().map(Json).map_err(|error| {
+            if error.is_data() {
+                Response::new(StatusCode::UnprocessableEntity)
+                    .content_type(problem::MEDIA_TYPE)
+                    .body(validation::to_json(vec![FieldError::new("body", error.to_string())]).into_bytes())
+            } else {
+                Response::problem(StatusCode::BadRequest, format!("The body is not valid JSON: {error}"))
+            }
+        })
+    }
+}
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        match json::to_string(&self.0) {
+            Ok(body) => Response::json(StatusCode::Ok, body),
+            Err(error) => Response::problem(StatusCode::InternalServerError, format!("Could not serialize: {error}")),
+        }
+    }
+}
+
+// the body as UTF-8 text
+#[derive(Debug)]
+pub(crate) struct Text(pub(crate) String);
+
+impl FromRequest for Text {
+    fn from_request(request: &Request) -> Result<Self, Response> {
+        match request.text() {
+            Some(text) => Ok(Text(text.to_string())),
+            None => Err(Response::problem(StatusCode::BadRequest, "The body is not valid UTF-8")),
+        }
+    }
+}
+
+// a value the middleware put in the request's extensions. a handler taking
+// one registered without the middleware that sets it answers 500, taking an
+// Option of it lets the value be missing
+#[derive(Debug)]
+pub(crate) struct Extension<T>(pub(crate) T);
+
+impl<T: Clone + Send + Sync + 'static> FromRequest for Extension<T> {
+    fn from_request(request: &Request) -> Result<Self, Response> {
+        match request.extensions.get::<T>() {
+            Some(value) => Ok(Extension(value.clone())),
+            None => Err(Response::problem(
+                StatusCode::InternalServerError,
+                format!("No {} was set for this request", std::any::type_name::<T>()),
+            )),
+        }
+    }
+}
+
+impl<T: Clone + Send + Sync + 'static> FromRequest for Option<Extension<T>> {
+    fn from_request(request: &Request) -> Result<Self, Response> {
+        Ok(request.extensions.get::<T>().cloned().map(Extension))
+    }
+}
+
+impl FromRequest for HeaderMap {
+    fn from_request(request: &Request) -> Result<Self, Response> {
+        Ok(request.headers.clone())
+    }
+}
+
+// a function the router can register as a handler, `Args` tells apart the
+// signatures it accepts: the whole request or one or two extractors, each
+// with any return type that is IntoResponse
+pub(crate) trait IntoHandler<Args> {
+    fn into_handler(self) -> Handler;
+}
+
+// the signature of handlers reading the request themselves
+pub(crate) struct WholeRequest;
+
+impl<F, R> IntoHandler<(WholeRequest, R)> for F
+where
+    F: Fn(&Request) -> R + Send + Sync + 'static,
+    R: IntoResponse,
+{
+    fn into_handler(self) -> Handler {
+        Arc::new(move |request| self(request).into_response())
+    }
+}
+
+impl<F, R, A> IntoHandler<(R, (A,))> for F
+where
+    F: Fn(A) -> R + Send + Sync + 'static,
+    R: IntoResponse,
+    A: FromRequest,
+{
+    fn into_handler(self) -> Handler {
+        Arc::new(move |request| match A::from_request(request) {
+            Ok(a) => self(a).into_response(),
+            Err(response) => response,
+        })
+    }
+}
+
+impl<F, R, A, B> IntoHandler<(R, (A, B))> for F
+where
+    F: Fn(A, B) -> R + Send + Sync + 'static,
+    R: IntoResponse,
+    A: FromRequest,
+    B: FromRequest,
+{
+    fn into_handler(self) -> Handler {
+        Arc::new(move |request| {
+            let a = match A::from_request(request) {
+                Ok(a) => a,
+                Err(response) => return response,
+            };
+            match B::from_request(request) {
+                Ok(b) => self(a, b).into_response(),
+                Err(response) => response,
+            }
+        })
+    }
+}
@@ -0,0 +1,87 @@
+// fault injection for testing clients, enabled with the `faults` cargo feature
+//
+// each rule in `faults` in config.json covers some paths ("/entries" or
+// "/entries/*") and gives the probability, from 0 to 1, of each fault on a
+// request to them: a delay of `latency_ms` before it is handled, a 500
+// instead of the handler's answer, the connection closed without an answer,
+// or the answer cut off halfway through its body. the faults are drawn
+// independently, a request may be delayed and then dropped. the first rule
+// covering a path applies. dropped and truncated answers go over HTTP/1.1
+// only, an HTTP/2 stream gets the rest. never meant for a production build.
+
+use crate::config;
+use crate::http::{Abort, Request, Response, StatusCode};
+use crate::log;
+use crate::router::Next;
+use serde::Deserialize;
+use std::thread;
+use std::time::Duration;
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub(crate) struct FaultRule {
+    /// Paths ("/entries") and prefixes ("/entries/*") the rule covers.
+    pub(crate) paths: Vec<String>,
+    /// Milliseconds a delayed request waits before it is handled.
+    pub(crate) latency_ms: u64,
+    /// Chance a request is delayed.
+    pub(crate) latency_probability: f64,
+    /// Chance a request is answered 500 without being handled.
+    pub(crate) error_probability: f64,
+    /// Chance the connection is closed without an answer.
+    pub(crate) drop_probability: f64,
+    /// Chance the answer stops halfway through its body.
+    pub(crate) truncate_probability: f64,
+}
+
+impl FaultRule {
+    fn covers(&self, path: &str) -> bool {
+        self.paths.iter().any(|route| match route.strip_suffix('*') {
+            Some(prefix) => path.starts_with(prefix),
+            None => path == route,
+        })
+    }
+}
+
+// whether something with `probability` of happening happens this time
+fn chance(probability: f64) -> bool {
+    if probability <= 0.0 {
+        return false;
+    }
+    let mut random = [0u8; 8];
+    if getrandom::getrandom(&mut random).is_err() {
+        return false;
+    }
+    // the top 53 bits, uniform over [0, 1)
+    ((u64::from_le_bytes(random) >> 11) as f64 / (1u64 << 53) as f64) < probability
+}
+
+// router middleware injecting the faults of the first rule covering the path
+pub(crate) fn middleware(request: &mut Request, next: Next) -> Response {
+    let Some(rule) = config::get().faults.iter().find(|rule| rule.covers(&request.path)) else {
+        return next(request);
+    };
+    inject(rule, request, next)
+}
+
+pub(crate) fn inject(rule: &FaultRule, request: &mut Request, next: Next) -> Response {
+    if chance(rule.latency_probability) {
+        log::debug!("Delaying {} by {}ms", request.path, rule.latency_ms);
+        thread::sleep(Duration::from_millis(rule.latency_ms));
+    }
+    if chance(rule.drop_probability) {
+        log::debug!("Dropping the connection of {}", request.path);
+        return Response::new(StatusCode::InternalServerError).abort(Abort::Drop);
+    }
+    let response = if chance(rule.error_probability) {
+        log::debug!("Answering {} with an injected 500", request.path);
+        Response::problem(StatusCode::InternalServerError, "Injected fault")
+    } else {
+        next(request)
+    };
+    if chance(rule.truncate_probability) {
+        log::debug!("Truncating the answer to {}", request.path);
+        return response.abort(Abort::Truncate);
+    }
+    response
+}
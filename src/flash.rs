@@ -0,0 +1,75 @@
+// one-shot messages for the page a browser is redirected to, enabled with the `templates` cargo feature
+//
+// a form posted from an HTML page is answered with a redirect back to a page,
+// so reloading that page doesn't post the form again (post-redirect-get). the
+// outcome of the post goes along as a flash: set() keeps it in the browser's
+// session and take() hands it to the next page rendered, which is the only
+// one to see it. the session cookie holds nothing but the session's id, the
+// messages stay on the server for an hour at most.
+
+use crate::http::{Request, Response};
+use crate::log;
+use crate::session::Store;
+use serde::Serialize;
+
+const COOKIE: &str = "session";
+
+// how long messages nobody took are kept
+const LIFETIME_SECS: i64 = 60 * 60;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Kind {
+    Notice,
+    Error,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub(crate) struct Flash {
+    pub(crate) kind: Kind,
+    pub(crate) message: String,
+}
+
+impl Flash {
+    pub(crate) fn notice(message: impl Into<String>) -> Flash {
+        Flash { kind: Kind::Notice, message: message.into() }
+    }
+
+    pub(crate) fn error(message: impl Into<String>) -> Flash {
+        Flash { kind: Kind::Error, message: message.into() }
+    }
+}
+
+// the messages not taken yet, by the session they were set in
+static SESSIONS: Store<Vec<Flash>> = Store::new();
+
+// keeps `flash` for the next page the browser sending `request` loads,
+// starting a session on `response` when it has none
+pub(crate) fn set(request: &Request, response: Response, flash: Flash) -> Response {
+    let id = crate::parse_cookies(&request.headers).remove(COOKIE);
+    let mut flash = Some(flash);
+    if id.is_some_and(|id| SESSIONS.update(&id, |flashes| flashes.extend(flash.take())).is_some()) {
+        return response;
+    }
+    match SESSIONS.start(flash.into_iter().collect(), chrono::Duration::seconds(LIFETIME_SECS)) {
+        Ok(id) => {
+            let mut cookie = format!("{COOKIE}={id}; Path=/; HttpOnly; SameSite=Lax");
+            if request.connection.secure() {
+                cookie.push_str("; Secure");
+            }
+            response.header("Set-Cookie", cookie)
+        }
+        Err(e) => {
+            log::error!("Failed to start a session: {}", e);
+            response
+        }
+    }
+}
+
+// the messages set for the browser sending `request`, which nobody gets again
+pub(crate) fn take(request: &Request) -> Vec<Flash> {
+    crate::parse_cookies(&request.headers)
+        .remove(COOKIE)
+        .and_then(|id| SESSIONS.update(&id, std::mem::take))
+        .unwrap_or_default()
+}
@@ -0,0 +1,228 @@
+// response formats for the characters collection, picked from the Accept header
+//
+// JSON stays the default for clients that send no Accept or accept anything.
+// CSV is one character per row under a header row (RFC 4180), XML wraps each
+// character in a <character> element with one child per field. CSV is also
+// read back, for imports from spreadsheets.
+
+use crate::endpoints::Character;
+use crate::json;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Format {
+    Json,
+    Csv,
+    Xml,
+}
+
+// in order of preference when the client likes several equally
+const FORMATS: [Format; 3] = [Format::Json, Format::Csv, Format::Xml];
+
+// column order of the CSV output, also the field order of the XML elements
+pub(crate) const COLUMNS: [&str; 9] = [
+    "id",
+    "rank",
+    "trend",
+    "season",
+    "episode",
+    "name",
+    "start",
+    "total_votes",
+    "average_rating",
+];
+
+impl Format {
+    pub(crate) fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Csv => "text/csv; charset=utf-8; header=present",
+            Format::Xml => "application/xml; charset=utf-8",
+        }
+    }
+
+    // the media types a client may ask for this format with
+    fn media_types(self) -> &'static [&'static str] {
+        match self {
+            Format::Json => &["application/json"],
+            Format::Csv => &["text/csv"],
+            Format::Xml => &["application/xml", "text/xml"],
+        }
+    }
+
+    pub(crate) fn serialize(self, characters: &[Character]) -> serde_json::Result<String> {
+        match self {
+            Format::Json => json::to_string(characters),
+            Format::Csv => Ok(to_csv(characters)),
+            Format::Xml => Ok(to_xml(characters)),
+        }
+    }
+}
+
+// the supported media types, listed in 406 responses
+pub(crate) fn supported() -> String {
+    FORMATS
+        .iter()
+        .flat_map(|format| format.media_types())
+        .copied()
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// the format with the highest quality in the Accept header, None when the
+// client accepts none of them
+pub(crate) fn negotiate(accept: Option<&str>) -> Option<Format> {
+    let Some(accept) = accept.filter(|accept| !accept.trim().is_empty()) else {
+        return Some(Format::Json);
+    };
+    let ranges: Vec<(String, f32)> = accept
+        .split(',')
+        .map(|item| {
+            let mut parts = item.split(';');
+            let range = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+            let quality = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            (range, quality)
+        })
+        .collect();
+
+    let mut best: Option<(Format, f32)> = None;
+    for format in FORMATS {
+        let quality = format
+            .media_types()
+            .iter()
+            .filter_map(|media_type| quality(&ranges, media_type))
+            .fold(0.0, f32::max);
+        if quality > 0.0 && best.is_none_or(|(_, best_quality)| quality > best_quality) {
+            best = Some((format, quality));
+        }
+    }
+    best.map(|(format, _)| format)
+}
+
+// the quality the most specific matching range gives a media type (RFC 9110 12.5.1)
+fn quality(ranges: &[(String, f32)], media_type: &str) -> Option<f32> {
+    let main_type = media_type.split('/').next().unwrap_or("");
+    ranges
+        .iter()
+        .filter_map(|(range, quality)| {
+            let specificity = if range == media_type {
+                2
+            } else if range.strip_suffix("/*") == Some(main_type) {
+                1
+            } else if range == "*/*" {
+                0
+            } else {
+                return None;
+            };
+            Some((specificity, *quality))
+        })
+        .max_by_key(|(specificity, _)| *specificity)
+        .map(|(_, quality)| quality)
+}
+
+fn to_csv(characters: &[Character]) -> String {
+    let mut output = COLUMNS.join(",");
+    output.push_str("\r\n");
+    for character in characters {
+        let row: Vec<String> = character.fields().iter().map(|field| csv_field(field)).collect();
+        output.push_str(&row.join(","));
+        output.push_str("\r\n");
+    }
+    output
+}
+
+// quotes a field holding a separator, quote or line break, doubling its quotes
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+// the records of a CSV document as maps from the header row's names to the
+// fields, quoted fields may hold separators, doubled quotes and line breaks
+pub(crate) fn parse_csv(text: &str) -> Result<Vec<HashMap<String, String>>, String> {
+    let mut records = csv_records(text.strip_prefix('\u{feff}').unwrap_or(text))?.into_iter();
+    let header: Vec<String> = records
+        .next()
+        .ok_or("The CSV has no header row")?
+        .into_iter()
+        .map(|name| name.trim().to_string())
+        .collect();
+    records
+        .enumerate()
+        .map(|(index, record)| {
+            if record.len() != header.len() {
+                return Err(format!(
+                    "Row {} has {} fields, the header has {}",
+                    index + 1,
+                    record.len(),
+                    header.len()
+                ));
+            }
+            Ok(header.iter().cloned().zip(record).collect())
+        })
+        .collect()
+}
+
+fn csv_records(text: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if field.is_empty() => loop {
+                match chars.next() {
+                    Some('"') if chars.peek() == Some(&'"') => {
+                        chars.next();
+                        field.push('"');
+                    }
+                    Some('"') => break,
+                    Some(c) => field.push(c),
+                    None => return Err("The CSV has an unterminated quoted field".to_string()),
+                }
+            },
+            ',' => record.push(std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            c => field.push(c),
+        }
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    // blank lines separate nothing
+    records.retain(|record| record.len() > 1 || record.first().is_some_and(|field| !field.is_empty()));
+    Ok(records)
+}
+
+fn to_xml(characters: &[Character]) -> String {
+    let mut output = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<characters>");
+    for character in characters {
+        output.push_str("<character>");
+        for (column, field) in COLUMNS.iter().zip(character.fields()) {
+            output.push_str(&format!("<{column}>{}</{column}>", xml_escape(&field)));
+        }
+        output.push_str("</character>");
+    }
+    output.push_str("</characters>\n");
+    output
+}
+
+// escapes text for XML, and for HTML the same way
+pub(crate) fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
@@ -0,0 +1,739 @@
+// a GraphQL endpoint over the entries at POST /graphql, enabled with the `graphql` cargo feature
+//
+// the schema is fixed, the entries as Character and the calls the REST
+// routes make, so validation, the trash, events and the audit log apply the
+// same way:
+//
+//   type Query {
+//     characters(limit: Int, season: Int, minRating: Float): [Character!]!
+//     character(id: Int!): Character
+//   }
+//   type Mutation {
+//     createCharacter(input: CharacterInput!): Character
+//     updateCharacter(id: Int!, input: CharacterInput!): Character
+//     deleteCharacter(id: Int!): Boolean
+//   }
+//   type Character {
+//     id: Int! rank: String! trend: String! season: Int! episode: Int!
+//     name: String! start: Int! totalVotes: String! averageRating: Float!
+//   }
+//
+// CharacterInput takes the fields of Character but id, all of them to create
+// and any of them to update. deleteCharacter moves the entry to the trash.
+//
+// documents hold query and mutation operations with variables, aliases and
+// __typename. fragments, directives and introspection aren't supported.
+// a document that doesn't parse or fit the schema is a 400, errors while
+// running it are reported next to the data, the failing field being null.
+
+use crate::endpoints::{self, Character};
+use crate::extract::Json;
+use crate::http::{Response, StatusCode};
+use crate::search::EntryQuery;
+use serde::ser::{SerializeMap, SerializeSeq};
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::{json, Map, Value};
+
+// deepest nesting of selections and values a document may have
+const MAX_DEPTH: usize = 32;
+
+// Character's fields and the keys of the entries' JSON they are read from
+const CHARACTER_FIELDS: [(&str, &str); 9] = [
+    ("id", "id"),
+    ("rank", "rank"),
+    ("trend", "trend"),
+    ("season", "season"),
+    ("episode", "episode"),
+    ("name", "name"),
+    ("start", "start"),
+    ("totalVotes", "total_votes"),
+    ("averageRating", "average_rating"),
+];
+
+// a field of Query or Mutation, its arguments and whether they are required
+struct RootField {
+    name: &'static str,
+    arguments: &'static [(&'static str, bool)],
+    returns_character: bool,
+}
+
+const QUERY_FIELDS: [RootField; 2] = [
+    RootField {
+        name: "characters",
+        arguments: &[("limit", false), ("season", false), ("minRating", false)],
+        returns_character: true,
+    },
+    RootField { name: "character", arguments: &[("id", true)], returns_character: true },
+];
+
+const MUTATION_FIELDS: [RootField; 3] = [
+    RootField { name: "createCharacter", arguments: &[("input", true)], returns_character: true },
+    RootField { name: "updateCharacter", arguments: &[("id", true), ("input", true)], returns_character: true },
+    RootField { name: "deleteCharacter", arguments: &[("id", true)], returns_character: false },
+];
+
+// the body of a POST /graphql
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Request {
+    query: String,
+    #[serde(default)]
+    variables: Option<Map<String, Value>>,
+    #[serde(default)]
+    operation_name: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Outcome {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<Output>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<Error>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub(crate) struct Error {
+    message: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    locations: Vec<Location>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    path: Vec<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    extensions: Option<Value>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+struct Location {
+    line: usize,
+    column: usize,
+}
+
+impl Error {
+    fn new(message: impl Into<String>) -> Error {
+        Error { message: message.into(), locations: Vec::new(), path: Vec::new(), extensions: None }
+    }
+
+    // what an endpoint answered besides success, with its status, and the
+    // invalid fields named as in the schema when it was a validation problem
+    fn from_endpoint(status: StatusCode, message: &str) -> Error {
+        let problem: Value = serde_json::from_str(message).unwrap_or(Value::Null);
+        let mut error = Error::new(problem["detail"].as_str().unwrap_or(message));
+        let mut extensions = json!({ "status": status.code() });
+        if let Some(errors) = problem["errors"].as_array() {
+            let errors: Vec<Value> = errors
+                .iter()
+                .map(|e| {
+                    let field = e["field"].as_str().unwrap_or_default();
+                    let field = CHARACTER_FIELDS.iter().find(|(_, key)| *key == field).map_or(field, |(name, _)| name);
+                    json!({ "field": field, "message": e["message"] })
+                })
+                .collect();
+            extensions["errors"] = Value::Array(errors);
+        }
+        error.extensions = Some(extensions);
+        error
+    }
+}
+
+// a document's parts the executor needs
+#[derive(Debug)]
+struct Operation {
+    mutation: bool,
+    name: Option<String>,
+    // the declared variables and their defaults
+    variables: Vec<(String, Option<Value>)>,
+    selection: Vec<Field>,
+}
+
+#[derive(Debug)]
+struct Field {
+    alias: Option<String>,
+    name: String,
+    arguments: Vec<(String, Input)>,
+    selection: Vec<Field>,
+}
+
+impl Field {
+    // the key the field's value is answered under
+    fn key(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.name)
+    }
+}
+
+// an argument as written, the variables in it are read when it is used
+#[derive(Debug)]
+enum Input {
+    Variable(String),
+    Constant(Value),
+    List(Vec<Input>),
+    Object(Vec<(String, Input)>),
+}
+
+// a result, its objects keeping the order their fields were selected in
+#[derive(Debug)]
+enum Output {
+    Value(Value),
+    Object(Vec<(String, Output)>),
+    List(Vec<Output>),
+}
+
+impl Serialize for Output {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Output::Value(value) => value.serialize(serializer),
+            Output::Object(fields) => {
+                let mut map = serializer.serialize_map(Some(fields.len()))?;
+                for (key, value) in fields {
+                    map.serialize_entry(key, value)?;
+                }
+                map.end()
+            }
+            Output::List(items) => {
+                let mut seq = serializer.serialize_seq(Some(items.len()))?;
+                for item in items {
+                    seq.serialize_element(item)?;
+                }
+                seq.end()
+            }
+        }
+    }
+}
+
+pub(crate) fn endpoint(Json(request): Json<Request>) -> Response {
+    let (status, outcome) = match execute(&request) {
+        Ok((data, errors)) => (StatusCode::Ok, Outcome { data: Some(data), errors }),
+        Err(errors) => (StatusCode::BadRequest, Outcome { data: None, errors }),
+    };
+    Response::json(status, serde_json::to_string(&outcome).expect("GraphQL results always serialize"))
+}
+
+// the data and the errors running the request ran into, or why it couldn't run
+fn execute(request: &Request) -> Result<(Output, Vec<Error>), Vec<Error>> {
+    let operations = Parser::new(&request.query).document().map_err(|e| vec![e])?;
+    let operation = match &request.operation_name {
+        Some(name) => operations.iter().find(|operation| operation.name.as_ref() == Some(name)),
+        None if operations.len() == 1 => operations.first(),
+        None => return Err(vec![Error::new("operationName is required when the document has several operations")]),
+    };
+    let Some(operation) = operation else {
+        return Err(vec![Error::new("No operation with this operationName")]);
+    };
+    let errors = validate(operation);
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let given = request.variables.clone().unwrap_or_default();
+    let mut variables = Map::new();
+    for (name, default) in &operation.variables {
+        let value = given.get(name).cloned().or_else(|| default.clone()).unwrap_or(Value::Null);
+        variables.insert(name.clone(), value);
+    }
+    let mut executor = Executor { variables, errors: Vec::new() };
+    // mutations run one after the other, in the order they were written
+    let mut data = Vec::new();
+    for field in &operation.selection {
+        let value = match executor.root_field(field, operation.mutation) {
+            Ok(value) => value,
+            Err(mut error) => {
+                error.path = vec![Value::from(field.key())];
+                executor.errors.push(error);
+                Output::Value(Value::Null)
+            }
+        };
+        data.push((field.key().to_string(), value));
+    }
+    Ok((Output::Object(data), executor.errors))
+}
+
+// checks the operation against the schema before anything runs
+fn validate(operation: &Operation) -> Vec<Error> {
+    let (type_name, fields) = match operation.mutation {
+        true => ("Mutation", &MUTATION_FIELDS[..]),
+        false => ("Query", &QUERY_FIELDS[..]),
+    };
+    let mut errors = Vec::new();
+    for field in &operation.selection {
+        if field.name == "__typename" {
+            check_leaf(field, &mut errors);
+            continue;
+        }
+        let Some(root) = fields.iter().find(|root| root.name == field.name) else {
+            errors.push(Error::new(format!("Cannot query field {} on type {type_name}", field.name)));
+            continue;
+        };
+        for (name, _) in &field.arguments {
+            if !root.arguments.iter().any(|(argument, _)| argument == name) {
+                errors.push(Error::new(format!("Unknown argument {name} on field {}", field.name)));
+            }
+        }
+        for (argument, required) in root.arguments {
+            if *required && !field.arguments.iter().any(|(name, _)| name == argument) {
+                errors.push(Error::new(format!("Field {} requires the argument {argument}", field.name)));
+            }
+        }
+        if !root.returns_character {
+            check_leaf(field, &mut errors);
+            continue;
+        }
+        if field.selection.is_empty() {
+            errors.push(Error::new(format!("Field {} of type Character must have a selection of subfields", field.name)));
+        }
+        for subfield in &field.selection {
+            if subfield.name != "__typename" && !CHARACTER_FIELDS.iter().any(|(name, _)| *name == subfield.name) {
+                errors.push(Error::new(format!("Cannot query field {} on type Character", subfield.name)));
+            }
+            check_leaf(subfield, &mut errors);
+        }
+    }
+    for name in operation.selection.iter().flat_map(|field| variables_used(&field.arguments)) {
+        if !operation.variables.iter().any(|(declared, _)| declared == name) {
+            errors.push(Error::new(format!("Variable ${name} is not defined")));
+        }
+    }
+    errors
+}
+
+// a scalar field takes no selection, and the ones of Character no arguments either
+fn check_leaf(field: &Field, errors: &mut Vec<Error>) {
+    if !field.selection.is_empty() {
+        errors.push(Error::new(format!("Field {} is a scalar and can't have a selection of subfields", field.name)));
+    }
+    if field.name == "__typename" || CHARACTER_FIELDS.iter().any(|(name, _)| *name == field.name) {
+        if let Some((name, _)) = field.arguments.first() {
+            errors.push(Error::new(format!("Unknown argument {name} on field {}", field.name)));
+        }
+    }
+}
+
+fn variables_used(arguments: &[(String, Input)]) -> Vec<&str> {
+    let mut used = Vec::new();
+    arguments.iter().for_each(|(_, input)| variables_in(input, &mut used));
+    used
+}
+
+fn variables_in<'a>(input: &'a Input, used: &mut Vec<&'a str>) {
+    match input {
+        Input::Variable(name) => used.push(name),
+        Input::Constant(_) => {}
+        Input::List(items) => items.iter().for_each(|item| variables_in(item, used)),
+        Input::Object(fields) => fields.iter().for_each(|(_, value)| variables_in(value, used)),
+    }
+}
+
+// the value of an input with the variables in it filled in, null for missing ones
+fn resolve(input: &Input, variables: &Map<String, Value>) -> Value {
+    match input {
+        Input::Variable(name) => variables.get(name).cloned().unwrap_or(Value::Null),
+        Input::Constant(value) => value.clone(),
+        Input::List(items) => Value::Array(items.iter().map(|item| resolve(item, variables)).collect()),
+        Input::Object(fields) => {
+            Value::Object(fields.iter().map(|(name, value)| (name.clone(), resolve(value, variables))).collect())
+        }
+    }
+}
+
+struct Executor {
+    variables: Map<String, Value>,
+    errors: Vec<Error>,
+}
+
+impl Executor {
+    fn root_field(&mut self, field: &Field, mutation: bool) -> Result<Output, Error> {
+        let failed = |(status, message): (StatusCode, String)| Error::from_endpoint(status, &message);
+        match (mutation, field.name.as_str()) {
+            (false, "__typename") => Ok(Output::Value(json!("Query"))),
+            (true, "__typename") => Ok(Output::Value(json!("Mutation"))),
+            (false, "characters") => {
+                let query = EntryQuery {
+                    season: self.integer(field, "season")?,
+                    min_rating: self.float(field, "minRating")?,
+                    ..EntryQuery::default()
+                };
+                let limit = self.integer(field, "limit")?.unwrap_or(0);
+                let characters = endpoints::find_entries(&query, limit).map_err(Error::new)?;
+                let characters = characters.iter().map(|character| select(&entry_value(character), &field.selection));
+                Ok(Output::List(characters.collect()))
+            }
+            (false, "character") => {
+                let id = self.id(field)?;
+                Ok(match endpoints::get_entry(id) {
+                    Some(entry) => select(&serde_json::from_str(&entry).unwrap_or_default(), &field.selection),
+                    None => Output::Value(Value::Null),
+                })
+            }
+            (true, "createCharacter") => {
+                let mut entry = self.entry_input(field)?;
+                if let Some((name, _)) = CHARACTER_FIELDS[1..].iter().find(|(_, key)| !entry.contains_key(*key)) {
+                    return Err(Error::new(format!("input.{name} is required")));
+                }
+                entry.insert("id".to_string(), json!(0));
+                let character: Character =
+                    serde_json::from_value(Value::Object(entry)).map_err(|e| Error::new(format!("Invalid input: {e}")))?;
+                let (_, entry) = endpoints::post_entry(character).map_err(failed)?;
+                Ok(select(&serde_json::from_str(&entry).unwrap_or_default(), &field.selection))
+            }
+            (true, "updateCharacter") => {
+                let id = self.id(field)?;
+                let patch = Value::Object(self.entry_input(field)?).to_string();
+                match endpoints::merge_patch_entry(id, &patch, None) {
+                    (StatusCode::Ok, entry) => Ok(select(&serde_json::from_str(&entry).unwrap_or_default(), &field.selection)),
+                    outcome => Err(failed(outcome)),
+                }
+            }
+            (true, "deleteCharacter") => {
+                let id = self.id(field)?;
+                match endpoints::trash_entry(id, None) {
+                    (StatusCode::NoContent, _) => Ok(Output::Value(json!(true))),
+                    outcome => Err(failed(outcome)),
+                }
+            }
+            // validate() only lets the schema's fields through
+            _ => Err(Error::new(format!("Cannot query field {}", field.name))),
+        }
+    }
+
+    // the argument's value with its variables filled in, null when it is missing
+    fn argument(&self, field: &Field, name: &str) -> Value {
+        field.arguments.iter().find(|(argument, _)| argument == name).map_or(Value::Null, |(_, input)| resolve(input, &self.variables))
+    }
+
+    fn integer<T: TryFrom<u64>>(&self, field: &Field, name: &str) -> Result<Option<T>, Error> {
+        match self.argument(field, name) {
+            Value::Null => Ok(None),
+            value => value
+                .as_u64()
+                .and_then(|value| T::try_from(value).ok())
+                .map(Some)
+                .ok_or_else(|| Error::new(format!("Argument {name} must be a non-negative Int, not {value}"))),
+        }
+    }
+
+    fn float(&self, field: &Field, name: &str) -> Result<Option<f32>, Error> {
+        match self.argument(field, name) {
+            Value::Null => Ok(None),
+            value => {
+                let float = value.as_f64().ok_or_else(|| Error::new(format!("Argument {name} must be a Float, not {value}")))?;
+                Ok(Some(float as f32))
+            }
+        }
+    }
+
+    fn id(&self, field: &Field) -> Result<usize, Error> {
+        self.integer(field, "id")?.ok_or_else(|| Error::new("Argument id must not be null"))
+    }
+
+    // the input argument as an entry's JSON keys
+    fn entry_input(&self, field: &Field) -> Result<Map<String, Value>, Error> {
+        let Value::Object(input) = self.argument(field, "input") else {
+            return Err(Error::new("Argument input must be a CharacterInput object"));
+        };
+        let mut entry = Map::new();
+        for (name, value) in input {
+            match CHARACTER_FIELDS[1..].iter().find(|(field, _)| *field == name) {
+                Some((_, key)) => entry.insert(key.to_string(), value),
+                None => return Err(Error::new(format!("Unknown field {name} in CharacterInput"))),
+            };
+        }
+        Ok(entry)
+    }
+}
+
+// an entry's JSON, through the text so the f32 ratings aren't widened to 7.800000190734863
+fn entry_value(character: &Character) -> Value {
+    serde_json::to_string(character).ok().and_then(|text| serde_json::from_str(&text).ok()).unwrap_or_default()
+}
+
+// the selected fields of an entry
+fn select(entry: &Value, selection: &[Field]) -> Output {
+    let fields = selection.iter().map(|field| {
+        let value = match field.name.as_str() {
+            "__typename" => json!("Character"),
+            name => {
+                let key = CHARACTER_FIELDS.iter().find(|(field, _)| *field == name).map_or(name, |(_, key)| key);
+                entry[key].clone()
+            }
+        };
+        (field.key().to_string(), Output::Value(value))
+    });
+    Output::Object(fields.collect())
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    position: usize,
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(source: &'a str) -> Parser<'a> {
+        Parser { source, position: 0, depth: 0 }
+    }
+
+    fn document(&mut self) -> Result<Vec<Operation>, Error> {
+        let mut operations = Vec::new();
+        while self.peek().is_some() {
+            operations.push(self.operation()?);
+        }
+        if operations.is_empty() {
+            return Err(self.error("The document has no operation"));
+        }
+        Ok(operations)
+    }
+
+    fn operation(&mut self) -> Result<Operation, Error> {
+        // a bare selection set is a query
+        if self.peek() == Some('{') {
+            return Ok(Operation { mutation: false, name: None, variables: Vec::new(), selection: self.selection()? });
+        }
+        let mutation = match self.name()?.as_str() {
+            "query" => false,
+            "mutation" => true,
+            "fragment" => return Err(self.error("Fragments are not supported")),
+            other => return Err(self.error(format!("Unsupported operation {other}"))),
+        };
+        let name = match self.peek() {
+            Some(c) if c == '_' || c.is_ascii_alphabetic() => Some(self.name()?),
+            _ => None,
+        };
+        let mut variables = Vec::new();
+        if self.eat('(') {
+            while !self.eat(')') {
+                self.expect('$')?;
+                let variable = self.name()?;
+                self.expect(':')?;
+                self.type_reference()?;
+                let default = if self.eat('=') { Some(self.constant()?) } else { None };
+                variables.push((variable, default));
+            }
+        }
+        Ok(Operation { mutation, name, variables, selection: self.selection()? })
+    }
+
+    fn selection(&mut self) -> Result<Vec<Field>, Error> {
+        self.expect('{')?;
+        self.nest()?;
+        let mut fields = Vec::new();
+        while !self.eat('}') {
+            if self.source[self.position..].starts_with("...") {
+                return Err(self.error("Fragments are not supported"));
+            }
+            let mut name = self.name()?;
+            let mut alias = None;
+            if self.eat(':') {
+                alias = Some(name);
+                name = self.name()?;
+            }
+            let arguments = if self.eat('(') { self.arguments()? } else { Vec::new() };
+            if self.peek() == Some('@') {
+                return Err(self.error("Directives are not supported"));
+            }
+            let selection = if self.peek() == Some('{') { self.selection()? } else { Vec::new() };
+            fields.push(Field { alias, name, arguments, selection });
+        }
+        self.depth -= 1;
+        if fields.is_empty() {
+            return Err(self.error("A selection set can't be empty"));
+        }
+        Ok(fields)
+    }
+
+    // the arguments after their opening parenthesis
+    fn arguments(&mut self) -> Result<Vec<(String, Input)>, Error> {
+        let mut arguments = Vec::new();
+        while !self.eat(')') {
+            let name = self.name()?;
+            self.expect(':')?;
+            arguments.push((name, self.value()?));
+        }
+        Ok(arguments)
+    }
+
+    fn value(&mut self) -> Result<Input, Error> {
+        match self.peek() {
+            Some('$') => {
+                self.position += 1;
+                Ok(Input::Variable(self.name()?))
+            }
+            Some('[') => {
+                self.position += 1;
+                self.nest()?;
+                let mut items = Vec::new();
+                while !self.eat(']') {
+                    items.push(self.value()?);
+                }
+                self.depth -= 1;
+                Ok(Input::List(items))
+            }
+            Some('{') => {
+                self.position += 1;
+                self.nest()?;
+                let mut fields = Vec::new();
+                while !self.eat('}') {
+                    let name = self.name()?;
+                    self.expect(':')?;
+                    fields.push((name, self.value()?));
+                }
+                self.depth -= 1;
+                Ok(Input::Object(fields))
+            }
+            Some('"') => Ok(Input::Constant(Value::String(self.string()?))),
+            Some(c) if c == '-' || c.is_ascii_digit() => Ok(Input::Constant(self.number()?)),
+            // enum values are taken as their names
+            _ => Ok(Input::Constant(match self.name()?.as_str() {
+                "true" => Value::Bool(true),
+                "false" => Value::Bool(false),
+                "null" => Value::Null,
+                name => Value::String(name.to_string()),
+            })),
+        }
+    }
+
+    // a value without variables, for defaults
+    fn constant(&mut self) -> Result<Value, Error> {
+        let start = self.position;
+        let value = self.value()?;
+        let mut used = Vec::new();
+        variables_in(&value, &mut used);
+        if !used.is_empty() {
+            self.position = start;
+            return Err(self.error("A default value can't use variables"));
+        }
+        Ok(resolve(&value, &Map::new()))
+    }
+
+    fn type_reference(&mut self) -> Result<(), Error> {
+        if self.eat('[') {
+            self.nest()?;
+            self.type_reference()?;
+            self.expect(']')?;
+            self.depth -= 1;
+        } else {
+            self.name()?;
+        }
+        self.eat('!');
+        Ok(())
+    }
+
+    fn name(&mut self) -> Result<String, Error> {
+        self.skip();
+        let rest = &self.source[self.position..];
+        let length = rest.find(|c: char| c != '_' && !c.is_ascii_alphanumeric()).unwrap_or(rest.len());
+        if length == 0 || rest.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err(self.error(match rest.chars().next() {
+                Some(c) => format!("Expected a name, found {c}"),
+                None => "Expected a name, found the end of the document".to_string(),
+            }));
+        }
+        self.position += length;
+        Ok(rest[..length].to_string())
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        if self.source[self.position..].starts_with("\"\"\"") {
+            return Err(self.error("Block strings are not supported"));
+        }
+        let start = self.position;
+        self.position += 1;
+        let mut text = String::new();
+        let mut chars = self.source[self.position..].char_indices();
+        while let Some((offset, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.position += offset + 1;
+                    return Ok(text);
+                }
+                '\n' | '\r' => break,
+                '\\' => {
+                    let escaped = match chars.next().map(|(_, c)| c) {
+                        Some('u') => {
+                            let hex: String = chars.by_ref().take(4).map(|(_, c)| c).collect();
+                            u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32)
+                        }
+                        Some('n') => Some('\n'),
+                        Some('r') => Some('\r'),
+                        Some('t') => Some('\t'),
+                        Some('b') => Some('\u{8}'),
+                        Some('f') => Some('\u{c}'),
+                        Some(c @ ('"' | '\\' | '/')) => Some(c),
+                        _ => None,
+                    };
+                    let Some(escaped) = escaped else {
+                        self.position += offset;
+                        return Err(self.error("Invalid escape sequence in string"));
+                    };
+                    text.push(escaped);
+                }
+                c => text.push(c),
+            }
+        }
+        self.position = start;
+        Err(self.error("Unterminated string"))
+    }
+
+    fn number(&mut self) -> Result<Value, Error> {
+        let rest = &self.source[self.position..];
+        let length = rest
+            .find(|c: char| !(c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E')))
+            .unwrap_or(rest.len());
+        let text = &rest[..length];
+        let number = match text.contains(['.', 'e', 'E']) {
+            true => text.parse::<f64>().ok().and_then(|float| serde_json::Number::from_f64(float).map(Value::Number)),
+            false => text.parse::<i64>().ok().map(Value::from),
+        };
+        let number = number.ok_or_else(|| self.error(format!("Invalid number {text}")))?;
+        self.position += length;
+        Ok(number)
+    }
+
+    // one level deeper into selections, lists or objects
+    fn nest(&mut self) -> Result<(), Error> {
+        self.depth += 1;
+        match self.depth > MAX_DEPTH {
+            true => Err(self.error("The document nests too deeply")),
+            false => Ok(()),
+        }
+    }
+
+    // skips whitespace, commas and comments
+    fn skip(&mut self) {
+        loop {
+            let rest = &self.source[self.position..];
+            let trimmed = rest.trim_start_matches(|c: char| c.is_whitespace() || c == ',' || c == '\u{feff}');
+            self.position += rest.len() - trimmed.len();
+            if !trimmed.starts_with('#') {
+                return;
+            }
+            self.position += trimmed.find('\n').unwrap_or(trimmed.len());
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip();
+        self.source[self.position..].chars().next()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        let found = self.peek() == Some(c);
+        if found {
+            self.position += c.len_utf8();
+        }
+        found
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), Error> {
+        match self.peek() {
+            _ if self.eat(c) => Ok(()),
+            Some(found) => Err(self.error(format!("Expected {c}, found {found}"))),
+            None => Err(self.error(format!("Expected {c}, found the end of the document"))),
+        }
+    }
+
+    // a syntax error at the current position
+    fn error(&self, message: impl Into<String>) -> Error {
+        let before = &self.source[..self.position];
+        let line = before.matches('\n').count() + 1;
+        let column = before.rsplit('\n').next().unwrap_or_default().chars().count() + 1;
+        Error { locations: vec![Location { line, column }], ..Error::new(format!("Syntax error: {}", message.into())) }
+    }
+}
@@ -0,0 +1,611 @@
+// request and response types shared by the router and the handlers
+
+use crate::chunked::StreamBody;
+use crate::connection::ConnectionInfo;
+use crate::keep_alive;
+use crate::problem::{self, Problem};
+use crate::sendfile::FileBody;
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+
+// a parsed request as handlers see it
+#[derive(Clone)]
+pub(crate) struct Request {
+    pub(crate) method: String,
+    // the request target as received, path and query
+    pub(crate) uri: String,
+    pub(crate) path: String,
+    pub(crate) query: HashMap<String, String>,
+    // what the `{name}` segments of the matched route pattern stood for
+    pub(crate) params: HashMap<String, String>,
+    pub(crate) headers: HeaderMap,
+    // the host name from the Host header, lowercased and without the port
+    pub(crate) host: Option<String>,
+    // the bytes as received, text() and json() decode them for handlers that want it
+    pub(crate) body: Vec<u8>,
+    // the client, local address and TLS details of the connection it came over
+    pub(crate) connection: ConnectionInfo,
+    // what the middleware that ran before the handler found out, by type
+    pub(crate) extensions: Extensions,
+}
+
+impl Request {
+    pub(crate) fn new(uri: &str, headers: &HeaderMap, body: &[u8]) -> Request {
+        let (path, query) = split_uri(uri);
+        Request {
+            // route() fills in the method and client, requests built anywhere else are GETs
+            method: "GET".to_string(),
+            uri: uri.to_string(),
+            path: path.to_string(),
+            query,
+            params: HashMap::new(),
+            headers: headers.clone(),
+            host: headers.get("Host").map(host_name),
+            body: body.to_vec(),
+            connection: ConnectionInfo::default(),
+            extensions: Extensions::default(),
+        }
+    }
+
+    // the body as text, None when it isn't valid UTF-8
+    pub(crate) fn text(&self) -> Option<&str> {
+        std::str::from_utf8(&self.body).ok()
+    }
+
+    pub(crate) fn json<T: DeserializeOwned>(&self) -> serde_json::Result<T> {
+        serde_json::from_slice(&self.body)
+    }
+}
+
+// values of any type a middleware passes on to the middleware and handler
+// after it, one per type: the key a request authenticated with, the user
+// signed in. handlers take one with the Extension extractor
+#[derive(Clone, Default)]
+pub(crate) struct Extensions {
+    values: HashMap<TypeId, Arc<dyn Any + Send + Sync>>,
+}
+
+impl Extensions {
+    // keeps `value`, replacing the one of its type set earlier
+    pub(crate) fn insert<T: Send + Sync + 'static>(&mut self, value: T) {
+        self.values.insert(TypeId::of::<T>(), Arc::new(value));
+    }
+
+    pub(crate) fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.values.get(&TypeId::of::<T>()).and_then(|value| value.downcast_ref())
+    }
+}
+
+// the protocol version named in a request line
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Version {
+    Http10,
+    Http11,
+}
+
+impl Version {
+    // "HTTP/1.0" or "HTTP/1.1", a later HTTP/1.x is answered as the 1.1 it is
+    // compatible with (RFC 9110 2.5). None for any other version
+    pub(crate) fn parse(version: &str) -> Option<Version> {
+        let minor = version.strip_prefix("HTTP/1.")?;
+        match minor {
+            "0" => Some(Version::Http10),
+            _ if !minor.is_empty() && minor.bytes().all(|b| b.is_ascii_digit()) => Some(Version::Http11),
+            _ => None,
+        }
+    }
+}
+
+// "Example.com:8080" as "example.com", "[::1]:7878" as "[::1]"
+pub(crate) fn host_name(host: &str) -> String {
+    let name = match host.rsplit_once(':') {
+        Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) && !name.ends_with(':') => name,
+        _ => host,
+    };
+    name.to_ascii_lowercase()
+}
+
+// splits "/path?a=1&b=2" into the path and its decoded query parameters
+pub(crate) fn split_uri(uri: &str) -> (&str, HashMap<String, String>) {
+    let (path, query) = match uri.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (uri, ""),
+    };
+    (path, parse_query(query))
+}
+
+// decodes "a=1&b=2", a query string or an application/x-www-form-urlencoded body
+pub(crate) fn parse_query(query: &str) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        params.insert(percent_decode(name), percent_decode(value));
+    }
+    params
+}
+
+// decodes %XX escapes and '+' as used in query strings
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("");
+                match u8::from_str_radix(hex, 16) {
+                    Ok(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    Err(_) => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+// response status codes, with the reason phrases this server sends
+// the whole common table is listed even where no handler uses a code yet
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum StatusCode {
+    Continue,
+    Ok,
+    Created,
+    Accepted,
+    NoContent,
+    PartialContent,
+    MovedPermanently,
+    Found,
+    SeeOther,
+    NotModified,
+    TemporaryRedirect,
+    PermanentRedirect,
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    NotAcceptable,
+    RequestTimeout,
+    Conflict,
+    Gone,
+    LengthRequired,
+    PreconditionFailed,
+    PayloadTooLarge,
+    UriTooLong,
+    UnsupportedMediaType,
+    RangeNotSatisfiable,
+    ExpectationFailed,
+    UnprocessableEntity,
+    TooManyRequests,
+    RequestHeaderFieldsTooLarge,
+    InternalServerError,
+    NotImplemented,
+    BadGateway,
+    ServiceUnavailable,
+    GatewayTimeout,
+    HttpVersionNotSupported,
+}
+
+impl StatusCode {
+    pub(crate) fn code(self) -> u16 {
+        match self {
+            StatusCode::Continue => 100,
+            StatusCode::Ok => 200,
+            StatusCode::Created => 201,
+            StatusCode::Accepted => 202,
+            StatusCode::NoContent => 204,
+            StatusCode::PartialContent => 206,
+            StatusCode::MovedPermanently => 301,
+            StatusCode::Found => 302,
+            StatusCode::SeeOther => 303,
+            StatusCode::NotModified => 304,
+            StatusCode::TemporaryRedirect => 307,
+            StatusCode::PermanentRedirect => 308,
+            StatusCode::BadRequest => 400,
+            StatusCode::Unauthorized => 401,
+            StatusCode::Forbidden => 403,
+            StatusCode::NotFound => 404,
+            StatusCode::MethodNotAllowed => 405,
+            StatusCode::NotAcceptable => 406,
+            StatusCode::RequestTimeout => 408,
+            StatusCode::Conflict => 409,
+            StatusCode::Gone => 410,
+            StatusCode::LengthRequired => 411,
+            StatusCode::PreconditionFailed => 412,
+            StatusCode::PayloadTooLarge => 413,
+            StatusCode::UriTooLong => 414,
+            StatusCode::UnsupportedMediaType => 415,
+            StatusCode::RangeNotSatisfiable => 416,
+            StatusCode::ExpectationFailed => 417,
+            StatusCode::UnprocessableEntity => 422,
+            StatusCode::TooManyRequests => 429,
+            StatusCode::RequestHeaderFieldsTooLarge => 431,
+            StatusCode::InternalServerError => 500,
+            StatusCode::NotImplemented => 501,
+            StatusCode::BadGateway => 502,
+            StatusCode::ServiceUnavailable => 503,
+            StatusCode::GatewayTimeout => 504,
+            StatusCode::HttpVersionNotSupported => 505,
+        }
+    }
+
+    pub(crate) fn reason(self) -> &'static str {
+        match self {
+            StatusCode::Continue => "CONTINUE",
+            StatusCode::Ok => "OK",
+            StatusCode::Created => "CREATED",
+            StatusCode::Accepted => "ACCEPTED",
+            StatusCode::NoContent => "NO CONTENT",
+            StatusCode::PartialContent => "PARTIAL CONTENT",
+            StatusCode::MovedPermanently => "MOVED PERMANENTLY",
+            StatusCode::Found => "FOUND",
+            StatusCode::SeeOther => "SEE OTHER",
+            StatusCode::NotModified => "NOT MODIFIED",
+            StatusCode::TemporaryRedirect => "TEMPORARY REDIRECT",
+            StatusCode::PermanentRedirect => "PERMANENT REDIRECT",
+            StatusCode::BadRequest => "BAD REQUEST",
+            StatusCode::Unauthorized => "UNAUTHORIZED",
+            StatusCode::Forbidden => "FORBIDDEN",
+            StatusCode::NotFound => "NOT FOUND",
+            StatusCode::MethodNotAllowed => "METHOD NOT ALLOWED",
+            StatusCode::NotAcceptable => "NOT ACCEPTABLE",
+            StatusCode::RequestTimeout => "REQUEST TIMEOUT",
+            StatusCode::Conflict => "CONFLICT",
+            StatusCode::Gone => "GONE",
+            StatusCode::LengthRequired => "LENGTH REQUIRED",
+            StatusCode::PreconditionFailed => "PRECONDITION FAILED",
+            StatusCode::PayloadTooLarge => "PAYLOAD TOO LARGE",
+            StatusCode::UriTooLong => "URI TOO LONG",
+            StatusCode::UnsupportedMediaType => "UNSUPPORTED MEDIA TYPE",
+            StatusCode::RangeNotSatisfiable => "RANGE NOT SATISFIABLE",
+            StatusCode::ExpectationFailed => "EXPECTATION FAILED",
+            StatusCode::UnprocessableEntity => "UNPROCESSABLE ENTITY",
+            StatusCode::TooManyRequests => "TOO MANY REQUESTS",
+            StatusCode::RequestHeaderFieldsTooLarge => "REQUEST HEADER FIELDS TOO LARGE",
+            StatusCode::InternalServerError => "INTERNAL SERVER ERROR",
+            StatusCode::NotImplemented => "NOT IMPLEMENTED",
+            StatusCode::BadGateway => "BAD GATEWAY",
+            StatusCode::ServiceUnavailable => "SERVICE UNAVAILABLE",
+            StatusCode::GatewayTimeout => "GATEWAY TIMEOUT",
+            StatusCode::HttpVersionNotSupported => "HTTP VERSION NOT SUPPORTED",
+        }
+    }
+
+    // the status a numeric code stands for, None for codes not in the table
+    #[cfg_attr(not(feature = "proxy"), allow(dead_code))]
+    pub(crate) fn from_code(code: u16) -> Option<StatusCode> {
+        use StatusCode::*;
+        [
+            Continue, Ok, Created, Accepted, NoContent, PartialContent, MovedPermanently, Found, SeeOther,
+            NotModified, TemporaryRedirect, PermanentRedirect, BadRequest, Unauthorized, Forbidden, NotFound,
+            MethodNotAllowed, NotAcceptable, RequestTimeout, Conflict, Gone, LengthRequired, PreconditionFailed,
+            PayloadTooLarge, UriTooLong, UnsupportedMediaType, RangeNotSatisfiable, ExpectationFailed,
+            UnprocessableEntity, TooManyRequests, RequestHeaderFieldsTooLarge, InternalServerError, NotImplemented,
+            BadGateway, ServiceUnavailable, GatewayTimeout, HttpVersionNotSupported,
+        ]
+        .into_iter()
+        .find(|status| status.code() == code)
+    }
+
+    // 1xx, 204 and 304 responses never have a body, nor a Content-Length (RFC 9110 8.6)
+    pub(crate) fn allows_body(self) -> bool {
+        let code = self.code();
+        !(100..200).contains(&code) && code != 204 && code != 304
+    }
+}
+
+// header fields keyed case-insensitively, keeping every value and the order
+// they were received or added in (Set-Cookie, Accept, ... may repeat)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct HeaderMap {
+    entries: Vec<(String, String)>,
+}
+
+impl HeaderMap {
+    pub(crate) fn new() -> HeaderMap {
+        HeaderMap::default()
+    }
+
+    // adds a value, keeping the ones already present under the same name
+    pub(crate) fn append(&mut self, name: &str, value: impl Into<String>) {
+        self.entries.push((name.to_string(), value.into()));
+    }
+
+    // sets the only value of a header, dropping earlier ones
+    pub(crate) fn insert(&mut self, name: &str, value: impl Into<String>) {
+        self.remove(name);
+        self.append(name, value);
+    }
+
+    pub(crate) fn remove(&mut self, name: &str) {
+        self.entries.retain(|(existing, _)| !existing.eq_ignore_ascii_case(name));
+    }
+
+    // the first value of a header
+    pub(crate) fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(existing, _)| existing.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub(crate) fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(existing, _)| existing.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+}
+
+// a response under construction, serialized by to_bytes once the handler is done
+#[derive(Clone)]
+pub(crate) struct Response {
+    pub(crate) status: StatusCode,
+    pub(crate) headers: HeaderMap,
+    pub(crate) body: Vec<u8>,
+    // sent after `body` straight from the file, for large static files
+    pub(crate) file: Option<FileBody>,
+    // written while it is sent, with chunked transfer coding
+    pub(crate) stream: Option<StreamBody>,
+    // cut off on purpose, to see how a client copes
+    pub(crate) abort: Option<Abort>,
+}
+
+// how a response is broken on purpose by fault injection
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(not(feature = "faults"), allow(dead_code))]
+pub(crate) enum Abort {
+    // the connection is closed without sending anything
+    Drop,
+    // the head and half the body are sent, then the connection is closed
+    Truncate,
+}
+
+impl Response {
+    pub(crate) fn new(status: StatusCode) -> Response {
+        Response {
+            status,
+            headers: HeaderMap::new(),
+            body: Vec::new(),
+            file: None,
+            stream: None,
+            abort: None,
+        }
+    }
+
+    // a response with a plain text body
+    pub(crate) fn text(status: StatusCode, body: impl Into<String>) -> Response {
+        Response::new(status)
+            .content_type("text/plain; charset=utf-8")
+            .body(body.into().into_bytes())
+    }
+
+    // a response whose body is already serialized JSON
+    pub(crate) fn json(status: StatusCode, body: impl Into<String>) -> Response {
+        Response::new(status)
+            .content_type("application/json")
+            .body(body.into().into_bytes())
+    }
+
+    // an error response, `detail` explaining it in a problem details document
+    pub(crate) fn problem(status: StatusCode, detail: impl Into<String>) -> Response {
+        Response::new(status)
+            .content_type(problem::MEDIA_TYPE)
+            .body(Problem::new(status, detail).to_json().into_bytes())
+    }
+
+    // an HTML page from the built-in template `name`, filled in from `context`
+    #[cfg(feature = "templates")]
+    pub(crate) fn render(name: &str, context: impl serde::Serialize) -> Response {
+        let Some(template) = crate::templates::get(name) else {
+            return Response::problem(StatusCode::InternalServerError, format!("No template named {name}"));
+        };
+        match serde_json::to_value(context) {
+            Ok(context) => Response::new(StatusCode::Ok)
+                .content_type("text/html; charset=utf-8")
+                .body(template.render(&context).into_bytes()),
+            Err(e) => Response::problem(StatusCode::InternalServerError, format!("Failed to render {name}: {e}")),
+        }
+    }
+
+    // a redirect to `location`, with a short text body for clients that don't follow it
+    pub(crate) fn redirect(status: StatusCode, location: impl Into<String>) -> Response {
+        let location = location.into();
+        Response::text(status, format!("Moved to {location}")).header("Location", location)
+    }
+
+    // adds a header, repeated names are sent as separate fields
+    pub(crate) fn header(mut self, name: &str, value: impl Into<String>) -> Response {
+        self.headers.append(name, value);
+        self
+    }
+
+    // sets the Content-Type, replacing any earlier one
+    pub(crate) fn content_type(mut self, content_type: &str) -> Response {
+        self.headers.insert("Content-Type", content_type);
+        self
+    }
+
+    pub(crate) fn body(mut self, body: Vec<u8>) -> Response {
+        self.body = body;
+        self
+    }
+
+    // a body sent from the file instead of memory
+    pub(crate) fn file(mut self, file: FileBody) -> Response {
+        self.body = Vec::new();
+        self.file = Some(file);
+        self
+    }
+
+    // a body written while it is sent instead of built first
+    pub(crate) fn stream(mut self, stream: StreamBody) -> Response {
+        self.body = Vec::new();
+        self.stream = Some(stream);
+        self
+    }
+
+    // sends the response broken, `abort` telling how
+    #[cfg_attr(not(feature = "faults"), allow(dead_code))]
+    pub(crate) fn abort(mut self, abort: Abort) -> Response {
+        self.abort = Some(abort);
+        self
+    }
+
+    // the length of the body, wherever it is, unknown to the client for a stream
+    pub(crate) fn body_len(&self) -> u64 {
+        self.body.len() as u64 + self.file.as_ref().map_or(0, FileBody::length)
+    }
+
+    // status line, Content-Length, headers and (unless head_only) the body,
+    // a file or streamed body is left to the connection to send
+    pub(crate) fn serialize(&self, head_only: bool) -> Serialized {
+        let mut bytes = self.head().into_bytes();
+        let head_len = bytes.len();
+        let (mut file, mut stream) = (None, None);
+        if !head_only && self.status.allows_body() {
+            bytes.extend_from_slice(&self.body);
+            file = self.file.clone();
+            stream = self.stream.clone();
+        }
+        let serialized = Serialized { bytes, file, stream, close: keep_alive::has_option(&self.headers, "close") };
+        match self.abort {
+            None => serialized,
+            Some(Abort::Drop) => Serialized { bytes: Vec::new(), file: None, stream: None, close: true },
+            Some(Abort::Truncate) => {
+                let mut bytes = serialized.into_bytes().unwrap_or_default();
+                bytes.truncate(head_len + (bytes.len().saturating_sub(head_len)) / 2);
+                Serialized { bytes, file: None, stream: None, close: true }
+            }
+        }
+    }
+
+    // the status line, the framing header and the other headers
+    fn head(&self) -> String {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status.code(), self.status.reason());
+        if self.status.allows_body() {
+            match self.stream {
+                Some(_) => head.push_str("Transfer-Encoding: chunked\r\n"),
+                None => head.push_str(&format!("Content-Length: {}\r\n", self.body_len())),
+            }
+        }
+        for (name, value) in self.headers.iter() {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        head.push_str("\r\n");
+        head
+    }
+
+    // the bytes serialize sends, a stream counted before chunking, without
+    // copying the body
+    pub(crate) fn serialized_len(&self, head_only: bool) -> u64 {
+        let mut len = self.head().len() as u64;
+        if !head_only && self.status.allows_body() {
+            len += self.body_len() + self.stream.as_ref().map_or(0, StreamBody::length);
+        }
+        len
+    }
+
+    // the whole response in memory, for responses built without a file body
+    pub(crate) fn to_bytes(&self, head_only: bool) -> Vec<u8> {
+        self.serialize(head_only).into_bytes().unwrap_or_default()
+    }
+}
+
+// a response ready to go out, the bytes then the file or streamed body if any
+pub(crate) struct Serialized {
+    pub(crate) bytes: Vec<u8>,
+    pub(crate) file: Option<FileBody>,
+    pub(crate) stream: Option<StreamBody>,
+    // whether the connection is closed once it is sent
+    pub(crate) close: bool,
+}
+
+impl Serialized {
+    // a stream counts with its length before chunking and compression
+    pub(crate) fn len(&self) -> usize {
+        self.bytes.len()
+            + self.file.as_ref().map_or(0, |file| file.length() as usize)
+            + self.stream.as_ref().map_or(0, |stream| stream.length() as usize)
+    }
+
+    // everything in memory, the file body read in and the stream written out
+    pub(crate) fn into_bytes(self) -> io::Result<Vec<u8>> {
+        let mut bytes = self.bytes;
+        if let Some(file) = self.file {
+            bytes.extend(file.read()?);
+        }
+        if let Some(stream) = self.stream {
+            bytes.extend(stream.read()?);
+        }
+        Ok(bytes)
+    }
+}
+
+// what a handler may return, converted into the response sent for it
+pub(crate) trait IntoResponse {
+    fn into_response(self) -> Response;
+}
+
+impl IntoResponse for Response {
+    fn into_response(self) -> Response {
+        self
+    }
+}
+
+// a 200 with a plain text body
+impl IntoResponse for String {
+    fn into_response(self) -> Response {
+        Response::text(StatusCode::Ok, self)
+    }
+}
+
+impl IntoResponse for &'static str {
+    fn into_response(self) -> Response {
+        Response::text(StatusCode::Ok, self)
+    }
+}
+
+// the response of the second element sent with another status
+impl<R: IntoResponse> IntoResponse for (StatusCode, R) {
+    fn into_response(self) -> Response {
+        Response { status: self.0, ..self.1.into_response() }
+    }
+}
+
+impl<T: IntoResponse, E: IntoResponse> IntoResponse for Result<T, E> {
+    fn into_response(self) -> Response {
+        match self {
+            Ok(value) => value.into_response(),
+            Err(error) => error.into_response(),
+        }
+    }
+}
+
+// the media type of a Content-Type value, lowercased and without parameters
+pub(crate) fn media_type(content_type: &str) -> String {
+    let media_type = content_type.split(';').next().unwrap_or("");
+    media_type.trim().to_ascii_lowercase()
+}
+
+// formats a timestamp as an HTTP date (IMF-fixdate, RFC 9110 5.6.7)
+pub(crate) fn http_date(time: DateTime<Utc>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
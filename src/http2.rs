@@ -0,0 +1,223 @@
+// HTTP/2 on the TLS listeners, enabled with the `http2` cargo feature
+//
+// a client offering h2 in ALPN gets HTTP/2 on its TLS connection, framed by
+// the h2 crate. every stream it opens is a request answered on a task of its
+// own, so one connection carries any number of them at once. a request is
+// read whole and written out as HTTP/1.1 for the same parsing, limits and
+// handlers as any other, and its response goes back as HEADERS and DATA
+// frames without the headers that only make sense on an HTTP/1.1 connection.
+// like a kept alive one, the connection is closed (GOAWAY) once it has been
+// idle for `keep_alive_timeout_secs`, has carried
+// `max_requests_per_connection` streams or the server is stopping. the event
+// stream is only served over HTTP/1.1.
+
+use crate::async_server::Transport;
+use crate::config;
+use crate::connection::ConnectionInfo;
+use crate::http::{Response, StatusCode};
+use crate::listener::Routes;
+use crate::slow_log::Timing;
+use crate::{keep_alive, log, rate_limit, reload, respond, stats};
+use bytes::Bytes;
+use h2::server::{self, SendResponse};
+use h2::RecvStream;
+use std::io::{self, BufReader, Cursor};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_rustls::server::TlsStream;
+
+// the ALPN protocol id of HTTP/2 over TLS
+pub(crate) const ALPN: &[u8] = b"h2";
+
+// headers about an HTTP/1.1 connection, which HTTP/2 doesn't allow (RFC 9113 8.2.2)
+const CONNECTION_HEADERS: [&str; 5] = ["Connection", "Keep-Alive", "Proxy-Connection", "Transfer-Encoding", "Upgrade"];
+
+// streams of a connection being answered, counted down when one is done
+struct Open(Arc<AtomicUsize>);
+
+impl Drop for Open {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub(crate) async fn serve(stream: TlsStream<TcpStream>, routes: Routes) {
+    let _in_flight = reload::InFlight::start();
+    stats::connection_accepted();
+    let info = stream.connection();
+    let mut connection = match server::handshake(stream).await {
+        Ok(connection) => connection,
+        Err(e) => {
+            log::debug!("HTTP/2 handshake failed: {}", e);
+            return;
+        }
+    };
+    let open = Arc::new(AtomicUsize::new(0));
+    let mut served = 0;
+    let mut closing = false;
+    loop {
+        let idle = keep_alive::idle_timeout(config::get());
+        // the connection has to be polled for the open streams to be sent,
+        // it only counts as idle without any
+        let accepted = match idle.filter(|_| !closing && open.load(Ordering::SeqCst) == 0) {
+            Some(timeout) => match tokio::time::timeout(timeout, connection.accept()).await {
+                Ok(accepted) => accepted,
+                Err(_) => {
+                    connection.graceful_shutdown();
+                    closing = true;
+                    continue;
+                }
+            },
+            None => connection.accept().await,
+        };
+        let (request, reply) = match accepted {
+            Some(Ok(stream)) => stream,
+            Some(Err(e)) => {
+                log::debug!("HTTP/2 connection failed: {}", e);
+                return;
+            }
+            None => return,
+        };
+        served += 1;
+        let max = config::get().max_requests_per_connection;
+        if !closing && (idle.is_none() || (max > 0 && served >= max) || reload::stopping()) {
+            connection.graceful_shutdown();
+            closing = true;
+        }
+        open.fetch_add(1, Ordering::SeqCst);
+        let open = Open(Arc::clone(&open));
+        let info = info.clone();
+        tokio::spawn(async move {
+            let _open = open;
+            answer_stream(request, reply, routes, info, served).await;
+        });
+    }
+}
+
+async fn answer_stream(
+    request: h2_http::Request<RecvStream>,
+    mut reply: SendResponse<Bytes>,
+    routes: Routes,
+    connection: ConnectionInfo,
+    served: usize,
+) {
+    // the first stream was counted when the connection was accepted
+    if served > 1 {
+        if let Some(Err(response)) = connection.peer.map(|peer| rate_limit::check(config::get(), peer.ip())) {
+            send(&mut reply, response, false).await;
+            return;
+        }
+    }
+    let mut timing = Timing::start();
+    let raw_request = match read_request(request).await {
+        Ok(raw_request) => raw_request,
+        Err(e) => {
+            log::debug!("Failed to read HTTP/2 request: {}", e);
+            return;
+        }
+    };
+    let answered = tokio::task::spawn_blocking(move || {
+        let mut buf_reader = BufReader::new(Cursor::new(raw_request));
+        let answered = respond(&mut buf_reader, &mut io::sink(), routes, &connection, served, &mut timing);
+        (answered, timing)
+    })
+    .await;
+    match answered {
+        Ok((answered, timing)) => {
+            let (response, head_only) = answered.unwrap_or_else(|| {
+                (Response::problem(StatusCode::NotImplemented, "The event stream is only served over HTTP/1.1"), false)
+            });
+            send(&mut reply, response, head_only).await;
+            timing.finish();
+        }
+        Err(e) => {
+            log::error!("Failed to answer request: {}", e);
+            reply.send_reset(h2::Reason::INTERNAL_ERROR);
+        }
+    }
+}
+
+// the request as HTTP/1.1, its body read whole, for answer to parse like any other
+async fn read_request(request: h2_http::Request<RecvStream>) -> Result<Vec<u8>, h2::Error> {
+    let (parts, mut body) = request.into_parts();
+    let mut data = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        let _ = body.flow_control().release_capacity(chunk.len());
+        data.extend_from_slice(&chunk);
+    }
+
+    let target = parts.uri.path_and_query().map_or("/", |target| target.as_str());
+    let mut raw = format!("{} {} HTTP/1.1\r\n", parts.method, target).into_bytes();
+    // :authority stands in for Host
+    if let Some(authority) = parts.uri.authority().filter(|_| !parts.headers.contains_key("host")) {
+        raw.extend_from_slice(format!("Host: {authority}\r\n").as_bytes());
+    }
+    for (name, value) in &parts.headers {
+        if name != "content-length" {
+            raw.extend_from_slice(name.as_str().as_bytes());
+            raw.extend_from_slice(b": ");
+            raw.extend_from_slice(value.as_bytes());
+            raw.extend_from_slice(b"\r\n");
+        }
+    }
+    // the frames delimit the body, the length is the one that arrived
+    if !data.is_empty() {
+        raw.extend_from_slice(format!("Content-Length: {}\r\n", data.len()).as_bytes());
+    }
+    raw.extend_from_slice(b"\r\n");
+    raw.extend_from_slice(&data);
+    Ok(raw)
+}
+
+// sends `response` on the stream, HEADERS then the body in DATA
+async fn send(reply: &mut SendResponse<Bytes>, response: Response, head_only: bool) {
+    let framed = tokio::task::spawn_blocking(move || frame(response, head_only)).await;
+    let (head, body) = match framed {
+        Ok(Ok(framed)) => framed,
+        Ok(Err(e)) => {
+            log::error!("Failed to send HTTP/2 response: {}", e);
+            reply.send_reset(h2::Reason::INTERNAL_ERROR);
+            return;
+        }
+        Err(e) => {
+            log::error!("Failed to send HTTP/2 response: {}", e);
+            reply.send_reset(h2::Reason::INTERNAL_ERROR);
+            return;
+        }
+    };
+    let end = body.is_empty();
+    match reply.send_response(head, end) {
+        Ok(mut stream) if !end => {
+            if let Err(e) = stream.send_data(Bytes::from(body), true) {
+                log::debug!("Failed to send HTTP/2 body: {}", e);
+            }
+        }
+        Ok(_) => {}
+        Err(e) => log::debug!("Failed to send HTTP/2 response: {}", e),
+    }
+}
+
+// the head of `response` for h2 and its body, a file or stream read into memory
+fn frame(response: Response, head_only: bool) -> io::Result<(h2_http::Response<()>, Vec<u8>)> {
+    let with_body = response.status.allows_body();
+    let body = match (&response.file, &response.stream) {
+        _ if head_only || !with_body => Vec::new(),
+        (Some(file), _) => file.read()?,
+        (None, Some(stream)) => stream.plain()?,
+        (None, None) => response.body.clone(),
+    };
+    let mut head = h2_http::Response::builder().status(response.status.code());
+    for (name, value) in response.headers.iter() {
+        if !CONNECTION_HEADERS.iter().any(|header| header.eq_ignore_ascii_case(name)) {
+            head = head.header(name, value);
+        }
+    }
+    // h2 clients take the length of an answer to HEAD for the DATA to expect
+    if with_body && !head_only {
+        head = head.header("content-length", body.len());
+    }
+    let head = head.body(()).map_err(io::Error::other)?;
+    Ok((head, body))
+}
@@ -0,0 +1,43 @@
+// _links on the entries /api/v2 answers with
+//
+// a JSON object with an "id" in a 2xx answer is taken for an entry and gets
+// `_links` next to its fields, HAL style: "self", "collection", "update" and
+// "delete", each an href and the method to follow it with, and each left out
+// when no route takes it. they are read off the router's own patterns, so
+// clients follow them instead of building URLs. the entry is the one at the
+// request's path, or at Location for one just created. ETag stays that of the
+// entry alone, an If-Match taken from it still matches the stored entry. the
+// unversioned routes and /api/v1 are frozen and answer without links.
+
+use crate::http::{Request, Response};
+use crate::json;
+use crate::router::Next;
+use serde_json::{Map, Value};
+
+pub(crate) fn middleware(request: &mut Request, next: Next) -> Response {
+    let response = next(request);
+    let is_json = response.headers.get("Content-Type").is_some_and(|media_type| media_type.starts_with("application/json"));
+    if !(200..300).contains(&response.status.code()) || !is_json {
+        return response;
+    }
+    let Ok(Value::Object(mut entry)) = serde_json::from_slice(&response.body) else {
+        return response;
+    };
+    if !entry.contains_key("id") {
+        return response;
+    }
+    let path = response.headers.get("Location").unwrap_or(&request.path).to_string();
+    entry.insert("_links".to_string(), links(&path));
+    let body = json::to_string(&entry).expect("Error parsing to string").into_bytes();
+    response.body(body)
+}
+
+// the links of the resource at `path`, { "self": { "href": ..., "method": "GET" }, ... }
+pub(crate) fn links(path: &str) -> Value {
+    let links: Map<String, Value> = crate::ROUTER
+        .links(path)
+        .into_iter()
+        .map(|(rel, link)| (rel.to_string(), serde_json::to_value(link).expect("a link serializes")))
+        .collect();
+    Value::Object(links)
+}
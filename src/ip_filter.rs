@@ -0,0 +1,137 @@
+// allow and deny lists of client addresses
+//
+// `ip_filter` in config.json holds CIDR ranges like "10.0.0.0/8" or single
+// addresses. the top level `allow` and `deny` lists apply to every connection
+// and are checked as it is accepted, `rules` restrict groups of paths further
+// and are checked by the router's middleware once a request matched a route.
+// a denied client is answered 403. within a list a deny wins over an allow,
+// and a non-empty allow list lets in only the addresses it names.
+
+use crate::config::{self, Config};
+use crate::http::{Request, Response, StatusCode};
+use crate::router::Next;
+use serde::Deserialize;
+use std::net::IpAddr;
+
+// an address range, "192.168.0.0/16" or "::1"
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(try_from = "String")]
+pub(crate) struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub(crate) fn parse(value: &str) -> Result<Cidr, String> {
+        let invalid = || format!("{value} is not an address or CIDR range");
+        let (address, prefix) = match value.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix.parse::<u8>().map_err(|_| invalid())?)),
+            None => (value, None),
+        };
+        let network: IpAddr = address.parse().map_err(|_| invalid())?;
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(bits);
+        if prefix > bits {
+            return Err(invalid());
+        }
+        Ok(Cidr { network, prefix })
+    }
+
+    pub(crate) fn contains(&self, ip: IpAddr) -> bool {
+        // an IPv4 client on an IPv6 socket shows up as ::ffff:a.b.c.d
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                masked(u32::from(network).into(), 32, self.prefix) == masked(u32::from(ip).into(), 32, self.prefix)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                masked(network.into(), 128, self.prefix) == masked(ip.into(), 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+// the first `prefix` of the `bits` bits of an address
+fn masked(address: u128, bits: u32, prefix: u8) -> u128 {
+    match bits - u32::from(prefix) {
+        shift if shift >= 128 => 0,
+        shift => address >> shift,
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Cidr, String> {
+        Cidr::parse(&value)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub(crate) struct Lists {
+    pub(crate) allow: Vec<Cidr>,
+    pub(crate) deny: Vec<Cidr>,
+}
+
+impl Lists {
+    pub(crate) fn admits(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|range| range.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|range| range.contains(ip))
+    }
+}
+
+// lists for a group of paths, each path also covers the paths below it
+#[derive(Deserialize, Debug, Clone, Default)]
+pub(crate) struct Rule {
+    pub(crate) paths: Vec<String>,
+    #[serde(flatten)]
+    pub(crate) lists: Lists,
+}
+
+impl Rule {
+    fn covers(&self, path: &str) -> bool {
+        self.paths.iter().any(|prefix| {
+            path.strip_prefix(prefix.trim_end_matches('/'))
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub(crate) struct IpFilter {
+    #[serde(flatten)]
+    pub(crate) lists: Lists,
+    pub(crate) rules: Vec<Rule>,
+}
+
+impl IpFilter {
+    // whether the rules covering `path` let the client in
+    pub(crate) fn admits_to(&self, path: &str, ip: IpAddr) -> bool {
+        self.rules.iter().filter(|rule| rule.covers(path)).all(|rule| rule.lists.admits(ip))
+    }
+}
+
+fn forbidden() -> Response {
+    Response::problem(StatusCode::Forbidden, "Your address may not use this server")
+}
+
+// the 403 for a client the top level lists keep out, checked on accepting it
+pub(crate) fn check(config: &Config, client: IpAddr) -> Result<(), Response> {
+    if config.ip_filter.lists.admits(client) {
+        Ok(())
+    } else {
+        Err(forbidden())
+    }
+}
+
+// router middleware applying the `rules`, requests not from a socket pass
+pub(crate) fn middleware(request: &mut Request, next: Next) -> Response {
+    match request.connection.peer {
+        Some(peer) if !config::get().ip_filter.admits_to(&request.path, peer.ip()) => forbidden(),
+        _ => next(request),
+    }
+}
@@ -0,0 +1,89 @@
+// append-only journal of the writes to the data file
+//
+// before the data file is rewritten, what the write adds, replaces and removes
+// is appended to the journal as one JSON line and synced to disk. once the new
+// data file is in place the journal is emptied again, so a journal still
+// holding lines at startup belongs to writes that never finished. they are
+// applied to the data file before the server takes requests. a last line cut
+// short by the crash was never acknowledged to anyone and is skipped.
+
+use crate::endpoints::Character;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+// one write, the entries it stored in full and the ids it removed
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub(crate) struct Record {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    upserted: Vec<Character>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    removed: Vec<usize>,
+}
+
+impl Record {
+    // what changed from `before` to `after`
+    pub(crate) fn diff(before: &[Character], after: &[Character]) -> Record {
+        let previous: HashMap<usize, &Character> = before.iter().map(|c| (c.id(), c)).collect();
+        let kept: HashSet<usize> = after.iter().map(Character::id).collect();
+        Record {
+            upserted: after
+                .iter()
+                .filter(|c| previous.get(&c.id()) != Some(c))
+                .cloned()
+                .collect(),
+            removed: before.iter().map(Character::id).filter(|id| !kept.contains(id)).collect(),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.upserted.is_empty() && self.removed.is_empty()
+    }
+
+    // redoes the write on `characters`, a replaced entry keeps its place and a
+    // new one is added at the end
+    pub(crate) fn apply(self, characters: &mut Vec<Character>) {
+        characters.retain(|c| !self.removed.contains(&c.id()));
+        for entry in self.upserted {
+            match characters.iter_mut().find(|c| c.id() == entry.id()) {
+                Some(existing) => *existing = entry,
+                None => characters.push(entry),
+            }
+        }
+    }
+}
+
+pub(crate) fn append(path: &Path, record: &Record) -> io::Result<()> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(&line)?;
+    file.sync_data()
+}
+
+// the records of unfinished writes, oldest first
+pub(crate) fn read(path: &Path) -> io::Result<Vec<Record>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut records = Vec::new();
+    let mut lines = contents.split_terminator('\n').peekable();
+    while let Some(line) = lines.next() {
+        match serde_json::from_str(line) {
+            Ok(record) => records.push(record),
+            // only the line being written when the process died can be torn
+            Err(_) if lines.peek().is_none() && !contents.ends_with('\n') => break,
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+    Ok(records)
+}
+
+// empties the journal once the data file holds every record
+pub(crate) fn clear(path: &Path) -> io::Result<()> {
+    File::create(path)?.sync_all()
+}
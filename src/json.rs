@@ -0,0 +1,77 @@
+// json serialization helpers
+//
+// the canonical form sorts object keys, drops all whitespace and prints
+// floats with at most 6 decimals, so the same data always produces the same
+// bytes regardless of field order or serde_json's float printing. this is
+// what etags, snapshot tests and the audit log should hash or compare.
+
+use crate::config;
+use serde::Serialize;
+use serde_json::Value;
+
+// serializes with the canonical form when `canonical_json` is enabled,
+// otherwise with serde_json's regular compact output
+pub(crate) fn to_string<T: Serialize + ?Sized>(value: &T) -> serde_json::Result<String> {
+    if config::get().canonical_json {
+        to_canonical_string(value)
+    } else {
+        serde_json::to_string(value)
+    }
+}
+
+// serializes to the canonical form, independent of the config flag
+pub(crate) fn to_canonical_string<T: Serialize + ?Sized>(value: &T) -> serde_json::Result<String> {
+    let value = serde_json::to_value(value)?;
+    let mut output = String::new();
+    write_canonical(&value, &mut output)?;
+    Ok(output)
+}
+
+fn write_canonical(value: &Value, output: &mut String) -> serde_json::Result<()> {
+    match value {
+        Value::Null | Value::Bool(_) | Value::String(_) => {
+            output.push_str(&serde_json::to_string(value)?);
+        }
+        Value::Number(number) => match number.as_f64() {
+            Some(float) if number.is_f64() => output.push_str(&format_float(float)),
+            _ => output.push_str(&number.to_string()),
+        },
+        Value::Array(items) => {
+            output.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    output.push(',');
+                }
+                write_canonical(item, output)?;
+            }
+            output.push(']');
+        }
+        Value::Object(fields) => {
+            let mut keys: Vec<&String> = fields.keys().collect();
+            keys.sort();
+            output.push('{');
+            for (i, key) in keys.into_iter().enumerate() {
+                if i > 0 {
+                    output.push(',');
+                }
+                output.push_str(&serde_json::to_string(key)?);
+                output.push(':');
+                write_canonical(&fields[key], output)?;
+            }
+            output.push('}');
+        }
+    }
+    Ok(())
+}
+
+// 6 decimals hide the noise of f32 -> f64 widening (7.7 instead of 7.699999809265137),
+// trailing zeros are trimmed but one decimal is kept so floats stay floats
+fn format_float(float: f64) -> String {
+    let fixed = format!("{:.6}", float);
+    let trimmed = fixed.trim_end_matches('0');
+    if trimmed.ends_with('.') {
+        format!("{trimmed}0")
+    } else {
+        trimmed.to_string()
+    }
+}
@@ -0,0 +1,158 @@
+// JSON Patch (RFC 6902) and JSON Merge Patch (RFC 7386) applied to a serde_json document
+//
+// JSON Patch supports the add, remove, replace and test operations with JSON
+// Pointer paths (RFC 6901). the whole patch is applied to a copy, so a failing
+// operation leaves the document exactly as it was. a merge patch is a partial
+// document, its members replace the target's and null removes one.
+
+use serde_json::Value;
+use thiserror::Error;
+
+pub(crate) const MEDIA_TYPE: &str = "application/json-patch+json";
+pub(crate) const MERGE_MEDIA_TYPE: &str = "application/merge-patch+json";
+
+#[derive(Error, Debug, PartialEq)]
+pub(crate) enum PatchError {
+    #[error("invalid patch document: {0}")]
+    Invalid(String),
+    #[error("path {0} does not exist")]
+    MissingPath(String),
+    #[error("test failed at {0}")]
+    TestFailed(String),
+}
+
+// applies every operation of `patch` to `document`, or none of them
+pub(crate) fn apply(document: &mut Value, patch: &Value) -> Result<(), PatchError> {
+    let operations = patch
+        .as_array()
+        .ok_or_else(|| PatchError::Invalid("expected an array of operations".to_string()))?;
+    let mut patched = document.clone();
+    for operation in operations {
+        apply_operation(&mut patched, operation)?;
+    }
+    *document = patched;
+    Ok(())
+}
+
+// applies a merge patch, which can't fail: any JSON value is a valid one
+pub(crate) fn merge(target: &mut Value, patch: &Value) {
+    let Value::Object(members) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(serde_json::Map::new());
+    }
+    let Value::Object(target_members) = target else {
+        unreachable!("target was just made an object");
+    };
+    for (name, value) in members {
+        if value.is_null() {
+            target_members.remove(name);
+        } else {
+            merge(target_members.entry(name.clone()).or_insert(Value::Null), value);
+        }
+    }
+}
+
+fn apply_operation(document: &mut Value, operation: &Value) -> Result<(), PatchError> {
+    let member = |name: &str| {
+        operation
+            .get(name)
+            .ok_or_else(|| PatchError::Invalid(format!("operation without {name:?}")))
+    };
+    let op = member("op")?.as_str().unwrap_or_default();
+    let path = member("path")?
+        .as_str()
+        .ok_or_else(|| PatchError::Invalid("path must be a string".to_string()))?;
+    let tokens = parse_pointer(path)?;
+
+    match op {
+        "add" => add(document, &tokens, member("value")?.clone(), path),
+        "remove" => remove(document, &tokens, path),
+        "replace" => {
+            let value = member("value")?.clone();
+            let target = resolve(document, &tokens).ok_or_else(|| PatchError::MissingPath(path.to_string()))?;
+            *target = value;
+            Ok(())
+        }
+        "test" => {
+            let expected = member("value")?;
+            match resolve(document, &tokens) {
+                Some(actual) if actual == expected => Ok(()),
+                _ => Err(PatchError::TestFailed(path.to_string())),
+            }
+        }
+        other => Err(PatchError::Invalid(format!("unsupported op {other:?}"))),
+    }
+}
+
+// splits "/a/b~1c" into ["a", "b/c"], "" being the whole document
+fn parse_pointer(pointer: &str) -> Result<Vec<String>, PatchError> {
+    if pointer.is_empty() {
+        return Ok(Vec::new());
+    }
+    let rest = pointer
+        .strip_prefix('/')
+        .ok_or_else(|| PatchError::Invalid(format!("path {pointer:?} must start with /")))?;
+    Ok(rest.split('/').map(|token| token.replace("~1", "/").replace("~0", "~")).collect())
+}
+
+fn resolve<'a>(document: &'a mut Value, tokens: &[String]) -> Option<&'a mut Value> {
+    tokens.iter().try_fold(document, |value, token| match value {
+        Value::Object(map) => map.get_mut(token),
+        Value::Array(items) => {
+            let index = array_index(token, items.len())?;
+            items.get_mut(index)
+        }
+        _ => None,
+    })
+}
+
+// an existing array index, no leading zeros allowed
+fn array_index(token: &str, len: usize) -> Option<usize> {
+    if token.len() > 1 && token.starts_with('0') {
+        return None;
+    }
+    token.parse().ok().filter(|index| *index < len)
+}
+
+fn add(document: &mut Value, tokens: &[String], value: Value, path: &str) -> Result<(), PatchError> {
+    let Some((last, parent)) = tokens.split_last() else {
+        *document = value;
+        return Ok(());
+    };
+    match resolve(document, parent) {
+        Some(Value::Object(map)) => {
+            map.insert(last.clone(), value);
+            Ok(())
+        }
+        Some(Value::Array(items)) => {
+            let index = if last == "-" {
+                items.len()
+            } else {
+                // inserting right after the last element is allowed
+                array_index(last, items.len() + 1).ok_or_else(|| PatchError::MissingPath(path.to_string()))?
+            };
+            items.insert(index, value);
+            Ok(())
+        }
+        _ => Err(PatchError::MissingPath(path.to_string())),
+    }
+}
+
+fn remove(document: &mut Value, tokens: &[String], path: &str) -> Result<(), PatchError> {
+    let missing = || PatchError::MissingPath(path.to_string());
+    let (last, parent) = tokens
+        .split_last()
+        .ok_or_else(|| PatchError::Invalid("cannot remove the whole document".to_string()))?;
+    match resolve(document, parent) {
+        Some(Value::Object(map)) => map.remove(last).map(|_| ()).ok_or_else(missing),
+        Some(Value::Array(items)) => {
+            let index = array_index(last, items.len()).ok_or_else(missing)?;
+            items.remove(index);
+            Ok(())
+        }
+        _ => Err(missing()),
+    }
+}
@@ -0,0 +1,85 @@
+// persistent connections
+//
+// an HTTP/1.1 connection stays open after a response unless the client sends
+// Connection: close, an HTTP/1.0 one only when it sends Connection: keep-alive.
+// the server closes it once it has been idle for `keep_alive_timeout_secs`
+// between requests (0 closes every connection after one response) or has
+// carried `max_requests_per_connection` requests (0 for no limit). responses
+// on a connection that stays open tell how long it may idle and how many
+// requests it has left in Keep-Alive, the last one carries Connection: close.
+// so does an answer the rest of its request wasn't read past, and every
+// response once the server is stopping. the blocking server's pool thread
+// stays with a connection while it idles, the tokio server only keeps a task.
+//
+// requests a client pipelines, sending the next before the previous one is
+// answered, are read no further than each one's end and what follows is kept
+// for the next, so they are answered one after the other in the order they
+// came. those sent after one that closes the connection are dropped with it.
+
+use crate::config::Config;
+use crate::http::{HeaderMap, Response, Version};
+use crate::reload;
+use std::io::{BufRead, BufReader};
+use std::net::TcpStream;
+use std::time::Duration;
+
+// whether the Connection header lists `option`
+pub(crate) fn has_option(headers: &HeaderMap, option: &str) -> bool {
+    headers
+        .get_all("Connection")
+        .flat_map(|value| value.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case(option))
+}
+
+// how long to wait for the next request on a connection, None when it isn't kept open at all
+pub(crate) fn idle_timeout(config: &Config) -> Option<Duration> {
+    (config.keep_alive_timeout_secs > 0).then(|| Duration::from_secs(config.keep_alive_timeout_secs))
+}
+
+// whether the connection stays open after answering the `served`th request on it
+fn persists(config: &Config, version: Version, headers: &HeaderMap, served: usize) -> bool {
+    let wanted = match version {
+        Version::Http10 => has_option(headers, "keep-alive"),
+        _ => !has_option(headers, "close"),
+    };
+    let max = config.max_requests_per_connection;
+    wanted && idle_timeout(config).is_some() && (max == 0 || served < max) && !reload::stopping()
+}
+
+// adds the headers saying whether the connection stays open after `response`
+// to the `served`th request on it
+pub(crate) fn announce(config: &Config, version: Version, headers: &HeaderMap, served: usize, mut response: Response) -> Response {
+    if has_option(&response.headers, "close") {
+        return response;
+    }
+    if !persists(config, version, headers, served) {
+        response.headers.insert("Connection", "close");
+        return response;
+    }
+    if version == Version::Http10 {
+        response.headers.insert("Connection", "keep-alive");
+    }
+    let mut keep_alive = format!("timeout={}", config.keep_alive_timeout_secs);
+    if config.max_requests_per_connection > 0 {
+        keep_alive.push_str(&format!(", max={}", config.max_requests_per_connection - served));
+    }
+    response.headers.insert("Keep-Alive", keep_alive);
+    response
+}
+
+// waits for the next request on a connection kept open, false when the client
+// closed it or left it idle for too long. a request already read ahead counts
+pub(crate) fn wait_for_request(stream: &TcpStream, reader: &mut BufReader<&TcpStream>, config: &Config) -> bool {
+    if !reader.buffer().is_empty() {
+        return true;
+    }
+    let Some(timeout) = idle_timeout(config) else {
+        return false;
+    };
+    if stream.set_read_timeout(Some(timeout)).is_err() {
+        return false;
+    }
+    let arrived = matches!(reader.fill_buf(), Ok(buffer) if !buffer.is_empty());
+    // the request itself is read without the idle timeout
+    arrived && stream.set_read_timeout(None).is_ok()
+}
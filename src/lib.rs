@@ -1,34 +1,69 @@
+pub mod test_client;
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
 
 pub struct ThreadPool{
     workers: Vec<Worker>,
-    sender: mpsc::Sender<Job>,
+    sender: Option<mpsc::Sender<Job>>,
+    counters: Arc<Counters>,
+}
+
+/// What the pool's workers are doing at the moment it was taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Number of worker threads.
+    pub workers: usize,
+    /// Workers running a job.
+    pub busy: usize,
+    /// Jobs waiting for a free worker.
+    pub queued: usize,
+    /// Jobs finished since the pool was created.
+    pub completed: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    busy: AtomicUsize,
+    queued: AtomicUsize,
+    completed: AtomicU64,
 }
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
 impl ThreadPool{
+    /// Create a new ThreadPool.
+    ///
+    /// The size is the number of threads in the pool.
+    ///
+    /// # Panics
+    ///
+    /// The `new` function will panic if the size is zero.
     pub fn new (size: usize) -> ThreadPool{
-        /// Create a new ThreadPool.
-        ///
-        /// The size is the number of threads in the pool.
-        ///
-        /// # Panics
-        ///
-        /// The `new` function will panic if the size is zero.
         assert!(size > 0);
 
         let (sender, receiver) = mpsc::channel();
 
         let receiver = Arc::new(Mutex::new(receiver));
+        let counters = Arc::new(Counters::default());
 
         let mut workers = Vec::with_capacity(size);
 
         for id in 0..size{
-            workers.push(Worker::new(id, Arc::clone(&receiver)))
+            workers.push(Worker::new(id, Arc::clone(&receiver), Arc::clone(&counters)))
         };
 
-        ThreadPool { workers, sender }
+        ThreadPool { workers, sender: Some(sender), counters }
+    }
+
+    /// Returns how many workers are busy and how many jobs are waiting.
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            workers: self.workers.len(),
+            busy: self.counters.busy.load(Ordering::Relaxed),
+            queued: self.counters.queued.load(Ordering::Relaxed),
+            completed: self.counters.completed.load(Ordering::Relaxed),
+        }
     }
 
     pub fn execute<F>(&self, f: F)
@@ -36,24 +71,47 @@ impl ThreadPool{
         F: FnOnce() + Send + 'static,
     {
         let job = Box::new(f);
-        self.sender.send(job).unwrap()
+        self.counters.queued.fetch_add(1, Ordering::Relaxed);
+        self.sender.as_ref().unwrap().send(job).unwrap()
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        // closing the channel makes every worker leave its loop
+        drop(self.sender.take());
+
+        for worker in &mut self.workers {
+            println!("Shutting down worker {}", worker.id);
+            if let Some(thread) = worker.thread.take() {
+                thread.join().unwrap();
+            }
+        }
     }
 }
 
 struct Worker{
     id: usize,
-    thread: thread::JoinHandle<(Arc<Mutex<mpsc::Receiver<Job>>>)>
+    thread: Option<thread::JoinHandle<()>>
 }
 
 impl Worker{
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
+    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>, counters: Arc<Counters>) -> Worker {
         let thread  = thread::spawn(move || loop {
-            let job = receiver.lock().unwrap().recv().unwrap();
-            println!("Worker {id} executing");
-
-            job();
+            let message = receiver.lock().unwrap().recv();
+            match message {
+                Ok(job) => {
+                    counters.queued.fetch_sub(1, Ordering::Relaxed);
+                    counters.busy.fetch_add(1, Ordering::Relaxed);
+                    println!("Worker {id} executing");
+                    job();
+                    counters.busy.fetch_sub(1, Ordering::Relaxed);
+                    counters.completed.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(_) => break,
+            }
         });
 
-        Worker {id, thread}
+        Worker {id, thread: Some(thread)}
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,362 @@
+// binding the listening socket
+//
+// when the configured port is taken the optional fallback range is tried in
+// order. if nothing can be bound the process exits with a distinct code after
+// printing what it could find out about the port, instead of panicking.
+// the socket is set up by hand rather than through TcpListener::bind so the
+// options in `socket` (address and port reuse, the accept backlog) apply.
+// with `listeners` configured the server listens on each of them instead,
+// every one with its own socket options and the routes it serves, and with
+// the `tls` feature the certificate it serves HTTPS with. sockets systemd
+// passed in are used as they are, nothing is bound then.
+
+use crate::config::Config;
+#[cfg(feature = "tls")]
+use crate::tls::{self, TlsFiles};
+use crate::{log, systemd};
+use serde::Deserialize;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::process;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+
+// exit code when every candidate port is already in use (EADDRINUSE on linux)
+pub(crate) const EXIT_ADDRESS_IN_USE: i32 = 98;
+// exit code for any other bind failure (bad host, permission denied, ...)
+pub(crate) const EXIT_BIND_FAILED: i32 = 2;
+
+// options of the listening socket and the connections it accepts
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub(crate) struct SocketOptions {
+    /// Send small responses right away instead of waiting to coalesce them (Nagle).
+    pub(crate) tcp_nodelay: bool,
+    /// Rebind the port while connections of a previous process are in TIME_WAIT.
+    pub(crate) reuse_address: bool,
+    /// Let several processes listen on the same port, the kernel spreads
+    /// connections between them. unix only.
+    pub(crate) reuse_port: bool,
+    /// Connections the kernel queues before they are accepted.
+    pub(crate) backlog: i32,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        SocketOptions { tcp_nodelay: true, reuse_address: true, reuse_port: false, backlog: 128 }
+    }
+}
+
+// routes below this prefix are the admin ones
+pub(crate) const ADMIN_SCOPE: &str = "/admin";
+
+// one address the server listens on
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct ListenerConfig {
+    pub(crate) host: String,
+    pub(crate) port: u16,
+    /// Replaces the top level `socket` options for this listener.
+    #[serde(default)]
+    pub(crate) socket: Option<SocketOptions>,
+    #[serde(default)]
+    pub(crate) routes: Routes,
+    /// Certificate and key to serve HTTPS with, cleartext HTTP when unset.
+    #[cfg(feature = "tls")]
+    #[serde(default)]
+    pub(crate) tls: Option<TlsFiles>,
+}
+
+// which routes a listener serves, the others are a 404 on it
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Routes {
+    #[default]
+    All,
+    // everything but the admin routes
+    Public,
+    // only the admin routes
+    Admin,
+    // none, every request is redirected to the HTTPS listener
+    #[serde(rename = "https_redirect")]
+    HttpsRedirect,
+}
+
+impl Routes {
+    pub(crate) fn serves(self, path: &str) -> bool {
+        let admin = path
+            .strip_prefix(ADMIN_SCOPE)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'));
+        match self {
+            Routes::All => true,
+            Routes::Public => !admin,
+            Routes::Admin => admin,
+            Routes::HttpsRedirect => false,
+        }
+    }
+}
+
+// the port of the first listener serving HTTPS, the one an https_redirect
+// listener sends its clients to
+#[cfg(feature = "tls")]
+pub(crate) fn https_port(config: &Config) -> Option<u16> {
+    config.listeners.iter().find(|listener| listener.tls.is_some()).map(|listener| listener.port)
+}
+
+#[cfg(not(feature = "tls"))]
+pub(crate) fn https_port(_config: &Config) -> Option<u16> {
+    None
+}
+
+// what the connections of a listener serving HTTPS are secured with, None
+// for a cleartext one
+#[cfg(feature = "tls")]
+pub(crate) type Tls = Option<Arc<rustls::ServerConfig>>;
+// without the `tls` feature every listener is cleartext
+#[cfg(not(feature = "tls"))]
+pub(crate) type Tls = Option<NoTls>;
+#[cfg(not(feature = "tls"))]
+#[derive(Clone)]
+pub(crate) enum NoTls {}
+
+// a bound listener and what it was configured with
+pub(crate) struct Bound {
+    pub(crate) listener: TcpListener,
+    pub(crate) socket: SocketOptions,
+    pub(crate) routes: Routes,
+    // only the tokio server, which the `tls` feature brings along, serves HTTPS
+    #[cfg_attr(not(feature = "tokio"), allow(dead_code))]
+    pub(crate) tls: Tls,
+    // passed in by systemd, which keeps it open across restarts
+    pub(crate) inherited: bool,
+}
+
+// binds every configured listener, or the one `host` and `port` describe
+pub(crate) fn bind_all_or_exit(config: &Config) -> Vec<Bound> {
+    let redirecting = config.listeners.iter().any(|listener| listener.routes == Routes::HttpsRedirect);
+    if redirecting && https_port(config).is_none() {
+        log::error!("An https_redirect listener needs another listener serving HTTPS to redirect to");
+        process::exit(EXIT_BIND_FAILED);
+    }
+    match systemd::listeners() {
+        Ok(passed) if !passed.is_empty() => return activated(config, passed),
+        Ok(_) => {}
+        Err(e) => {
+            log::error!("Failed to take the sockets passed by systemd: {}", e);
+            process::exit(EXIT_BIND_FAILED);
+        }
+    }
+    if config.listeners.is_empty() {
+        let listener = bind_or_exit(config);
+        let tls = tls_or_exit(config, None);
+        return vec![Bound { listener, socket: config.socket.clone(), routes: Routes::All, tls, inherited: false }];
+    }
+    config
+        .listeners
+        .iter()
+        .map(|listener| {
+            let socket = listener.socket.clone().unwrap_or_else(|| config.socket.clone());
+            match bind(&listener.host, listener.port, None, &socket) {
+                Ok(bound) => {
+                    log::info!("Listening on {}:{} ({:?} routes)", listener.host, listener.port, listener.routes);
+                    let tls = tls_or_exit(config, Some(listener));
+                    Bound { listener: bound, socket, routes: listener.routes, tls, inherited: false }
+                }
+                Err(e) => exit_bind_failed(&listener.host, listener.port, None, e),
+            }
+        })
+        .collect()
+}
+
+// the sockets from systemd, in order they take the options and routes of the
+// configured listeners when there is one for each
+fn activated(config: &Config, passed: Vec<TcpListener>) -> Vec<Bound> {
+    let configured = config.listeners.len() == passed.len();
+    if !configured && !config.listeners.is_empty() {
+        log::warning!(
+            "systemd passed {} sockets for {} listeners, serving all routes on each",
+            passed.len(),
+            config.listeners.len()
+        );
+    }
+    passed
+        .into_iter()
+        .enumerate()
+        .map(|(i, listener)| {
+            let configured = config.listeners.get(i).filter(|_| configured);
+            let (socket, routes) = match configured {
+                Some(configured) => (
+                    configured.socket.clone().unwrap_or_else(|| config.socket.clone()),
+                    configured.routes,
+                ),
+                None => (config.socket.clone(), Routes::All),
+            };
+            if let Ok(address) = listener.local_addr() {
+                log::info!("Listening on {} from systemd ({:?} routes)", address, routes);
+            }
+            let tls = tls_or_exit(config, configured);
+            Bound { listener, socket, routes, tls, inherited: true }
+        })
+        .collect()
+}
+
+// the TLS setup of `listener`, or of the top level listener, exiting when
+// its certificate can't be loaded. a configured listener without a `tls` of
+// its own is cleartext, like the one the ACME CA's challenges are fetched on
+#[cfg(feature = "tls")]
+fn tls_or_exit(config: &Config, listener: Option<&ListenerConfig>) -> Tls {
+    let files = listener.map_or(config.tls.as_ref(), |listener| listener.tls.as_ref())?;
+    #[cfg(feature = "acme")]
+    let issued_later = crate::acme::issues(config, files);
+    #[cfg(not(feature = "acme"))]
+    let issued_later = false;
+    match tls::server_config(files, issued_later) {
+        Ok(tls) => Some(tls),
+        Err(e) => {
+            log::error!("Failed to load the TLS certificate: {}", e);
+            process::exit(EXIT_BIND_FAILED);
+        }
+    }
+}
+
+#[cfg(not(feature = "tls"))]
+fn tls_or_exit(_config: &Config, _listener: Option<&ListenerConfig>) -> Tls {
+    None
+}
+
+// applies the per connection options to an accepted stream
+pub(crate) fn configure(stream: &TcpStream, options: &SocketOptions) {
+    if let Err(e) = stream.set_nodelay(options.tcp_nodelay) {
+        log::warning!("Failed to set TCP_NODELAY: {}", e);
+    }
+}
+
+pub(crate) fn bind_or_exit(config: &Config) -> TcpListener {
+    match bind(&config.host, config.port, config.fallback_ports, &config.socket) {
+        Ok(listener) => listener,
+        Err(e) => exit_bind_failed(&config.host, config.port, config.fallback_ports, e),
+    }
+}
+
+fn exit_bind_failed(host: &str, port: u16, fallback_ports: Option<(u16, u16)>, error: io::Error) -> ! {
+    log::error!("Failed to bind {}:{}: {}", host, port, error);
+    if error.kind() != io::ErrorKind::AddrInUse {
+        process::exit(EXIT_BIND_FAILED);
+    }
+    match port_owner(port) {
+        Some(owner) => {
+            log::error!("Port {} is held by {}", port, owner);
+        }
+        None => {
+            log::error!("Could not determine which process holds port {}", port);
+        }
+    }
+    if let Some((first, last)) = fallback_ports {
+        log::error!("No free port in the fallback range {}-{} either", first, last);
+    }
+    process::exit(EXIT_ADDRESS_IN_USE);
+}
+
+// binds the port, or the first free port of the inclusive fallback range
+pub(crate) fn bind(
+    host: &str,
+    port: u16,
+    fallback_ports: Option<(u16, u16)>,
+    options: &SocketOptions,
+) -> io::Result<TcpListener> {
+    let error = match bind_port(host, port, options) {
+        Ok(listener) => return Ok(listener),
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse => e,
+        Err(e) => return Err(e),
+    };
+
+    if let Some((first, last)) = fallback_ports {
+        for fallback in first..=last {
+            if let Ok(listener) = bind_port(host, fallback, options) {
+                println!("Port {} is in use, listening on {} instead", port, fallback);
+                return Ok(listener);
+            }
+        }
+    }
+    Err(error)
+}
+
+// listens on the first address `host` resolves to that can be bound
+fn bind_port(host: &str, port: u16, options: &SocketOptions) -> io::Result<TcpListener> {
+    let mut last_error = None;
+    // "[::1]" as written in a URL is the same host as "::1"
+    let host = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
+    for address in (host, port).to_socket_addrs()? {
+        match bind_address(address, options) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "host resolved to no address")))
+}
+
+fn bind_address(address: SocketAddr, options: &SocketOptions) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(options.reuse_address)?;
+    // an IPv6 socket takes only IPv6, so [::] and 0.0.0.0 can listen side by side
+    if address.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_port(options.reuse_port)?;
+    socket.bind(&address.into())?;
+    socket.listen(options.backlog)?;
+    Ok(socket.into())
+}
+
+// "pid 1234 (nginx)" for the process listening on the port, when /proc lets us see it
+#[cfg(target_os = "linux")]
+pub(crate) fn port_owner(port: u16) -> Option<String> {
+    use std::fs;
+
+    let inode = ["/proc/net/tcp", "/proc/net/tcp6"]
+        .iter()
+        .filter_map(|table| fs::read_to_string(table).ok())
+        .find_map(|table| listening_inode(&table, port))?;
+    let socket = format!("socket:[{inode}]");
+
+    for process in fs::read_dir("/proc").ok()?.flatten() {
+        let pid = process.file_name().to_string_lossy().to_string();
+        if !pid.chars().all(|c| c.is_ascii_digit()) {
+            continue;
+        }
+        let Ok(fds) = fs::read_dir(process.path().join("fd")) else {
+            continue;
+        };
+        let holds_socket = fds
+            .flatten()
+            .filter_map(|fd| fs::read_link(fd.path()).ok())
+            .any(|target| target.to_string_lossy() == socket);
+        if holds_socket {
+            let name = fs::read_to_string(process.path().join("comm")).unwrap_or_default();
+            return Some(format!("pid {} ({})", pid, name.trim()));
+        }
+    }
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn port_owner(_port: u16) -> Option<String> {
+    None
+}
+
+// finds the socket inode listening on `port` in a /proc/net/tcp style table
+#[cfg(target_os = "linux")]
+fn listening_inode(table: &str, port: u16) -> Option<String> {
+    const LISTEN: &str = "0A";
+    table.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let local_port = fields.get(1)?.rsplit(':').next()?;
+        let listening = *fields.get(3)? == LISTEN;
+        if listening && u16::from_str_radix(local_port, 16).ok()? == port {
+            fields.get(9).map(|inode| inode.to_string())
+        } else {
+            None
+        }
+    })
+}
@@ -0,0 +1,73 @@
+// leveled logging to stdout and stderr
+//
+// the level starts as `log_level` from config.json and can be changed while
+// the server runs through PATCH /admin/runtime. messages above it are dropped
+// before they are formatted. lines written while a request is handled start
+// with its id, see request_id.rs.
+
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU8, Ordering};
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, PartialOrd)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Debug as u8);
+
+pub(crate) fn level() -> Level {
+    match LEVEL.load(Ordering::Relaxed) {
+        0 => Level::Error,
+        1 => Level::Warn,
+        2 => Level::Info,
+        _ => Level::Debug,
+    }
+}
+
+pub(crate) fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub(crate) fn enabled(level: Level) -> bool {
+    level <= self::level()
+}
+
+// a failure a request or the server couldn't recover from, written to stderr
+macro_rules! error {
+    ($($arg:tt)*) => {
+        eprintln!("{}{}", $crate::request_id::log_prefix(), format_args!($($arg)*));
+    };
+}
+
+// a problem the server works around, written to stderr
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Warn) {
+            eprintln!("{}{}", $crate::request_id::log_prefix(), format_args!($($arg)*));
+        }
+    };
+}
+
+// what the server is doing, like starting up or reloading its data
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Info) {
+            println!("{}{}", $crate::request_id::log_prefix(), format_args!($($arg)*));
+        }
+    };
+}
+
+// the details of every connection and request
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Debug) {
+            println!("{}{}", $crate::request_id::log_prefix(), format_args!($($arg)*));
+        }
+    };
+}
+
+pub(crate) use {debug, error, info, warning};
@@ -1,67 +1,270 @@
+#[cfg(feature = "acme")]
+mod acme;
+mod api_keys;
+mod api_version;
+#[cfg(feature = "tokio")]
+mod async_server;
+mod audit;
+mod autoindex;
+mod bench;
+mod caching;
+mod chunked;
+mod client;
+mod clock;
+mod compression;
+mod conditional;
+mod config;
+mod connection;
+mod connections;
+mod csrf;
+#[cfg(feature = "docs")]
+mod docs;
 mod endpoints;
+mod envelope;
+mod events;
+mod expect;
+mod extract;
+#[cfg(feature = "faults")]
+mod faults;
+#[cfg(feature = "templates")]
+mod flash;
+mod formats;
+#[cfg(feature = "graphql")]
+mod graphql;
+mod http;
+#[cfg(feature = "http2")]
+mod http2;
+mod hypermedia;
+mod ip_filter;
+mod journal;
+mod json;
+mod json_patch;
+mod keep_alive;
+mod listener;
+mod log;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod multipart;
+#[cfg(feature = "oauth")]
+mod oauth;
+mod openapi;
+mod pagination;
+mod parser;
+mod problem;
+#[cfg(feature = "proxy")]
+mod proxy;
+mod quotas;
+mod range;
+mod rate_limit;
+mod redirects;
+mod reload;
+mod request_id;
+mod response_cache;
+mod router;
+mod search;
+mod security_headers;
+mod self_test;
+mod sendfile;
+#[cfg(any(feature = "oauth", feature = "templates"))]
+mod session;
+mod slow_log;
+mod static_files;
+mod stats;
+mod store;
+mod systemd;
+#[cfg(feature = "templates")]
+mod templates;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(feature = "templates")]
+mod ui;
+mod upload;
+mod validation;
 
-use chrono::{DateTime, TimeZone, Utc};
+use api_version::ApiVersion;
+use caching::CachePolicy;
+use connection::ConnectionInfo;
+use endpoints::{Character, Page};
+use openapi::{ApiSchema, Doc};
+use problem::{ApiError, Problem};
+use serde_json::json;
+use validation::FieldError;
+use chrono::{DateTime, Utc};
+use http::{http_date, split_uri, HeaderMap, IntoResponse, Request, Response, Serialized, StatusCode, Version};
+use expect::Expectation;
+use extract::{FromRequest, Json, Text};
+use parser::RequestHead;
+use redirects::Rewrite;
+use listener::Routes;
+use router::{Endpoint, Router, SlashMatch};
+use search::EntryQuery;
 use rust_http_server::ThreadPool;
-use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
-    fs,
-    io::{prelude::*, BufReader, Cursor},
-    net::{TcpListener, TcpStream},
+    io::{prelude::*, BufReader},
+    panic::{self, AssertUnwindSafe},
+    net::TcpStream,
+    path::Path,
+    sync::{mpsc, LazyLock, OnceLock},
     thread,
     time::{Duration, Instant, SystemTime},
 };
-use thiserror::Error;
-
-#[derive(Error, Debug)]
-enum RequestError {
-    #[error("Failed to read request line")]
-    ReadRequestLineError,
-    #[error("Invalid request line format")]
-    InvalidRequestLineFormat,
-    #[error("Failed to read header line")]
-    ReadHeaderLineError,
-    #[error("Invalid header line: {0}")]
-    InvalidHeaderLine(String),
-    #[error("Content-Length exceeds available data")]
-    ContentLengthExceedsData,
-    #[error("Body length does not match Content-Length header")]
-    BodyLengthMismatch,
-    #[error("Failed to read body")]
-    ReadBodyError,
-    #[error("Invalid Content-Length value")]
-    InvalidContentLength,
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-struct Data {
-    id: u32,
-    name: String,
-    password: String,
+
+// cargo features this binary was compiled with
+fn enabled_features() -> Vec<&'static str> {
+    let features = [
+        ("tls", cfg!(feature = "tls")),
+        ("http2", cfg!(feature = "http2")),
+        ("acme", cfg!(feature = "acme")),
+        ("oauth", cfg!(feature = "oauth")),
+        ("proxy", cfg!(feature = "proxy")),
+        ("metrics", cfg!(feature = "metrics")),
+        ("templates", cfg!(feature = "templates")),
+        ("docs", cfg!(feature = "docs")),
+        ("faults", cfg!(feature = "faults")),
+        ("tokio", cfg!(feature = "tokio")),
+    ];
+    features
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| *name)
+        .collect()
 }
 
 fn main() {
-    let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
-    let pool = ThreadPool::new(5);
-    for stream in listener.incoming() {
-        println!("1 {:?}", stream);
-        let stream = stream.unwrap();
-        println!("2 {:?}", stream);
-
-        pool.execute(|| {
-            handle_connection(stream);
+    config::init(config::Config::load(Path::new(config::CONFIG_FILE)));
+    log::set_level(config::get().log_level);
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("--self-test") {
+        let suite = args.get(1).map(String::as_str).unwrap_or("");
+        std::process::exit(self_test::run(suite));
+    }
+    if args.first().map(String::as_str) == Some("bench") {
+        match bench::parse_args(&args[1..], config::get()) {
+            Ok(options) => {
+                println!("Benchmarking http://{}{}", options.address, options.path);
+                println!("{}", bench::run(&options));
+                std::process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(2);
+            }
+        }
+    }
+
+    log::info!("Enabled features: {:?}", enabled_features());
+    if let Err(e) = store::open() {
+        eprintln!("Failed to read the data file: {e}");
+        std::process::exit(1);
+    }
+    match store::recover() {
+        Ok(0) => {}
+        Ok(count) => log::info!("Replayed {count} unfinished writes from the journal"),
+        Err(e) => {
+            eprintln!("Failed to replay the journal: {e}");
+            std::process::exit(1);
+        }
+    }
+    clock::spawn_monitor();
+    if config::get().reload_data_file {
+        store::spawn_watcher();
+    }
+    if config::get().purge_deleted_after_secs > 0 {
+        store::spawn_purger();
+    }
+    let listeners = listener::bind_all_or_exit(config::get());
+    // the CA's challenges are answered by the listeners, so they come first
+    #[cfg(feature = "acme")]
+    acme::spawn_renewer(config::get());
+    let reloading = args.first().map(String::as_str) == Some("--reload");
+    if let Err(e) = reload::take_over(config::get(), reloading) {
+        eprintln!("Failed to take over the pid file: {e}");
+        std::process::exit(1);
+    }
+    stats::start();
+    reload::register_hooks();
+    if let Err(e) = reload::watch(&listeners) {
+        eprintln!("Failed to watch for SIGTERM: {e}");
+        std::process::exit(1);
+    }
+
+    #[cfg(feature = "tokio")]
+    async_server::run(listeners);
+
+    #[cfg(not(feature = "tokio"))]
+    run(listeners);
+
+    // the listeners were shut down by SIGTERM
+    reload::finish(config::get());
+}
+
+// blocking accept loops, one thread per listener, all handing connections to
+// the same pool of 5 threads
+#[cfg_attr(feature = "tokio", allow(dead_code))]
+fn run(listeners: Vec<listener::Bound>) {
+    let pool = POOL.get_or_init(|| ThreadPool::new(5));
+    let accept_loops: Vec<_> = listeners
+        .into_iter()
+        .map(|bound| thread::spawn(move || accept(bound, pool)))
+        .collect();
+    for accept_loop in accept_loops {
+        let _ = accept_loop.join();
+    }
+}
+
+fn accept(bound: listener::Bound, pool: &ThreadPool) {
+    loop {
+        connections::wait_for_slot(config::get().connection_limit);
+        let stream = bound.listener.accept().map(|(stream, _)| stream);
+        log::debug!("1 {:?}", stream);
+        let stream = match stream {
+            Ok(stream) => stream,
+            // the listener was shut down to stop the server
+            Err(_) if reload::stopping() => return,
+            Err(e) => {
+                log::error!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        log::debug!("2 {:?}", stream);
+        listener::configure(&stream, &bound.socket);
+
+        // over the limit the connection is answered here, without a pool thread
+        let Some(slot) = connections::try_open(config::get().connection_limit) else {
+            let _ = (&stream).write_all(&connections::rejection().to_bytes(false));
+            continue;
+        };
+        let routes = bound.routes;
+        pool.execute(move || {
+            let _slot = slot;
+            handle_connection(stream, routes);
         });
     }
 }
 
-fn parse_cookies(headers: &HashMap<String, String>) -> HashMap<String, String> {
+// the cookies of every Cookie header (RFC 6265 5.4), by name. a value in
+// double quotes is taken without them and may be empty. pairs without a name
+// or an `=` are skipped. when a name comes more than once the first wins, the
+// browser sends the cookie of the most specific path first
+fn parse_cookies(headers: &HeaderMap) -> HashMap<String, String> {
     let mut cookies = HashMap::new();
-    if let Some(cookie_header) = headers.get("Cookie") {
-        for cookie in cookie_header.split(';') {
-            let parts: Vec<&str> = cookie.splitn(2, '=').collect();
-            if parts.len() == 2 {
-                cookies.insert(parts[0].trim().to_string(), parts[1].trim().to_string());
+    for cookie_header in headers.get_all("Cookie") {
+        for pair in cookie_header.split(';') {
+            let Some((name, value)) = pair.split_once('=') else {
+                continue;
+            };
+            let name = name.trim_matches([' ', '\t']);
+            if name.is_empty() {
+                continue;
             }
+            let value = value.trim_matches([' ', '\t']);
+            let value = value
+                .strip_prefix('"')
+                .and_then(|quoted| quoted.strip_suffix('"'))
+                .unwrap_or(value);
+            cookies.entry(name.to_string()).or_insert_with(|| value.to_string());
         }
     }
     cookies
@@ -75,134 +278,269 @@ fn set_cookie(cookies: &mut Vec<String>, name: &str, value: &str, expires: Optio
     cookies.push(cookie);
 }
 
-fn is_cookie_expired(expiration_date: &str) -> bool {
-    if let Ok(expiration) = DateTime::parse_from_rfc2822(expiration_date) {
-        return expiration < Utc::now();
-    }
-    false
-}
-
 fn get_cookie_expiration(duration_secs: u64) -> String {
     let expiration_time = SystemTime::now() + Duration::from_secs(duration_secs);
-    let datetime: DateTime<Utc> = DateTime::<Utc>::from(expiration_time);
-    datetime.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+    http_date(DateTime::<Utc>::from(expiration_time))
 }
 
-fn parse_request(
-    buf_reader: &mut BufReader<&mut TcpStream>,
-) -> std::result::Result<(String, String, HashMap<String, String>, String), RequestError> {
-    let mut request_line = String::new();
-    println!("Request Line: {:?}", buf_reader);
-    if buf_reader.read_line(&mut request_line).is_err() {
-        return Err(RequestError::ReadRequestLineError);
-    }
-
-    let parts: Vec<&str> = request_line.split_whitespace().collect();
-    if parts.len() < 2 {
-        return Err(RequestError::InvalidRequestLineFormat);
-    }
+// `bytes` random bytes from the system in hex, for tokens and keys nobody can guess
+fn random_hex(bytes: usize) -> Result<String, getrandom::Error> {
+    let mut random = vec![0u8; bytes];
+    getrandom::getrandom(&mut random)?;
+    Ok(random.iter().map(|byte| format!("{byte:02x}")).collect())
+}
 
-    let method = parts[0].to_string();
-    let uri = parts[1].to_string();
+// the blocking server's pool, for /admin/runtime
+static POOL: OnceLock<ThreadPool> = OnceLock::new();
 
-    // Read headers
-    let mut headers = HashMap::new();
+fn handle_connection(mut stream: TcpStream, routes: Routes) {
+    let _in_flight = reload::InFlight::start();
+    stats::connection_accepted();
+    let connection = ConnectionInfo::plain(stream.peer_addr().ok(), stream.local_addr().ok());
+    log::debug!("New Connection {}", connection);
+    if let Some(Err(response)) = connection.peer.map(|peer| ip_filter::check(config::get(), peer.ip())) {
+        let _ = stream.write_all(&response.to_bytes(false));
+        return;
+    }
+    let mut buf_reader = BufReader::new(&stream);
+    let mut served = 0;
     loop {
-        let mut header_line = String::new();
-        if buf_reader.read_line(&mut header_line).is_err() {
-            return Err(RequestError::ReadHeaderLineError);
+        served += 1;
+        if served > 1 && !keep_alive::wait_for_request(&stream, &mut buf_reader, config::get()) {
+            return;
         }
-
-        if header_line == "\r\n" || header_line.is_empty() {
-            break;
+        // the limit counts requests, however many come on one connection
+        if let Some(Err(response)) = connection.peer.map(|peer| rate_limit::check(config::get(), peer.ip())) {
+            let _ = (&stream).write_all(&response.header("Connection", "close").to_bytes(false));
+            return;
         }
-
-        let header_parts: Vec<&str> = header_line.splitn(2, ": ").collect();
-        if header_parts.len() == 2 {
-            headers.insert(
-                header_parts[0].to_string(),
-                header_parts[1].trim().to_string(),
-            );
-        } else {
-            return Err(RequestError::InvalidHeaderLine(header_line));
+        let mut timing = slow_log::Timing::start();
+        match answer(&mut buf_reader, &mut &stream, routes, &connection, served, &mut timing) {
+            Some(response) => {
+                let mut written = (&stream).write_all(&response.bytes);
+                if let (Ok(()), Some(file)) = (&written, &response.file) {
+                    written = file.send(&stream);
+                    if let Err(e) = &written {
+                        log::debug!("Failed to send file body: {}", e);
+                    }
+                }
+                if let (Ok(()), Some(body)) = (&written, &response.stream) {
+                    written = body.send(&mut &stream);
+                    if let Err(e) = &written {
+                        log::debug!("Failed to send streamed body: {}", e);
+                    }
+                }
+                timing.finish();
+                if response.close || written.is_err() {
+                    return;
+                }
+            }
+            // event streams stay open, so they get their own thread instead of a pool worker
+            None => {
+                thread::spawn(move || events::stream_events(stream));
+                return;
+            }
         }
     }
+}
 
-    // Read body based on Content-Length header
-    let mut body = String::new();
-    if let Some(content_length) = headers.get("Content-Length") {
-        if let Ok(length) = content_length.parse::<usize>() {
-            let available_data = buf_reader.buffer().len();
-            if length > available_data {
-                return Err(RequestError::ContentLengthExceedsData);
-            }
+// reads one request and returns the serialized response, None for a request
+// of the event stream, which takes the connection over. a 100 Continue the
+// client waits for is written to `interim` before the body is read. `routes`
+// are those of the listener the request came in on, `connection` what is
+// known of the connection it came over, `served` counts the request among
+// those on it and `timing` is told when the request was
+// read and answered
+fn answer<R: Read, W: Write>(
+    buf_reader: &mut BufReader<R>,
+    interim: &mut W,
+    routes: Routes,
+    connection: &ConnectionInfo,
+    served: usize,
+    timing: &mut slow_log::Timing,
+) -> Option<Serialized> {
+    let (response, head_only) = respond(buf_reader, interim, routes, connection, served, timing)?;
+    let response = response.serialize(head_only);
+    timing.handled(response.len());
+    Some(response)
+}
 
-            let mut buffer = vec![0; length];
-            if buf_reader.read_exact(&mut buffer).is_ok() {
-                body = String::from_utf8_lossy(&buffer).to_string();
-                if body.len() != length {
-                    return Err(RequestError::BodyLengthMismatch);
-                }
-            } else {
-                return Err(RequestError::ReadBodyError);
-            }
-        } else {
-            return Err(RequestError::InvalidContentLength);
+// what answer sends before it is serialized, with whether it goes without its
+// body, for HTTP/2 to frame it instead
+fn respond<R: Read, W: Write>(
+    buf_reader: &mut BufReader<R>,
+    interim: &mut W,
+    routes: Routes,
+    connection: &ConnectionInfo,
+    served: usize,
+    timing: &mut slow_log::Timing,
+) -> Option<(Response, bool)> {
+    let secure = connection.secure();
+    let head = match parser::read_head(buf_reader, config::get().hardened) {
+        Ok(head) => head,
+        Err(e) => {
+            let _id = request_id::enter(request_id::generate());
+            return Some((parse_error_response(&e, secure), false));
         }
+    };
+    let _id = request_id::enter(request_id::assign(&head.headers, connection.peer, &config::get().trusted_proxies));
+    // HEAD answers exactly like GET, minus the body (RFC 9110 9.3.2)
+    let head_only = head.method == "HEAD";
+    if let Some(mut response) = redirects::to_https(config::get(), routes, &head) {
+        // the connection can't be used again with a body left unread
+        let length = head.headers.get("Content-Length");
+        if head.headers.get("Transfer-Encoding").is_some() || length.is_some_and(|length| length.trim() != "0") {
+            response = response.header("Connection", "close");
+        }
+        return Some((finish_response(&head, served, secure, response), head_only));
+    }
+    match expect_continue(&head) {
+        Ok(true) => {
+            let _ = interim.write_all(&Response::new(StatusCode::Continue).to_bytes(false));
+        }
+        Ok(false) => {}
+        // refused before its body was sent, the connection closes with the answer
+        Err(response) => {
+            let response = response.header("Connection", "close");
+            return Some((finish_response(&head, served, secure, response), head_only));
+        }
+    }
+    let body = match parser::read_body(buf_reader, &head.headers) {
+        Ok(body) => body,
+        Err(e) => return Some((parse_error_response(&e, secure), false)),
+    };
+    if head.method == "GET" && split_uri(&head.uri).0 == events::EVENTS_PATH {
+        return None;
     }
+    timing.parsed(&head.method, &head.uri, body.len());
 
-    // Check Content-Type and parse body accordingly
-    if let Some(content_type) = headers.get("Content-Type") {
-        match content_type.as_str() {
-            "application/json" => {
-                // Handle JSON body
-                if let Err(e) = serde_json::from_str::<serde_json::Value>(&body) {
-                    return Err(RequestError::InvalidRequestLineFormat);
-                }
-            }
-            "text/plain" => {
-                // Handle plain text body
-                // No additional parsing needed for plain text
-            }
-            _ => {
-                return Err(RequestError::InvalidRequestLineFormat);
-            }
+    let quota = quotas::client(config::get(), &head.headers, connection.peer);
+    let over_quota = quota
+        .as_ref()
+        .and_then(|client| quotas::receive(config::get(), client, quotas::request_len(&head, body.len())).err());
+    let refused = over_quota.or_else(|| csrf::check(config::get(), &head.method, &head.uri, &head.headers, &body).err());
+    let response = if upload::is_upload(&head.method, &head.uri, &head.headers) {
+        let mut response = refused.unwrap_or_else(|| upload::handle(buf_reader, &head.headers));
+        // a refused upload may have left its body unread
+        if response.status != StatusCode::Created {
+            response = response.header("Connection", "close");
         }
+        finish_response(&head, served, secure, response)
+    } else if let Some(response) = refused {
+        finish_response(&head, served, secure, response)
+    } else {
+        build_response(&head, &body, routes, connection, served)
+    };
+    let response = match &quota {
+        Some(client) => quotas::send(config::get(), client, response, head_only),
+        None => response,
+    };
+    Some((response, head_only))
+}
+
+// whether to send 100 Continue before reading the body of a request, or the
+// final answer refusing it when the client expects something else or no
+// route would take the request
+fn expect_continue(head: &RequestHead) -> Result<bool, Response> {
+    match expect::expectation(head.version, &head.headers) {
+        Expectation::None => Ok(false),
+        Expectation::Continue if accepts(&head.method, &head.uri, &head.headers) => Ok(true),
+        Expectation::Continue => Err(Response::problem(
+            StatusCode::ExpectationFailed,
+            format!("No route takes {} {}", head.method, split_uri(&head.uri).0),
+        )),
+        Expectation::Unsupported(value) => Err(Response::problem(
+            StatusCode::ExpectationFailed,
+            format!("Unsupported expectation: {value}"),
+        )),
     }
+}
 
-    Ok((method, uri, headers, body))
+// whether a request would reach a handler or a redirect, aliases applied
+fn accepts(method: &str, uri: &str, headers: &HeaderMap) -> bool {
+    let uri = match redirects::resolve(config::get(), uri) {
+        Rewrite::Redirect(_) => return true,
+        Rewrite::Route(uri) => uri,
+    };
+    let host = headers.get("Host").map(http::host_name);
+    if static_files::for_host(config::get(), host.as_deref()).is_some() {
+        return matches!(method, "GET" | "HEAD");
+    }
+    let path = split_uri(&uri).0;
+    match ROUTER.match_trailing_slash(path) {
+        Some(SlashMatch::Redirect(_)) => true,
+        Some(SlashMatch::Route(path)) => ROUTER.find(method, &path).is_some(),
+        None => upload::is_upload(method, &uri, headers) || ROUTER.find(method, path).is_some(),
+    }
 }
 
-fn handle_connection(mut stream: TcpStream) {
-    println!("New Connection");
-    let mut buf_reader = BufReader::new(&mut stream);
-    let (method, uri, headers, body) = match parse_request(&mut buf_reader) {
-        Ok(result) => result,
-        Err(e) => {
-            eprintln!("Failed to parse request: {}", e);
-            return;
-        }
+// the answer to a request the parser refused, the connection is closed after it
+fn parse_error_response(error: &parser::RequestError, secure: bool) -> Response {
+    log::warning!("Failed to parse request: {}", error);
+    let status = error.status();
+    // the method may not have been read, the request counts as another one
+    stats::request("", status);
+    let mut response = Response::problem(status, error.to_string()).header("Connection", "close");
+    if let Some(id) = request_id::current() {
+        response = response.header(request_id::HEADER, id);
+    }
+    security_headers::apply(config::get(), "", secure, response)
+}
+
+// runs the handler for a parsed request and completes its response
+fn build_response(
+    head: &RequestHead,
+    body: &[u8],
+    routes: Routes,
+    connection: &ConnectionInfo,
+    served: usize,
+) -> Response {
+    let RequestHead { method, uri, headers, .. } = head;
+    let secure = connection.secure();
+    log::debug!("Method: {}, URI: {}", method, uri);
+    log::debug!("Connection: {}", connection);
+    log::debug!("Headers: {:?}", loggable_headers(config::get(), headers));
+    log::debug!("Body: {} bytes", body.len());
+
+    // the CA validating a domain gets its challenge whatever the redirects and routes
+    #[cfg(feature = "acme")]
+    if let Some(response) = acme::challenge(method, uri) {
+        return finish_response(head, served, secure, response);
+    }
+    let response = match redirects::resolve(config::get(), uri) {
+        Rewrite::Redirect(response) => response,
+        Rewrite::Route(uri) => route(method, &uri, headers, body, routes, connection),
     };
-    println!("Method: {}, URI: {}", method, uri);
-    println!("Headers: {:?}", headers);
-    println!("Body: {}", body);
+    finish_response(head, served, secure, response)
+}
 
-    // Parse cookies from the request
-    let cookies = parse_cookies(&headers);
-    println!("Cookies: {:?}", cookies);
+// the request's headers as the debug log shows them, without the values that
+// would let whoever reads the log act as the client
+fn loggable_headers(config: &config::Config, headers: &HeaderMap) -> HeaderMap {
+    let csrf_header = config.csrf.as_ref().map(|csrf| csrf.header.as_str());
+    let secret = |name: &str| {
+        ["Authorization", "Proxy-Authorization", "Cookie", api_keys::HEADER]
+            .into_iter()
+            .chain(csrf_header)
+            .any(|secret| name.eq_ignore_ascii_case(secret))
+    };
+    let mut loggable = HeaderMap::new();
+    for (name, value) in headers.iter() {
+        loggable.append(name, if secret(name) { "[redacted]" } else { value });
+    }
+    loggable
+}
 
+// adds the headers every response carries, `served` being the number of the
+// request on its connection and `secure` whether that is over TLS
+fn finish_response(head: &RequestHead, served: usize, secure: bool, mut response: Response) -> Response {
+    let RequestHead { method, uri, version, headers } = head;
+    let (method, version) = (method.as_str(), *version);
     // Parse cookies from the request
-    let cookies = parse_cookies(&headers);
-    let mut valid_cookies = HashMap::new();
-    for (name, value) in cookies {
-        if !is_cookie_expired(&value) {
-            valid_cookies.insert(name, value);
-        }
-    }
-    println!("Valid Cookies: {:?}", valid_cookies);
+    let cookies = parse_cookies(headers);
+    log::debug!("Cookies: {:?}", cookies.keys().collect::<Vec<_>>());
 
     // Prepare response headers
-    let mut response_headers: HashMap<String, String> = HashMap::new();
     let mut set_cookie_headers = Vec::new();
 
     // Set a cookie expiration time
@@ -223,129 +561,3042 @@ fn handle_connection(mut stream: TcpStream) {
         Some(expiration_new.as_str()),
     );
 
-    let (status_line, response_body) = match method.as_str() {
-        "GET" => handle_get(&uri),
-        "POST" => handle_post(&uri, &body),
-        "PUT" => handle_put(&uri, &body),
-        "DELETE" => handle_delete(&uri, &body),
-        "PATCH" => handle_patch(&uri, &body),
-        _ => (
-            "HTTP/1.1 405 METHOD NOT ALLOWED",
-            "405 - Method Not Allowed".to_string(),
-        ),
-    };
+    // HTTP/1.0 has no chunked coding, its clients get a streamed body whole
+    if version == Version::Http10 {
+        if let Some(stream) = response.stream.take() {
+            match stream.plain() {
+                Ok(body) => response.body = body,
+                Err(e) => response = Response::problem(StatusCode::InternalServerError, format!("Failed to write the body: {e}")),
+            }
+        }
+    }
+
+    let config = config::get();
+    let accept_encoding = headers.get("Accept-Encoding");
+    let length = response.stream.as_ref().map_or(response.body.len(), |stream| stream.length() as usize);
+    // a proxied response may come encoded already, a file body is sent as it is
+    let encoding = compression::choose(config, accept_encoding, length)
+        .filter(|_| !range::requested(method, headers, &response))
+        .filter(|_| response.headers.get("Content-Encoding").is_none() && response.file.is_none());
+    if let (Some(encoding), Some(etag)) = (encoding, response.headers.get("ETag")) {
+        let etag = conditional::encoded_etag(etag, encoding);
+        response.headers.insert("ETag", etag);
+    }
+    if config.compression {
+        response = response.header("Vary", "Accept-Encoding");
+    }
+
+    response = conditional::evaluate(method, headers, response);
+    response = range::apply(method, headers, response);
+    if let Some(encoding) = encoding.filter(|_| response.status.allows_body()) {
+        match &mut response.stream {
+            Some(stream) => stream.encode(encoding),
+            None => response.body = compression::compress(&response.body, encoding),
+        }
+        response = response.header("Content-Encoding", encoding.name());
+    }
+
+    response = response.header("Date", http_date(Utc::now()));
+    if let Some(id) = request_id::current() {
+        response.headers.insert(request_id::HEADER, id);
+    }
+    if !config.server_name.is_empty() {
+        response = response.header("Server", config.server_name.as_str());
+    }
+    for cookie in set_cookie_headers {
+        response = response.header("Set-Cookie", cookie);
+    }
+    response = csrf::issue(config, headers, secure, response);
+    response = security_headers::apply(config, split_uri(uri).0, secure, response);
+    stats::request(method, response.status);
+    keep_alive::announce(config, version, headers, served, response)
+}
+
+// runs the handler registered for the method and path
+fn route(
+    method: &str,
+    uri: &str,
+    headers: &HeaderMap,
+    body: &[u8],
+    routes: Routes,
+    connection: &ConnectionInfo,
+) -> Response {
+    let mut request = Request::new(uri, headers, body);
+    request.method = method.to_string();
+    request.connection = connection.clone();
+    let _actor = audit::enter(audit::Actor::client(
+        format!("{method} {}", request.path),
+        principal(config::get(), headers),
+        headers,
+        connection.peer,
+        config::get(),
+    ));
+    if !routes.serves(&request.path) {
+        let response = router_error(&ROUTER, StatusCode::NotFound, &request, "No resource at this path");
+        return problem::with_instance(response, &request.path);
+    }
+    // a virtual host mapped to a directory is served from it, not by the routes
+    if let Some(mount) = static_files::for_host(config::get(), request.host.as_deref()) {
+        let response = problem::with_instance(static_files::serve(mount, method, &request.path, &request.headers), &request.path);
+        return caching::apply(config::get(), &request.path, None, response);
+    }
+    #[cfg(feature = "metrics")]
+    let started = Instant::now();
+    let response = match ROUTER.match_trailing_slash(&request.path) {
+        Some(SlashMatch::Redirect(path)) => {
+            let location = match uri.split_once('?') {
+                Some((_, query)) => format!("{path}?{query}"),
+                None => path,
+            };
+            Response::redirect(StatusCode::PermanentRedirect, location)
+        }
+        Some(SlashMatch::Route(path)) => {
+            request.path = path;
+            dispatch(&ROUTER, method, &mut request)
+        }
+        None => dispatch(&ROUTER, method, &mut request),
+    };
+    #[cfg(feature = "metrics")]
+    {
+        let pattern = ROUTER.pattern(method, &request.path).unwrap_or(metrics::UNMATCHED);
+        metrics::record(method, pattern, response.status, started.elapsed());
+    }
+    let response = problem::with_instance(response, &request.path);
+    let declared = ROUTER.cache_policy(method, &request.path);
+    caching::apply(config::get(), &request.path, declared, response)
+}
+
+// the response of the matching handler, or the router's own OPTIONS, 404 or 405. a
+// path no route matches is a 404, one matched only for other methods a 405
+fn dispatch(router: &Router, method: &str, request: &mut Request) -> Response {
+    let allowed = router.allowed_methods(&request.path);
+
+    if matches!(method, "GET" | "HEAD") {
+        if let Some(response) = upload::serve(&request.path) {
+            return response;
+        }
+    }
+
+    match router.find(method, &request.path) {
+        Some((endpoint, params)) => {
+            request.params = params;
+            let timeout = config::get().handler_timeout_secs;
+            let deadline = (timeout > 0).then(|| (Duration::from_secs(timeout), &*HANDLERS));
+            match call_endpoint(endpoint, request, deadline) {
+                Ok(response) => response,
+                Err(HandlerFailure::Panicked) => {
+                    router_error(router, StatusCode::InternalServerError, request, "The request could not be answered")
+                }
+                Err(HandlerFailure::TimedOut) => {
+                    log::warning!("{method} {} ran past its {timeout}s deadline", request.path);
+                    router_error(router, StatusCode::ServiceUnavailable, request, "The request took too long to answer")
+                }
+                Err(HandlerFailure::Busy) => {
+                    log::warning!("{method} {} found every handler thread taken", request.path);
+                    router_error(router, StatusCode::ServiceUnavailable, request, "Too many requests are being answered, try again")
+                        .header("Retry-After", "1")
+                }
+            }
+        }
+        None if method == "OPTIONS" && !allowed.is_empty() => {
+            Response::new(StatusCode::NoContent).header("Allow", allowed.join(", "))
+        }
+        None if !router::KNOWN_METHODS.contains(&method) => {
+            router_error(router, StatusCode::MethodNotAllowed, request, format!("{method} is not a supported method"))
+                .header("Allow", allowed.join(", "))
+        }
+        // the path exists, just not for this method
+        None if !allowed.is_empty() => {
+            router_error(router, StatusCode::MethodNotAllowed, request, format!("{method} is not allowed on {}", request.path))
+                .header("Allow", allowed.join(", "))
+        }
+        None => router_error(router, StatusCode::NotFound, request, "No resource at this path"),
+    }
+}
+
+// why a handler gave no response
+#[derive(Debug, PartialEq)]
+enum HandlerFailure {
+    Panicked,
+    TimedOut,
+    // every thread of the pool was taken, by late handlers among others
+    Busy,
+}
+
+// the threads handlers with a deadline run on, see call_endpoint
+static HANDLERS: LazyLock<ThreadPool> = LazyLock::new(|| ThreadPool::new(config::get().handler_threads.max(1)));
+
+// runs the endpoint, on a thread of `pool` when there is a deadline so a
+// handler running past it costs its request a 503 instead of holding the
+// worker. the late handler still finishes on its thread, its response is
+// dropped, and while late ones take every thread new requests get a 503 too.
+fn call_endpoint(
+    endpoint: Endpoint,
+    request: &mut Request,
+    deadline: Option<(Duration, &ThreadPool)>,
+) -> Result<Response, HandlerFailure> {
+    let Some((deadline, pool)) = deadline else {
+        // a panicking handler costs its request a 500, not the worker thread
+        return panic::catch_unwind(AssertUnwindSafe(|| endpoint.call(request))).map_err(|_| HandlerFailure::Panicked);
+    };
+    let stats = pool.stats();
+    if stats.busy + stats.queued >= stats.workers {
+        return Err(HandlerFailure::Busy);
+    }
+    let (sender, receiver) = mpsc::channel();
+    let mut request = request.clone();
+    let started = Instant::now();
+    let id = request_id::current();
+    let actor = audit::current();
+    // a panic drops the sender, which is how it is told from a timeout. it is
+    // caught so the pool keeps its thread
+    pool.execute(move || {
+        let _id = id.map(request_id::enter);
+        let _actor = actor.map(audit::enter);
+        let Ok(response) = panic::catch_unwind(AssertUnwindSafe(|| endpoint.call(&mut request))) else {
+            return;
+        };
+        if sender.send(response).is_err() {
+            log::warning!("{} finished after {:?}, its request was already answered", request.path, started.elapsed());
+        }
+    });
+    match receiver.recv_timeout(deadline) {
+        Ok(response) => Ok(response),
+        Err(mpsc::RecvTimeoutError::Timeout) => Err(HandlerFailure::TimedOut),
+        Err(mpsc::RecvTimeoutError::Disconnected) => Err(HandlerFailure::Panicked),
+    }
+}
+
+// an error the router answers itself, from the handler registered for the
+// status or else as a problem document
+fn router_error(router: &Router, status: StatusCode, request: &Request, detail: impl Into<String>) -> Response {
+    match router.error_handler(status) {
+        Some(handler) => handler(request),
+        None => Response::problem(status, detail),
+    }
+}
+
+static ROUTER: LazyLock<Router> = LazyLock::new(routes);
+
+fn routes() -> Router {
+    let json = "application/json";
+    let problem = || openapi::reference::<Problem>();
+
+    let router = Router::new()
+        .trailing_slash(config::get().trailing_slash)
+        .on_error(StatusCode::NotFound, not_found)
+        .middleware(ip_filter::middleware)
+        .get("/", home)
+        .get("/hello", hello)
+        .get("/data", data)
+        // the unversioned entry routes, frozen, and each version's under /api
+        .scope("", |unversioned| {
+            entry_routes(unversioned.middleware(api_version::middleware).middleware(envelope::middleware), ApiVersion::V1)
+        });
+    #[cfg(feature = "faults")]
+    let router = router.middleware(faults::middleware);
+    let router = ApiVersion::ALL
+        .into_iter()
+        .fold(router, |router, version| {
+            router.scope(&version.prefix(), |scope| {
+                let scope = scope.middleware(api_version::middleware).middleware(envelope::middleware);
+                // entries link to what can be done with them from v2 on
+                let scope = match version {
+                    ApiVersion::V1 => scope,
+                    ApiVersion::V2 => scope.middleware(hypermedia::middleware),
+                };
+                entry_routes(scope, version)
+            })
+        })
+        .scope(listener::ADMIN_SCOPE, |admin| {
+            admin
+                .middleware(require_admin_token)
+                .middleware(envelope::middleware)
+                .post("/compact", compact_store)
+                .post("/backup", backup_store)
+                .doc(
+                    Doc::new("Write a backup of the entries")
+                        .response_body(201, "The backup's name", json, json!({ "type": "object" })),
+                )
+                .get("/stats", server_stats)
+                .doc(Doc::new("Uptime, connections, requests by method, errors, pool utilization and store size").response_body(200, "The counters", json, json!({ "type": "object" })))
+                .get("/runtime", runtime_settings)
+                .doc(Doc::new("Pool, log level, rate limiting and connection state").response_body(200, "The state", json, json!({ "type": "object" })))
+                .patch("/runtime", change_runtime)
+                .doc(
+                    Doc::new("Change the log level or switch rate limiting")
+                        .request(json, json!({ "type": "object", "properties": {
+                            "log_level": { "type": "string", "enum": ["error", "warn", "info", "debug"] },
+                            "rate_limiting": { "type": "boolean" },
+                        } }))
+                        .response_body(200, "The new state", json, json!({ "type": "object" })),
+                )
+                .post("/flush", flush_store)
+                .doc(Doc::new("Write the entries held in memory to the data file").response_body(200, "The file's size", json, json!({ "type": "object" })))
+                .post("/restore", restore_store)
+                .doc(
+                    Doc::new("Replace the entries with a backup's")
+                        .request(json, json!({ "type": "object", "properties": { "name": { "type": "string" } } }))
+                        .response_body(200, "The restored backup and its entry count", json, json!({ "type": "object" }))
+                        .response_body(404, "No backup with this name", problem::MEDIA_TYPE, problem()),
+                )
+                .get("/audit", audit_log)
+                .doc(
+                    Doc::new("The changes made to the entries, newest first")
+                        .query("id", "integer", "Only changes to this entry")
+                        .query("principal", "string", "Only changes made by this principal")
+                        .query("ip", "string", "Only changes made from this address")
+                        .query("since", "string", "Only changes at or after this RFC 3339 time")
+                        .query("until", "string", "Only changes before this RFC 3339 time")
+                        .query("limit", "integer", "How many to return, 100 by default and at most 1000")
+                        .response_body(200, "The audit log lines", json, json!({ "type": "array", "items": { "type": "object" } }))
+                        .response_body(400, "An invalid parameter", problem::MEDIA_TYPE, problem()),
+                )
+                .post("/api-keys", api_keys::create)
+                .doc(
+                    Doc::new("Issue an API key for the entry routes")
+                        .request(json, json!({ "type": "object", "required": ["name"], "properties": {
+                            "name": { "type": "string" },
+                            "scopes": { "type": "array", "items": { "type": "string", "enum": ["read", "write"] } },
+                            "rate_limit": { "type": "object", "properties": {
+                                "requests": { "type": "integer" },
+                                "window_secs": { "type": "integer" },
+                            } },
+                        } }))
+                        .response_body(201, "The key, only ever shown here, and its settings", json, json!({ "type": "object" }))
+                        .response_body(404, "API keys are not configured", problem::MEDIA_TYPE, problem()),
+                )
+                .get("/quotas", quotas::index)
+                .doc(
+                    Doc::new("Today's bytes in and out of each client, with its quotas")
+                        .response_body(200, "The usage, by client", json, json!({ "type": "array", "items": { "type": "object" } }))
+                        .response_body(404, "Quotas are not configured", problem::MEDIA_TYPE, problem()),
+                )
+                .get("/api-keys", api_keys::index)
+                .doc(Doc::new("The issued API keys, without the keys themselves").response_body(200, "The keys", json, json!({ "type": "array", "items": { "type": "object" } })))
+                .delete("/api-keys/{id}", api_keys::destroy)
+                .doc(
+                    Doc::new("Revoke an API key")
+                        .response_body(200, "The revoked key", json, json!({ "type": "object" }))
+                        .response_body(404, "No key with this id", problem::MEDIA_TYPE, problem()),
+                )
+        })
+        .post(upload::UPLOAD_PATH, upload_not_multipart)
+        .get("/openapi.json", openapi_document)
+        .doc(Doc::new("This document").response_body(200, "The OpenAPI document", json, json!({ "type": "object" })));
+
+    #[cfg(feature = "proxy")]
+    let router = config::get()
+        .proxy_routes
+        .iter()
+        .fold(router, |router, route| {
+            router.proxy(&route.path, proxy::Pool::new(route.upstream.clone(), route.options))
+        });
+
+    #[cfg(feature = "metrics")]
+    let router = router.scope(listener::ADMIN_SCOPE, |admin| {
+        admin
+            .middleware(require_admin_token)
+            .get("/metrics", metrics::endpoint)
+            .doc(Doc::new("Request durations and statuses by route, for Prometheus").response(200, "The metrics as text"))
+    });
+
+    #[cfg(feature = "docs")]
+    let router = router
+        .get("/docs", docs::page)
+        .doc(Doc::new("Swagger UI for this API").response(200, "An HTML page"))
+        .get("/docs/{asset}", docs::asset)
+        .cache(CachePolicy::MaxAge(24 * 60 * 60));
+
+    #[cfg(feature = "templates")]
+    let router = router
+        .get("/ui", ui::page)
+        .cache(CachePolicy::NoCache)
+        .doc(Doc::new("An HTML page to list, add, edit and remove entries").response(200, "An HTML page"))
+        .post("/ui/entries/{id}/trash", ui::trash)
+        .doc(Doc::new("Move an entry to the trash from the HTML page").response(303, "Back to /ui, which shows the outcome once"));
+
+    #[cfg(feature = "graphql")]
+    let router = router.post("/graphql", graphql::endpoint).doc(
+        Doc::new("Query and change the entries with GraphQL")
+            .request(json, json!({ "type": "object", "required": ["query"], "properties": {
+                "query": { "type": "string" },
+                "variables": { "type": "object" },
+                "operationName": { "type": "string" },
+            } }))
+            .response_body(200, "The data, with the errors of any field that failed", json, json!({ "type": "object" }))
+            .response_body(400, "A document that doesn't parse or fit the schema", json, json!({ "type": "object" })),
+    );
+
+    // the protected paths are checked last, behind the ip filter
+    #[cfg(feature = "oauth")]
+    let router = router
+        .get(oauth::LOGIN_PATH, oauth::login_page)
+        .doc(Doc::new("Sign in through the OAuth provider, then go back to `next`").response(302, "Off to the provider"))
+        .get("/auth/callback", oauth::callback_page)
+        .doc(
+            Doc::new("Where the provider sends the browser back, starting its session")
+                .response(303, "Signed in, back to where the browser was going")
+                .response_body(403, "The user isn't allowed in", problem::MEDIA_TYPE, problem()),
+        )
+        .post("/auth/logout", oauth::logout_endpoint)
+        .doc(Doc::new("End the session").response(204, "Signed out"))
+        .middleware(oauth::middleware);
+    router
+}
+
+// the entry routes as `version` serves them, v1's being the unversioned ones
+fn entry_routes(router: Router, version: ApiVersion) -> Router {
+    let json = "application/json";
+    let problem = || openapi::reference::<Problem>();
+    let entry = || openapi::reference::<Character>();
+    let csv = json!({ "type": "string" });
+
+    // the reads of the entries, answered from memory until the data changes
+    let router = router
+        .middleware(api_keys::middleware)
+        .scope("/entries", |entries| {
+            entries
+                .middleware(response_cache::middleware)
+                .get("", get_entries)
+                .cache(CachePolicy::NoCache)
+                .doc(
+                    Doc::new("List, search and page through the entries")
+                        .query("offset", "integer", "entries to skip, needs a limit")
+                        .query("limit", "integer", "page size, 0 for every entry")
+                        .query("after", "string", "next_cursor of the previous page, or an id, to page after it; empty for the first page")
+                        .query("season", "integer", "only this season")
+                        .query("episode", "integer", "only this episode")
+                        .query("start", "integer", "only entries that started this year")
+                        .query("min_rating", "number", "lowest average_rating")
+                        .query("max_rating", "number", "highest average_rating")
+                        .query("name_contains", "string", "case-insensitive part of the name")
+                        .query("sort", "string", "field to sort by")
+                        .query("order", "string", "asc or desc")
+                        .query("include_deleted", "boolean", "also list the entries in the trash")
+                        .response_body(200, "The entries, as JSON, CSV or XML by Accept", json, openapi::array_of::<Character>())
+                        .response_body(400, "Invalid parameters or too many entries", problem::MEDIA_TYPE, problem())
+                        .response_body(406, "No acceptable format", problem::MEDIA_TYPE, problem()),
+                )
+                .get("/stats", entry_stats)
+                .cache(CachePolicy::NoCache)
+                .doc(Doc::new("Count, ratings and votes over all entries").response_body(200, "The statistics", json, json!({ "type": "object" })))
+                .get("/export.csv", export_entries)
+                .doc(Doc::new("Every entry as CSV").response_body(200, "The entries", "text/csv", csv.clone()))
+                .get("/{id}", get_entry)
+                .cache(CachePolicy::NoCache)
+                .doc(
+                    Doc::new("Fetch an entry")
+                        .response_body(200, "The entry", json, entry())
+                        .response_body(404, "No entry with this id", problem::MEDIA_TYPE, problem()),
+                )
+        })
+        .post("/entries/import", import_entries)
+        .doc(
+            Doc::new("Merge CSV rows into the entries")
+                .request("text/csv", csv)
+                .response_body(200, "How many entries were created and updated", json, json!({ "type": "object" }))
+                .response_body(422, "Invalid rows, nothing was imported", problem::MEDIA_TYPE, problem()),
+        )
+        .post("/entries/bulk", post_entries)
+        .doc(
+            Doc::new("Add several entries at once")
+                .request(json, openapi::array_of::<Character>())
+                .response_body(201, "The stored entries", json, openapi::array_of::<Character>())
+                .response_body(422, "Invalid entries, nothing was added", problem::MEDIA_TYPE, problem()),
+        )
+        .delete("/entries/bulk", delete_entries)
+        .doc(
+            Doc::new("Remove several entries at once")
+                .request(json, json!({ "type": "array", "items": { "type": "integer" } }))
+                .response(204, "The entries were removed")
+                .response_body(404, "Some ids don't exist, nothing was removed", problem::MEDIA_TYPE, problem()),
+        )
+        .patch("/entries/{id}", patch_entry)
+        .doc(
+            Doc::new("Apply a JSON Merge Patch or JSON Patch to an entry")
+                .request(json_patch::MERGE_MEDIA_TYPE, json!({ "type": "object" }))
+                .response_body(200, "The patched entry", json, entry())
+                .response_body(404, "No entry with this id", problem::MEDIA_TYPE, problem())
+                .response_body(422, "The patched entry would be invalid", problem::MEDIA_TYPE, problem()),
+        )
+        .delete("/entries/{id}", trash_entry)
+        .doc(
+            Doc::new("Move an entry to the trash")
+                .response(204, "The entry is in the trash")
+                .response_body(404, "No entry with this id outside the trash", problem::MEDIA_TYPE, problem()),
+        )
+        .post("/entries/{id}/restore", restore_entry)
+        .doc(
+            Doc::new("Take an entry out of the trash")
+                .response_body(200, "The restored entry", json, entry())
+                .response_body(404, "No entry with this id", problem::MEDIA_TYPE, problem())
+                .response_body(409, "The entry is not in the trash", problem::MEDIA_TYPE, problem()),
+        );
+    match version {
+        ApiVersion::V1 => router
+            .post("/submit", post_entry)
+            .doc(
+                Doc::new("Add an entry")
+                    .request(json, entry())
+                    .response_body(201, "The stored entry, its URL in Location", json, entry())
+                    .response_body(422, "Invalid fields", problem::MEDIA_TYPE, problem()),
+            )
+            .put("/put_entry", put_entry)
+            .doc(
+                Doc::new("Replace the entry with the body's id")
+                    .request(json, entry())
+                    .response(200, "The entry was replaced")
+                    .response_body(404, "No entry with this id", problem::MEDIA_TYPE, problem())
+                    .response_body(422, "Invalid fields", problem::MEDIA_TYPE, problem()),
+            )
+            .patch("/patch_entry_name", patch_entry_name)
+            .doc(
+                Doc::new("Rename an entry")
+                    .request(json, json!({ "type": "object", "properties": { "id": { "type": "integer" }, "name": { "type": "string" } } }))
+                    .response(200, "The entry was renamed"),
+            )
+            .delete("/delete_entry", delete_entry),
+        // resources instead of actions
+        ApiVersion::V2 => router
+            .post("/entries", create_entry)
+            .doc(
+                Doc::new("Add an entry")
+                    .request(json, entry())
+                    .response_body(201, "The stored entry, its URL in Location", json, entry())
+                    .response_body(422, "Invalid fields", problem::MEDIA_TYPE, problem()),
+            )
+            .put("/entries/{id}", replace_entry)
+            .doc(
+                Doc::new("Replace an entry")
+                    .request(json, entry())
+                    .response_body(200, "The stored entry", json, entry())
+                    .response_body(404, "No entry with this id", problem::MEDIA_TYPE, problem())
+                    .response_body(422, "Invalid fields", problem::MEDIA_TYPE, problem()),
+            ),
+    }
+}
+
+fn openapi_document(_request: &Request) -> Json<serde_json::Value> {
+    let components = [
+        (Character::NAME, Character::schema()),
+        (Problem::NAME, Problem::schema()),
+        (FieldError::NAME, FieldError::schema()),
+    ]
+    .into_iter()
+    .map(|(name, schema)| (name.to_string(), schema))
+    .collect();
+    Json(openapi::document(&ROUTER, components))
+}
+
+// multipart uploads are streamed before routing, anything reaching the route isn't one
+fn upload_not_multipart(_request: &Request) -> Response {
+    upload::requires_multipart()
+}
+
+fn home(_request: &Request) -> &'static str {
+    "Welcome to the homepage!"
+}
+
+// whether the client names text/html in Accept, as browsers do
+fn wants_html(headers: &HeaderMap) -> bool {
+    headers.get("Accept").is_some_and(|accept| {
+        accept
+            .split(',')
+            .any(|range| range.split(';').next().unwrap_or("").trim().eq_ignore_ascii_case("text/html"))
+    })
+}
+
+// browsers get a page, API clients the usual problem document
+fn not_found(request: &Request) -> Response {
+    if !wants_html(&request.headers) {
+        return Response::problem(StatusCode::NotFound, "No resource at this path");
+    }
+    let page = format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head><meta charset=\"utf-8\"><title>Not found</title></head>\n\
+         <body>\n<h1>404 - Not found</h1>\n<p>Nothing lives at <code>{}</code>. Try <a href=\"/entries\">the entries</a>.</p>\n</body>\n</html>\n",
+        formats::xml_escape(&request.path)
+    );
+    Response::new(StatusCode::NotFound)
+        .content_type("text/html; charset=utf-8")
+        .body(page.into_bytes())
+}
+
+fn hello(_request: &Request) -> &'static str {
+    "Hello, world!"
+}
+
+fn data(_request: &Request) -> &'static str {
+    "Here is your data."
+}
+
+fn get_entries(request: &Request) -> Response {
+    let Some(format) = formats::negotiate(request.headers.get("Accept")) else {
+        return Response::problem(
+            StatusCode::NotAcceptable,
+            format!("Available formats: {}", formats::supported()),
+        )
+        .header("Vary", "Accept");
+    };
+    // read before the data so Last-Modified is never newer than what is sent
+    let modified = store::modified();
+    if let Some(after) = request.query.get("after") {
+        return entries_after(request, after, format, modified);
+    }
+    let page = EntryQuery::parse(&request.query).and_then(|query| {
+        let (offset, limit) = page_params(&request.query)?;
+        let (entries, total) = endpoints::get_entries(&query, offset, limit, format)?;
+        Ok((entries, total, offset, limit))
+    });
+    match page {
+        Ok((entries, total, offset, limit)) => {
+            let response = Response::new(StatusCode::Ok)
+                .content_type(format.content_type())
+                .header("ETag", entries.etag())
+                .header("Vary", "Accept");
+            let mut response = match entries {
+                Page::Serialized(entries) => response.body(entries.into_bytes()).header("Accept-Ranges", "bytes"),
+                // no ranges of a body that isn't built before it is sent
+                Page::Streamed(entries) => response.stream(entries),
+            };
+            if limit > 0 {
+                response = response
+                    .header("X-Total-Count", total.to_string())
+                    .header("Link", pagination::links(&request.path, &request.query, offset, limit, total));
+            }
+            match modified.and_then(|modified| conditional::last_modified(modified, SystemTime::now())) {
+                Some(modified) => response.header("Last-Modified", modified),
+                None => response,
+            }
+        }
+        Err(message) => Response::problem(StatusCode::BadRequest, message),
+    }
+}
+
+// entries on a cursor page without a ?limit=
+const CURSOR_PAGE: usize = 50;
+
+// a page of ?after=<cursor>&limit=, in JSON as { "entries": [...], "next_cursor": ... },
+// in the other formats the bare entries. an empty `after` asks for the first page
+fn entries_after(request: &Request, after: &str, format: formats::Format, modified: Option<SystemTime>) -> Response {
+    let page = EntryQuery::parse(&request.query).and_then(|query| {
+        if request.query.contains_key("offset") {
+            return Err("Use either offset or after, not both".to_string());
+        }
+        let cursor = match after {
+            "" => None,
+            after => Some(pagination::Cursor::decode(after).ok_or_else(|| format!("Invalid after parameter: {after}"))?),
+        };
+        let limit = match page_params(&request.query)?.1 {
+            0 => CURSOR_PAGE,
+            limit => limit,
+        };
+        let (entries, next) = endpoints::entries_after(&query, cursor.as_ref(), limit)?;
+        Ok((entries, next, limit))
+    });
+    let (entries, next, limit) = match page {
+        Ok(page) => page,
+        Err(message) => return Response::problem(StatusCode::BadRequest, message),
+    };
+    let body = if format == formats::Format::Json {
+        let envelope = json!({ "entries": entries, "next_cursor": next.as_ref().map(pagination::Cursor::encode) });
+        json::to_string(&envelope).expect("Error parsing to string")
+    } else {
+        format.serialize(&entries).expect("Error parsing to string")
+    };
+    let mut response = Response::new(StatusCode::Ok)
+        .content_type(format.content_type())
+        .header("ETag", conditional::etag(body.as_bytes()))
+        .header("Vary", "Accept")
+        .body(body.into_bytes());
+    if let Some(next) = &next {
+        response = response.header("Link", pagination::next_link(&request.path, &request.query, next, limit));
+    }
+    match modified.and_then(|modified| conditional::last_modified(modified, SystemTime::now())) {
+        Some(modified) => response.header("Last-Modified", modified),
+        None => response,
+    }
+}
+
+fn entry_stats(_request: &Request) -> Response {
+    Response::json(StatusCode::Ok, endpoints::entry_stats())
+}
+
+// reads the optional ?offset=&limit= pagination parameters (0 means "no limit")
+fn page_params(query: &HashMap<String, String>) -> Result<(usize, usize), String> {
+    let parse = |name: &str| match query.get(name) {
+        Some(value) => value
+            .parse::<usize>()
+            .map_err(|_| format!("Invalid {name} parameter: {value}")),
+        None => Ok(0),
+    };
+    Ok((parse("offset")?, parse("limit")?))
+}
+
+// answers 201 with the stored entry, Location telling the client its id
+fn post_entry(Json(character): Json<Character>) -> Result<Response, ApiError> {
+    let (id, entry) = endpoints::post_entry(character)?;
+    Ok(Response::json(StatusCode::Created, entry).header("Location", format!("/entries/{id}")))
+}
+
+fn get_entry(request: &Request) -> Response {
+    let entry = request
+        .params
+        .get("id")
+        .and_then(|id| id.parse().ok())
+        .and_then(endpoints::get_entry);
+    match entry {
+        // browsers get a page made from the entry
+        #[cfg(feature = "templates")]
+        Some(entry) if wants_html(&request.headers) => {
+            let character: serde_json::Value = serde_json::from_str(&entry).unwrap_or_default();
+            let response = Response::render("character.html", json!({ "character": character }));
+            let etag = conditional::etag(&response.body);
+            response.header("ETag", etag).header("Vary", "Accept")
+        }
+        Some(entry) => {
+            let etag = conditional::etag(entry.as_bytes());
+            Response::json(StatusCode::Ok, entry).header("ETag", etag).header("Vary", "Accept")
+        }
+        None => Response::problem(StatusCode::NotFound, "Character not found"),
+    }
+}
+
+fn put_entry(headers: HeaderMap, Json(character): Json<Character>) -> Result<(StatusCode, String), ApiError> {
+    ApiError::check(endpoints::put_entry(character, headers.get("If-Match")))
+}
+
+// POST /entries of v2, like /submit but with Location under the same prefix
+fn create_entry(request: &Request) -> Response {
+    let Json(character) = match Json::<Character>::from_request(request) {
+        Ok(json) => json,
+        Err(rejection) => return rejection,
+    };
+    match endpoints::post_entry(character) {
+        Ok((id, entry)) => Response::json(StatusCode::Created, entry).header("Location", format!("{}/{id}", request.path)),
+        Err(outcome) => ApiError::from(outcome).into_response(),
+    }
+}
+
+// PUT /entries/{id} of v2, the id taken from the path and the stored entry answered
+fn replace_entry(request: &Request) -> Response {
+    let Some(id) = request.params.get("id").and_then(|id| id.parse::<usize>().ok()) else {
+        return Response::problem(StatusCode::NotFound, "Character not found");
+    };
+    let Json(mut body) = match Json::<serde_json::Value>::from_request(request) {
+        Ok(json) => json,
+        Err(rejection) => return rejection,
+    };
+    if let Some(fields) = body.as_object_mut() {
+        fields.insert("id".to_string(), json!(id));
+    }
+    let character: Character = match serde_json::from_value(body) {
+        Ok(character) => character,
+        Err(e) => {
+            let errors = vec![FieldError::new("body", e.to_string())];
+            return Response::new(StatusCode::UnprocessableEntity)
+                .content_type(problem::MEDIA_TYPE)
+                .body(validation::to_json(errors).into_bytes());
+        }
+    };
+    match endpoints::put_entry(character, request.headers.get("If-Match")) {
+        (StatusCode::Ok, _) => match endpoints::get_entry(id) {
+            Some(entry) => Response::json(StatusCode::Ok, entry),
+            None => Response::problem(StatusCode::NotFound, "Character not found"),
+        },
+        outcome => ApiError::from(outcome).into_response(),
+    }
+}
+
+// the answer to a body that should be text and isn't
+fn not_text() -> Response {
+    Response::problem(StatusCode::BadRequest, "The body is not valid UTF-8")
+}
+
+// renames an entry, or applies a JSON Patch to the entry named by ?id=
+fn patch_entry_name(request: &Request) -> Response {
+    let Some(body) = request.text() else {
+        return not_text();
+    };
+    let content_type = request.headers.get("Content-Type").map(http::media_type);
+    if content_type.as_deref() == Some(json_patch::MEDIA_TYPE) {
+        let Some(id) = request.query.get("id").and_then(|id| id.parse().ok()) else {
+            return Response::problem(StatusCode::BadRequest, "JSON Patch requests need ?id=<entry id>");
+        };
+        let if_match = request.headers.get("If-Match");
+        return match endpoints::json_patch_entry(id, body, if_match) {
+            (StatusCode::Ok, entry) => Response::json(StatusCode::Ok, entry),
+            outcome => ApiError::from(outcome).into_response(),
+        };
+    }
+    ApiError::check(endpoints::patch_entry_name(body, request.headers.get("If-Match"))).into_response()
+}
+
+// PATCH /entries/{id} with a merge patch or a JSON Patch, picked by Content-Type
+fn patch_entry(request: &Request) -> Response {
+    let Some(id) = request.params.get("id").and_then(|id| id.parse().ok()) else {
+        return Response::problem(StatusCode::NotFound, "Character not found");
+    };
+    let Some(body) = request.text() else {
+        return not_text();
+    };
+    let if_match = request.headers.get("If-Match");
+    let content_type = request.headers.get("Content-Type").map(http::media_type);
+    let result = match content_type.as_deref() {
+        Some(json_patch::MERGE_MEDIA_TYPE) => endpoints::merge_patch_entry(id, body, if_match),
+        Some(json_patch::MEDIA_TYPE) => endpoints::json_patch_entry(id, body, if_match),
+        _ => {
+            return Response::problem(
+                StatusCode::UnsupportedMediaType,
+                format!("Send {} or {}", json_patch::MERGE_MEDIA_TYPE, json_patch::MEDIA_TYPE),
+            )
+            // RFC 5789 3.1, tells the client which patch formats this resource takes
+            .header("Accept-Patch", format!("{}, {}", json_patch::MERGE_MEDIA_TYPE, json_patch::MEDIA_TYPE));
+        }
+    };
+    match result {
+        (StatusCode::Ok, entry) => Response::json(StatusCode::Ok, entry),
+        outcome => ApiError::from(outcome).into_response(),
+    }
+}
+
+// moves an entry to the trash, DELETE /delete_entry removes it for good
+fn trash_entry(request: &Request) -> Response {
+    let Some(id) = request.params.get("id").and_then(|id| id.parse().ok()) else {
+        return Response::problem(StatusCode::NotFound, "Character not found");
+    };
+    match endpoints::trash_entry(id, request.headers.get("If-Match")) {
+        (StatusCode::NoContent, _) => Response::new(StatusCode::NoContent),
+        outcome => ApiError::from(outcome).into_response(),
+    }
+}
+
+fn restore_entry(request: &Request) -> Response {
+    let Some(id) = request.params.get("id").and_then(|id| id.parse().ok()) else {
+        return Response::problem(StatusCode::NotFound, "Character not found");
+    };
+    match endpoints::restore_entry(id, request.headers.get("If-Match")) {
+        (StatusCode::Ok, entry) => Response::json(StatusCode::Ok, entry),
+        outcome => ApiError::from(outcome).into_response(),
+    }
+}
+
+fn delete_entry(headers: HeaderMap, Text(body): Text) -> Result<(StatusCode, String), ApiError> {
+    let (status, message) = endpoints::delete_entry(&body, headers.get("If-Match"));
+    ApiError::check((status, message.to_string()))
+}
+
+fn post_entries(Text(body): Text) -> Result<Response, ApiError> {
+    match endpoints::post_entries(&body) {
+        (StatusCode::Created, entries) => Ok(Response::json(StatusCode::Created, entries)),
+        outcome => Err(outcome.into()),
+    }
+}
+
+fn delete_entries(headers: HeaderMap, Text(body): Text) -> Result<(StatusCode, String), ApiError> {
+    ApiError::check(endpoints::delete_entries(&body, headers.get("If-Match")))
+}
+
+fn export_entries(_request: &Request) -> Response {
+    Response::new(StatusCode::Ok)
+        .content_type(formats::Format::Csv.content_type())
+        .header("Content-Disposition", "attachment; filename=\"entries.csv\"")
+        .body(endpoints::export_entries().into_bytes())
+}
+
+// merges a text/csv body into the entries
+fn import_entries(request: &Request) -> Response {
+    let content_type = request.headers.get("Content-Type").map(http::media_type);
+    if content_type.as_deref() != Some("text/csv") {
+        return Response::problem(StatusCode::UnsupportedMediaType, "Send the entries as text/csv");
+    }
+    let Some(body) = request.text() else {
+        return not_text();
+    };
+    match endpoints::import_entries(body) {
+        (StatusCode::Ok, counts) => Response::json(StatusCode::Ok, counts),
+        outcome => ApiError::from(outcome).into_response(),
+    }
+}
+
+// guards the /admin scope with the configured admin_token
+fn require_admin_token(request: &mut Request, next: router::Next) -> Response {
+    // or a browser signed in through the OAuth provider
+    #[cfg(feature = "oauth")]
+    if request.extensions.get::<oauth::User>().is_some() {
+        return next(request);
+    }
+    match admin_authorized(config::get(), request.headers.get("Authorization")) {
+        true => next(request),
+        false => Response::problem(StatusCode::Unauthorized, "A valid admin token is required")
+            .header("WWW-Authenticate", "Bearer"),
+    }
+}
+
+fn admin_authorized(config: &config::Config, authorization: Option<&str>) -> bool {
+    // closed to everyone until a token is configured
+    let Some(expected) = &config.admin_token else {
+        return false;
+    };
+    let given = authorization
+        .and_then(|value| value.split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("Bearer"))
+        .map_or("", |(_, token)| token.trim());
+    // compared without stopping at the first difference, so the time taken
+    // doesn't tell how much of a guess was right
+    given.len() == expected.len() && given.bytes().zip(expected.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+// who the request authenticated as, for the audit log
+fn principal(config: &config::Config, headers: &HeaderMap) -> Option<String> {
+    let authorization = headers.get("Authorization");
+    if admin_authorized(config, authorization) {
+        return Some("admin".to_string());
+    }
+    #[cfg(feature = "oauth")]
+    if let Some(user) = oauth::principal(config, headers) {
+        return Some(user);
+    }
+    api_keys::principal(config, headers)
+}
+
+fn compact_store(_request: &Request) -> Result<Response, ApiError> {
+    match endpoints::compact_store() {
+        (StatusCode::Ok, sizes) => Ok(Response::json(StatusCode::Ok, sizes)),
+        outcome => Err(outcome.into()),
+    }
+}
+
+fn backup_store(_request: &Request) -> Result<Response, ApiError> {
+    match endpoints::backup_store() {
+        (StatusCode::Created, name) => Ok(Response::json(StatusCode::Created, name)),
+        outcome => Err(outcome.into()),
+    }
+}
+
+// the settings PATCH /admin/runtime changes, the ones left out keep their value
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuntimeChange {
+    log_level: Option<log::Level>,
+    rate_limiting: Option<bool>,
+}
+
+fn runtime_settings(_request: &Request) -> Json<serde_json::Value> {
+    Json(runtime_state())
+}
+
+fn change_runtime(Json(change): Json<RuntimeChange>) -> Json<serde_json::Value> {
+    if let Some(level) = change.log_level {
+        log::set_level(level);
+    }
+    if let Some(enabled) = change.rate_limiting {
+        rate_limit::set_enabled(enabled);
+    }
+    Json(runtime_state())
+}
+
+// counters since the start, the pool's utilization and the size of the store
+fn server_stats(_request: &Request) -> Json<serde_json::Value> {
+    let mut stats = stats::snapshot();
+    stats["pool"] = json!(POOL.get().map(|pool| {
+        let pool = pool.stats();
+        json!({
+            "workers": pool.workers,
+            "busy": pool.busy,
+            "queued": pool.queued,
+            "completed": pool.completed,
+            "utilization": pool.busy as f64 / pool.workers.max(1) as f64,
+        })
+    }));
+    stats["store"] = json!({ "entries": store::load().len(), "bytes": store::size() });
+    Json(stats)
+}
+
+// the pool is null under tokio, which has no fixed set of workers
+fn runtime_state() -> serde_json::Value {
+    let pool = POOL.get().map(|pool| {
+        let stats = pool.stats();
+        json!({ "workers": stats.workers, "busy": stats.busy, "queued": stats.queued, "completed": stats.completed })
+    });
+    json!({
+        "pool": pool,
+        "log_level": log::level(),
+        "rate_limiting": {
+            "configured": config::get().rate_limit.is_some(),
+            "enabled": rate_limit::is_enabled(),
+        },
+        "connections": {
+            "open": connections::open(),
+            "max": config::get().connection_limit.map(|limit| limit.max),
+            "rejected": connections::rejected(),
+        },
+    })
+}
+
+fn audit_log(request: &Request) -> Result<Json<Vec<audit::Entry>>, ApiError> {
+    let query = audit::Query::parse(&request.query).map_err(|e| ApiError::from((StatusCode::BadRequest, e)))?;
+    audit::search(&query)
+        .map(Json)
+        .map_err(|e| ApiError::from((StatusCode::InternalServerError, format!("Failed to read the audit log: {e}"))))
+}
+
+fn flush_store(_request: &Request) -> Result<Response, ApiError> {
+    match endpoints::flush_store() {
+        (StatusCode::Ok, size) => Ok(Response::json(StatusCode::Ok, size)),
+        outcome => Err(outcome.into()),
+    }
+}
+
+fn restore_store(Json(backup): Json<endpoints::BackupName>) -> Result<Response, ApiError> {
+    match endpoints::restore_store(backup) {
+        (StatusCode::Ok, restored) => Ok(Response::json(StatusCode::Ok, restored)),
+        outcome => Err(outcome.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufReader;
+    use std::net::{Shutdown, SocketAddr, TcpListener};
+    use extract::Extension;
+    use rust_http_server::test_client::TestClient;
+    use std::sync::mpsc;
+    use std::time::Instant;
+
+    fn send_request(request: &str) -> String {
+        let response = client::send_raw("127.0.0.1:7878", request.as_bytes()).expect("Could not connect to server");
+        String::from_utf8(response).unwrap()
+    }
+
+    // an in-process client of a listener serving `routes`, its requests
+    // answered as if they came over `connection`
+    fn client_on_connection(routes: Routes, connection: ConnectionInfo) -> TestClient {
+        TestClient::new(move |raw, interim| {
+            let reader = &mut BufReader::new(std::io::Cursor::new(raw));
+            answer(reader, interim, routes, &connection, 1, &mut slow_log::Timing::start())
+                .expect("the event stream needs a real connection")
+                .into_bytes()
+                .expect("Failed to read the file body")
+        })
+    }
+
+    fn new_client() -> TestClient {
+        client_on(Routes::default())
+    }
+
+    fn client_on(routes: Routes) -> TestClient {
+        client_on_connection(routes, ConnectionInfo::default())
+    }
+
+    // a client of an HTTPS listener, for the responses that depend on it
+    fn secure_client() -> TestClient {
+        client_on_connection(Routes::default(), secure_connection())
+    }
+
+    // what the requests of a secure_client() come over
+    fn secure_connection() -> ConnectionInfo {
+        let tls = connection::TlsInfo { version: "TLSv1_3".to_string(), cipher: "TLS13_AES_128_GCM_SHA256".to_string(), sni: None };
+        ConnectionInfo { tls: Some(tls), ..ConnectionInfo::default() }
+    }
+
+    // a client sending the admin token the tests run with
+    fn admin_client() -> TestClient {
+        new_client().with_header("Authorization", &format!("Bearer {}", config::TEST_ADMIN_TOKEN))
+    }
+
+    // holds the write lock until the second the entries last changed in is
+    // over, from then on they are served with a Last-Modified that stays
+    fn settled_store() -> std::sync::MutexGuard<'static, ()> {
+        let lock = store::lock();
+        let changed = store::modified().map_or(0, |modified| DateTime::<Utc>::from(modified).timestamp());
+        while Utc::now().timestamp() <= changed {
+            thread::sleep(Duration::from_millis(20));
+        }
+        lock
+    }
+
+    fn start_server() {
+        // Check if the server is already running
+        if TcpStream::connect("127.0.0.1:7878").is_ok() {
+            return;
+        }
+        // Start the server in a separate thread
+        thread::spawn(|| {
+            let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
+            let pool = ThreadPool::new(5);
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+
+                pool.execute(|| {
+                    handle_connection(stream, Routes::All);
+                });
+            }
+        });
+    }
+
+    // HTTP Operations Unit Tests
+    #[test]
+    fn test_get_entries() {
+        // Start the server
+        start_server();
+        thread::sleep(Duration::from_secs(1));
+
+        // Send a GET request
+        let request = "GET /entries HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n";
+        let response = send_request(request);
+        let expected_json = r#"{"id":3,"rank":"28,818","trend":"8","season":1,"episode":4,"name":"Luffy's Past! The Red-haired Shanks Appears!","start":1999,"total_votes":"449","average_rating":8.1}"#;
+
+        // Check the response
+        assert!(response.contains(expected_json));
+        assert!(response.contains("Content-Type: application/json\r\n"));
+    }
+
+    #[test]
+    fn test_standard_headers() {
+        // Start the server
+        start_server();
+        thread::sleep(Duration::from_secs(1));
+
+        let request = "GET /hello HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n";
+        let response = send_request(request);
+        assert!(response.contains("Content-Type: text/plain; charset=utf-8\r\n"));
+        assert!(response.contains(&format!("Server: {}\r\n", config::get().server_name)));
+
+        // Date is a valid, current HTTP date
+        let date = response
+            .lines()
+            .find_map(|line| line.strip_prefix("Date: "))
+            .unwrap();
+        let date = DateTime::parse_from_rfc2822(date).unwrap();
+        assert!((Utc::now() - date.with_timezone(&Utc)).num_seconds().abs() < 10);
+    }
+
+    #[test]
+    fn test_get_entries_paginated() {
+        // Start the server
+        start_server();
+        thread::sleep(Duration::from_secs(1));
+
+        // Ask for a single page of two entries
+        let request = "GET /entries?offset=1&limit=2 HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n";
+        let response = send_request(request);
+
+        // Check the response only contains the requested page
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+        let entries: Vec<serde_json::Value> = serde_json::from_str(body).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert_eq!(entries.len(), 2);
+
+        // An offset on its own isn't silently dropped
+        let response = new_client().get("/entries?offset=2");
+        assert_eq!(response.status, 400);
+        assert!(response.json()["detail"].as_str().unwrap().starts_with("offset needs limit"));
+        assert_eq!(new_client().get("/entries?offset=0").status, 200);
+    }
+
+    #[test]
+    fn test_entries_etag() {
+        start_server();
+        thread::sleep(Duration::from_secs(1));
+
+        // An entry no other test modifies
+        let response = send_request("GET /entries?offset=2&limit=1 HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n");
+        let etag = response
+            .lines()
+            .find_map(|line| line.strip_prefix("ETag: "))
+            .expect("ETag header")
+            .to_string();
+
+        let request = format!("GET /entries?offset=2&limit=1 HTTP/1.1\r\nHost: 127.0.0.1\r\nIf-None-Match: \"other\", {}\r\n\r\n", etag);
+        let response = send_request(&request);
+        assert!(response.starts_with("HTTP/1.1 304 NOT MODIFIED"));
+        assert!(response.contains(&format!("ETag: {}", etag)));
+        assert!(response.ends_with("\r\n\r\n"));
+        assert!(!response.contains("Content-Length"));
+
+        let request = "GET /entries?offset=2&limit=1 HTTP/1.1\r\nHost: 127.0.0.1\r\nIf-None-Match: \"other\"\r\n\r\n";
+        assert!(send_request(request).starts_with("HTTP/1.1 200 OK"));
+    }
+
+    #[test]
+    fn test_conditional_etags() {
+        let etag = conditional::etag(b"[]");
+        assert_eq!(etag, conditional::etag(b"[]"));
+        assert_ne!(etag, conditional::etag(b"[ ]"));
+
+        // Each encoding is its own representation
+        let gzip = conditional::encoded_etag(&etag, compression::Encoding::Gzip);
+        assert_eq!(gzip, format!("{}-gzip\"", etag.trim_end_matches('"')));
+        assert_eq!(conditional::encoded_etag("W/\"abc\"", compression::Encoding::Deflate), "W/\"abc-deflate\"");
+
+        assert!(conditional::none_match(&format!("W/{}", etag), &etag));
+        assert!(conditional::none_match("*", &etag));
+        assert!(!conditional::none_match(&gzip, &etag));
+
+        let mut headers = HeaderMap::new();
+        headers.append("If-None-Match", gzip.clone());
+        let response = || {
+            Response::json(StatusCode::Ok, "[]")
+                .header("ETag", gzip.clone())
+                .header("Vary", "Accept-Encoding")
+        };
+        let not_modified = conditional::evaluate("GET", &headers, response());
+        assert_eq!(not_modified.status, StatusCode::NotModified);
+        assert_eq!(not_modified.headers.get("Vary"), Some("Accept-Encoding"));
+        assert_eq!(not_modified.headers.get("Content-Type"), None);
+        assert_eq!(conditional::evaluate("POST", &headers, response()).status, StatusCode::Ok);
+    }
+
+    #[test]
+    fn test_if_match() {
+        start_server();
+        thread::sleep(Duration::from_secs(1));
+
+        // A stale ETag is refused before anything is written
+        let put_request = r#"{"id": 3, "rank": "1", "trend": "-", "season": 1, "episode": 1, "name": "Stale", "start": 1999, "total_votes": "0", "average_rating": 0.0}"#;
+        let request = format!(
+            "PUT /put_entry HTTP/1.1\r\nHost: 127.0.0.1\r\nIf-Match: \"0000000000000000\"\r\nContent-Length: {}\r\n\r\n{}",
+            put_request.len(),
+            put_request
+        );
+        assert!(send_request(&request).starts_with("HTTP/1.1 412 PRECONDITION FAILED"));
+        let response = send_request("GET /entries?offset=2&limit=1 HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n");
+        assert!(!response.contains("Stale"));
+
+        let request = "DELETE /delete_entry HTTP/1.1\r\nHost: 127.0.0.1\r\nIf-Match: W/\"0\"\r\nContent-Length: 9\r\n\r\n{\"id\": 3}";
+        assert!(send_request(request).starts_with("HTTP/1.1 412 PRECONDITION FAILED"));
+
+        // The tag of the full listing, or of a compressed copy of it, matches
+        let _lock = store::lock();
+        let characters = store::load();
+        let etag = endpoints::dataset_etag(&characters);
+        let listing = endpoints::get_entries(&EntryQuery::default(), 0, 0, formats::Format::Json).unwrap().0;
+        assert_eq!(etag, listing.etag());
+        assert!(conditional::if_match(&format!("\"x\", {}", etag), &etag));
+        assert!(conditional::if_match(&conditional::encoded_etag(&etag, compression::Encoding::Gzip), &etag));
+        assert!(conditional::if_match("*", &etag));
+        assert!(!conditional::if_match(&format!("W/{}", etag), &etag));
+    }
+
+    #[test]
+    fn test_entry_if_match() {
+        let client = new_client();
+        let entry = |name: &str| {
+            format!(r#"{{"id": 0, "rank": "1", "trend": "-", "season": 1, "episode": 1, "name": "{name}", "start": 1999, "total_votes": "0", "average_rating": 0.0}}"#)
+        };
+        let created = |name: &str| {
+            let response = client.request("POST", "/submit", &[("Content-Type", "application/json")], &entry(name));
+            assert_eq!(response.status, 201);
+            response.header("Location").unwrap().to_string()
+        };
+        let (first, second) = (created("Match One"), created("Match Two"));
+        let etag = client.get(&first).header("ETag").unwrap().to_string();
+
+        // An edit to another entry doesn't fail the precondition
+        let merge = [("Content-Type", json_patch::MERGE_MEDIA_TYPE)];
+        assert_eq!(client.request("PATCH", &second, &merge, r#"{"season": 2}"#).status, 200);
+        let if_match = [("Content-Type", json_patch::MERGE_MEDIA_TYPE), ("If-Match", etag.as_str())];
+        let patched = client.request("PATCH", &first, &if_match, r#"{"season": 3}"#);
+        assert_eq!(patched.status, 200);
+        assert_eq!(patched.json()["season"], 3);
+
+        // An edit to the entry itself does, the old tag no longer names it
+        let stale = [("Content-Type", "application/json"), ("If-Match", etag.as_str())];
+        let response = client.request("PUT", &format!("/api/v2{first}"), &stale, &entry("Stale"));
+        assert_eq!(response.status, 412);
+        assert_eq!(client.get(&first).json()["name"], "Match One");
+
+        // The tag GET gives now matches, for the single entry routes of both versions
+        let etag = client.get(&first).header("ETag").unwrap().to_string();
+        let if_match = [("Content-Type", "application/json"), ("If-Match", etag.as_str())];
+        assert_eq!(client.request("PUT", &format!("/api/v2{first}"), &if_match, &entry("Match Three")).status, 200);
+        let etag = client.get(&first).header("ETag").unwrap().to_string();
+        let id = first.trim_start_matches("/entries/");
+        let rename = format!(r#"{{"id": {id}, "name": "Match Four"}}"#);
+        let if_match = [("If-Match", etag.as_str())];
+        assert_eq!(client.request("PATCH", "/patch_entry_name", &if_match, &rename).status, 200);
+        let etag = client.get(&first).header("ETag").unwrap().to_string();
+        assert_eq!(client.request("DELETE", &first, &[("If-Match", etag.as_str())], "").status, 204);
+
+        let etag = client.get(&second).header("ETag").unwrap().to_string();
+        let id = second.trim_start_matches("/entries/");
+        let body = format!(r#"{{"id": {id}}}"#);
+        assert_eq!(client.request("DELETE", "/delete_entry", &[("If-Match", "\"0000000000000000\"")], &body).status, 412);
+        assert_eq!(client.request("DELETE", "/delete_entry", &[("If-Match", etag.as_str())], &body).status, 204);
+    }
+
+    #[test]
+    fn test_range_parse() {
+        assert_eq!(range::parse("bytes=0-9", 100), Some(Ok((0, 9))));
+        assert_eq!(range::parse("bytes=90-", 100), Some(Ok((90, 99))));
+        assert_eq!(range::parse("bytes=90-500", 100), Some(Ok((90, 99))));
+        assert_eq!(range::parse("bytes=-10", 100), Some(Ok((90, 99))));
+        assert_eq!(range::parse("bytes=-500", 100), Some(Ok((0, 99))));
+        assert_eq!(range::parse("bytes=100-", 100), Some(Err(())));
+        assert_eq!(range::parse("bytes=-0", 100), Some(Err(())));
+        assert_eq!(range::parse("bytes=0-", 0), Some(Err(())));
+        // Invalid or unsupported headers are ignored
+        assert_eq!(range::parse("bytes=9-0", 100), None);
+        assert_eq!(range::parse("bytes=0-1,5-6", 100), None);
+        assert_eq!(range::parse("items=0-9", 100), None);
+        assert_eq!(range::parse("bytes=a-9", 100), None);
+    }
+
+    #[test]
+    fn test_range_request() {
+        start_server();
+        thread::sleep(Duration::from_secs(1));
+
+        let full = send_request("GET /entries?offset=2&limit=1 HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n");
+        assert!(full.contains("Accept-Ranges: bytes"));
+        let full_body = full.split("\r\n\r\n").nth(1).unwrap();
+
+        let response = send_request("GET /entries?offset=2&limit=1 HTTP/1.1\r\nHost: 127.0.0.1\r\nRange: bytes=0-9\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 206 PARTIAL CONTENT"));
+        assert!(response.contains(&format!("Content-Range: bytes 0-9/{}", full_body.len())));
+        assert!(response.ends_with(&format!("\r\n\r\n{}", &full_body[..10])));
+
+        let request = "GET /entries?offset=2&limit=1 HTTP/1.1\r\nHost: 127.0.0.1\r\nRange: bytes=999999-\r\n\r\n";
+        let response = send_request(request);
+        assert!(response.starts_with("HTTP/1.1 416 RANGE NOT SATISFIABLE"));
+        assert!(response.contains(&format!("Content-Range: bytes */{}", full_body.len())));
+
+        // A stale If-Range gets the whole body
+        let request = "GET /entries?offset=2&limit=1 HTTP/1.1\r\nHost: 127.0.0.1\r\nRange: bytes=0-9\r\nIf-Range: \"stale\"\r\n\r\n";
+        assert!(send_request(request).starts_with("HTTP/1.1 200 OK"));
+    }
+
+    #[test]
+    fn test_cache_headers() {
+        start_server();
+        thread::sleep(Duration::from_secs(1));
+
+        let response = send_request("GET /entries?offset=2&limit=1 HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n");
+        assert!(response.contains("Cache-Control: no-cache"));
+        assert!(!send_request("GET /hello HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n").contains("Cache-Control"));
+
+        // Compared against when the entries last changed
+        let _lock = settled_store();
+        let request = "GET /entries?offset=2&limit=1 HTTP/1.1\r\nHost: 127.0.0.1\r\nIf-Modified-Since: Fri, 01 Jan 2100 00:00:00 GMT\r\n\r\n";
+        let response = send_request(request);
+        assert!(response.contains("Last-Modified: "), "{response}");
+        assert!(response.starts_with("HTTP/1.1 304 NOT MODIFIED"));
+        assert!(response.contains("Cache-Control: no-cache"));
+        let request = "GET /entries?offset=2&limit=1 HTTP/1.1\r\nHost: 127.0.0.1\r\nIf-Modified-Since: Thu, 01 Jan 1970 00:00:00 GMT\r\n\r\n";
+        assert!(send_request(request).starts_with("HTTP/1.1 200 OK"));
+    }
+
+    #[test]
+    fn test_last_modified() {
+        let changed = SystemTime::UNIX_EPOCH + Duration::from_millis(1_700_000_000_200);
+        // Not while a second change could still come within the same second
+        assert_eq!(conditional::last_modified(changed, changed + Duration::from_millis(700)), None);
+        let last_modified = conditional::last_modified(changed, changed + Duration::from_millis(800)).unwrap();
+        assert_eq!(last_modified, "Tue, 14 Nov 2023 22:13:20 GMT");
+        assert!(conditional::not_modified_since(&last_modified, &last_modified));
+        assert!(!conditional::not_modified_since("Tue, 14 Nov 2023 22:13:19 GMT", &last_modified));
+
+        // The entries' own time, that of what is served
+        let _lock = settled_store();
+        let modified = store::modified().unwrap();
+        let client = new_client();
+        let response = client.get("/entries?limit=1");
+        let last_modified = response.header("Last-Modified").expect("Last-Modified once the second of the change is over");
+        assert_eq!(last_modified, http::http_date(DateTime::<Utc>::from(modified)));
+        let again = client.request("GET", "/entries?limit=1", &[("If-Modified-Since", last_modified)], "");
+        assert_eq!(again.status, 304);
+    }
+
+    #[test]
+    fn test_cache_policies() {
+        assert_eq!(CachePolicy::try_from("max-age=60".to_string()), Ok(CachePolicy::MaxAge(60)));
+        assert_eq!(CachePolicy::try_from("no-store".to_string()), Ok(CachePolicy::NoStore));
+        assert!(CachePolicy::try_from("max-age=soon".to_string()).is_err());
+
+        let config: config::Config = serde_json::from_str(
+            r#"{"cache_control": {"/uploads/*": "max-age=60", "/uploads/private/*": "no-store", "/entries": "no-store"}}"#,
+        )
+        .unwrap();
+        assert_eq!(caching::configured(&config, "/uploads/a.png"), Some(CachePolicy::MaxAge(60)));
+        assert_eq!(caching::configured(&config, "/uploads/private/a.png"), Some(CachePolicy::NoStore));
+        assert_eq!(caching::configured(&config, "/hello"), None);
+
+        // The config wins over the route, the route only fills in a missing header
+        let ok = || Response::text(StatusCode::Ok, "ok");
+        let declared = Some(CachePolicy::NoCache);
+        let response = caching::apply(&config, "/entries", declared, ok());
+        assert_eq!(response.headers.get("Cache-Control"), Some("no-store"));
+        let response = caching::apply(&config, "/hello", declared, ok());
+        assert_eq!(response.headers.get("Cache-Control"), Some("no-cache"));
+        let response = caching::apply(&config, "/hello", declared, ok().header("Cache-Control", "private"));
+        assert_eq!(response.headers.get("Cache-Control"), Some("private"));
+        let response = caching::apply(&config, "/entries", declared, Response::new(StatusCode::NotFound));
+        assert_eq!(response.headers.get("Cache-Control"), None);
+
+        assert!(conditional::not_modified_since("Sun, 06 Nov 1994 08:49:37 GMT", "Sun, 06 Nov 1994 08:49:37 GMT"));
+        assert!(!conditional::not_modified_since("Sun, 06 Nov 1994 08:49:36 GMT", "Sun, 06 Nov 1994 08:49:37 GMT"));
+        assert!(!conditional::not_modified_since("yesterday", "Sun, 06 Nov 1994 08:49:37 GMT"));
+    }
+
+    #[test]
+    fn test_security_headers() {
+        let response = new_client().get("/hello");
+        assert_eq!(response.header("X-Content-Type-Options"), Some("nosniff"));
+        assert_eq!(response.header("X-Frame-Options"), Some("DENY"));
+        assert_eq!(response.header("Referrer-Policy"), Some("no-referrer"));
+        assert_eq!(response.header("Content-Security-Policy"), Some("default-src 'self'; frame-ancestors 'none'"));
+        // HSTS only over TLS
+        assert_eq!(response.header("Strict-Transport-Security"), None);
+        let response = secure_client().get("/hello");
+        assert_eq!(response.header("Strict-Transport-Security"), Some("max-age=31536000"));
+        // Errors too, even a request that couldn't be parsed
+        assert_eq!(new_client().get("/missing").header("X-Frame-Options"), Some("DENY"));
+        assert_eq!(new_client().send(b"GARBAGE\r\n\r\n").header("X-Content-Type-Options"), Some("nosniff"));
+
+        // The configured values replace the defaults, a route's replace those and the handler's
+        let config: config::Config = serde_json::from_str(
+            r#"{"security_headers": {"frame_options": "SAMEORIGIN", "referrer_policy": ""},
+                "route_security_headers": {"/docs*": {"content_security_policy": "default-src *"}, "/docs/x": {"frame_options": ""}}}"#,
+        )
+        .unwrap();
+        let ok = || Response::text(StatusCode::Ok, "ok");
+        let response = security_headers::apply(&config, "/hello", false, ok());
+        assert_eq!(response.headers.get("X-Frame-Options"), Some("SAMEORIGIN"));
+        assert_eq!(response.headers.get("Referrer-Policy"), None);
+        assert_eq!(response.headers.get("X-Content-Type-Options"), Some("nosniff"));
+        let response = security_headers::apply(&config, "/hello", false, ok().header("Content-Security-Policy", "img-src *"));
+        assert_eq!(response.headers.get("Content-Security-Policy"), Some("img-src *"));
+        let response = security_headers::apply(&config, "/docs", false, ok().header("Content-Security-Policy", "img-src *"));
+        assert_eq!(response.headers.get("Content-Security-Policy"), Some("default-src *"));
+        let response = security_headers::apply(&config, "/docs/x", false, ok());
+        assert_eq!(response.headers.get("X-Frame-Options"), None);
+        assert_eq!(response.headers.get("Content-Security-Policy"), Some("default-src 'self'; frame-ancestors 'none'"));
+    }
+
+    #[test]
+    fn test_csrf() {
+        let config: config::Config =
+            serde_json::from_str(r#"{"csrf": {"routes": ["/entries", "/admin/*"]}, "aliases": {"/characters": "/entries"}}"#)
+                .unwrap();
+        let headers = |pairs: &[(&str, &str)]| {
+            let mut headers = HeaderMap::new();
+            for &(name, value) in pairs {
+                headers.append(name, value);
+            }
+            headers
+        };
+
+        // A client without the cookie is issued one, readable by scripts
+        let response = csrf::issue(&config, &headers(&[]), true, Response::new(StatusCode::Ok));
+        let cookie = response.headers.get("Set-Cookie").unwrap();
+        let token = cookie.strip_prefix("csrf_token=").unwrap().split(';').next().unwrap();
+        assert_eq!(token.len(), 64);
+        assert!(cookie.ends_with("; Path=/; SameSite=Strict; Secure") && !cookie.contains("HttpOnly"));
+        let cookies = format!("session=abc; csrf_token={token}");
+        let response = csrf::issue(&config, &headers(&[("Cookie", &cookies)]), false, Response::new(StatusCode::Ok));
+        assert_eq!(response.headers.get("Set-Cookie"), None);
+
+        // The token comes back in the header or a form field
+        let check = |method, uri, headers: &HeaderMap, body: &str| {
+            csrf::check(&config, method, uri, headers, body.as_bytes()).map_err(|response| response.status)
+        };
+        assert!(check("POST", "/entries", &headers(&[("Cookie", &cookies), ("X-CSRF-Token", token)]), "").is_ok());
+        let form = headers(&[("Cookie", &cookies), ("Content-Type", "application/x-www-form-urlencoded")]);
+        assert!(check("DELETE", "/admin/backup", &form, &format!("name=Luffy&csrf_token={token}")).is_ok());
+        let forged = headers(&[("Cookie", &cookies), ("X-CSRF-Token", "0123")]);
+        assert_eq!(check("PUT", "/entries", &forged, ""), Err(StatusCode::Forbidden));
+        assert_eq!(check("PATCH", "/characters?x=1", &headers(&[("Cookie", &cookies)]), ""), Err(StatusCode::Forbidden));
+        let stolen = headers(&[("Cookie", "session=abc"), ("X-CSRF-Token", token)]);
+        assert_eq!(check("POST", "/entries", &stolen, ""), Err(StatusCode::Forbidden));
+        // Safe methods, requests without cookies and unprotected routes aren't checked
+        assert!(check("GET", "/entries", &headers(&[("Cookie", &cookies)]), "").is_ok());
+        assert!(check("POST", "/entries", &headers(&[]), "").is_ok());
+        assert!(check("POST", "/hello", &headers(&[("Cookie", &cookies)]), "").is_ok());
+
+        // A browser's form post, read the way the server reads it, passes with the
+        // token in its body and the cookie set, and only then
+        let ui: config::Config = serde_json::from_str(r#"{"csrf": {"routes": ["/ui/*"]}}"#).unwrap();
+        let form_post = |cookie: &str, body: &str| {
+            let raw = format!(
+                "POST /ui/entries/1/trash HTTP/1.1\r\nHost: localhost\r\nCookie: {cookie}\r\n\
+                 Content-Type: application/x-www-form-urlencoded\r\nContent-Length: {}\r\n\r\n{body}",
+                body.len()
+            );
+            let mut reader = BufReader::new(raw.as_bytes());
+            let head = parser::read_head(&mut reader, true).unwrap();
+            let body = parser::read_body(&mut reader, &head.headers).unwrap();
+            csrf::check(&ui, &head.method, &head.uri, &head.headers, &body).map_err(|response| response.status)
+        };
+        assert!(form_post(&cookies, &format!("csrf_token={token}")).is_ok());
+        assert_eq!(form_post(&cookies, "csrf_token=0123"), Err(StatusCode::Forbidden));
+        assert_eq!(form_post(&cookies, ""), Err(StatusCode::Forbidden));
+        assert_eq!(form_post("session=abc", &format!("csrf_token={token}")), Err(StatusCode::Forbidden));
+
+        // A page's forms get the cookie's token, or a new one with its cookie
+        let form = csrf::form_token(&ui, &headers(&[("Cookie", &cookies)]), false).unwrap();
+        assert_eq!((form.field.as_str(), form.token.as_str(), form.set_cookie), ("csrf_token", token, None));
+        let new = csrf::form_token(&ui, &headers(&[]), false).unwrap();
+        assert_eq!(new.set_cookie, Some(format!("csrf_token={}; Path=/; SameSite=Strict", new.token)));
+        // which issue doesn't replace with another one
+        let page = Response::new(StatusCode::Ok).header("Set-Cookie", new.set_cookie.clone().unwrap());
+        let page = csrf::issue(&ui, &headers(&[]), false, page);
+        assert_eq!(page.headers.get_all("Set-Cookie").count(), 1);
+        assert_eq!(csrf::form_token(&config::Config::default(), &headers(&[]), false), None);
+
+        // Off unless configured
+        assert!(new_client().get("/hello").header("Set-Cookie").is_some_and(|cookie| !cookie.contains("csrf")));
+        let no_csrf = config::Config::default();
+        assert!(csrf::check(&no_csrf, "POST", "/entries", &headers(&[("Cookie", &cookies)]), b"").is_ok());
+    }
+
+    #[test]
+    fn test_accept_negotiation() {
+        use formats::{negotiate, Format};
+        assert_eq!(negotiate(None), Some(Format::Json));
+        assert_eq!(negotiate(Some("*/*")), Some(Format::Json));
+        assert_eq!(negotiate(Some("text/csv")), Some(Format::Csv));
+        assert_eq!(negotiate(Some("text/*")), Some(Format::Csv));
+        assert_eq!(negotiate(Some("text/xml")), Some(Format::Xml));
+        assert_eq!(negotiate(Some("application/json;q=0.5, application/xml")), Some(Format::Xml));
+        // The most specific range decides, so q=0 excludes a type even under */*
+        assert_eq!(negotiate(Some("application/json;q=0, */*;q=0.1")), Some(Format::Csv));
+        assert_eq!(negotiate(Some("image/png")), None);
+        assert_eq!(negotiate(Some("*/*;q=0")), None);
+    }
+
+    #[test]
+    fn test_entries_formats() {
+        start_server();
+        thread::sleep(Duration::from_secs(1));
+
+        let response = send_request("GET /entries?offset=2&limit=1 HTTP/1.1\r\nHost: 127.0.0.1\r\nAccept: text/csv\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("Content-Type: text/csv; charset=utf-8; header=present"));
+        assert!(response.contains("Vary: Accept"));
+        let csv = response.split("\r\n\r\n").nth(1).unwrap();
+        let lines: Vec<&str> = csv.split("\r\n").collect();
+        assert_eq!(lines[0], formats::COLUMNS.join(","));
+        // The rank contains a comma and has to be quoted
+        assert!(lines[1].starts_with("3,\"28,818\","));
+
+        let response = send_request("GET /entries?offset=2&limit=1 HTTP/1.1\r\nHost: 127.0.0.1\r\nAccept: application/xml\r\n\r\n");
+        assert!(response.contains("Content-Type: application/xml; charset=utf-8"));
+        assert!(response.contains("<characters><character><id>3</id><rank>28,818</rank>"));
+        assert!(response.contains("<name>Luffy&apos;s Past! The Red-haired Shanks Appears!</name>"));
+
+        let response = send_request("GET /entries HTTP/1.1\r\nHost: 127.0.0.1\r\nAccept: image/png\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 406 NOT ACCEPTABLE"));
+        assert!(response.contains("application/json, text/csv, application/xml, text/xml"));
+    }
+
+    #[test]
+    fn test_json_patch_apply() {
+        use serde_json::json;
+        let mut document = json!({"name": "Luffy", "crew": ["Zoro", "Nami"], "a/b": {"~c": 1}});
+        let patch = json!([
+            {"op": "test", "path": "/name", "value": "Luffy"},
+            {"op": "replace", "path": "/name", "value": "Monkey D. Luffy"},
+            {"op": "add", "path": "/crew/1", "value": "Usopp"},
+            {"op": "add", "path": "/crew/-", "value": "Sanji"},
+            {"op": "remove", "path": "/crew/0"},
+            {"op": "add", "path": "/a~1b/~0c", "value": 2},
+            {"op": "add", "path": "/bounty", "value": 30000000}
+        ]);
+        json_patch::apply(&mut document, &patch).unwrap();
+        assert_eq!(
+            document,
+            json!({"name": "Monkey D. Luffy", "crew": ["Usopp", "Nami", "Sanji"], "a/b": {"~c": 2}, "bounty": 30000000})
+        );
+
+        // A failing operation leaves the document untouched
+        let before = document.clone();
+        let patch = json!([{"op": "remove", "path": "/bounty"}, {"op": "test", "path": "/name", "value": "Zoro"}]);
+        assert_eq!(json_patch::apply(&mut document, &patch), Err(json_patch::PatchError::TestFailed("/name".to_string())));
+        assert_eq!(document, before);
+
+        let missing = json!([{"op": "replace", "path": "/crew/9", "value": 1}]);
+        assert!(matches!(json_patch::apply(&mut document, &missing), Err(json_patch::PatchError::MissingPath(_))));
+        let invalid = json!([{"op": "move", "from": "/name", "path": "/alias"}]);
+        assert!(matches!(json_patch::apply(&mut document, &invalid), Err(json_patch::PatchError::Invalid(_))));
+        assert!(json_patch::apply(&mut document, &json!({"op": "add"})).is_err());
+    }
+
+    #[test]
+    fn test_json_patch_entry() {
+        start_server();
+        thread::sleep(Duration::from_secs(1));
+
+        let patch_request = |uri: &str, patch: &str| {
+            send_request(&format!(
+                "PATCH {} HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Type: application/json-patch+json\r\nContent-Length: {}\r\n\r\n{}",
+                uri,
+                patch.len(),
+                patch
+            ))
+        };
+
+        let patch = r#"[{"op": "test", "path": "/id", "value": 4}, {"op": "replace", "path": "/average_rating", "value": 9.5}]"#;
+        let response = patch_request("/patch_entry_name?id=4", patch);
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        let entry: serde_json::Value = serde_json::from_str(response.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        assert_eq!(entry["id"], 4);
+        assert_eq!(entry["average_rating"], 9.5);
+
+        let failed_test = r#"[{"op": "test", "path": "/average_rating", "value": 1.0}]"#;
+        assert!(patch_request("/patch_entry_name?id=4", failed_test).starts_with("HTTP/1.1 409 CONFLICT"));
+        let removes_field = r#"[{"op": "remove", "path": "/name"}]"#;
+        assert!(patch_request("/patch_entry_name?id=4", removes_field).starts_with("HTTP/1.1 422 UNPROCESSABLE ENTITY"));
+        let changes_id = r#"[{"op": "replace", "path": "/id", "value": 40}]"#;
+        assert!(patch_request("/patch_entry_name?id=4", changes_id).starts_with("HTTP/1.1 422 UNPROCESSABLE ENTITY"));
+        assert!(patch_request("/patch_entry_name", patch).starts_with("HTTP/1.1 400 BAD REQUEST"));
+        assert!(patch_request("/patch_entry_name?id=999999", patch).starts_with("HTTP/1.1 404 NOT FOUND"));
+    }
+
+    #[test]
+    fn test_merge_patch() {
+        use serde_json::json;
+        // Examples from RFC 7386 appendix A
+        let cases = [
+            (json!({"a": "b"}), json!({"a": "c"}), json!({"a": "c"})),
+            (json!({"a": "b"}), json!({"b": "c"}), json!({"a": "b", "b": "c"})),
+            (json!({"a": "b"}), json!({"a": null}), json!({})),
+            (json!({"a": {"b": "c"}}), json!({"a": {"b": "d", "c": null}}), json!({"a": {"b": "d"}})),
+            (json!({"a": [{"b": "c"}]}), json!({"a": [1]}), json!({"a": [1]})),
+            (json!(["a", "b"]), json!({"a": "b"}), json!({"a": "b"})),
+            (json!({"a": "foo"}), json!("bar"), json!("bar")),
+            (json!({"e": null}), json!({"a": 1}), json!({"e": null, "a": 1})),
+        ];
+        for (mut target, patch, expected) in cases {
+            json_patch::merge(&mut target, &patch);
+            assert_eq!(target, expected);
+        }
+    }
+
+    #[test]
+    fn test_openapi_document() {
+        let response = new_client().get("/openapi.json");
+        assert_eq!(response.status, 200);
+        let document = response.json();
+        assert_eq!(document["openapi"], "3.0.3");
+
+        // Every route is listed, documented or not
+        let paths = document["paths"].as_object().unwrap();
+        for (method, path, _) in ROUTER.routes() {
+            assert!(paths[path].get(method.to_ascii_lowercase()).is_some(), "{method} {path}");
+        }
+        let get_entry = &paths["/entries/{id}"]["get"];
+        assert_eq!(get_entry["parameters"][0]["name"], "id");
+        assert_eq!(get_entry["parameters"][0]["in"], "path");
+        assert_eq!(get_entry["responses"]["200"]["content"]["application/json"]["schema"]["$ref"], "#/components/schemas/Character");
+        assert!(paths["/hello"]["get"]["responses"]["default"].is_object());
+        let offset = &paths["/entries"]["get"]["parameters"][0];
+        assert_eq!((offset["name"].as_str(), offset["in"].as_str()), (Some("offset"), Some("query")));
+
+        // Each reference points at a component schema
+        let schemas = document["components"]["schemas"].as_object().unwrap();
+        let text = document.to_string();
+        for reference in text.split("\"$ref\":\"#/components/schemas/").skip(1) {
+            let name = reference.split('"').next().unwrap();
+            assert!(schemas.contains_key(name), "{name}");
+        }
+        assert_eq!(schemas["Character"]["required"].as_array().unwrap().len(), formats::COLUMNS.len());
+    }
+
+    #[cfg(feature = "docs")]
+    #[test]
+    fn test_docs() {
+        let client = new_client();
+        let response = client.get("/docs");
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("Content-Type"), Some("text/html; charset=utf-8"));
+        assert!(response.text().contains(r#"SwaggerUIBundle({ url: "/openapi.json""#));
+        // Its inline bootstrap script is let through
+        assert!(response.header("Content-Security-Policy").unwrap().contains("script-src 'self' 'unsafe-inline'"));
+
+        let response = client.request("HEAD", "/docs/swagger-ui-bundle.js", &[], "");
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("Content-Type"), Some("text/javascript; charset=utf-8"));
+        assert_eq!(response.header("Cache-Control"), Some("max-age=86400"));
+        let response = client.request("HEAD", "/docs/swagger-ui.css", &[], "");
+        assert_eq!(response.header("Content-Type"), Some("text/css; charset=utf-8"));
+        assert_eq!(client.get("/docs/index.js").status, 404);
+    }
+
+    #[test]
+    fn test_route_params() {
+        let router = routes();
+        let (_, params) = router.find("PATCH", "/entries/42").unwrap();
+        assert_eq!(params.get("id").map(String::as_str), Some("42"));
+        assert!(router.find("PATCH", "/entries/").is_none());
+        assert!(router.find("PATCH", "/entries/42/extra").is_none());
+        assert!(router.find("GET", "/entries").unwrap().1.is_empty());
+        assert_eq!(router.allowed_methods("/entries/42"), vec!["GET", "HEAD", "PATCH", "DELETE", "OPTIONS"]);
+    }
+
+    // an upstream answering every request with what it received, chunked and
+    // with its name in X-Upstream
+    #[cfg(feature = "proxy")]
+    fn echo_upstream(name: &'static str) -> u16 {
+        use std::io::{BufRead, Read};
+
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = upstream.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for stream in upstream.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(&stream);
+                let mut received = String::new();
+                while !received.ends_with("\r\n\r\n") {
+                    reader.read_line(&mut received).unwrap();
+                }
+                let length = received
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Content-Length: "))
+                    .map_or(0, |length| length.parse().unwrap());
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                received.push_str(&String::from_utf8(body).unwrap());
+                let response = format!(
+                    "HTTP/1.1 201 Created\r\nTransfer-Encoding: chunked\r\nX-Upstream: {name}\r\nConnection: close\r\n\r\n{:x}\r\n{received}\r\n0\r\n\r\n",
+                    received.len()
+                );
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        port
+    }
+
+    #[cfg(feature = "proxy")]
+    #[test]
+    fn test_proxy() {
+
+        assert!(proxy::Upstream::parse("https://example.com").is_err());
+        assert!(proxy::Upstream::parse("http://:9000").is_err());
+        assert!(proxy::Upstream::parse("http://example.com:port").is_err());
+        assert_eq!(proxy::Upstream::parse("http://example.com/v1/").unwrap().to_string(), "http://example.com:80/v1");
+
+        let port = echo_upstream("first");
+        let router = Router::new()
+            .proxy("/legacy/*", format!("http://127.0.0.1:{port}").as_str())
+            .proxy("/v2/*", format!("http://127.0.0.1:{port}/api").as_str());
+        let mut headers = HeaderMap::new();
+        headers.append("Host", "example.com");
+        headers.append("X-Forwarded-For", "203.0.113.9");
+        headers.append("Connection", "keep-alive");
+        let body = br#"{"id": 1}"#;
+        let mut request = Request::new("/legacy/users/1?sort=name&x=%20", &headers, body);
+        request.method = "POST".to_string();
+        request.connection.peer = Some("10.0.0.7:50000".parse().unwrap());
+        let (endpoint, params) = router.find("POST", "/legacy/users/1").unwrap();
+        request.params = params;
+        let response = endpoint.call(&mut request);
+        assert_eq!(response.status, StatusCode::Created);
+        assert_eq!(response.headers.get("X-Upstream"), Some("first"));
+        assert!(response.headers.get("Transfer-Encoding").is_none());
+        let forwarded = String::from_utf8(response.body).unwrap();
+        assert!(forwarded.starts_with("POST /legacy/users/1?sort=name&x=%20 HTTP/1.1\r\n"), "{forwarded}");
+        assert!(forwarded.contains(&format!("Host: 127.0.0.1:{port}\r\n")));
+        assert!(forwarded.contains("X-Forwarded-For: 203.0.113.9, 10.0.0.7\r\n"));
+        assert!(forwarded.contains("X-Forwarded-Proto: http\r\n"));
+        assert!(forwarded.contains("X-Forwarded-Host: example.com\r\n"));
+        assert!(forwarded.contains("Connection: close\r\n") && !forwarded.contains("keep-alive"));
+        assert!(forwarded.ends_with(r#"{"id": 1}"#));
+
+        // An upstream with a path gets what the wildcard matched below it
+        let mut request = Request::new("/v2/users", &HeaderMap::new(), b"");
+        let (endpoint, params) = router.find("GET", "/v2/users").unwrap();
+        request.params = params;
+        assert!(String::from_utf8(endpoint.call(&mut request).body).unwrap().starts_with("GET /api/users HTTP/1.1"));
+        // Over HTTPS the upstream is told so
+        request.connection = secure_connection();
+        assert!(String::from_utf8(endpoint.call(&mut request).body).unwrap().contains("X-Forwarded-Proto: https\r\n"));
+        assert!(router.find("GET", "/legacy").is_none());
+        assert_eq!(router.allowed_methods("/legacy/a/b").len(), 7);
+
+        // Nothing listening is a 502
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let router = Router::new().proxy("/gone/*", format!("http://127.0.0.1:{closed}").as_str());
+        let response = router.find("GET", "/gone/x").unwrap().0.call(&mut Request::new("/gone/x", &HeaderMap::new(), b""));
+        assert_eq!(response.status, StatusCode::BadGateway);
+    }
+
+    #[cfg(feature = "proxy")]
+    #[test]
+    fn test_proxy_pool() {
+        use proxy::{Balance, Pool, PoolOptions, Upstream};
+
+        let upstream = |port: u16| Upstream::parse(&format!("http://127.0.0.1:{port}")).unwrap();
+        let closed = || TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let options: PoolOptions = serde_json::from_str(r#"{"max_failures": 1}"#).unwrap();
+        assert_eq!(options.balance, Balance::RoundRobin);
+        let call = |router: &Router| {
+            let mut request = Request::new("/pool/x", &HeaderMap::new(), b"");
+            router.find("GET", "/pool/x").unwrap().0.call(&mut request)
+        };
+
+        // Round robin takes turns
+        let pool = Pool::new(vec![upstream(echo_upstream("a")), upstream(echo_upstream("b"))], options);
+        let router = Router::new().proxy("/pool/*", pool);
+        let names: Vec<String> = (0..4).map(|_| call(&router).headers.get("X-Upstream").unwrap().to_string()).collect();
+        assert_eq!(names, ["a", "b", "a", "b"]);
+
+        // A refused connection is tried on the next upstream
+        let pool = Pool::new(vec![upstream(closed()), upstream(echo_upstream("c"))], options);
+        let router = Router::new().proxy("/pool/*", pool);
+        for _ in 0..3 {
+            assert_eq!(call(&router).status, StatusCode::Created);
+        }
+
+        // An upstream that accepts and hangs up fails its request, then is left out
+        let broken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let broken_port = broken.local_addr().unwrap().port();
+        let (accepted, accepts) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in broken.incoming() {
+                drop(stream);
+                accepted.send(()).unwrap();
+            }
+        });
+        let pool = Pool::new(vec![upstream(broken_port), upstream(echo_upstream("d"))], options);
+        let router = Router::new().proxy("/pool/*", pool);
+        assert_eq!(call(&router).status, StatusCode::BadGateway);
+        for _ in 0..3 {
+            assert_eq!(call(&router).headers.get("X-Upstream"), Some("d"));
+        }
+        assert_eq!(accepts.try_iter().count(), 1);
+
+        // With every upstream left out they are still tried
+        let pool = Pool::new(vec![upstream(closed())], options);
+        let router = Router::new().proxy("/pool/*", pool);
+        assert_eq!(call(&router).status, StatusCode::BadGateway);
+        assert_eq!(call(&router).status, StatusCode::BadGateway);
+
+        let route: proxy::ProxyRoute = serde_json::from_str(
+            r#"{"path": "/x/*", "upstreams": ["http://a:1", "http://b:2"], "balance": "least_connections"}"#,
+        )
+        .unwrap();
+        assert_eq!((route.upstream.len(), route.options.balance, route.options.max_failures), (2, Balance::LeastConnections, 3));
+        assert!(serde_json::from_str::<proxy::ProxyRoute>(r#"{"path": "/x/*", "upstream": []}"#).is_err());
+    }
+
+    #[test]
+    fn test_route_scopes() {
+        fn outer(request: &mut Request, next: router::Next) -> Response {
+            next(request).header("X-Order", "outer")
+        }
+        fn inner(request: &mut Request, next: router::Next) -> Response {
+            next(request).header("X-Order", "inner")
+        }
+        fn deny(_request: &mut Request, _next: router::Next) -> Response {
+            Response::problem(StatusCode::Forbidden, "No")
+        }
+        let router = Router::new()
+            .get("/", home)
+            .scope("/api", |api| {
+                api.middleware(outer)
+                    .get("/hello", hello)
+                    .scope("/v1", |v1| v1.get("/entries/{id}", data).middleware(inner))
+            })
+            .scope("/private", |private| private.middleware(deny).get("/data", data));
+
+        let paths: Vec<&str> = router.routes().map(|(_, path, _)| path).collect();
+        assert_eq!(paths, ["/", "/api/hello", "/api/v1/entries/{id}", "/private/data"]);
+        assert!(router.find("GET", "/hello").is_none());
+        let (endpoint, params) = router.find("GET", "/api/v1/entries/7").unwrap();
+        assert_eq!(params.get("id").map(String::as_str), Some("7"));
+
+        let mut request = Request::new("/api/v1/entries/7", &HeaderMap::new(), b"");
+        // The outer scope's middleware wraps the inner one's, so it adds its header last
+        let response = endpoint.call(&mut request);
+        assert_eq!(response.headers.get_all("X-Order").collect::<Vec<_>>(), ["inner", "outer"]);
+        assert_eq!(router.find("GET", "/api/hello").unwrap().0.call(&mut request).headers.get_all("X-Order").count(), 1);
+        // Middleware only wraps its own scope
+        assert_eq!(router.find("GET", "/").unwrap().0.call(&mut request).headers.get("X-Order"), None);
+        assert_eq!(router.find("GET", "/private/data").unwrap().0.call(&mut request).status, StatusCode::Forbidden);
+    }
+
+    #[test]
+    fn test_extensions() {
+        #[derive(Clone)]
+        struct Locale(&'static str);
+        fn locale(request: &mut Request, next: router::Next) -> Response {
+            if request.headers.get("Accept-Language").is_some_and(|language| language.starts_with("es")) {
+                request.extensions.insert(Locale("en"));
+                // A later value of the same type replaces the first
+                request.extensions.insert(Locale("es"));
+            }
+            next(request)
+        }
+        fn greet(Extension(Locale(locale)): Extension<Locale>) -> Response {
+            Response::text(StatusCode::Ok, if locale == "es" { "Hola" } else { "Hello" })
+        }
+        fn greet_any(locale: Option<Extension<Locale>>) -> Response {
+            greet(locale.unwrap_or(Extension(Locale("en"))))
+        }
+        let router = Router::new().middleware(locale).get("/greet", greet).get("/any", greet_any);
+        let call = |path: &str, language: Option<&str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(language) = language {
+                headers.append("Accept-Language", language);
+            }
+            router.find("GET", path).unwrap().0.call(&mut Request::new(path, &headers, b""))
+        };
+        assert_eq!(call("/greet", Some("es-ES")).body, b"Hola");
+        assert_eq!(call("/any", Some("es")).body, b"Hola");
+        assert_eq!(call("/any", None).body, b"Hello");
+        // Nothing set it, so the handler can't be run
+        assert_eq!(call("/greet", Some("fr")).status, StatusCode::InternalServerError);
+        assert!(Request::new("/", &HeaderMap::new(), b"").extensions.get::<Locale>().is_none());
+    }
+
+    #[test]
+    fn test_extractors() {
+        fn echo(headers: HeaderMap, Json(value): Json<Vec<u32>>) -> Response {
+            let tag = headers.get("X-Tag").unwrap_or_default().to_string();
+            Response::text(StatusCode::Ok, format!("{tag} {value:?}"))
+        }
+        let router = Router::new().post("/echo", echo);
+        let call = |body: &[u8]| {
+            let mut headers = HeaderMap::new();
+            headers.append("X-Tag", "list");
+            router.find("POST", "/echo").unwrap().0.call(&mut Request::new("/echo", &headers, body))
+        };
+        assert_eq!(call(b"[1, 2]").body, b"list [1, 2]");
+        assert_eq!(call(b"[1, 2").status, StatusCode::BadRequest);
+        assert_eq!(call(br#"{"id": 1}"#).status, StatusCode::UnprocessableEntity);
+
+        // The entry routes refuse a body before it reaches the store
+        let client = new_client();
+        let response = client.request("POST", "/submit", &[], "{\"name\": ");
+        assert_eq!(response.status, 400);
+        assert!(response.json()["detail"].as_str().unwrap().starts_with("The body is not valid JSON"));
+        // Labelled as JSON too, and the connection stays open for the next request
+        let response = client.request("POST", "/submit", &[("Content-Type", "application/json")], "{\"name\": ");
+        assert_eq!(response.status, 400);
+        assert_eq!(response.header("Content-Type"), Some(problem::MEDIA_TYPE));
+        assert!(response.json()["detail"].as_str().unwrap().starts_with("The body is not valid JSON"));
+        assert_eq!(response.header("Connection"), None);
+        let response = client.request("PUT", "/put_entry", &[], r#"{"id": "three"}"#);
+        assert_eq!(response.status, 422);
+        assert_eq!(response.json()["errors"][0]["field"], "body");
+    }
+
+    #[test]
+    fn test_into_response() {
+        let response = "hi".into_response();
+        assert_eq!((response.status, response.headers.get("Content-Type")), (StatusCode::Ok, Some("text/plain; charset=utf-8")));
+        let response = (StatusCode::Created, Json(json!({ "id": 3 }))).into_response();
+        assert_eq!(response.status, StatusCode::Created);
+        assert_eq!(response.headers.get("Content-Type"), Some("application/json"));
+        assert_eq!(response.body, br#"{"id":3}"#);
+
+        let outcome: Result<String, ApiError> = Err((StatusCode::NotFound, "Character not found".to_string()).into());
+        let response = outcome.into_response();
+        assert_eq!(response.status, StatusCode::NotFound);
+        assert_eq!(response.headers.get("Content-Type"), Some(problem::MEDIA_TYPE));
+        assert!(ApiError::check((StatusCode::Ok, "Entry updated".to_string())).is_ok());
+        // A 422 already carries its problem document
+        let invalid = validation::to_json(vec![FieldError::new("name", "is empty")]);
+        let response = ApiError::from((StatusCode::UnprocessableEntity, invalid.clone())).into_response();
+        assert_eq!(response.body, invalid.as_bytes());
+    }
+
+    #[test]
+    fn test_data_file_reload() {
+        let _lock = store::lock();
+        let characters = store::load();
+        store::save(&characters);
+        // The server's own writes don't count as changes
+        assert!(!store::reload_if_changed());
+
+        // Another process replacing the file does
+        let path = Path::new(&config::get().data_file);
+        let copy = path.with_extension("json.reload-test");
+        std::fs::copy(path, &copy).unwrap();
+        std::fs::rename(&copy, path).unwrap();
+        assert!(store::reload_if_changed());
+        assert!(!store::reload_if_changed());
+        assert_eq!(store::load().len(), characters.len());
+
+        // A file that doesn't parse keeps the entries in memory
+        let generation = store::generation();
+        std::fs::write(&copy, "[{\"id\": 1,").unwrap();
+        std::fs::rename(&copy, path).unwrap();
+        assert!(!store::reload_if_changed());
+        assert_eq!(store::generation(), generation);
+        assert_eq!(store::load().len(), characters.len());
+        // Put the file back
+        store::save(&characters);
+        assert!(!store::reload_if_changed());
+    }
+
+    #[test]
+    fn test_journal() {
+        use journal::Record;
+        use std::io::Write as _;
+        let entry = |id: usize, name: &str| -> Character {
+            serde_json::from_value(json!({
+                "id": id, "rank": "1", "trend": "-", "season": 1, "episode": id, "name": name,
+                "start": 1999, "total_votes": "1,000", "average_rating": 8.5,
+            }))
+            .unwrap()
+        };
+        let before = vec![entry(1, "Luffy"), entry(2, "Zoro"), entry(3, "Nami")];
+        let renamed = vec![entry(1, "Luffy"), entry(2, "Roronoa Zoro"), entry(3, "Nami")];
+        let after = vec![entry(2, "Roronoa Zoro"), entry(3, "Nami"), entry(4, "Usopp")];
+        assert!(Record::diff(&before, &before).is_empty());
+
+        let path = std::env::temp_dir().join(format!("journal-test-{}", std::process::id()));
+        journal::append(&path, &Record::diff(&before, &renamed)).unwrap();
+        journal::append(&path, &Record::diff(&renamed, &after)).unwrap();
+        // A line the crash cut short is dropped, the ones before it are replayed
+        std::fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(br#"{"upserted":[{"id""#).unwrap();
+        let records = journal::read(&path).unwrap();
+        assert_eq!(records.len(), 2);
+        let mut replayed = before.clone();
+        for record in records {
+            record.apply(&mut replayed);
+        }
+        assert_eq!(replayed, after);
+
+        journal::clear(&path).unwrap();
+        assert!(journal::read(&path).unwrap().is_empty());
+        std::fs::remove_file(&path).unwrap();
+        assert!(journal::read(&path).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_backup_and_restore() {
+        let client = admin_client();
+        let response = client.request("POST", "/admin/backup", &[], "");
+        assert_eq!(response.status, 201);
+        let name = response.json()["name"].as_str().unwrap().to_string();
+        assert!(name.starts_with("one_piece2-") && name.ends_with(".json"), "{name}");
+        let dir = Path::new(&config::get().backup_dir);
+        let backup = store::read_backup(dir, &name).unwrap();
+        assert!(backup.iter().any(|entry| entry.id() == 3));
+
+        // Only names of files in the backup directory are restored
+        for (name, status) in [("../one_piece2.json", 400), (".hidden.json", 400), ("missing.json", 404)] {
+            let body = json!({ "name": name }).to_string();
+            assert_eq!(client.request("POST", "/admin/restore", &[], &body).status, status, "{name}");
+        }
+        assert_eq!(client.request("POST", "/admin/restore", &[], "{}").status, 422);
+        std::fs::remove_file(dir.join(name)).unwrap();
+    }
+
+    #[test]
+    fn test_connection_limit() {
+        let limit = |json: serde_json::Value| serde_json::from_value::<connections::ConnectionLimit>(json).unwrap();
+        let reject = limit(json!({ "max": 0 }));
+        assert_eq!(reject.when_full, connections::WhenFull::Reject);
+        let wait = limit(json!({ "max": 0, "when_full": "wait" }));
+        assert!(serde_json::from_value::<connections::ConnectionLimit>(json!({ "max": 1, "when_full": "drop" })).is_err());
+
+        // Other tests open connections too, so only a full limit is predictable
+        assert!(connections::try_open(Some(reject)).is_none());
+        assert!(!connections::must_wait(Some(reject)) && connections::must_wait(Some(wait)));
+        assert!(!connections::must_wait(None));
+        let slot = connections::try_open(None).unwrap();
+        assert!(connections::open() >= 1);
+        drop(slot);
+
+        let rejected = connections::rejected();
+        let response = connections::rejection();
+        assert_eq!(response.status, StatusCode::ServiceUnavailable);
+        assert_eq!(response.headers.get("Retry-After"), Some("1"));
+        assert!(connections::rejected() > rejected);
+        let state = admin_client().get("/admin/runtime").json();
+        assert_eq!(state["connections"]["max"], json!(null));
+        assert!(state["connections"]["rejected"].as_u64().unwrap() >= 1);
+    }
+
+    #[test]
+    fn test_runtime_admin() {
+        let client = admin_client();
+        let state = client.get("/admin/runtime").json();
+        assert_eq!(state["rate_limiting"], json!({ "configured": false, "enabled": true }));
+
+        let change = |body: &str| client.request("PATCH", "/admin/runtime", &[], body);
+        let state = change(r#"{"log_level": "warn"}"#).json();
+        assert_eq!(state["log_level"], "warn");
+        assert!(!log::enabled(log::Level::Info));
+        assert_eq!(change(r#"{"log_level": "loud"}"#).status, 422);
+        assert_eq!(change(r#"{"threads": 9}"#).status, 422);
+        assert_eq!(change(r#"{"log_level": "debug"}"#).json()["log_level"], "debug");
+
+        let response = client.request("POST", "/admin/flush", &[], "");
+        assert_eq!(response.status, 200);
+        assert!(response.json()["bytes"].as_u64().unwrap() > 0);
+    }
+
+    #[test]
+    fn test_admin_stats() {
+        let client = admin_client();
+        let before = client.get("/admin/stats").json();
+        assert_eq!(client.request("PUT", "/nowhere", &[], "{}").status, 404);
+        assert_eq!(client.send(b"NOT A REQUEST\r\n\r\n").status, 400);
+        let after = client.get("/admin/stats").json();
+
+        // other tests count requests too, so only the least increase is known
+        let count = |stats: &serde_json::Value, pointer: &str| stats.pointer(pointer).and_then(|count| count.as_u64()).unwrap();
+        assert!(count(&after, "/requests/total") >= count(&before, "/requests/total") + 3);
+        assert!(count(&after, "/requests/by_method/PUT") > count(&before, "/requests/by_method/PUT"));
+        assert!(count(&after, "/requests/by_method/other") > count(&before, "/requests/by_method/other"));
+        assert!(count(&after, "/errors/client") >= count(&before, "/errors/client") + 2);
+        assert!(after["uptime_secs"].is_u64() && after["connections_accepted"].is_u64());
+        assert!(after["errors"]["server"].is_u64());
+        assert!(count(&after, "/store/entries") > 0 && count(&after, "/store/bytes") > 0);
+        if let Some(pool) = after["pool"].as_object() {
+            assert!(pool["utilization"].as_f64().is_some_and(|utilization| (0.0..=1.0).contains(&utilization)));
+        }
+    }
+
+    #[test]
+    fn test_ip_filter() {
+        use ip_filter::{Cidr, IpFilter};
+
+        let ip = |ip: &str| ip.parse::<std::net::IpAddr>().unwrap();
+        let range = Cidr::parse("10.0.0.0/8").unwrap();
+        assert!(range.contains(ip("10.200.3.4")) && !range.contains(ip("11.0.0.1")));
+        // An IPv4 client accepted on an IPv6 socket
+        assert!(range.contains(ip("::ffff:10.0.0.1")));
+        assert!(Cidr::parse("::1").unwrap().contains(ip("::1")));
+        assert!(Cidr::parse("fd00::/8").unwrap().contains(ip("fd12::1")));
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(ip("8.8.8.8")));
+        assert!(!Cidr::parse("0.0.0.0/0").unwrap().contains(ip("::2")));
+        for invalid in ["10.0.0.0/33", "10.0.0/8", "localhost", "::/129"] {
+            assert!(Cidr::parse(invalid).is_err(), "{invalid}");
+        }
+
+        let filter: IpFilter = serde_json::from_value(json!({
+            "deny": ["203.0.113.0/24"],
+            "rules": [
+                { "paths": ["/submit", "/put_entry", "/delete_entry"], "allow": ["10.0.0.0/8"], "deny": ["10.9.9.9"] },
+                { "paths": ["/admin/"], "allow": ["127.0.0.1"] }
+            ]
+        }))
+        .unwrap();
+        assert!(!filter.lists.admits(ip("203.0.113.5")) && filter.lists.admits(ip("198.51.100.1")));
+        assert!(filter.admits_to("/submit", ip("10.1.1.1")));
+        assert!(!filter.admits_to("/submit", ip("192.168.1.1")));
+        assert!(!filter.admits_to("/delete_entry", ip("10.9.9.9")));
+        assert!(filter.admits_to("/submitted", ip("192.168.1.1")));
+        assert!(filter.admits_to("/entries", ip("192.168.1.1")));
+        assert!(!filter.admits_to("/admin/compact", ip("10.1.1.1")));
+        assert!(filter.admits_to("/admin", ip("127.0.0.1")));
+        assert!(serde_json::from_value::<IpFilter>(json!({ "allow": ["nope"] })).is_err());
+
+        // Without lists configured everyone gets through
+        assert!(ip_filter::check(config::get(), ip("203.0.113.5")).is_ok());
+        let router = Router::new().middleware(ip_filter::middleware).get("/hello", hello);
+        let mut request = Request::new("/hello", &HeaderMap::new(), b"");
+        request.connection.peer = Some("203.0.113.5:4000".parse().unwrap());
+        assert_eq!(router.find("GET", "/hello").unwrap().0.call(&mut request).status, StatusCode::Ok);
+    }
+
+    #[cfg(feature = "faults")]
+    #[test]
+    fn test_fault_injection() {
+        use faults::FaultRule;
+
+        let rule = |fault: fn(&mut FaultRule)| {
+            let mut rule = FaultRule { paths: vec!["/entries/*".to_string()], latency_ms: 50, ..FaultRule::default() };
+            fault(&mut rule);
+            rule
+        };
+        let call = |rule: &FaultRule| {
+            let mut request = Request::new("/entries/1", &HeaderMap::new(), b"");
+            faults::inject(rule, &mut request, &|_| Response::text(StatusCode::Ok, "the whole entry"))
+        };
+        let serialized = |response: Response| response.serialize(false);
+        let full = serialized(call(&rule(|_| {})));
+        assert!(!full.close);
+        assert!(full.bytes.ends_with(b"the whole entry"));
+
+        let started = Instant::now();
+        assert_eq!(call(&rule(|rule| rule.latency_probability = 1.0)).status, StatusCode::Ok);
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        let error = call(&rule(|rule| rule.error_probability = 1.0));
+        assert_eq!(error.status, StatusCode::InternalServerError);
+        assert!(String::from_utf8(error.body).unwrap().contains("Injected fault"));
+
+        // Nothing goes out on a dropped connection, half the body on a truncated one
+        let dropped = serialized(call(&rule(|rule| rule.drop_probability = 1.0)));
+        assert!(dropped.bytes.is_empty() && dropped.close);
+        let truncated = serialized(call(&rule(|rule| rule.truncate_probability = 1.0)));
+        assert!(truncated.close);
+        assert!(truncated.bytes.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(truncated.bytes.ends_with(b"\r\n\r\nthe who"));
+
+        let config: config::Config =
+            serde_json::from_value(json!({ "faults": [{ "paths": ["/slow"], "latency_ms": 100, "latency_probability": 0.5 }] })).unwrap();
+        assert_eq!(config.faults[0].latency_ms, 100);
+        assert_eq!(config.faults[0].error_probability, 0.0);
+    }
+
+    #[test]
+    fn test_quotas() {
+        let config: config::Config = serde_json::from_value(json!({ "quotas": {
+            "daily_bytes_in": 100,
+            "daily_bytes_out": 1000,
+            "clients": { "198.51.100.8": { "daily_bytes_out": 50 } },
+        } }))
+        .unwrap();
+        let peer = |address: &str| Some(format!("{address}:4000").parse().unwrap());
+        assert_eq!(quotas::client(&config::Config::default(), &HeaderMap::new(), peer("198.51.100.7")), None);
+        let client = quotas::client(&config, &HeaderMap::new(), peer("198.51.100.7")).unwrap();
+
+        let head = parser::read_head(&mut BufReader::new(&b"GET /hello HTTP/1.1\r\nHost: a\r\n\r\n"[..]), true).unwrap();
+        assert_eq!(quotas::request_len(&head, 0), 32);
+        assert!(quotas::receive(&config, &client, 32).is_ok());
+        let response = Response::text(StatusCode::Ok, "hello");
+        let sent = response.serialized_len(false);
+        assert_eq!(sent, response.to_bytes(false).len() as u64);
+        let response = quotas::send(&config, &client, response, false);
+        // 68 bytes left to receive, fewer than there are left to send
+        assert_eq!(response.headers.get(quotas::HEADER), Some("68"));
+
+        // Over the quota the client is refused until tomorrow
+        let refused = quotas::receive(&config, &client, 71).unwrap_err();
+        assert_eq!(refused.status, StatusCode::TooManyRequests);
+        assert!(refused.headers.get("Retry-After").unwrap().parse::<u64>().unwrap() <= 24 * 60 * 60);
+        let refused_len = refused.serialized_len(false);
+        let refused = quotas::send(&config, &client, refused, false);
+        assert_eq!(refused.headers.get(quotas::HEADER), Some("0"));
+
+        // A client's own quotas replace the others
+        let other = quotas::client(&config, &HeaderMap::new(), peer("198.51.100.8")).unwrap();
+        assert!(quotas::receive(&config, &other, 1000).is_ok());
+        let no_content = Response::new(StatusCode::NoContent);
+        let left = 50 - no_content.serialized_len(false);
+        let response = quotas::send(&config, &other, no_content, false);
+        assert_eq!(response.headers.get(quotas::HEADER), Some(left.to_string().as_str()));
+
+        let usage = quotas::usage(config.quotas.as_ref().unwrap());
+        let first = usage.iter().find(|usage| usage.client == "198.51.100.7").unwrap();
+        assert_eq!((first.bytes_in, first.bytes_out, first.limits.daily_bytes_in), (103, sent + refused_len, 100));
+    }
+
+    #[test]
+    fn test_connection_info() {
+        let plain = ConnectionInfo::plain("203.0.113.5:4000".parse().ok(), "0.0.0.0:7878".parse().ok());
+        assert!(!plain.secure());
+        assert_eq!(plain.to_string(), "from 203.0.113.5:4000 to 0.0.0.0:7878 in cleartext");
+        assert_eq!(ConnectionInfo::default().to_string(), "from an unknown client in cleartext");
+        let secure = secure_connection();
+        assert!(secure.secure());
+        assert_eq!(secure.to_string(), "from an unknown client over TLSv1_3 TLS13_AES_128_GCM_SHA256");
+
+        // A TLS connection reports what its handshake settled on
+        #[cfg(feature = "tls")]
+        {
+            use async_server::Transport;
+
+            let files = tls::TlsFiles { cert: "assets/tls/localhost.pem".into(), key: "assets/tls/localhost-key.pem".into() };
+            let config = tls::server_config(&files, false).unwrap();
+            let (info, address, client) = tokio::runtime::Runtime::new().unwrap().block_on(async {
+                let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let address = listener.local_addr().unwrap();
+                let client = tokio::spawn(async move { connect_tls(address, &[b"http/1.1"]).await });
+                let (stream, _) = listener.accept().await.unwrap();
+                let info = tls::accept(stream, config).await.unwrap().connection();
+                let client = client.await.unwrap();
+                (info, address, client.get_ref().0.local_addr().unwrap())
+            });
+            assert_eq!((info.peer, info.local), (Some(client), Some(address)));
+            let tls = info.tls.as_ref().unwrap();
+            assert_eq!(tls.version, "TLSv1_3");
+            assert!(tls.cipher.starts_with("TLS13_"), "{}", tls.cipher);
+            assert_eq!(tls.sni.as_deref(), Some("localhost"));
+            assert_eq!(info.alpn.as_deref(), Some("http/1.1"));
+            assert!(info.to_string().ends_with("(SNI localhost), ALPN http/1.1"), "{info}");
+        }
+    }
+
+    #[test]
+    fn test_request_id() {
+        let response = new_client().get("/hello");
+        let id = response.header("X-Request-Id").unwrap().to_string();
+        assert_eq!(id.len(), 24);
+        assert_ne!(new_client().get("/hello").header("X-Request-Id"), Some(id.as_str()));
+        // Requests the parser refuses get one too
+        assert!(new_client().send(b"NOT A REQUEST\r\n\r\n").header("X-Request-Id").is_some());
+        // An id sent by anyone else than a trusted proxy is replaced
+        let sent = new_client().request("GET", "/hello", &[("X-Request-Id", "from-client")], "");
+        assert_ne!(sent.header("X-Request-Id"), Some("from-client"));
+
+        let proxies = [ip_filter::Cidr::parse("10.0.0.0/8").unwrap()];
+        let mut headers = HeaderMap::new();
+        headers.append("X-Request-Id", "abc-123");
+        let proxy = Some("10.1.2.3:5000".parse().unwrap());
+        assert_eq!(request_id::assign(&headers, proxy, &proxies), "abc-123");
+        assert_ne!(request_id::assign(&headers, Some("192.0.2.1:5000".parse().unwrap()), &proxies), "abc-123");
+        assert_ne!(request_id::assign(&headers, None, &proxies), "abc-123");
+        headers.insert("X-Request-Id", "has spaces");
+        assert_ne!(request_id::assign(&headers, proxy, &proxies), "has spaces");
+
+        assert_eq!(request_id::log_prefix(), "");
+        {
+            let _outer = request_id::enter("outer".to_string());
+            {
+                let _inner = request_id::enter("inner".to_string());
+                assert_eq!(request_id::log_prefix(), "[inner] ");
+            }
+            assert_eq!(request_id::current().as_deref(), Some("outer"));
+        }
+        assert_eq!(request_id::current(), None);
+    }
+
+    #[test]
+    fn test_response_cache() {
+        let client = new_client();
+        let entry = |uri: &str| {
+            let response = client.get(uri);
+            assert_eq!(response.status, 200);
+            response.json()
+        };
+        let (hits, misses) = response_cache::counts();
+        let first = entry("/entries/8?cache=test");
+        assert_eq!(entry("/entries/8?cache=test"), first);
+        let (later_hits, later_misses) = response_cache::counts();
+        assert!(later_hits > hits && later_misses > misses);
+
+        // Any write makes what was cached stale
+        let rating = if first["average_rating"] == 8.25 { 8.5 } else { 8.25 };
+        let patch = json!({ "average_rating": rating }).to_string();
+        let response = client.request("PATCH", "/entries/8", &[("Content-Type", "application/merge-patch+json")], &patch);
+        assert_eq!(response.status, 200);
+        assert_eq!(entry("/entries/8?cache=test")["average_rating"], rating);
+
+        // Another format of the same path is cached apart
+        let csv = client.request("GET", "/entries?limit=1", &[("Accept", "text/csv")], "");
+        let json = client.request("GET", "/entries?limit=1", &[("Accept", "application/json")], "");
+        assert!(csv.header("Content-Type").unwrap().starts_with("text/csv"));
+        assert!(json.header("Content-Type").unwrap().starts_with("application/json"));
+    }
+
+    #[cfg(feature = "templates")]
+    #[test]
+    fn test_templates() {
+        use templates::{Template, TemplateError};
+        let template = Template::parse(
+            "<h1>{{ title }}</h1>{% if entries %}<ol>{% for entry in entries %}<li id={{ loop.index }}>{{ entry.name }}\
+             {% if entry.tags %} [{% for tag in entry.tags %}{{ tag }}{% endfor %}]{% endif %}</li>{% endfor %}</ol>\
+             {% else %}<p>None</p>{% endif %}{{ note | raw }}{{ missing.path }}{{ entries.1.name }}",
+        )
+        .unwrap();
+        let context = json!({
+            "title": "Crew & <friends>",
+            "entries": [{ "name": "Luffy", "tags": ["a", "b"] }, { "name": "Zoro", "tags": [] }],
+            "note": "<em>raw</em>",
+        });
+        assert_eq!(
+            template.render(&context),
+            "<h1>Crew &amp; &lt;friends&gt;</h1><ol><li id=1>Luffy [ab]</li><li id=2>Zoro</li></ol><em>raw</em>Zoro"
+        );
+        assert_eq!(template.render(&json!({ "entries": [] })), "<h1></h1><p>None</p>");
+
+        assert_eq!(Template::parse("{{ name").unwrap_err(), TemplateError::Unclosed("{{", 0));
+        assert_eq!(Template::parse("a{% if x %}b").unwrap_err(), TemplateError::Unclosed("{% if %}", 1));
+        assert_eq!(Template::parse("{% endfor %}").unwrap_err(), TemplateError::Unexpected("endfor".to_string(), 0));
+        assert_eq!(Template::parse("{% for x in y %}{% endif %}").unwrap_err(), TemplateError::Unexpected("endif".to_string(), 16));
+        assert_eq!(Template::parse("{{ x | upper }}").unwrap_err(), TemplateError::UnknownFilter("upper".to_string()));
+        let json_filter = Template::parse("<tr data-entry=\"{{ entry | json }}\">").unwrap();
+        assert_eq!(
+            json_filter.render(&json!({ "entry": { "name": "\"Zoro\" & co" } })),
+            "<tr data-entry=\"{&quot;name&quot;:&quot;\\&quot;Zoro\\&quot; &amp; co&quot;}\">"
+        );
+
+        // Browsers asking for an entry get a page made from it
+        let client = new_client();
+        let page = client.request("GET", "/entries/1", &[("Accept", "text/html,*/*;q=0.8")], "");
+        assert_eq!(page.status, 200);
+        assert_eq!(page.header("Content-Type"), Some("text/html; charset=utf-8"));
+        assert!(page.text().contains("<th>Rating</th>") && page.text().contains("<a href=\"/entries\">"), "{}", page.text());
+        assert_ne!(page.header("ETag"), client.get("/entries/1").header("ETag"));
+        let missing = Response::render("no-such-template.html", json!({}));
+        assert_eq!(missing.status, StatusCode::InternalServerError);
+
+        // The admin UI lists the entries with the data its script edits
+        let ui = client.get("/ui");
+        assert_eq!(ui.status, 200);
+        assert_eq!(ui.header("Content-Type"), Some("text/html; charset=utf-8"));
+        assert!(ui.text().contains("<tr data-entry=\"{&quot;") && ui.text().contains("&quot;id&quot;:1,"), "{}", ui.text());
+        assert!(ui.text().contains("<td>7.8</td>"), "{}", ui.text());
+        assert!(ui.text().contains("action=\"/ui/entries/1/trash\"") && !ui.text().contains("{{"), "{}", ui.text());
+        assert!(!ui.text().contains("type=\"hidden\" name=\"csrf_token\""));
+        // With CSRF on, every delete form carries the token
+        let entries = json!([{ "id": 1, "name": "Luffy" }]);
+        let csrf = json!({ "field": "csrf_token", "token": "abc123" });
+        let page = Response::render("ui.html", json!({ "count": 1, "entries": entries, "flashes": [], "csrf": csrf }));
+        let page = String::from_utf8(page.body).unwrap();
+        assert!(page.contains("<input type=\"hidden\" name=\"csrf_token\" value=\"abc123\"><button type=\"submit\">Delete"), "{page}");
+    }
+
+    #[cfg(feature = "templates")]
+    #[test]
+    fn test_ui_flash() {
+        let client = new_client();
+        let entry = r#"{"id": 0, "rank": "1", "trend": "-", "season": 1, "episode": 1, "name": "Flashed once",
+            "start": 2020, "total_votes": "1", "average_rating": 5.0}"#;
+        let created = client.request("POST", "/submit", &[("Content-Type", "application/json")], entry);
+        let path = format!("/ui/entries/{}/trash", created.json()["id"]);
+
+        // The post, as a browser sends the form, redirects back to the page, with
+        // a session for the message
+        let form = ("Content-Type", "application/x-www-form-urlencoded");
+        let trashed = client.request("POST", &path, &[form], "csrf_token=");
+        assert_eq!(trashed.status, 303);
+        assert_eq!(trashed.header("Location"), Some("/ui"));
+        let session = trashed.header_all("Set-Cookie").find(|cookie| cookie.starts_with("session=")).unwrap();
+        assert!(session.ends_with("; Path=/; HttpOnly; SameSite=Lax"), "{session}");
+        let cookie = [("Cookie", session.split(';').next().unwrap())];
+
+        // The next page shows it, the one after doesn't
+        let page = client.request("GET", "/ui", &cookie, "");
+        assert!(page.text().contains("<li class=\"notice\">Moved Flashed once to the trash</li>"), "{}", page.text());
+        assert!(!page.text().contains("Flashed once</td>"));
+        assert!(!client.request("GET", "/ui", &cookie, "").text().contains("id=\"flashes\""));
+        assert!(!client.get("/ui").text().contains("id=\"flashes\""));
+
+        // Failures are flashed too, into the session the browser has
+        let again = client.request("POST", &path, &[cookie[0], form], "csrf_token=");
+        assert_eq!(again.status, 303);
+        assert!(again.header_all("Set-Cookie").all(|cookie| !cookie.starts_with("session=")));
+        assert!(client.request("GET", "/ui", &cookie, "").text().contains("<li class=\"error\">No such entry</li>"));
+    }
+
+    #[test]
+    fn test_slow_request_report() {
+        let mut timing = slow_log::Timing::start();
+        thread::sleep(Duration::from_millis(30));
+        timing.parsed("POST", "/entries?dry_run=true", 12);
+        timing.handled(345);
+        let now = Instant::now();
+
+        let report = timing.report(Duration::from_millis(20), now).expect("slower than the threshold");
+        assert!(report.starts_with("Slow request: POST /entries took "), "{report}");
+        assert!(report.contains("mostly parse"), "{report}");
+        assert!(report.contains("request body 12 bytes, response 345 bytes"), "{report}");
+        assert_eq!(timing.report(Duration::from_secs(10), now), None);
+
+        // a request that was never fully read is no request to report
+        let unread = slow_log::Timing::start();
+        assert_eq!(unread.report(Duration::ZERO, Instant::now()), None);
+
+        let mut timing = slow_log::Timing::start();
+        timing.parsed("GET", "/entries", 0);
+        thread::sleep(Duration::from_millis(30));
+        timing.handled(10);
+        let report = timing.report(Duration::from_millis(20), Instant::now()).unwrap();
+        assert!(report.contains("mostly handler"), "{report}");
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_metrics() {
+        let client = new_client();
+        client.get("/entries/1");
+        client.get("/entries/1");
+        client.get("/entries/999999");
+        client.get("/no-such-route");
+
+        let response = admin_client().get("/admin/metrics");
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("Content-Type"), Some(metrics::MEDIA_TYPE));
+        let text = response.text();
+        let value = |series: &str| -> u64 {
+            let line = text.lines().find(|line| line.starts_with(series)).unwrap_or_else(|| panic!("{series}"));
+            line.rsplit(' ').next().unwrap().parse().unwrap()
+        };
+        // Other tests run in parallel, so only lower bounds hold
+        let labels = r#"method="GET",route="/entries/{id}""#;
+        assert!(value(&format!("http_request_duration_seconds_count{{{labels}}}")) >= 3);
+        assert!(value(&format!("http_request_duration_seconds_bucket{{{labels},le=\"+Inf\"}}")) >= 3);
+        assert!(value(&format!("http_responses_total{{{labels},status=\"200\"}}")) >= 2);
+        assert!(value(&format!("http_responses_total{{{labels},status=\"404\"}}")) >= 1);
+        assert!(value(r#"http_responses_total{method="GET",route="unmatched",status="404"}"#) >= 1);
+        assert!(text.contains("# TYPE http_request_duration_seconds histogram"));
+        assert!(text.contains("\nhttp_response_cache_hits_total "));
+        assert!(text.contains("# TYPE http_connections_open gauge\nhttp_connections_open "));
+
+        // Buckets count every request at or below their bound
+        metrics::record("PUT", "/metrics-test", StatusCode::Ok, Duration::from_millis(30));
+        metrics::record("PUT", "/metrics-test", StatusCode::Ok, Duration::from_secs(20));
+        let text = metrics::render();
+        let labels = r#"method="PUT",route="/metrics-test""#;
+        assert!(text.contains(&format!("http_request_duration_seconds_bucket{{{labels},le=\"0.025\"}} 0\n")));
+        assert!(text.contains(&format!("http_request_duration_seconds_bucket{{{labels},le=\"0.05\"}} 1\n")));
+        assert!(text.contains(&format!("http_request_duration_seconds_bucket{{{labels},le=\"10\"}} 1\n")));
+        assert!(text.contains(&format!("http_request_duration_seconds_bucket{{{labels},le=\"+Inf\"}} 2\n")));
+        assert!(text.contains(&format!("http_request_duration_seconds_sum{{{labels}}} 20.03\n")));
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let config: config::Config = serde_json::from_str(r#"{"rate_limit": {"requests": 2, "window_secs": 60}}"#).unwrap();
+        let client: std::net::IpAddr = "10.1.2.3".parse().unwrap();
+        assert!(rate_limit::check(&config, client).is_ok());
+        assert!(rate_limit::check(&config, client).is_ok());
+        let response = rate_limit::check(&config, client).unwrap_err();
+        assert_eq!(response.status, StatusCode::TooManyRequests);
+        assert!(response.headers.get("Retry-After").is_some());
+        // Every client has a window of its own
+        assert!(rate_limit::check(&config, "10.1.2.4".parse().unwrap()).is_ok());
+
+        rate_limit::set_enabled(false);
+        let unlimited = rate_limit::check(&config, client);
+        rate_limit::set_enabled(true);
+        assert!(unlimited.is_ok());
+    }
+
+    #[test]
+    fn test_pool_stats() {
+        let pool = ThreadPool::new(2);
+        let (release, wait) = mpsc::channel::<()>();
+        let wait = std::sync::Arc::new(std::sync::Mutex::new(wait));
+        for _ in 0..3 {
+            let wait = wait.clone();
+            pool.execute(move || {
+                let _ = wait.lock().unwrap().recv();
+            });
+        }
+        // One job holds the lock waiting, one waits for the lock, one is queued
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pool.stats().busy < 2 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        let stats = pool.stats();
+        assert_eq!((stats.workers, stats.busy, stats.queued, stats.completed), (2, 2, 1, 0));
+        for _ in 0..3 {
+            release.send(()).unwrap();
+        }
+        drop(pool);
+    }
+
+    #[test]
+    fn test_handler_timeout() {
+        fn slow(_request: &Request) -> &'static str {
+            thread::sleep(Duration::from_millis(300));
+            "late"
+        }
+        fn panics(_request: &Request) -> Response {
+            panic!("handler bug");
+        }
+        let router = Router::new().get("/slow", slow).get("/boom", panics);
+        let mut request = Request::new("/slow", &HeaderMap::new(), b"");
+        let endpoint = |path| router.find("GET", path).unwrap().0;
+
+        let pool = ThreadPool::new(1);
+        let within = |deadline| Some((deadline, &pool));
+        let settle = || {
+            while pool.stats().busy > 0 {
+                thread::sleep(Duration::from_millis(10));
+            }
+        };
+
+        let started = Instant::now();
+        let outcome = call_endpoint(endpoint("/slow"), &mut request, within(Duration::from_millis(20)));
+        assert_eq!(outcome.err(), Some(HandlerFailure::TimedOut));
+        assert!(started.elapsed() < Duration::from_millis(250));
+        // The late handler keeps the only thread, no other one is started for the next request
+        let outcome = call_endpoint(endpoint("/slow"), &mut request, within(Duration::from_secs(5)));
+        assert_eq!(outcome.err(), Some(HandlerFailure::Busy));
+        settle();
+        let outcome = call_endpoint(endpoint("/slow"), &mut request, within(Duration::from_secs(5)));
+        assert_eq!(outcome.ok().map(|response| response.body), Some(b"late".to_vec()));
+        assert_eq!(call_endpoint(endpoint("/slow"), &mut request, None).ok().map(|response| response.status), Some(StatusCode::Ok));
+
+        for deadline in [None, within(Duration::from_secs(5))] {
+            assert_eq!(call_endpoint(endpoint("/boom"), &mut request, deadline).err(), Some(HandlerFailure::Panicked));
+        }
+        // A panic doesn't cost the pool its thread
+        settle();
+        assert_eq!(pool.stats().workers, 1);
+        let outcome = call_endpoint(endpoint("/slow"), &mut request, within(Duration::from_secs(5)));
+        assert!(outcome.is_ok());
+    }
+
+    #[test]
+    fn test_admin_token() {
+        let unset: config::Config = serde_json::from_str("{}").unwrap();
+        for authorization in [None, Some("Bearer "), Some("Bearer s3cret")] {
+            assert!(!admin_authorized(&unset, authorization), "{authorization:?}");
+        }
+        let config: config::Config = serde_json::from_str(r#"{"admin_token": "s3cret"}"#).unwrap();
+        assert!(admin_authorized(&config, Some("Bearer s3cret")));
+        assert!(admin_authorized(&config, Some("bearer s3cret")));
+        for authorization in [None, Some("Bearer s3cre"), Some("Bearer s3cret2"), Some("Basic s3cret"), Some("s3cret")] {
+            assert!(!admin_authorized(&config, authorization), "{authorization:?}");
+        }
+        let response = new_client().get("/admin/runtime");
+        assert_eq!(response.status, 401);
+        assert_eq!(response.header("WWW-Authenticate"), Some("Bearer"));
+        assert_eq!(admin_client().get("/admin/runtime").status, 200);
+    }
+
+    #[test]
+    fn test_api_keys() {
+        let dir = std::env::temp_dir().join(format!("api-keys-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("keys.json").display().to_string();
+        let config: config::Config =
+            serde_json::from_str(&json!({ "api_keys": { "file": file, "required": true } }).to_string()).unwrap();
+        let api_keys = config.api_keys.as_ref().unwrap();
+        let new_key = |body: &str| serde_json::from_str::<api_keys::NewKey>(body).unwrap();
+        let with_key = |key: &str| {
+            let mut headers = HeaderMap::new();
+            headers.append(api_keys::HEADER, key);
+            headers
+        };
+        let status = |result: Result<Option<api_keys::ApiKey>, Response>| result.map(|_| ()).map_err(|response| response.status);
+
+        // Only the hash is stored, the key is shown once
+        let (reader, read_key) = api_keys::issue(api_keys, new_key(r#"{"name": "reader"}"#)).unwrap();
+        assert_eq!(reader.scopes, vec![api_keys::Scope::Read]);
+        let stored = std::fs::read_to_string(&file).unwrap();
+        assert!(stored.contains("\"hash\"") && !stored.contains(&read_key));
+        let (writer, write_key) = api_keys::issue(
+            api_keys,
+            new_key(r#"{"name": "importer", "scopes": ["read", "write"], "rate_limit": {"requests": 2, "window_secs": 60}}"#),
+        )
+        .unwrap();
+        assert!(api_keys::issue(api_keys, new_key(r#"{"name": " "}"#)).is_err());
+
+        // Scopes cover the methods, the key's limit its requests
+        assert_eq!(status(api_keys::authenticate(api_keys, "GET", &with_key(&read_key))), Ok(()));
+        assert_eq!(status(api_keys::authenticate(api_keys, "POST", &with_key(&read_key))), Err(StatusCode::Forbidden));
+        assert_eq!(status(api_keys::authenticate(api_keys, "POST", &with_key(&write_key))), Ok(()));
+        assert_eq!(status(api_keys::authenticate(api_keys, "GET", &with_key(&write_key))), Ok(()));
+        assert_eq!(status(api_keys::authenticate(api_keys, "GET", &with_key(&write_key))), Err(StatusCode::TooManyRequests));
+        assert_eq!(status(api_keys::authenticate(api_keys, "GET", &with_key("ak_guess"))), Err(StatusCode::Unauthorized));
+        assert_eq!(status(api_keys::authenticate(api_keys, "GET", &HeaderMap::new())), Err(StatusCode::Unauthorized));
+        assert_eq!(api_keys::principal(&config, &with_key(&read_key)), Some("api-key:reader".to_string()));
+
+        // A revoked key is refused but stays listed
+        let revoked = api_keys::revoke(api_keys, &reader.id).unwrap().unwrap();
+        assert!(revoked.revoked.is_some());
+        assert!(api_keys::revoke(api_keys, "missing").unwrap().is_none());
+        assert_eq!(status(api_keys::authenticate(api_keys, "GET", &with_key(&read_key))), Err(StatusCode::Unauthorized));
+        let listed = api_keys::list(api_keys).unwrap();
+        assert_eq!(listed.iter().map(|key| key.id.as_str()).collect::<Vec<_>>(), [reader.id.as_str(), writer.id.as_str()]);
+        assert_eq!(api_keys::principal(&config, &with_key(&read_key)), None);
+
+        // Unconfigured, the entry routes are open and the admin routes say so
+        assert_eq!(new_client().get("/entries?limit=1").status, 200);
+        assert_eq!(admin_client().get("/admin/api-keys").status, 404);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_trailing_slash() {
+        use router::TrailingSlash;
+        let router = |mode| Router::new().trailing_slash(mode).get("/entries", hello).get("/docs/", hello);
+        assert_eq!(router(TrailingSlash::Strict).match_trailing_slash("/entries/"), None);
+        let ignore = router(TrailingSlash::Ignore);
+        assert_eq!(ignore.match_trailing_slash("/entries/"), Some(SlashMatch::Route("/entries".to_string())));
+        assert_eq!(ignore.match_trailing_slash("/docs"), Some(SlashMatch::Route("/docs/".to_string())));
+        let redirect = router(TrailingSlash::Redirect);
+        assert_eq!(redirect.match_trailing_slash("/entries/"), Some(SlashMatch::Redirect("/entries".to_string())));
+        // Registered spellings, unknown paths and the root are left alone
+        for path in ["/entries", "/missing/", "/"] {
+            assert_eq!(redirect.match_trailing_slash(path), None, "{path}");
+        }
+
+        // The server redirects to the registered spelling, keeping the query
+        let client = new_client();
+        let response = client.get("/entries/?limit=2");
+        assert_eq!(response.status, 308);
+        assert_eq!(response.header("Location"), Some("/entries?limit=2"));
+        assert_eq!(client.get("/entries/3/").header("Location"), Some("/entries/3"));
+        assert_eq!(client.get("/missing/").status, 404);
+    }
+
+    #[test]
+    fn test_error_handlers() {
+        fn panics(_request: &Request) -> Response {
+            panic!("handler bug");
+        }
+        fn branded(request: &Request) -> Response {
+            Response::text(StatusCode::InternalServerError, format!("Sorry, {} broke", request.path))
+        }
+
+        // A panic is a 500, from the registered handler when there is one
+        let router = Router::new().get("/boom", panics);
+        let response = dispatch(&router, "GET", &mut Request::new("/boom", &HeaderMap::new(), b""));
+        assert_eq!(response.status, StatusCode::InternalServerError);
+        assert_eq!(response.headers.get("Content-Type"), Some(problem::MEDIA_TYPE));
+        let router = router.on_error(StatusCode::InternalServerError, branded).on_error(StatusCode::MethodNotAllowed, branded);
+        let response = dispatch(&router, "GET", &mut Request::new("/boom", &HeaderMap::new(), b""));
+        assert_eq!(response.body, b"Sorry, /boom broke");
+        // Fallbacks keep the headers the router adds
+        let response = dispatch(&router, "BREW", &mut Request::new("/boom", &HeaderMap::new(), b""));
+        assert_eq!(response.headers.get("Allow"), Some("GET, HEAD, OPTIONS"));
+
+        // The server's own 404 is a page for browsers, a problem document otherwise
+        let client = new_client();
+        let response = client.request("GET", "/no/<such>", &[("Accept", "text/html,*/*;q=0.8")], "");
+        assert_eq!(response.status, 404);
+        assert_eq!(response.header("Content-Type"), Some("text/html; charset=utf-8"));
+        assert!(response.text().contains("<code>/no/&lt;such&gt;</code>"));
+        assert_eq!(client.get("/no/such").header("Content-Type"), Some(problem::MEDIA_TYPE));
+    }
+
+    #[test]
+    fn test_merge_patch_entry() {
+        start_server();
+        thread::sleep(Duration::from_secs(1));
+
+        let patch_request = |uri: &str, content_type: &str, patch: &str| {
+            send_request(&format!(
+                "PATCH {} HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+                uri,
+                content_type,
+                patch.len(),
+                patch
+            ))
+        };
+        let merge = "application/merge-patch+json";
+
+        let response = patch_request("/entries/6", merge, r#"{"trend": "-", "average_rating": 8.5}"#);
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        let entry: serde_json::Value = serde_json::from_str(response.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        assert_eq!(entry["id"], 6);
+        assert_eq!(entry["trend"], "-");
+        assert_eq!(entry["average_rating"], 8.5);
+        assert!(entry["name"].is_string());
+
+        // Removing a required field or changing the id leaves an invalid entry
+        let response = patch_request("/entries/6", merge, r#"{"name": null}"#);
+        assert!(response.starts_with("HTTP/1.1 422 UNPROCESSABLE ENTITY"));
+        let response = patch_request("/entries/6", merge, r#"{"id": 7}"#);
+        assert!(response.starts_with("HTTP/1.1 422 UNPROCESSABLE ENTITY"));
+        let response = patch_request("/entries/6", merge, r#"{"average_rating": 11.0}"#);
+        assert!(response.starts_with("HTTP/1.1 422 UNPROCESSABLE ENTITY"));
+        assert!(response.contains(r#""errors":[{"field":"average_rating","message":"must be between 0.0 and 10.0"}]"#));
+
+        let response = patch_request("/entries/6", "application/json-patch+json", r#"[{"op": "test", "path": "/trend", "value": "-"}]"#);
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        let response = patch_request("/entries/6", "text/plain", "trend=up");
+        assert!(response.starts_with("HTTP/1.1 415 UNSUPPORTED MEDIA TYPE"));
+        assert!(response.contains("Accept-Patch: application/merge-patch+json, application/json-patch+json"));
+        assert!(patch_request("/entries/abc", merge, "{}").starts_with("HTTP/1.1 404 NOT FOUND"));
+        assert!(patch_request("/entries/999999", merge, "{}").starts_with("HTTP/1.1 404 NOT FOUND"));
+    }
+
+    #[test]
+    fn test_search_entries() {
+        let client = new_client();
+        let ids = |query: &str| -> Vec<u64> {
+            let response = client.get(&format!("/entries?{query}"));
+            assert_eq!(response.status, 200, "{query}: {}", response.text());
+            response.json().as_array().unwrap().iter().map(|entry| entry["id"].as_u64().unwrap()).collect()
+        };
+
+        assert_eq!(ids("name_contains=SHANKS&season=1&sort=id&order=desc"), [488, 315, 3]);
+        assert_eq!(ids("name_contains=Shanks&limit=1&offset=1"), [315]);
+        assert_eq!(ids("min_rating=9.6"), [807]);
+        assert_eq!(ids("sort=average_rating&order=desc&limit=1"), [807]);
+        assert!(ids("season=2").is_empty());
+
+        for query in ["season=first", "sort=colour", "order=up", "min_rating=high"] {
+            assert_eq!(client.get(&format!("/entries?{query}")).status, 400, "{query}");
+        }
+    }
+
+    #[test]
+    fn test_pagination_links() {
+        let query = HashMap::from([("limit".to_string(), "10".to_string()), ("name_contains".to_string(), "Red hair".to_string())]);
+        assert_eq!(
+            pagination::links("/entries", &query, 10, 10, 35),
+            "</entries?limit=10&name_contains=Red%20hair&offset=0>; rel=\"first\", \
+             </entries?limit=10&name_contains=Red%20hair&offset=0>; rel=\"prev\", \
+             </entries?limit=10&name_contains=Red%20hair&offset=20>; rel=\"next\", \
+             </entries?limit=10&name_contains=Red%20hair&offset=30>; rel=\"last\""
+        );
+        // The first page has no prev, the last no next, an empty result only itself
+        let first = pagination::links("/entries", &HashMap::new(), 0, 10, 35);
+        assert!(!first.contains("prev") && first.contains("offset=10>; rel=\"next\""));
+        let last = pagination::links("/entries", &HashMap::new(), 30, 10, 35);
+        assert!(last.contains("offset=20>; rel=\"prev\"") && !last.contains("next"));
+        assert_eq!(
+            pagination::links("/entries", &HashMap::new(), 0, 10, 0),
+            "</entries?limit=10&offset=0>; rel=\"first\", </entries?limit=10&offset=0>; rel=\"last\""
+        );
+        // Past the end, prev leads back to the last page
+        assert!(pagination::links("/entries", &HashMap::new(), 90, 10, 35).contains("offset=30>; rel=\"prev\""));
+    }
+
+    #[test]
+    fn test_cursor_pagination() {
+        let client = new_client();
+        // walks every page of `query`, a cursor page being JSON in an envelope
+        let walk = |query: &str| {
+            let (mut names, mut after) = (Vec::new(), String::new());
+            loop {
+                let response = client.get(&format!("/entries?{query}&limit=2&after={after}"));
+                assert_eq!(response.status, 200);
+                let page = response.json();
+                names.extend(page["entries"].as_array().unwrap().iter().map(|entry| (entry["id"].as_u64().unwrap(), entry["name"].clone())));
+                match page["next_cursor"].as_str() {
+                    Some(cursor) => {
+                        assert!(response.header("Link").unwrap().contains(&format!("after={cursor}")));
+                        after = cursor.to_string();
+                    }
+                    None => return names,
+                }
+            }
+        };
+        let by_id = walk("name_contains=Shanks");
+        assert_eq!(by_id.len(), 3);
+        assert!(by_id.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        let by_name = walk("name_contains=Shanks&sort=name&order=desc");
+        assert_eq!(by_name.len(), 3);
+        assert!(by_name.windows(2).all(|pair| pair[0].1.as_str() >= pair[1].1.as_str()));
+
+        // A bare id works as a cursor of the id order
+        let page = client.get(&format!("/entries?name_contains=Shanks&after={}", by_id[0].0)).json();
+        assert_eq!(page["entries"].as_array().unwrap().len(), 2);
+        assert!(page["next_cursor"].is_null());
+
+        let cursor = pagination::Cursor::at_id(7);
+        assert_eq!(pagination::Cursor::decode(&cursor.encode()), Some(cursor.clone()));
+        assert_eq!(pagination::Cursor::decode("zz"), None);
+        for uri in [
+            format!("/entries?after={}&sort=name", cursor.encode()),
+            "/entries?after=zz".to_string(),
+            "/entries?after=3&offset=2".to_string(),
+        ] {
+            assert_eq!(client.get(&uri).status, 400, "{uri}");
+        }
+    }
+
+    #[test]
+    fn test_paginated_headers() {
+        let client = new_client();
+        let response = client.get("/entries?name_contains=Shanks&limit=1&offset=1");
+        assert_eq!(response.header("X-Total-Count"), Some("3"));
+        let link = response.header("Link").unwrap();
+        assert!(link.contains("</entries?limit=1&name_contains=Shanks&offset=0>; rel=\"prev\""));
+        assert!(link.contains("</entries?limit=1&name_contains=Shanks&offset=2>; rel=\"next\""));
+        assert!(link.contains("</entries?limit=1&name_contains=Shanks&offset=2>; rel=\"last\""));
+
+        // An unpaginated response is the whole collection, there is nothing to link to
+        let response = client.get("/entries?name_contains=Shanks");
+        assert!(response.header("X-Total-Count").is_none() && response.header("Link").is_none());
+    }
+
+    #[test]
+    fn test_entry_stats() {
+        start_server();
+        thread::sleep(Duration::from_secs(1));
+
+        // Writers wait for the lock, so both reads see the same data
+        let _lock = store::lock();
+        let body = |uri: &str| -> serde_json::Value {
+            let response = send_request(&format!("GET {uri} HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n"));
+            assert!(response.starts_with("HTTP/1.1 200 OK"));
+            serde_json::from_str(response.split("\r\n\r\n").nth(1).unwrap()).unwrap()
+        };
+        let stats = body("/entries/stats");
+        let entries = body("/entries?limit=0");
+        let entries = entries.as_array().unwrap();
+
+        assert_eq!(stats["count"], entries.len());
+        let votes: u64 = entries
+            .iter()
+            .map(|entry| entry["total_votes"].as_str().unwrap().replace(',', "").parse::<u64>().unwrap())
+            .sum();
+        assert_eq!(stats["total_votes"], votes);
+        let ratings = entries.iter().map(|entry| entry["average_rating"].as_f64().unwrap());
+        assert_eq!(stats["min_rating"].as_f64(), ratings.clone().reduce(f64::min));
+        assert_eq!(stats["max_rating"].as_f64(), ratings.reduce(f64::max));
+        let seasons = stats["seasons"].as_array().unwrap();
+        let season_counts: u64 = seasons.iter().map(|season| season["count"].as_u64().unwrap()).sum();
+        assert_eq!(season_counts, entries.len() as u64);
+        assert!(seasons.windows(2).all(|pair| pair[0]["season"].as_u64() < pair[1]["season"].as_u64()));
+    }
+
+    #[test]
+    fn test_get_entries_invalid_page() {
+        // Start the server
+        start_server();
+        thread::sleep(Duration::from_secs(1));
+
+        let request = "GET /entries?limit=many HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n";
+        let response = send_request(request);
+        assert!(response.starts_with("HTTP/1.1 400 BAD REQUEST"));
+    }
+
+    #[test]
+    fn test_check_unpaginated_size() {
+        // Within both limits
+        assert!(endpoints::check_unpaginated_size(10, 100, 10, 100).is_ok());
+
+        // Too many rows or too many bytes are refused with guidance
+        let rows = endpoints::check_unpaginated_size(11, 100, 10, 100).unwrap_err();
+        assert!(rows.starts_with("Too many entries"));
+        assert!(rows.contains("?limit="));
+        let bytes = endpoints::check_unpaginated_size(10, 101, 10, 100).unwrap_err();
+        assert!(bytes.contains("?limit="));
+    }
 
-    let length = response_body.len();
-    let mut response = format!("{status_line}\r\nContent-Length: {length}\r\n");
+    #[test]
+    fn test_split_uri() {
+        let (path, query) = split_uri("/entries?limit=5&name=Monkey+D.%20Luffy&flag");
+        assert_eq!(path, "/entries");
+        assert_eq!(query.get("limit").unwrap(), "5");
+        assert_eq!(query.get("name").unwrap(), "Monkey D. Luffy");
+        assert_eq!(query.get("flag").unwrap(), "");
 
-    for cookie in set_cookie_headers {
-        response.push_str(&format!("Set-Cookie: {}\r\n", cookie));
+        let (path, query) = split_uri("/hello");
+        assert_eq!(path, "/hello");
+        assert!(query.is_empty());
     }
 
-    response.push_str(&format!("\r\n{response_body}"));
-    stream.write_all(response.as_bytes()).unwrap();
-}
+    #[test]
+    fn test_canonical_json() {
+        #[derive(serde::Serialize)]
+        struct Entry {
+            name: &'static str,
+            average_rating: f32,
+            id: u32,
+            tags: Vec<f64>,
+        }
 
-const SERVER_RESPONSE_OK: &str = "HTTP/1.1 200 OK";
-const SERVER_RESPONSE_ERROR: &str = "HTTP/1.1 404 NOT FOUND";
+        let entry = Entry {
+            name: "Luffy",
+            average_rating: 7.7,
+            id: 1,
+            tags: vec![8.0, 0.125],
+        };
 
-fn handle_get(uri: &str) -> (&str, String) {
-    match uri {
-        "/" => (SERVER_RESPONSE_OK, "Welcome to the homepage!".to_string()),
-        "/hello" => (SERVER_RESPONSE_OK, "Hello, world!".to_string()),
-        "/data" => (SERVER_RESPONSE_OK, "Here is your data.".to_string()),
-        "/entries" => (SERVER_RESPONSE_OK, endpoints::get_entries(0).to_string()),
-        _ => ("HTTP/1.1 404 NOT FOUND", "404 - Not Found".to_string()),
-    }
-}
+        // Keys are sorted, whitespace dropped and floats printed without widening noise
+        let canonical = json::to_canonical_string(&entry).unwrap();
+        assert_eq!(
+            canonical,
+            r#"{"average_rating":7.7,"id":1,"name":"Luffy","tags":[8.0,0.125]}"#
+        );
 
-fn handle_post<'a>(uri: &'a str, body: &'a str) -> (&'a str, String) {
-    match uri {
-        "/submit" => (SERVER_RESPONSE_OK, endpoints::post_entry(body).to_string()),
-        _ => (SERVER_RESPONSE_ERROR, "404 - Not Found".to_string()),
+        // The same data always serializes to the same bytes
+        let value: serde_json::Value = serde_json::from_str(&canonical).unwrap();
+        assert_eq!(json::to_canonical_string(&value).unwrap(), canonical);
     }
-}
 
-fn handle_put<'a>(uri: &'a str, body: &'a str) -> (&'a str, String) {
-    match uri {
-        "/put_entry" => (SERVER_RESPONSE_OK, endpoints::put_entry(body).to_string()),
-        _ => (SERVER_RESPONSE_ERROR, "404 - Not Found".to_string()),
-    }
-}
+    #[test]
+    fn test_head() {
+        // Start the server
+        start_server();
+        thread::sleep(Duration::from_secs(1));
 
-fn handle_patch<'a>(uri: &'a str, body: &'a str) -> (&'a str, String) {
-    match uri {
-        "/patch_entry_name" => (
-            SERVER_RESPONSE_OK,
-            endpoints::patch_entry_name(body).to_string(),
-        ),
-        _ => (SERVER_RESPONSE_ERROR, "404 - Not Found".to_string()),
-    }
-}
+        // HEAD reports the GET headers but sends no body
+        let request = "HEAD /hello HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n";
+        let response = send_request(request);
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("Content-Length: 13\r\n"));
+        assert!(response.ends_with("\r\n\r\n"));
 
-fn handle_delete<'a>(uri: &'a str, body: &'a str) -> (&'a str, String) {
-    match uri {
-        "/delete_entry" => (
-            SERVER_RESPONSE_OK,
-            endpoints::delete_entry(body).to_string(),
-        ),
-        _ => (SERVER_RESPONSE_ERROR, "404 - Not Found".to_string()),
+        // Unknown paths keep their status
+        let request = "HEAD /missing HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n";
+        let response = send_request(request);
+        assert!(response.starts_with("HTTP/1.1 404 NOT FOUND"));
+        assert!(response.ends_with("\r\n\r\n"));
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::collections::HashMap;
-    use std::io::{BufReader, Cursor};
-    use std::sync::mpsc;
-
-    fn send_request(request: &str) -> String {
-        // Establish a connection to the server
-        let mut stream = TcpStream::connect("127.0.0.1:7878").expect("Could not connect to server");
+    #[test]
+    fn test_problem_details() {
+        let client = new_client();
+        let response = client.get("/missing");
+        assert_eq!(response.status, 404);
+        assert_eq!(response.header("Content-Type"), Some("application/problem+json"));
+        assert_eq!(
+            response.json(),
+            json!({
+                "type": "about:blank",
+                "title": "Not Found",
+                "status": 404,
+                "detail": "No resource at this path",
+                "instance": "/missing"
+            })
+        );
 
-        // Send the request
-        stream.write_all(request.as_bytes()).unwrap();
+        // Errors from the endpoints and the parser use the same envelope
+        let response = client.request("DELETE", "/delete_entry", &[], "{}");
+        assert_eq!(response.status, 400);
+        assert_eq!(response.json()["detail"], "Expected {\"id\"}");
+        assert_eq!(response.json()["instance"], "/delete_entry");
 
-        // Read the response
-        let mut response = String::new();
-        stream.read_to_string(&mut response).unwrap();
-        response
+        let response = client.send(b"GET / HTTP/1.1\r\nHost: 127.0.0.1\r\nBad Header: x\r\n\r\n");
+        assert_eq!(response.json()["status"], 400);
+        assert_eq!(response.json()["title"], "Bad Request");
     }
 
-    fn start_server() {
-        // Check if the server is already running
-        if TcpStream::connect("127.0.0.1:7878").is_ok() {
-            return;
-        }
-        // Start the server in a separate thread
-        thread::spawn(|| {
-            let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
-            let pool = ThreadPool::new(5);
-            for stream in listener.incoming() {
-                let stream = stream.unwrap();
+    #[test]
+    fn test_options() {
+        // Start the server
+        start_server();
+        thread::sleep(Duration::from_secs(1));
 
-                pool.execute(|| {
-                    handle_connection(stream);
-                });
-            }
-        });
+        // A registered path lists exactly its methods
+        let request = "OPTIONS /entries HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n";
+        let response = send_request(request);
+        assert!(response.starts_with("HTTP/1.1 204 NO CONTENT"));
+        assert!(response.contains("Allow: GET, HEAD, OPTIONS\r\n"));
+        assert!(!response.contains("Content-Length"));
+
+        let request = "OPTIONS /submit HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n";
+        let response = send_request(request);
+        assert!(response.contains("Allow: POST, OPTIONS\r\n"));
+
+        // Unknown paths are still 404
+        let request = "OPTIONS /missing HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n";
+        let response = send_request(request);
+        assert!(response.starts_with("HTTP/1.1 404 NOT FOUND"));
     }
 
-    // HTTP Operations Unit Tests
     #[test]
-    fn test_get_entries() {
+    fn test_unsupported_method() {
         // Start the server
         start_server();
         thread::sleep(Duration::from_secs(1));
 
-        // Send a GET request
-        let request = "GET /entries HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n";
+        let request = "TRACE /hello HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n";
         let response = send_request(request);
-        let expected_json = r#"{"id":3,"rank":"28,818","trend":"8","season":1,"episode":4,"name":"Luffy's Past! The Red-haired Shanks Appears!","start":1999,"total_votes":"449","average_rating":8.1}"#;
+        assert!(response.starts_with("HTTP/1.1 405 METHOD NOT ALLOWED"));
+        assert!(response.contains("Allow: GET, HEAD, OPTIONS\r\n"));
 
-        // Check the response
-        assert!(response.contains(expected_json));
+        // A known method on a path that exists only for others is a 405 too
+        let client = new_client();
+        let response = client.request("POST", "/hello", &[], "");
+        assert_eq!(response.status, 405);
+        assert_eq!(response.header("Allow"), Some("GET, HEAD, OPTIONS"));
+        assert_eq!(response.json()["detail"], "POST is not allowed on /hello");
+        let response = client.request("DELETE", "/entries", &[], "");
+        assert_eq!(response.status, 405);
+        assert_eq!(response.header("Allow"), Some("GET, HEAD, OPTIONS"));
+        // Paths no route matches stay 404 whatever the method
+        assert_eq!(client.request("POST", "/missing", &[], "").status, 404);
+    }
+
+    #[test]
+    fn test_lowercase_content_length() {
+        // Start the server
+        start_server();
+        thread::sleep(Duration::from_secs(1));
+
+        // Header names are matched case-insensitively when reading the body
+        let patch_request = r#"{"id": 2, "name": "Morgan vs. Luffy! Who's This Beautiful Young Girl?"}"#;
+        let request = format!(
+            "PATCH /patch_entry_name HTTP/1.1\r\nHost: 127.0.0.1\r\ncontent-length: {}\r\n\r\n{}",
+            patch_request.len(),
+            patch_request
+        );
+        let response = send_request(&request);
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("Success"));
     }
 
     #[test]
@@ -369,7 +3620,7 @@ mod tests {
 
         // Create a POST request
         let request = format!(
-            "POST /submit HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            "POST /submit HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Length: {}\r\n\r\n{}",
             new_character.len(),
             new_character
         );
@@ -377,7 +3628,458 @@ mod tests {
         // Send the request
         let response = send_request(&request);
         println!("Response:({})", response);
-        assert!(response.contains("Success!"));
+        assert!(response.starts_with("HTTP/1.1 201 CREATED"));
+        let created: serde_json::Value = serde_json::from_str(response.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        let id = created["id"].as_u64().unwrap();
+        assert!(id > 0);
+        assert_eq!(created["name"], "Morgan vs. Luffy! Who's This Beautiful Young Girl?");
+        assert!(response.contains(&format!("Location: /entries/{id}\r\n")));
+
+        // The Location names the new entry
+        let response = send_request(&format!("GET /entries/{id} HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n"));
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("ETag: \""));
+        let fetched: serde_json::Value = serde_json::from_str(response.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        assert_eq!(fetched, created);
+        let response = send_request("GET /entries/999999 HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 NOT FOUND"));
+    }
+
+    #[test]
+    fn test_validate_entry() {
+        use serde_json::json;
+        let entry = |changes: serde_json::Value| {
+            let mut entry = json!({
+                "id": 0, "rank": "32,043", "trend": "-", "season": 1, "episode": 3, "name": "Morgan vs. Luffy!",
+                "start": 1999, "total_votes": "1,428", "average_rating": 7.7
+            });
+            json_patch::merge(&mut entry, &changes);
+            serde_json::from_value::<endpoints::Character>(entry).unwrap().validate()
+        };
+        let fields = |changes| entry(changes).unwrap_err().into_iter().map(|e| e.field).collect::<Vec<_>>();
+
+        assert!(entry(json!({})).is_ok());
+        assert!(entry(json!({"trend": "-12", "average_rating": 0.0})).is_ok());
+        assert_eq!(fields(json!({"name": "  "})), ["name"]);
+        assert_eq!(fields(json!({"season": 0, "episode": 0})), ["season", "episode"]);
+        assert_eq!(fields(json!({"average_rating": 10.5})), ["average_rating"]);
+        assert_eq!(fields(json!({"rank": "32043,1", "total_votes": "1,42"})), ["rank", "total_votes"]);
+        assert_eq!(fields(json!({"trend": "up", "start": 1950})), ["trend", "start"]);
+    }
+
+    #[test]
+    fn test_post_invalid_entry() {
+        use serde_json::json;
+        start_server();
+        thread::sleep(Duration::from_secs(1));
+
+        let invalid = r#"{"id": 0, "rank": "1", "trend": "-", "season": 0, "episode": 1, "name": "",
+            "start": 1999, "total_votes": "1", "average_rating": 12.5}"#;
+        let response = send_request(&format!("POST /submit HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Length: {}\r\n\r\n{}", invalid.len(), invalid));
+        assert!(response.starts_with("HTTP/1.1 422 UNPROCESSABLE ENTITY"));
+        assert!(response.contains("Content-Type: application/problem+json"));
+        let problem: serde_json::Value = serde_json::from_str(response.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        assert_eq!(problem["instance"], "/submit");
+        assert_eq!(
+            problem["errors"],
+            json!([
+                {"field": "season", "message": "must be greater than 0"},
+                {"field": "name", "message": "must not be empty"},
+                {"field": "average_rating", "message": "must be between 0.0 and 10.0"}
+            ])
+        );
+    }
+
+    #[test]
+    fn test_bulk_entries() {
+        start_server();
+        thread::sleep(Duration::from_secs(1));
+
+        let send = |method: &str, body: &str| {
+            send_request(&format!(
+                "{method} /entries/bulk HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            ))
+        };
+        let entry = |name: &str, season: u32| {
+            format!(
+                r#"{{"id": 0, "rank": "1,000", "trend": "-", "season": {season}, "episode": 1, "name": "{name}",
+                    "start": 2020, "total_votes": "10", "average_rating": 8.0}}"#
+            )
+        };
+
+        // One invalid entry rejects the whole batch
+        let response = send("POST", &format!("[{}, {}]", entry("Bulk A", 1), entry("Bulk B", 0)));
+        assert!(response.starts_with("HTTP/1.1 422 UNPROCESSABLE ENTITY"));
+        assert!(response.contains(r#"{"field":"[1].season","message":"must be greater than 0"}"#));
+
+        let response = send("POST", &format!("[{}, {}]", entry("Bulk A", 1), entry("Bulk B", 2)));
+        assert!(response.starts_with("HTTP/1.1 201 CREATED"));
+        let created: serde_json::Value = serde_json::from_str(response.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        let ids: Vec<u64> = created.as_array().unwrap().iter().map(|e| e["id"].as_u64().unwrap()).collect();
+        assert_eq!(ids[1], ids[0] + 1);
+        assert_eq!(created[1]["name"], "Bulk B");
+
+        // Deleting is all or nothing too
+        let response = send("DELETE", &format!("[{}, 999999]", ids[0]));
+        assert!(response.starts_with("HTTP/1.1 404 NOT FOUND"));
+        assert!(response.contains("Characters not found: 999999"));
+        let response = send("DELETE", &format!("[{}, {}]", ids[0], ids[1]));
+        assert!(response.starts_with("HTTP/1.1 204 NO CONTENT"));
+
+        assert!(send("POST", "[]").starts_with("HTTP/1.1 400 BAD REQUEST"));
+        assert!(send("DELETE", r#"{"id": 1}"#).starts_with("HTTP/1.1 400 BAD REQUEST"));
+    }
+
+    #[test]
+    fn test_parse_csv() {
+        let csv = "\u{feff}id,name\r\n1,\"Luffy, \"\"Straw Hat\"\"\"\r\n\n2,\"Two\nlines\"\n";
+        let records = formats::parse_csv(csv).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["id"], "1");
+        assert_eq!(records[0]["name"], "Luffy, \"Straw Hat\"");
+        assert_eq!(records[1]["name"], "Two\nlines");
+
+        assert!(formats::parse_csv("id,name\n1\n").unwrap_err().contains("Row 1"));
+        assert!(formats::parse_csv("id,name\n1,\"open\n").is_err());
+        assert!(formats::parse_csv("").is_err());
+    }
+
+    #[test]
+    fn test_csv_import_export() {
+        start_server();
+        thread::sleep(Duration::from_secs(1));
+
+        let response = send_request("GET /entries/export.csv HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("Content-Type: text/csv; charset=utf-8; header=present\r\n"));
+        assert!(response.contains("Content-Disposition: attachment; filename=\"entries.csv\"\r\n"));
+        assert!(response.contains("\r\n\r\nid,rank,trend,season,episode,name,start,total_votes,average_rating\r\n"));
+        assert!(response.contains("\r\n3,\"28,818\",8,1,4,Luffy's Past! The Red-haired Shanks Appears!,1999,"));
+
+        let import = |csv: &str| {
+            send_request(&format!(
+                "POST /entries/import HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Type: text/csv\r\nContent-Length: {}\r\n\r\n{}",
+                csv.len(),
+                csv
+            ))
+        };
+        let header = "rank,trend,season,episode,name,start,total_votes,average_rating\r\n";
+
+        // Invalid rows reject the whole import
+        let response = import(&format!("{header}\"1,000\",-,1,1,Imported,2020,10,8.0\r\n\"1,000\",-,zero,1,Imported,2020,10,8.0\r\n"));
+        assert!(response.starts_with("HTTP/1.1 422 UNPROCESSABLE ENTITY"));
+        assert!(response.contains(r#"{"field":"[1].season","message":"must be a number"}"#));
+
+        let response = import(&format!("{header}\"1,000\",-,1,1,\"Imported, first\",2020,10,8.0\r\n"));
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with(r#"{"created":1,"updated":0}"#));
+
+        // A row naming an existing id replaces that entry
+        let found = send_request("GET /entries?name_contains=imported,%20first HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n");
+        let found: serde_json::Value = serde_json::from_str(found.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        let id = found[0]["id"].as_u64().unwrap();
+        let response = import(&format!("id,{header}{id},\"1,000\",-,1,1,Imported again,2020,10,8.0\r\n"));
+        assert!(response.ends_with(r#"{"created":0,"updated":1}"#));
+
+        let body = format!("[{id}]");
+        let request = format!("DELETE /entries/bulk HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Length: {}\r\n\r\n{body}", body.len());
+        assert!(send_request(&request).starts_with("HTTP/1.1 204 NO CONTENT"));
+        assert!(import("rank,name\r\n").starts_with("HTTP/1.1 200 OK"));
+        let request = "POST /entries/import HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Type: application/json\r\nContent-Length: 2\r\n\r\n[]";
+        assert!(send_request(request).starts_with("HTTP/1.1 415 UNSUPPORTED MEDIA TYPE"));
+    }
+
+    #[test]
+    fn test_ids_not_reused() {
+        start_server();
+        thread::sleep(Duration::from_secs(1));
+
+        let entry = r#"{"id": 0, "rank": "1", "trend": "-", "season": 1, "episode": 1, "name": "Short-lived",
+            "start": 2020, "total_votes": "1", "average_rating": 5.0}"#;
+        let post = || {
+            let response = send_request(&format!("POST /submit HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Length: {}\r\n\r\n{}", entry.len(), entry));
+            let created: serde_json::Value = serde_json::from_str(response.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+            created["id"].as_u64().unwrap()
+        };
+        let delete = |id: u64| {
+            let body = format!("[{id}]");
+            send_request(&format!("DELETE /entries/bulk HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Length: {}\r\n\r\n{body}", body.len()))
+        };
+
+        // The counter strategy skips the id of a deleted last entry
+        let first = post();
+        assert!(delete(first).starts_with("HTTP/1.1 204 NO CONTENT"));
+        let second = post();
+        assert!(second > first);
+        delete(second);
+
+        let _lock = store::lock();
+        let characters = store::load();
+        let next = store::next_id(&characters).unwrap();
+        assert!(characters.iter().all(|c| c.id() < next));
+        assert_eq!(store::next_id(&characters).unwrap(), next + 1);
+    }
+
+    #[test]
+    fn test_id_index() {
+        let _lock = store::lock();
+        let (characters, index) = store::load_indexed();
+        for (id, &position) in index.iter() {
+            assert_eq!(characters[position].id(), *id);
+        }
+        let ids: std::collections::HashSet<usize> = characters.iter().map(Character::id).collect();
+        assert_eq!(index.len(), ids.len());
+        let third = characters.iter().find(|c| c.id() == 3).cloned();
+        assert_eq!(store::get(3), third);
+        assert_eq!(store::get(usize::MAX), None);
+    }
+
+    #[test]
+    fn test_trash() {
+        let client = new_client();
+        let entry = r#"{"id": 0, "rank": "1", "trend": "-", "season": 1, "episode": 1, "name": "Trashed once",
+            "start": 2020, "total_votes": "1", "average_rating": 5.0}"#;
+        let created = client.request("POST", "/submit", &[("Content-Type", "application/json")], entry);
+        let id = created.json()["id"].as_u64().unwrap();
+        let path = format!("/entries/{id}");
+
+        // Trashed entries disappear from reads unless asked for
+        assert_eq!(client.request("DELETE", &path, &[], "").status, 204);
+        assert_eq!(client.request("DELETE", &path, &[], "").status, 404);
+        assert_eq!(client.get(&path).status, 404);
+        assert_eq!(client.get("/entries?name_contains=trashed%20once").json(), json!([]));
+        let listed = client.get("/entries?name_contains=trashed%20once&include_deleted=true").json();
+        assert_eq!(listed[0]["id"], id);
+        assert!(listed[0]["deleted_at"].as_str().unwrap().ends_with('Z'));
+        assert!(!client.get("/entries/export.csv").text().contains("Trashed once"));
+        let patch = [("Content-Type", json_patch::MERGE_MEDIA_TYPE)];
+        assert_eq!(client.request("PATCH", &path, &patch, r#"{"name": "x"}"#).status, 404);
+        assert_eq!(client.get("/entries?include_deleted=maybe").status, 400);
+
+        // Restoring brings it back as it was
+        let restored = client.request("POST", &format!("{path}/restore"), &[], "");
+        assert_eq!(restored.status, 200);
+        assert_eq!(restored.json()["name"], "Trashed once");
+        assert!(restored.json().get("deleted_at").is_none());
+        assert_eq!(client.get(&path).status, 200);
+        assert_eq!(client.request("POST", &format!("{path}/restore"), &[], "").status, 409);
+        assert_eq!(client.request("POST", "/entries/999999999/restore", &[], "").status, 404);
+        let trashed = client.request("PATCH", &path, &patch, r#"{"deleted_at": "2024-01-01T00:00:00Z"}"#);
+        assert_eq!(trashed.status, 422);
+
+        // Purging only removes what was trashed before the cutoff
+        client.request("DELETE", &path, &[], "");
+        assert_eq!(store::purge_deleted(Utc::now() - chrono::Duration::hours(1)), 0);
+        assert!(store::purge_deleted(Utc::now() + chrono::Duration::seconds(5)) >= 1);
+        assert_eq!(client.get("/entries?name_contains=trashed%20once&include_deleted=true").json(), json!([]));
+    }
+
+    #[test]
+    fn test_audit_log() {
+        let client = new_client();
+        let entry = r#"{"id": 0, "rank": "1", "trend": "-", "season": 1, "episode": 1, "name": "Audited",
+            "start": 2020, "total_votes": "1", "average_rating": 5.0}"#;
+        let created = client.request("POST", "/submit", &[("Content-Type", "application/json")], entry);
+        let id = created.json()["id"].as_u64().unwrap();
+        let patch = [("Content-Type", json_patch::MERGE_MEDIA_TYPE)];
+        client.request("PATCH", &format!("/entries/{id}"), &patch, r#"{"name": "Audited again"}"#);
+
+        // Newest first, each with the entry before and after
+        let admin = admin_client();
+        let log = admin.get(&format!("/admin/audit?id={id}")).json();
+        assert_eq!(log.as_array().unwrap().len(), 2, "{log}");
+        assert_eq!(log[0]["operation"], format!("PATCH /entries/{id}"));
+        assert_eq!(log[0]["changes"][0]["before"]["name"], "Audited");
+        assert_eq!(log[0]["changes"][0]["after"]["name"], "Audited again");
+        assert_eq!(log[1]["operation"], "POST /submit");
+        assert_eq!(log[1]["changes"], json!([{ "id": id, "before": null, "after": created.json() }]));
+        assert!(log[1]["request_id"].is_string());
+        assert_eq!(admin.get(&format!("/admin/audit?id={id}&limit=1")).json()[0]["operation"], log[0]["operation"]);
+        let later = admin.get(&format!("/admin/audit?id={id}&since=2999-01-01T00:00:00Z")).json();
+        assert_eq!(later, json!([]));
+        for query in ["since=yesterday", "limit=0", "id=x"] {
+            assert_eq!(admin.get(&format!("/admin/audit?{query}")).status, 400, "{query}");
+        }
+
+        // The client is the first hop a trusted proxy forwarded for
+        let mut headers = HeaderMap::new();
+        headers.append("X-Forwarded-For", "203.0.113.9, 10.0.0.8");
+        let proxied = audit::Actor::client(String::new(), None, &headers, Some("10.0.0.7:5000".parse().unwrap()), &config::Config {
+            trusted_proxies: vec![ip_filter::Cidr::parse("10.0.0.0/8").unwrap()],
+            ..config::Config::default()
+        });
+        assert_eq!(proxied.ip, Some("203.0.113.9".parse().unwrap()));
+        let direct = audit::Actor::client(String::new(), None, &headers, Some("192.0.2.1:5000".parse().unwrap()), config::get());
+        assert_eq!(direct.ip, Some("192.0.2.1".parse().unwrap()));
+    }
+
+    #[cfg(feature = "graphql")]
+    #[test]
+    fn test_graphql() {
+        let client = new_client();
+        let graphql = |query: &str, variables: serde_json::Value| {
+            let body = json!({ "query": query, "variables": variables }).to_string();
+            client.request("POST", "/graphql", &[("Content-Type", "application/json")], &body)
+        };
+
+        let create = "mutation Create($input: CharacterInput!) { created: createCharacter(input: $input) { id name __typename } }";
+        let input = json!({ "rank": "1", "trend": "-", "season": 1, "episode": 1, "name": "Through GraphQL",
+            "start": 2020, "totalVotes": "1,000", "averageRating": 4.5 });
+        let created = graphql(create, json!({ "input": input }));
+        assert_eq!(created.status, 200);
+        let id = created.json()["data"]["created"]["id"].as_u64().unwrap();
+        // Fields come back in the order they were selected
+        assert_eq!(created.text(), format!(r#"{{"data":{{"created":{{"id":{id},"name":"Through GraphQL","__typename":"Character"}}}}}}"#));
+
+        let update = format!(r#"mutation {{ updateCharacter(id: {id}, input: {{ averageRating: 4.7 }}) {{ averageRating totalVotes }} }}"#);
+        let updated = graphql(&update, json!({}));
+        assert_eq!(updated.json()["data"]["updateCharacter"], json!({ "averageRating": 4.7, "totalVotes": "1,000" }));
+        assert_eq!(client.get(&format!("/entries/{id}")).json()["average_rating"], 4.7);
+
+        let query = "query($min: Float = 4.6) { characters(season: 1, minRating: $min) { id averageRating } }";
+        let found = graphql(query, json!({})).json();
+        assert!(found["data"]["characters"].as_array().unwrap().iter().any(|c| c["id"] == id));
+        let found = graphql(query, json!({ "min": 4.8 })).json();
+        let characters = found["data"]["characters"].as_array().unwrap();
+        assert!(!characters.is_empty() && characters.iter().all(|c| c["id"] != id && c["averageRating"].as_f64().unwrap() >= 4.8));
+        assert_eq!(graphql("{ characters(limit: 2) { id } }", json!({})).json()["data"]["characters"].as_array().unwrap().len(), 2);
+
+        // Failing fields are null, with the error and its status next to the data
+        let invalid = graphql(&format!("mutation {{ updateCharacter(id: {id}, input: {{ season: 0 }}) {{ id }} }}"), json!({})).json();
+        assert_eq!(invalid["data"]["updateCharacter"], json!(null));
+        assert_eq!(invalid["errors"][0]["path"], json!(["updateCharacter"]));
+        assert_eq!(invalid["errors"][0]["extensions"]["status"], 422);
+        assert_eq!(invalid["errors"][0]["extensions"]["errors"][0]["field"], "season");
+
+        let delete = format!("mutation {{ deleteCharacter(id: {id}) }}");
+        assert_eq!(graphql(&delete, json!({})).json()["data"]["deleteCharacter"], true);
+        assert_eq!(client.get(&format!("/entries/{id}")).status, 404);
+        assert_eq!(graphql(&format!("{{ character(id: {id}) {{ name }} }}"), json!({})).json()["data"]["character"], json!(null));
+        let again = graphql(&delete, json!({})).json();
+        assert_eq!(again["errors"][0]["extensions"]["status"], 404);
+
+        // Documents that don't parse or fit the schema aren't run
+        for document in [
+            "{ characters { id",
+            "{ characters { powerLevel } }",
+            "{ characters }",
+            "{ character { id } }",
+            "{ characters(limit: $limit) { id } }",
+            "mutation { createCharacter(input: {}) }",
+            "fragment F on Character { id }",
+            "{ characters { ...F } }",
+        ] {
+            let response = graphql(document, json!({}));
+            assert_eq!(response.status, 400, "{document}");
+            assert!(response.json().get("data").is_none(), "{document}");
+        }
+        let unclosed = graphql("{\n  characters(limit: 1 { id } }", json!({})).json();
+        assert_eq!(unclosed["errors"][0]["locations"], json!([{ "line": 2, "column": 23 }]));
+        let nested = format!("{{ characters(limit: {}1{}) {{ id }} }}", "[".repeat(40), "]".repeat(40));
+        assert_eq!(graphql(&nested, json!({})).status, 400);
+    }
+
+    #[test]
+    fn test_api_versions() {
+        let client = new_client();
+        // v1 serves the unversioned routes as they are
+        assert_eq!(client.get("/api/v1/entries/1").text(), client.get("/entries/1").text());
+        assert_eq!(client.get("/api/v1/entries?limit=2").json(), client.get("/entries?limit=2").json());
+        assert_eq!(client.get("/api/v1/entries/1").header("Deprecation"), None);
+
+        // v2 has resources instead of actions
+        let entry = r#"{"id": 0, "rank": "1", "trend": "-", "season": 1, "episode": 1, "name": "Version two",
+            "start": 2020, "total_votes": "1", "average_rating": 5.0}"#;
+        let json = [("Content-Type", "application/json")];
+        assert_eq!(client.request("POST", "/api/v2/submit", &json, entry).status, 404);
+        assert_eq!(client.request("POST", "/api/v1/entries", &json, entry).status, 405);
+        let created = client.request("POST", "/api/v2/entries", &json, entry);
+        assert_eq!(created.status, 201);
+        let id = created.json()["id"].as_u64().unwrap();
+        assert_eq!(created.header("Location"), Some(format!("/api/v2/entries/{id}").as_str()));
+        let replaced = client.request("PUT", &format!("/api/v2/entries/{id}"), &json, &entry.replace("Version two", "Replaced"));
+        assert_eq!(replaced.status, 200);
+        assert_eq!(replaced.json()["id"], id);
+        assert_eq!(client.get(&format!("/api/v2/entries/{id}")).json()["name"], "Replaced");
+        assert_eq!(client.request("PUT", "/api/v2/entries/999999999", &json, entry).status, 404);
+        assert_eq!(client.request("PUT", &format!("/api/v2/entries/{id}"), &json, r#"{"name": 1}"#).status, 422);
+        assert_eq!(client.request("DELETE", &format!("/api/v2/entries/{id}"), &[], "").status, 204);
+
+        // Deprecation and Sunset as configured for the version
+        let lifecycle: api_version::Lifecycle = serde_json::from_value(json!({
+            "deprecation": "2025-01-01T00:00:00Z",
+            "sunset": "2026-01-01T00:00:00+01:00",
+            "successor": "/api/v2/entries",
+        }))
+        .unwrap();
+        let response = api_version::announce(&lifecycle, Response::new(StatusCode::Ok));
+        assert_eq!(response.headers.get("Deprecation"), Some("@1735689600"));
+        assert_eq!(response.headers.get("Sunset"), Some("Wed, 31 Dec 2025 23:00:00 GMT"));
+        assert_eq!(response.headers.get("Link"), Some(r#"</api/v2/entries>; rel="successor-version""#));
+        assert!(serde_json::from_value::<api_version::Lifecycle>(json!({ "sunset": "next year" })).is_err());
+    }
+
+    #[test]
+    fn test_hypermedia_links() {
+        let client = new_client();
+        let link = |href: &str, method: &str| json!({ "href": href, "method": method });
+        let entry = client.get("/api/v2/entries/1").json();
+        assert_eq!(entry["id"], 1);
+        assert_eq!(
+            entry["_links"],
+            json!({
+                "self": link("/api/v2/entries/1", "GET"),
+                "collection": link("/api/v2/entries", "GET"),
+                "update": link("/api/v2/entries/1", "PUT"),
+                "delete": link("/api/v2/entries/1", "DELETE"),
+            })
+        );
+        // The frozen routes answer as they always have
+        assert!(client.get("/entries/1").json().get("_links").is_none());
+        assert!(client.get("/api/v1/entries/1").json().get("_links").is_none());
+        // Neither collections nor errors are entries
+        assert!(client.get("/api/v2/entries?limit=1").json().is_array());
+        assert!(client.get("/api/v2/entries/999999999").json().get("_links").is_none());
+
+        // An entry just created links to where it was stored
+        let body = r#"{"id": 0, "rank": "1", "trend": "-", "season": 1, "episode": 1, "name": "Linked",
+            "start": 2020, "total_votes": "1", "average_rating": 5.0}"#;
+        let created = client.request("POST", "/api/v2/entries", &[("Content-Type", "application/json")], body);
+        let location = created.header("Location").unwrap().to_string();
+        assert_eq!(created.json()["_links"]["self"], link(&location, "GET"));
+        assert_eq!(client.request("DELETE", &location, &[], "").status, 204);
+
+        // PATCH stands in for a missing PUT, links without a route are left out
+        let router = Router::new().get("/things/{id}", hello).patch("/things/{id}", hello);
+        let links = router.links("/things/7");
+        let rels: Vec<&str> = links.iter().map(|(rel, _)| *rel).collect();
+        assert_eq!(rels, ["self", "update"]);
+        assert_eq!(links[1].1.method, "PATCH");
+    }
+
+    #[test]
+    fn test_success_envelope() {
+        let body = |response: &Response| serde_json::from_slice::<serde_json::Value>(&response.body).unwrap();
+        let wrapped = envelope::wrap(Response::text(StatusCode::Ok, "Success!"));
+        assert_eq!(wrapped.headers.get("Content-Type"), Some("application/json"));
+        assert_eq!(body(&wrapped), json!({ "status": "ok", "data": "Success!" }));
+        let wrapped = envelope::wrap(Response::json(StatusCode::Created, r#"{"id": 3}"#).header("Location", "/entries/3"));
+        assert_eq!((wrapped.status, wrapped.headers.get("Location")), (StatusCode::Created, Some("/entries/3")));
+        assert_eq!(body(&wrapped), json!({ "status": "ok", "data": { "id": 3 } }));
+
+        // Errors, other formats and empty answers are left as they are
+        let csv = Response::text(StatusCode::Ok, "id\n1\n").content_type("text/csv");
+        for response in [Response::problem(StatusCode::NotFound, "Character not found"), csv, Response::new(StatusCode::NoContent)] {
+            let before = (response.status, response.body.clone());
+            let after = envelope::wrap(response);
+            assert_eq!((after.status, after.body), before);
+        }
+
+        // Off by default, the legacy bodies stay
+        let response = new_client().get("/api/v2/entries/1");
+        assert!(response.json().get("status").is_none());
     }
 
     #[test]
@@ -401,7 +4103,7 @@ mod tests {
 
         // Create a PUT request
         let request = format!(
-            "PUT /put_entry HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            "PUT /put_entry HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Length: {}\r\n\r\n{}",
             updated_character.len(),
             updated_character
         );
@@ -421,7 +4123,7 @@ mod tests {
         // Create a DELETE request
         let delete_request = r#"{"id": 5}"#;
         let request = format!(
-            "DELETE /delete_entry HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            "DELETE /delete_entry HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Length: {}\r\n\r\n{}",
             delete_request.len(),
             delete_request
         );
@@ -429,7 +4131,7 @@ mod tests {
         // Send the request
         let response = send_request(&request);
         println!("Response:({})", response);
-        assert!(response.contains("Success!"));
+        assert!(response.starts_with("HTTP/1.1 204 NO CONTENT"));
     }
 
     #[test]
@@ -446,7 +4148,7 @@ mod tests {
 
         // Create a PATCH request
         let request = format!(
-            "PATCH /patch_entry_name HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            "PATCH /patch_entry_name HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Length: {}\r\n\r\n{}",
             patch_request.len(),
             patch_request
         );
@@ -457,6 +4159,305 @@ mod tests {
         assert!(response.contains("Success"));
     }
 
+    #[test]
+    fn test_events_stream() {
+        // Start the server
+        start_server();
+        thread::sleep(Duration::from_secs(1));
+
+        // Open the event stream and wait for its headers
+        let mut stream = TcpStream::connect("127.0.0.1:7878").unwrap();
+        stream
+            .write_all(b"GET /events HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n")
+            .unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") {
+            reader.read_line(&mut head).unwrap();
+        }
+        assert!(head.starts_with("HTTP/1.1 200 OK"));
+        assert!(head.contains("Content-Type: text/event-stream"));
+
+        // A published change is forwarded to the open connection
+        events::publish("test", r#"{"id":42}"#);
+        let mut received = String::new();
+        while !received.contains("data: {\"id\":42}") {
+            reader.read_line(&mut received).unwrap();
+        }
+        assert!(received.contains("event: test"));
+    }
+
+    #[test]
+    fn test_socket_options() {
+        let options: listener::SocketOptions =
+            serde_json::from_str(r#"{"reuse_port": true, "backlog": 16, "tcp_nodelay": false}"#).unwrap();
+        assert!(options.reuse_address);
+        let first = listener::bind("127.0.0.1", 0, None, &options).unwrap();
+        let port = first.local_addr().unwrap().port();
+        // With SO_REUSEPORT a second process (here a second socket) can share the port
+        #[cfg(unix)]
+        assert!(listener::bind("127.0.0.1", port, None, &options).is_ok());
+
+        let client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        listener::configure(&client, &listener::SocketOptions::default());
+        assert!(client.nodelay().unwrap());
+        listener::configure(&client, &options);
+        assert!(!client.nodelay().unwrap());
+    }
+
+    #[test]
+    fn test_listeners() {
+        let config: config::Config = serde_json::from_str(
+            r#"{"listeners": [
+                {"host": "127.0.0.1", "port": 0},
+                {"host": "[::1]", "port": 0, "routes": "admin", "socket": {"tcp_nodelay": false}}
+            ]}"#,
+        )
+        .unwrap();
+        let bound = listener::bind_all_or_exit(&config);
+        assert_eq!(bound.len(), 2);
+        assert!(bound[0].socket.tcp_nodelay && !bound[1].socket.tcp_nodelay);
+        assert_eq!(bound[1].routes, Routes::Admin);
+        let (first, second) = (&bound[0].listener, &bound[1].listener);
+        assert!(first.local_addr().unwrap().is_ipv4() && second.local_addr().unwrap().is_ipv6());
+        // The same port on both families
+        let port = first.local_addr().unwrap().port();
+        let options = listener::SocketOptions::default();
+        assert!(listener::bind("[::1]", port, None, &options).is_ok());
+
+        assert!(Routes::Admin.serves("/admin") && Routes::Admin.serves("/admin/compact"));
+        assert!(!Routes::Admin.serves("/hello") && !Routes::Admin.serves("/administrator"));
+        assert!(Routes::Public.serves("/administrator") && !Routes::Public.serves("/admin/backup"));
+
+        let public = client_on(Routes::Public);
+        assert_eq!(public.get("/hello").status, 200);
+        assert_eq!(public.request("POST", "/admin/flush", &[], "").status, 404);
+        let admin = client_on(Routes::Admin);
+        assert_eq!(admin.get("/hello").status, 404);
+        assert_ne!(admin.get("/admin/runtime").status, 404);
+    }
+
+    #[test]
+    fn test_https_redirect() {
+        let config: config::Config =
+            serde_json::from_str(r#"{"listeners": [{"host": "127.0.0.1", "port": 80, "routes": "https_redirect"}]}"#).unwrap();
+        assert_eq!(config.listeners[0].routes, Routes::HttpsRedirect);
+        assert!(!Routes::HttpsRedirect.serves("/hello"));
+        // Nothing to redirect to without a listener serving HTTPS
+        assert_eq!(listener::https_port(&config), None);
+
+        // Path and query are kept, the body is never read
+        let client = client_on(Routes::HttpsRedirect);
+        let response = client.get("/entries?limit=5");
+        assert_eq!(response.status, 301);
+        assert_eq!(response.header("Location"), Some("https://localhost/entries?limit=5"));
+        assert_eq!(response.header("Strict-Transport-Security"), None);
+        let response = client.request("POST", "/entries", &[], r#"{"name": "Zoro"}"#);
+        assert_eq!(response.status, 301);
+        assert_eq!(response.header("Connection"), Some("close"));
+        assert_eq!(client.send(b"GET /hello HTTP/1.0\r\n\r\n").status, 400);
+        assert_eq!(new_client().get("/entries?limit=5").status, 200);
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn test_https_port() {
+        let config: config::Config = serde_json::from_str(
+            r#"{"listeners": [
+                {"host": "0.0.0.0", "port": 80, "routes": "https_redirect"},
+                {"host": "0.0.0.0", "port": 8443, "tls": {"cert": "cert.pem", "key": "key.pem"}}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(listener::https_port(&config), Some(8443));
+    }
+
+    #[test]
+    fn test_socket_activation() {
+        let pid = std::process::id();
+        let own = pid.to_string();
+        assert_eq!(systemd::passed_fds(Some(&own), Some("2"), pid), 2);
+        // The sockets belong to another process, or nobody passed any
+        assert_eq!(systemd::passed_fds(Some("1"), Some("2"), pid), 0);
+        assert_eq!(systemd::passed_fds(None, Some("2"), pid), 0);
+        assert_eq!(systemd::passed_fds(Some(&own), None, pid), 0);
+        assert_eq!(systemd::passed_fds(Some(&own), Some("many"), pid), 0);
+        // Started outside systemd nothing is taken over
+        assert!(systemd::listeners().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_reload_pid_file() {
+        let pid_file = std::env::temp_dir().join(format!("reload-test-{}.pid", std::process::id()));
+        let _ = std::fs::remove_file(&pid_file);
+        assert_eq!(reload::read_pid(&pid_file).unwrap(), None);
+
+        // --reload without a pid file has nobody to take over from
+        assert!(reload::take_over(&config::Config::default(), true).is_err());
+        let config: config::Config =
+            serde_json::from_value(serde_json::json!({ "pid_file": pid_file.to_str().unwrap() })).unwrap();
+        reload::take_over(&config, false).unwrap();
+        assert_eq!(reload::read_pid(&pid_file).unwrap(), Some(std::process::id()));
+        // Our own pid is never signalled
+        reload::take_over(&config, true).unwrap();
+        std::fs::write(&pid_file, "not a pid").unwrap();
+        assert_eq!(reload::read_pid(&pid_file).unwrap(), None);
+        std::fs::remove_file(&pid_file).unwrap();
+
+        let before = reload::in_flight();
+        let connection = reload::InFlight::start();
+        assert!(reload::in_flight() > before);
+        drop(connection);
+        assert!(!reload::stopping());
+    }
+
+    #[test]
+    fn test_shutdown_hooks() {
+        let ran = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        for name in ["first", "failing", "slow", "last"] {
+            let ran = ran.clone();
+            reload::on_shutdown(name, move || {
+                ran.lock().unwrap().push(name);
+                match name {
+                    "failing" => Err("out of disk".to_string()),
+                    "slow" => {
+                        thread::sleep(Duration::from_millis(500));
+                        Ok(())
+                    }
+                    _ => Ok(()),
+                }
+            });
+        }
+        // A failing hook doesn't stop the next one, the deadline stops them all
+        let abandoned = reload::run_hooks(Duration::from_millis(200));
+        assert_eq!(abandoned, ["slow", "last"]);
+        assert_eq!(*ran.lock().unwrap(), ["first", "failing", "slow"]);
+        // Each hook runs once only
+        assert!(reload::run_hooks(Duration::from_millis(200)).is_empty());
+    }
+
+    #[test]
+    fn test_bind_port_in_use() {
+        // Hold a port so binding it again fails
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = taken.local_addr().unwrap().port();
+
+        let options = listener::SocketOptions::default();
+        let error = listener::bind("127.0.0.1", port, None, &options).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::AddrInUse);
+
+        // The fallback range is used instead (port 0 picks any free port)
+        let fallback = listener::bind("127.0.0.1", port, Some((0, 0)), &options).unwrap();
+        assert_ne!(fallback.local_addr().unwrap().port(), port);
+
+        // The owner of the port is this test process
+        #[cfg(target_os = "linux")]
+        {
+            let owner = listener::port_owner(port).unwrap();
+            assert!(owner.starts_with(&format!("pid {} ", std::process::id())));
+        }
+    }
+
+    #[test]
+    fn test_compression_negotiation() {
+        use compression::Encoding;
+
+        assert_eq!(compression::negotiate("gzip, deflate, br"), Some(Encoding::Gzip));
+        assert_eq!(compression::negotiate("deflate;q=1.0, gzip;q=0.5"), Some(Encoding::Deflate));
+        assert_eq!(compression::negotiate("gzip;q=0, deflate"), Some(Encoding::Deflate));
+        assert_eq!(compression::negotiate("*"), Some(Encoding::Gzip));
+        assert_eq!(compression::negotiate("br, identity"), None);
+
+        // Disabled by default, and small bodies are never compressed
+        let mut config = config::Config::default();
+        assert_eq!(compression::choose(&config, Some("gzip"), 1 << 20), None);
+        config.compression = true;
+        assert_eq!(compression::choose(&config, Some("gzip"), 10), None);
+        assert_eq!(compression::choose(&config, None, 1 << 20), None);
+        assert_eq!(compression::choose(&config, Some("gzip"), 1 << 20), Some(Encoding::Gzip));
+    }
+
+    #[test]
+    fn test_compress_roundtrip() {
+        let body = "Here is your data. ".repeat(100);
+        let compressed = compression::compress(body.as_bytes(), compression::Encoding::Gzip);
+        assert!(compressed.len() < body.len());
+
+        let mut decompressed = String::new();
+        flate2::read::GzDecoder::new(&compressed[..])
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, body);
+    }
+
+    #[test]
+    fn test_response_to_bytes() {
+        let response = Response::text(StatusCode::Created, "Success!").header("Location", "/entries/1");
+        let bytes = String::from_utf8(response.to_bytes(false)).unwrap();
+        assert_eq!(
+            bytes,
+            "HTTP/1.1 201 CREATED\r\nContent-Length: 8\r\n\
+             Content-Type: text/plain; charset=utf-8\r\nLocation: /entries/1\r\n\r\nSuccess!"
+        );
+
+        // HEAD keeps the Content-Length of the body it leaves out
+        let head = String::from_utf8(response.to_bytes(true)).unwrap();
+        assert!(head.ends_with("Location: /entries/1\r\n\r\n"));
+        assert!(head.contains("Content-Length: 8\r\n"));
+
+        // 304 never carries a body or a Content-Length
+        let not_modified = Response::new(StatusCode::NotModified)
+            .body(b"ignored".to_vec())
+            .to_bytes(false);
+        assert_eq!(not_modified, b"HTTP/1.1 304 NOT MODIFIED\r\n\r\n");
+    }
+
+    #[test]
+    fn test_redirects_and_aliases() {
+        let config: config::Config = serde_json::from_str(
+            r#"{
+                "redirects": [
+                    {"from": "/old-entries", "to": "/entries", "permanent": true},
+                    {"from": "/latest", "to": "/entries?limit=10"}
+                ],
+                "aliases": {"/characters": "/entries"}
+            }"#,
+        )
+        .unwrap();
+
+        // Permanent redirects keep the query string
+        let Rewrite::Redirect(response) = redirects::resolve(&config, "/old-entries?limit=5") else {
+            panic!("expected a redirect");
+        };
+        assert_eq!(response.status, StatusCode::PermanentRedirect);
+        assert_eq!(response.headers.get("Location"), Some("/entries?limit=5"));
+
+        let Rewrite::Redirect(response) = redirects::resolve(&config, "/latest") else {
+            panic!("expected a redirect");
+        };
+        assert_eq!(response.status, StatusCode::TemporaryRedirect);
+
+        // Aliases are routed to their target, other paths are untouched
+        let Rewrite::Route(uri) = redirects::resolve(&config, "/characters?offset=2") else {
+            panic!("expected an alias");
+        };
+        assert_eq!(uri, "/entries?offset=2");
+        let Rewrite::Route(uri) = redirects::resolve(&config, "/hello") else {
+            panic!("expected no rewrite");
+        };
+        assert_eq!(uri, "/hello");
+    }
+
+    #[test]
+    fn test_data_format_config() {
+        // Pretty by default, compact when asked for
+        assert_eq!(config::Config::default().data_format, config::DataFormat::Pretty);
+        let config: config::Config = serde_json::from_str(r#"{"data_format": "compact"}"#).unwrap();
+        assert_eq!(config.data_format, config::DataFormat::Compact);
+        assert!(serde_json::from_str::<config::Config>(r#"{"data_format": "yaml"}"#).is_err());
+    }
+
     // Cookie Management Unit Tests
     #[test]
     fn test_cookie_management() {
@@ -502,13 +4503,12 @@ mod tests {
 
             // Send a request in a separate thread
             pool.execute(move || {
-                let request =
-                    format!("GET /hello HTTP/1.1\r\nHost: 127.0.0.1\r\nConnection: close\r\n\r\n");
+                let request = "GET /hello HTTP/1.1\r\nHost: 127.0.0.1\r\nConnection: close\r\n\r\n";
 
                 // Measure the time taken to receive the response
                 let start = Instant::now();
 
-                let response = send_request(&request);
+                let response = send_request(request);
 
                 let duration = start.elapsed();
 
@@ -546,7 +4546,7 @@ mod tests {
         let mut buf_reader = BufReader::new(&mut stream);
 
         // Parse the request
-        let (method, uri, headers, body) = match parse_request(&mut buf_reader) {
+        let (method, uri, _, headers, body) = match parser::parse_request_with(&mut buf_reader, true) {
             Ok(result) => result,
             Err(e) => {
                 eprintln!("Failed to parse request: {}", e);
@@ -558,16 +4558,1036 @@ mod tests {
         assert_eq!(method, "GET");
         assert_eq!(uri, "/entries");
         assert_eq!(headers.get("Host").unwrap(), "localhost");
-        assert_eq!(body, "");
+        assert!(body.is_empty());
     }
 
     #[test]
-    fn test_parse_cookies() {
-        let mut headers = HashMap::new();
-        headers.insert(
-            "Cookie".to_string(),
-            "sessionId=abc123; userId=789; lang=en".to_string(),
+    fn test_client() {
+        let request = client::Outgoing::new("POST", "/entries").header("Accept", "application/json").body("{}");
+        assert_eq!(
+            String::from_utf8(request.to_bytes("example.com")).unwrap(),
+            "POST /entries HTTP/1.1\r\nHost: example.com\r\nAccept: application/json\r\nContent-Length: 2\r\n\r\n{}"
+        );
+
+        // Interim responses are skipped, a chunked body is put back together
+        let raw = "HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n";
+        let reply = client::read_response(&mut BufReader::new(raw.as_bytes()), false).unwrap();
+        assert_eq!((reply.status, reply.body.as_slice(), reply.keep_alive), (200, &b"abcde"[..], true));
+        // Without a length the body runs to the end of the connection, which can't be kept
+        let raw = "HTTP/1.0 200 OK\r\n\r\nall of it";
+        let reply = client::read_response(&mut BufReader::new(raw.as_bytes()), false).unwrap();
+        assert_eq!((reply.body.as_slice(), reply.keep_alive), (&b"all of it"[..], false));
+        let raw = "HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nshort";
+        assert!(client::read_response(&mut BufReader::new(raw.as_bytes()), false).is_err());
+
+        // Two requests on one kept-alive connection
+        start_server();
+        thread::sleep(Duration::from_secs(1));
+        let mut client = client::Client::new("127.0.0.1:7878").timeout(Duration::from_secs(5));
+        for _ in 0..2 {
+            let reply = client.send(&client::Outgoing::new("GET", "/hello")).unwrap();
+            assert_eq!(reply.status, 200);
+            assert!(reply.keep_alive);
+        }
+        let reply = client.send(&client::Outgoing::new("GET", "/hello").header("Connection", "close")).unwrap();
+        assert!(!reply.keep_alive);
+    }
+
+    #[test]
+    fn test_bench() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        let config = config::Config { host: "0.0.0.0".to_string(), ..config::Config::default() };
+        let options = bench::parse_args(&args(&["--url", "/entries", "--connections", "50", "--duration", "30s"]), &config).unwrap();
+        assert_eq!(options.address, format!("127.0.0.1:{}", config.port));
+        assert_eq!((options.path.as_str(), options.connections), ("/entries", 50));
+        assert_eq!(options.duration, Duration::from_secs(30));
+        let options = bench::parse_args(&args(&["--url", "http://example.com"]), &config).unwrap();
+        assert_eq!((options.address.as_str(), options.path.as_str()), ("example.com:80", "/"));
+        assert_eq!(bench::parse_duration("500ms"), Some(Duration::from_millis(500)));
+        assert_eq!(bench::parse_duration("2m"), Some(Duration::from_secs(120)));
+        assert_eq!(bench::parse_duration("soon"), None);
+        assert!(bench::parse_args(&args(&["--connections", "0"]), &config).is_err());
+        assert!(bench::parse_args(&args(&["--duration"]), &config).is_err());
+
+        let report = bench::Report {
+            latencies: (1..=100).map(Duration::from_millis).collect(),
+            ..bench::Report::default()
+        };
+        assert_eq!(report.percentile(50.0), Duration::from_millis(50));
+        assert_eq!(report.percentile(99.0), Duration::from_millis(99));
+        assert_eq!(bench::Report::default().percentile(99.0), Duration::ZERO);
+
+        start_server();
+        thread::sleep(Duration::from_secs(1));
+        let options = bench::Options {
+            address: "127.0.0.1:7878".to_string(),
+            path: "/hello".to_string(),
+            connections: 2,
+            duration: Duration::from_millis(300),
+        };
+        let report = bench::run(&options);
+        assert!(report.requests > 0);
+        assert_eq!(report.errors, 0);
+        assert_eq!(report.latencies.len() as u64, report.requests);
+        assert!(report.to_string().contains("requests/s"));
+    }
+
+    #[test]
+    fn test_http_hardening_self_test() {
+        let failures = self_test::failures("http-hardening").unwrap();
+        assert!(failures.is_empty(), "hardening vectors failed: {:?}", failures);
+        assert!(self_test::failures("no-such-suite").is_none());
+    }
+
+    #[test]
+    fn test_parse_request_lenient() {
+        // Without hardening the original lenient parsing still applies
+        let request = "GET / HTTP/1.1\nTransfer-Encoding : chunked\r\n\r\n";
+        let mut buf_reader = BufReader::new(request.as_bytes());
+        let (_, _, _, headers, _) = parser::parse_request_with(&mut buf_reader, false).unwrap();
+        assert_eq!(headers.get("Transfer-Encoding "), Some("chunked"));
+
+        let mut buf_reader = BufReader::new(request.as_bytes());
+        assert!(parser::parse_request_with(&mut buf_reader, true).is_err());
+    }
+
+    #[test]
+    fn test_head_parser_incremental() {
+        // Fed a byte at a time, the head completes on its blank line and the body is left over
+        let request = b"POST /submit HTTP/1.1\r\nHost: localhost\r\nContent-Length: 2\r\n\r\n{}";
+        let mut parser = parser::HeadParser::new(true);
+        let mut head = None;
+        let mut consumed = 0;
+        for byte in request.chunks(1) {
+            let (used, parsed) = parser.advance(byte).unwrap();
+            consumed += used;
+            if parsed.is_some() {
+                head = parsed;
+                break;
+            }
+        }
+        let head = head.unwrap();
+        assert_eq!((head.method.as_str(), head.uri.as_str()), ("POST", "/submit"));
+        assert_eq!(head.headers.get("Content-Length"), Some("2"));
+        assert_eq!(&request[consumed..], b"{}");
+
+        // Oversized lines are refused before their end arrives
+        let mut parser = parser::HeadParser::new(true);
+        let long_line = vec![b'a'; parser::MAX_REQUEST_LINE_BYTES + 1];
+        assert!(matches!(parser.advance(&long_line), Err(parser::RequestError::RequestLineTooLong)));
+
+        // Without hardening bare LF line endings work too
+        let mut parser = parser::HeadParser::new(false);
+        let (used, head) = parser.advance(b"GET /hello HTTP/1.1\nHost: localhost\n\nrest").unwrap();
+        assert_eq!((used, head.unwrap().headers.get("Host")), (37, Some("localhost")));
+    }
+
+    #[test]
+    fn test_parse_request_partial_reads() {
+        // A reader handing out a few bytes per read, like a slow connection
+        struct Trickle<'a>(&'a [u8]);
+        impl Read for Trickle<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let n = self.0.len().min(buf.len()).min(3);
+                buf[..n].copy_from_slice(&self.0[..n]);
+                self.0 = &self.0[n..];
+                Ok(n)
+            }
+        }
+
+        let request = b"PUT /put_entry HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Type: application/json\r\nContent-Length: 13\r\n\r\n{\"id\": \"one\"}";
+        let mut buf_reader = BufReader::new(Trickle(request));
+        let (method, _, _, _, body) = parser::parse_request_with(&mut buf_reader, true).unwrap();
+        assert_eq!((method.as_str(), &body[..]), ("PUT", &b"{\"id\": \"one\"}"[..]));
+
+        // A body cut short by the end of the stream is still refused
+        let request = b"POST /submit HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Length: 10\r\n\r\nshort";
+        let error = parser::parse_request_with(&mut BufReader::new(Trickle(request)), true).unwrap_err();
+        assert!(matches!(error, parser::RequestError::ContentLengthExceedsData));
+
+        // A length over max_body_bytes is refused before waiting for any of it
+        let request = b"POST /submit HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Length: 100000000000\r\n\r\n{}";
+        let error = parser::parse_request_with(&mut BufReader::new(Trickle(request)), true).unwrap_err();
+        assert!(matches!(error, parser::RequestError::BodyTooLarge));
+        let response = new_client().send(request);
+        assert_eq!(response.status, 413);
+        assert_eq!(response.header("Connection"), Some("close"));
+    }
+
+    #[test]
+    fn test_binary_body() {
+        // Bytes that aren't UTF-8 come through the parser untouched
+        let mut request = b"POST /entries/bulk HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Length: 4\r\n\r\n".to_vec();
+        request.extend_from_slice(&[0xff, 0x00, 0xfe, 0x80]);
+        let (_, uri, _, headers, body) = parser::parse_request_with(&mut BufReader::new(&request[..]), true).unwrap();
+        assert_eq!(body, [0xff, 0x00, 0xfe, 0x80]);
+
+        let request = Request::new(&uri, &headers, &body);
+        assert!(request.text().is_none());
+        assert!(request.json::<serde_json::Value>().is_err());
+        let request = Request::new("/", &headers, br#"{"id": 3}"#);
+        assert_eq!(request.text(), Some(r#"{"id": 3}"#));
+        assert_eq!(request.json::<serde_json::Value>().unwrap()["id"], 3);
+
+        // Whatever their type, a handler gets them back intact
+        let mut raw = b"POST /echo HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Type: application/octet-stream\r\nContent-Length: 4\r\n\r\n".to_vec();
+        raw.extend_from_slice(&[0xff, 0x00, 0xfe, 0x80]);
+        let (method, uri, _, headers, body) = parser::parse_request_with(&mut BufReader::new(&raw[..]), true).unwrap();
+        let echo = |request: &Request| Response::new(StatusCode::Ok).content_type("application/octet-stream").body(request.body.to_vec());
+        let router = Router::new().post("/echo", echo);
+        let response = router.find(&method, &uri).unwrap().0.call(&mut Request::new(&uri, &headers, &body));
+        assert_eq!(response.body, [0xff, 0x00, 0xfe, 0x80]);
+        for content_type in ["image/png", "application/pdf"] {
+            let raw = format!("POST /echo HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Type: {content_type}\r\nContent-Length: 1\r\n\r\nx");
+            assert!(parser::parse_request_with(&mut BufReader::new(raw.as_bytes()), true).is_ok(), "{content_type}");
+        }
+
+        // Handlers reading text refuse them instead of guessing
+        let mut raw = b"DELETE /entries/bulk HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Type: application/octet-stream\r\nContent-Length: 2\r\n\r\n".to_vec();
+        raw.extend_from_slice(&[0xc3, 0x28]);
+        let response = new_client().send(&raw);
+        assert_eq!(response.status, 400);
+        assert_eq!(response.json()["detail"], "The body is not valid UTF-8");
+    }
+
+    #[test]
+    fn test_http_versions() {
+        assert_eq!(Version::parse("HTTP/1.1"), Some(Version::Http11));
+        assert_eq!(Version::parse("HTTP/1.0"), Some(Version::Http10));
+        assert_eq!(Version::parse("HTTP/1.9"), Some(Version::Http11));
+        assert_eq!(Version::parse("HTTP/2.0"), None);
+        assert_eq!(Version::parse("HTTP/1."), None);
+
+        // HTTP/1.0 clients are told the connection closes, 1.1 ones aren't told anything
+        let client = new_client();
+        let response = client.send(b"GET /hello HTTP/1.0\r\n\r\n");
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("Connection"), Some("close"));
+        assert!(client.get("/hello").header("Connection").is_none());
+
+        // Unless a 1.0 client asks to keep the connection, which both are told for how long
+        let response = client.send(b"GET /hello HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n");
+        assert_eq!(response.header("Connection"), Some("keep-alive"));
+        assert_eq!(response.header("Keep-Alive"), Some("timeout=5, max=99"));
+        assert_eq!(client.get("/hello").header("Keep-Alive"), Some("timeout=5, max=99"));
+        let response = client.request("GET", "/hello", &[("Connection", "close")], "");
+        assert_eq!(response.header("Connection"), Some("close"));
+        assert!(response.header("Keep-Alive").is_none());
+
+        let response = client.send(b"GET /hello HTTP/2.0\r\n\r\n");
+        assert_eq!(response.status, 505);
+        assert_eq!(response.json()["detail"], "Unsupported HTTP version: HTTP/2.0");
+    }
+
+    #[test]
+    fn test_host_header() {
+        assert_eq!(http::host_name("API.local:7878"), "api.local");
+        assert_eq!(http::host_name("[::1]:7878"), "[::1]");
+        assert_eq!(http::host_name("[::1]"), "[::1]");
+        let mut headers = HeaderMap::new();
+        headers.append("Host", "Static.Local:80");
+        let request = Request::new("/", &headers, b"");
+        assert_eq!(request.host.as_deref(), Some("static.local"));
+
+        // HTTP/1.1 needs exactly one Host, HTTP/1.0 may leave it out
+        let client = new_client();
+        let response = client.send(b"GET /hello HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status, 400);
+        assert_eq!(response.json()["detail"], "HTTP/1.1 requests need a Host header");
+        let response = client.send(b"GET /hello HTTP/1.1\r\nHost: a.local\r\nHost: b.local\r\n\r\n");
+        assert_eq!(response.status, 400);
+        assert_eq!(client.send(b"GET /hello HTTP/1.0\r\n\r\n").status, 200);
+    }
+
+    #[test]
+    fn test_virtual_hosts() {
+        use std::fs;
+        let root = std::env::temp_dir().join(format!("vhost-test-{}", std::process::id()));
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::write(root.join("index.html"), "<h1>static</h1>").unwrap();
+        fs::write(root.join("docs/app.js"), "console.log(1)").unwrap();
+        fs::write(root.join(".env"), "SECRET=1").unwrap();
+
+        let config: config::Config = serde_json::from_value(json!({
+            "virtual_hosts": { "Static.Local": { "root": root.to_str().unwrap() } }
+        }))
+        .unwrap();
+        let mount = static_files::for_host(&config, Some("static.local")).unwrap();
+        assert!(static_files::for_host(&config, Some("api.local")).is_none());
+        assert!(static_files::for_host(&config, None).is_none());
+
+        let response = static_files::serve(mount, "GET", "/", &HeaderMap::new());
+        assert_eq!(response.status, StatusCode::Ok);
+        assert_eq!(response.headers.get("Content-Type"), Some("text/html; charset=utf-8"));
+        assert_eq!(response.body, b"<h1>static</h1>");
+        let response = static_files::serve(mount, "HEAD", "/docs/app.js", &HeaderMap::new());
+        assert_eq!(response.headers.get("Content-Type"), Some("text/javascript; charset=utf-8"));
+
+        // Nothing outside the root or hidden is reachable
+        for path in ["/.env", "/../etc/passwd", "/docs/../.env", "/missing.html"] {
+            assert_eq!(static_files::serve(mount, "GET", path, &HeaderMap::new()).status, StatusCode::NotFound, "{path}");
+        }
+        let response = static_files::serve(mount, "POST", "/", &HeaderMap::new());
+        assert_eq!(response.status, StatusCode::MethodNotAllowed);
+        assert_eq!(response.headers.get("Allow"), Some("GET, HEAD"));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_precompressed_static_files() {
+        use std::fs;
+        let root = std::env::temp_dir().join(format!("precompressed-test-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("app.js"), "console.log(1)").unwrap();
+        fs::write(root.join("app.js.gz"), "gzip bytes").unwrap();
+        fs::write(root.join("app.js.br"), "brotli bytes").unwrap();
+        fs::write(root.join("plain.txt"), "plain").unwrap();
+        let mount: static_files::StaticMount = serde_json::from_value(json!({ "root": root, "metadata_secs": 0 })).unwrap();
+
+        let serve = |path: &str, accept_encoding: Option<&str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(accept_encoding) = accept_encoding {
+                headers.insert("Accept-Encoding", accept_encoding);
+            }
+            static_files::serve(&mount, "GET", path, &headers)
+        };
+        let identity = serve("/app.js", None);
+        assert_eq!(identity.body, b"console.log(1)");
+        assert_eq!(identity.headers.get("Content-Encoding"), None);
+        assert_eq!(identity.headers.get("Vary"), Some("Accept-Encoding"));
+        let gzip = serve("/app.js", Some("gzip, deflate"));
+        assert_eq!(gzip.body, b"gzip bytes");
+        assert_eq!(gzip.headers.get("Content-Encoding"), Some("gzip"));
+        assert_eq!(gzip.headers.get("Content-Type"), Some("text/javascript; charset=utf-8"));
+        let brotli = serve("/app.js", Some("gzip, br"));
+        assert_eq!(brotli.headers.get("Content-Encoding"), Some("br"));
+        assert_eq!(serve("/app.js", Some("*, br;q=0")).headers.get("Content-Encoding"), Some("gzip"));
+        assert_eq!(serve("/app.js", Some("gzip;q=0, deflate")).body, b"console.log(1)");
+
+        // Each representation has its own strong tag, unchanged until the file is
+        let tags: Vec<_> = [&identity, &gzip, &brotli].iter().map(|response| response.headers.get("ETag").unwrap().to_string()).collect();
+        assert!(tags.iter().all(|tag| tag.starts_with('"')));
+        assert!(tags[0] != tags[1] && tags[1] != tags[2] && tags[0] != tags[2]);
+        assert_eq!(serve("/app.js", None).headers.get("ETag"), Some(tags[0].as_str()));
+        fs::write(root.join("app.js"), "console.log(22)").unwrap();
+        assert_ne!(serve("/app.js", None).headers.get("ETag"), Some(tags[0].as_str()));
+        let plain = serve("/plain.txt", Some("gzip"));
+        assert_eq!((plain.headers.get("Content-Encoding"), plain.headers.get("Vary")), (None, None));
+
+        // Within metadata_secs the file isn't looked at again
+        let cached = static_files::StaticMount { metadata_secs: 60, ..mount.clone() };
+        let tag = static_files::serve(&cached, "GET", "/plain.txt", &HeaderMap::new()).headers.get("ETag").unwrap().to_string();
+        fs::write(root.join("plain.txt"), "plain, but longer").unwrap();
+        let response = static_files::serve(&cached, "GET", "/plain.txt", &HeaderMap::new());
+        assert_eq!(response.headers.get("ETag"), Some(tag.as_str()));
+        assert_ne!(static_files::serve(&mount, "GET", "/plain.txt", &HeaderMap::new()).headers.get("ETag"), Some(tag.as_str()));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_spa_fallback() {
+        use std::fs;
+        let root = std::env::temp_dir().join(format!("spa-test-{}", std::process::id()));
+        fs::create_dir_all(root.join("assets")).unwrap();
+        fs::write(root.join("index.html"), "<div id=app></div>").unwrap();
+        fs::write(root.join("assets/app.js"), "route()").unwrap();
+        let mount: static_files::StaticMount = serde_json::from_value(json!({ "root": root, "spa": true })).unwrap();
+
+        let serve = |path: &str| static_files::serve(&mount, "GET", path, &HeaderMap::new());
+        for path in ["/settings/profile", "/assets", "/users/42/"] {
+            let response = serve(path);
+            assert_eq!(response.status, StatusCode::Ok, "{path}");
+            assert_eq!(response.body, b"<div id=app></div>", "{path}");
+        }
+        assert_eq!(serve("/assets/app.js").body, b"route()");
+        // Paths naming a file are still missing when there is none
+        for path in ["/logo.png", "/assets/missing.js", "/.env"] {
+            assert_eq!(serve(path).status, StatusCode::NotFound, "{path}");
+        }
+        assert_eq!(static_files::serve(&mount, "POST", "/settings", &HeaderMap::new()).status, StatusCode::MethodNotAllowed);
+        let plain = static_files::StaticMount { spa: false, ..mount.clone() };
+        assert_eq!(static_files::serve(&plain, "GET", "/settings/profile", &HeaderMap::new()).status, StatusCode::NotFound);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_autoindex() {
+        use std::fs;
+        let root = std::env::temp_dir().join(format!("autoindex-test-{}", std::process::id()));
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::create_dir_all(root.join("site")).unwrap();
+        fs::write(root.join("b.txt"), "abc").unwrap();
+        fs::write(root.join("<x>&y.txt"), "").unwrap();
+        fs::write(root.join(".hidden"), "").unwrap();
+        fs::write(root.join("sub/inner.txt"), "12345").unwrap();
+        fs::write(root.join("site/index.html"), "home").unwrap();
+        let mount: static_files::StaticMount = serde_json::from_value(json!({ "root": root, "autoindex": true })).unwrap();
+        let serve = |path: &str, accept: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("Accept", accept);
+            static_files::serve(&mount, "GET", path, &headers)
+        };
+
+        let response = serve("/", "text/html,application/xhtml+xml,*/*;q=0.8");
+        assert_eq!(response.status, StatusCode::Ok);
+        assert_eq!(response.headers.get("Content-Type"), Some("text/html; charset=utf-8"));
+        let page = String::from_utf8(response.body).unwrap();
+        assert!(page.contains("<title>Index of /</title>"), "{page}");
+        let (sub, file) = (page.find(r#"<a href="/sub/">sub/</a>"#).unwrap(), page.find(r#"<a href="/b.txt">b.txt</a>"#).unwrap());
+        assert!(sub < file, "directories come first");
+        assert!(page.contains(r#"<a href="/%3Cx%3E%26y.txt">&lt;x&gt;&amp;y.txt</a>"#), "{page}");
+        assert!(!page.contains(".hidden") && !page.contains("../"), "{page}");
+        let page = String::from_utf8(serve("/sub", "text/html").body).unwrap();
+        assert!(page.contains(r#"<a href="../">../</a>"#) && page.contains(r#"<a href="/sub/inner.txt">"#), "{page}");
+
+        let response = serve("/sub/", "application/json");
+        assert_eq!(response.headers.get("Content-Type"), Some("application/json"));
+        let listing: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(listing[0]["name"], "inner.txt");
+        assert_eq!(listing[0]["type"], "file");
+        assert_eq!(listing[0]["size"], 5);
+        assert!(listing[0]["modified"].as_str().unwrap().ends_with('Z'));
+        let listing: serde_json::Value = serde_json::from_slice(&serve("/", "application/json").body).unwrap();
+        assert_eq!(listing[0], json!({ "name": "site", "type": "directory", "size": null, "modified": listing[0]["modified"] }));
+
+        // An index.html is served instead, and without the flag nothing is listed
+        assert_eq!(serve("/site", "text/html").body, b"home");
+        let plain = static_files::StaticMount { autoindex: false, ..mount.clone() };
+        assert_eq!(static_files::serve(&plain, "GET", "/sub", &HeaderMap::new()).status, StatusCode::NotFound);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_large_static_file() {
+        use std::fs;
+        let root = std::env::temp_dir().join(format!("sendfile-test-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let contents: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(root.join("big.bin"), &contents).unwrap();
+        let mount: static_files::StaticMount = serde_json::from_value(json!({ "root": root, "metadata_secs": 0 })).unwrap();
+
+        // Sent from the file, not read into the response
+        let response = static_files::serve(&mount, "GET", "/big.bin", &HeaderMap::new());
+        assert!(response.body.is_empty());
+        assert_eq!(response.body_len(), 200_000);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let reader = thread::spawn(move || {
+            let mut received = Vec::new();
+            TcpStream::connect(address).unwrap().read_to_end(&mut received).unwrap();
+            received
+        });
+        let (stream, _) = listener.accept().unwrap();
+        let serialized = response.serialize(false);
+        assert!(String::from_utf8(serialized.bytes).unwrap().contains("Content-Length: 200000\r\n"));
+        serialized.file.unwrap().send(&stream).unwrap();
+        drop(stream);
+        assert_eq!(reader.join().unwrap(), contents);
+
+        // A range only sends its part of the file, HEAD none of it
+        let mut headers = HeaderMap::new();
+        headers.insert("Range", "bytes=1000-1999");
+        let ranged = range::apply("GET", &headers, response.clone());
+        assert_eq!(ranged.status, StatusCode::PartialContent);
+        assert_eq!(ranged.headers.get("Content-Range"), Some("bytes 1000-1999/200000"));
+        let serialized = ranged.serialize(false);
+        assert_eq!(serialized.len() - serialized.bytes.len(), 1000);
+        assert!(serialized.into_bytes().unwrap().ends_with(&contents[1000..2000]));
+        assert!(response.serialize(true).file.is_none());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_streamed_json() {
+        let items: Vec<serde_json::Value> = (0..5000).map(|i| json!({ "id": i, "name": format!("entry {i}") })).collect();
+        let expected = serde_json::to_vec(&items).unwrap();
+        let body = chunked::StreamBody::json_array(items).unwrap();
+        assert_eq!(body.length(), expected.len() as u64);
+        assert_eq!(body.etag(), conditional::etag(&expected));
+
+        // Sent in chunks after a head without a Content-Length
+        let response = Response::new(StatusCode::Ok).content_type("application/json").stream(body.clone());
+        let serialized = response.serialize(false);
+        let head = String::from_utf8(serialized.bytes.clone()).unwrap();
+        assert!(head.contains("Transfer-Encoding: chunked\r\n") && !head.contains("Content-Length"), "{head}");
+        let chunked = body.read().unwrap();
+        let first = String::from_utf8_lossy(&chunked[..chunked.iter().position(|&b| b == b'\r').unwrap()]).into_owned();
+        let first = usize::from_str_radix(&first, 16).unwrap();
+        assert!(first >= 16 * 1024 && first < expected.len(), "{first}");
+        assert!(chunked.ends_with(b"\r\n0\r\n\r\n"));
+        assert_eq!(serialized.into_bytes().unwrap(), [head.as_bytes(), &chunked].concat());
+        assert!(response.serialize(true).stream.is_none());
+
+        // Compressed while it is sent, and whole for HTTP/1.0 clients
+        let mut gzipped = body.clone();
+        gzipped.encode(compression::Encoding::Gzip);
+        let plain = gzipped.plain().unwrap();
+        assert_eq!(compression::decompress(&plain, compression::Encoding::Gzip, expected.len()).unwrap(), expected);
+        let head = RequestHead {
+            method: "GET".to_string(),
+            uri: "/entries".to_string(),
+            version: Version::Http10,
+            headers: HeaderMap::new(),
+        };
+        let old = finish_response(&head, 1, false, response).serialize(false);
+        assert!(old.stream.is_none());
+        assert!(String::from_utf8_lossy(&old.bytes).contains(&format!("Content-Length: {}\r\n", expected.len())));
+        assert!(old.bytes.ends_with(&expected));
+
+        // Small pages are still built in memory, with a length and ranges
+        let response = new_client().get("/entries?limit=10");
+        assert_eq!(response.header("Content-Length"), Some(response.body.len().to_string().as_str()));
+        assert_eq!(response.header("Accept-Ranges"), Some("bytes"));
+    }
+
+    #[test]
+    fn test_expect_continue() {
+        let client = new_client();
+        let expect = [("Expect", "100-continue"), ("Content-Type", "application/json")];
+        let response = client.request("POST", "/entries/bulk", &expect, "[]");
+        assert!(response.interim.starts_with(b"HTTP/1.1 100 CONTINUE\r\n"));
+        assert_eq!(response.status, 400);
+
+        // Refused before the body is sent, with nothing interim
+        let response = client.request("POST", "/nowhere", &expect, "[]");
+        assert_eq!((response.status, response.interim.is_empty()), (417, true));
+        assert_eq!(response.header("Connection"), Some("close"));
+        let response = client.request("POST", "/entries/bulk", &[("Expect", "teapot")], "[]");
+        assert_eq!(response.status, 417);
+        assert_eq!(response.json()["detail"], "Unsupported expectation: teapot");
+        // HTTP/1.0 has no interim responses, its Expect is ignored
+        let response = client.send(b"GET /hello HTTP/1.0\r\nExpect: 100-continue\r\n\r\n");
+        assert_eq!((response.status, response.interim.is_empty()), (200, true));
+
+        // Over a socket the body is only sent once the 100 arrived
+        start_server();
+        thread::sleep(Duration::from_secs(1));
+        let mut stream = TcpStream::connect("127.0.0.1:7878").unwrap();
+        stream
+            .write_all(b"POST /entries/bulk HTTP/1.1\r\nHost: 127.0.0.1\r\nExpect: 100-continue\r\nContent-Length: 2\r\n\r\n")
+            .unwrap();
+        let mut interim = [0; 25];
+        stream.read_exact(&mut interim).unwrap();
+        assert_eq!(&interim, b"HTTP/1.1 100 CONTINUE\r\n\r\n");
+        stream.write_all(b"[]").unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 400 BAD REQUEST"));
+    }
+
+    #[test]
+    fn test_keep_alive() {
+        start_server();
+        thread::sleep(Duration::from_secs(1));
+
+        // reads one response off the connection, by its Content-Length
+        fn read_response(reader: &mut BufReader<TcpStream>) -> String {
+            let mut head = String::new();
+            while !head.ends_with("\r\n\r\n") {
+                assert!(reader.read_line(&mut head).unwrap() > 0, "connection closed");
+            }
+            let length = head
+                .lines()
+                .find_map(|line| line.strip_prefix("Content-Length: "))
+                .map_or(0, |length| length.parse().unwrap());
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            head
+        }
+
+        // Several requests are answered on one connection, each told how many are left
+        let stream = TcpStream::connect("127.0.0.1:7878").unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        for left in [99, 98] {
+            (&stream).write_all(b"GET /hello HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n").unwrap();
+            let head = read_response(&mut reader);
+            assert!(head.starts_with("HTTP/1.1 200 OK"));
+            assert!(head.contains(&format!("Keep-Alive: timeout=5, max={left}\r\n")));
+        }
+        // A body is read to its end, the next request starts after it
+        (&stream)
+            .write_all(b"POST /entries/bulk HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Length: 2\r\n\r\n[]")
+            .unwrap();
+        assert!(read_response(&mut reader).starts_with("HTTP/1.1 400 BAD REQUEST"));
+        (&stream).write_all(b"GET /hello HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n").unwrap();
+        assert!(read_response(&mut reader).contains("Keep-Alive: timeout=5, max=96\r\n"));
+
+        // Until the client asks to close it
+        (&stream)
+            .write_all(b"GET /hello HTTP/1.1\r\nHost: 127.0.0.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        assert!(read_response(&mut reader).contains("Connection: close\r\n"));
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+
+        // An answer its request wasn't read past closes the connection too
+        let stream = TcpStream::connect("127.0.0.1:7878").unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        (&stream).write_all(b"GET / HTTP/1.1\r\nHost: 127.0.0.1\r\nBad Header: x\r\n\r\n").unwrap();
+        let mut response = String::new();
+        (&stream).read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 400 BAD REQUEST") && response.contains("Connection: close\r\n"));
+    }
+
+    // sends three requests in one write, the second with a body, and returns
+    // the status lines of the responses in the order they came back
+    fn send_pipelined(address: SocketAddr) -> Vec<String> {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        stream
+            .write_all(
+                b"GET /hello HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n\
+                  POST /entries/bulk HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Length: 2\r\n\r\n[]\
+                  GET /entries/1 HTTP/1.1\r\nHost: 127.0.0.1\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        // the bodies don't end in a line break, a status line may follow one on the same line
+        response
+            .match_indices("HTTP/1.1 ")
+            .filter_map(|(at, _)| response[at..].lines().next())
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn test_pipelining() {
+        start_server();
+        thread::sleep(Duration::from_secs(1));
+
+        // Every request already read ahead is answered, in the order they were sent
+        let statuses = send_pipelined("127.0.0.1:7878".parse().unwrap());
+        assert_eq!(statuses, ["HTTP/1.1 200 OK", "HTTP/1.1 400 BAD REQUEST", "HTTP/1.1 200 OK"]);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn test_async_pipelining() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        listener.set_nonblocking(true).unwrap();
+        thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async {
+                let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                async_server::serve(listener, listener::SocketOptions::default(), Routes::All, None).await;
+            })
+        });
+
+        // The requests read past the end of one are kept for the next
+        let statuses = send_pipelined(address);
+        assert_eq!(statuses, ["HTTP/1.1 200 OK", "HTTP/1.1 400 BAD REQUEST", "HTTP/1.1 200 OK"]);
+
+        // A body too large to take is refused without waiting for it
+        let request = b"POST /submit HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Length: 100000000000\r\n\r\n";
+        let response = client::send_raw(&address.to_string(), request).unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 413 PAYLOAD TOO LARGE"));
+        // and so is an upload over max_upload_bytes, which is buffered here
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Type: multipart/form-data; boundary=x\r\nContent-Length: {}\r\n\r\n",
+            upload::UPLOAD_PATH,
+            config::get().max_upload_bytes + 1
+        );
+        let response = client::send_raw(&address.to_string(), request.as_bytes()).unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 413 PAYLOAD TOO LARGE"));
+    }
+
+    // serves the test certificate on a port of its own, with the tokio accept loop
+    #[cfg(feature = "tls")]
+    fn start_tls_server() -> SocketAddr {
+        let files = tls::TlsFiles { cert: "assets/tls/localhost.pem".into(), key: "assets/tls/localhost-key.pem".into() };
+        serve_tls(tls::server_config(&files, false).unwrap())
+    }
+
+    #[cfg(feature = "tls")]
+    fn serve_tls(tls: std::sync::Arc<rustls::ServerConfig>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        listener.set_nonblocking(true).unwrap();
+        thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async {
+                let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                async_server::serve(listener, listener::SocketOptions::default(), Routes::All, Some(tls)).await;
+            })
+        });
+        address
+    }
+
+    // a TLS connection trusting the test CA, offering `alpn`
+    #[cfg(feature = "tls")]
+    async fn connect_tls(
+        address: SocketAddr,
+        alpn: &[&[u8]],
+    ) -> tokio_rustls::client::TlsStream<tokio::net::TcpStream> {
+        try_connect_tls(address, alpn).await.unwrap()
+    }
+
+    #[cfg(feature = "tls")]
+    async fn try_connect_tls(
+        address: SocketAddr,
+        alpn: &[&[u8]],
+    ) -> std::io::Result<tokio_rustls::client::TlsStream<tokio::net::TcpStream>> {
+        use rustls::pki_types::pem::PemObject;
+        use rustls::pki_types::{CertificateDer, ServerName};
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(CertificateDer::from_pem_file("assets/tls/ca.pem").unwrap()).unwrap();
+        let mut config = rustls::ClientConfig::builder_with_provider(std::sync::Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+        let connector = tokio_rustls::TlsConnector::from(std::sync::Arc::new(config));
+        let stream = tokio::net::TcpStream::connect(address).await?;
+        connector.connect(ServerName::try_from("localhost").unwrap(), stream).await
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn test_tls_listener() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // A bad certificate path is reported instead of served
+        let missing = tls::TlsFiles { cert: "assets/tls/missing.pem".into(), key: "assets/tls/localhost-key.pem".into() };
+        assert!(tls::server_config(&missing, false).unwrap_err().starts_with("assets/tls/missing.pem"));
+
+        let address = start_tls_server();
+        let response = tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let mut stream = connect_tls(address, &[b"http/1.1"]).await;
+            assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"http/1.1"[..]));
+            // Kept alive like on a cleartext listener, a large body read in before it is encrypted
+            stream
+                .write_all(
+                    b"GET /hello HTTP/1.1\r\nHost: localhost\r\n\r\n\
+                      GET /entries HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                )
+                .await
+                .unwrap();
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).await.unwrap();
+            String::from_utf8(response).unwrap()
+        });
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("Strict-Transport-Security: max-age=31536000\r\n"));
+        assert!(response.contains("Hello, world!HTTP/1.1 200 OK"));
+        assert!(response.contains(r#""id":3,"rank":"28,818""#));
+    }
+
+    #[cfg(feature = "http2")]
+    #[test]
+    fn test_http2() {
+        let address = start_tls_server();
+        let responses = tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let stream = connect_tls(address, &[b"h2", b"http/1.1"]).await;
+            assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+            let (client, connection) = h2::client::handshake(stream).await.unwrap();
+            tokio::spawn(connection);
+
+            // Every request goes out before any response is read, all on the one connection
+            let mut pending = Vec::new();
+            for (method, path, body) in [("GET", "/hello", ""), ("POST", "/entries/bulk", "[]"), ("HEAD", "/hello", "")] {
+                let mut client = client.clone().ready().await.unwrap();
+                let mut request = h2_http::Request::builder().method(method).uri(format!("https://localhost{path}"));
+                if !body.is_empty() {
+                    request = request.header("content-type", "application/json");
+                }
+                let request = request.body(()).unwrap();
+                let (response, mut send) = client.send_request(request, body.is_empty()).unwrap();
+                if !body.is_empty() {
+                    send.send_data(bytes::Bytes::from(body), true).unwrap();
+                }
+                pending.push(response);
+            }
+            let mut responses = Vec::new();
+            for response in pending {
+                let (head, mut body) = response.await.unwrap().into_parts();
+                let mut bytes = Vec::new();
+                while let Some(chunk) = body.data().await {
+                    bytes.extend_from_slice(&chunk.unwrap());
+                }
+                responses.push((head, String::from_utf8(bytes).unwrap()));
+            }
+            responses
+        });
+
+        let (head, body) = &responses[0];
+        assert_eq!((head.status.as_u16(), body.as_str()), (200, "Hello, world!"));
+        assert_eq!(head.headers["content-length"], "13");
+        assert!(head.headers.contains_key("x-request-id"));
+        // Nothing about an HTTP/1.1 connection
+        assert!(!head.headers.contains_key("keep-alive") && !head.headers.contains_key("connection"));
+        let (head, body) = &responses[1];
+        assert_eq!(head.status.as_u16(), 400);
+        assert!(body.contains("Expected at least one entry"));
+        let (head, body) = &responses[2];
+        assert_eq!((head.status.as_u16(), body.as_str()), (200, ""));
+    }
+
+    #[cfg(feature = "acme")]
+    #[test]
+    fn test_acme() {
+        use std::fs;
+
+        // The CA's challenge is answered ahead of the routes, on any listener
+        let mut answering = acme::Answering::default();
+        answering.add("token-1", "token-1.thumbprint");
+        let response = client_on(Routes::Admin).get("/.well-known/acme-challenge/token-1");
+        assert_eq!((response.status, response.text().as_str()), (200, "token-1.thumbprint"));
+        assert_eq!(new_client().get("/.well-known/acme-challenge/token-2").status, 404);
+        drop(answering);
+        assert_eq!(new_client().get("/.well-known/acme-challenge/token-1").status, 404);
+
+        // A listener waiting for its first certificate refuses handshakes, then
+        // serves the certificate written to its files without a restart
+        let root = std::env::temp_dir().join(format!("acme-test-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let files = tls::TlsFiles {
+            cert: root.join("cert.pem").to_str().unwrap().into(),
+            key: root.join("key.pem").to_str().unwrap().into(),
+        };
+        assert!(tls::server_config(&files, false).is_err());
+        let address = serve_tls(tls::server_config(&files, true).unwrap());
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        assert!(runtime.block_on(try_connect_tls(address, &[b"http/1.1"])).is_err());
+        assert!(tls::reload(&files).is_err());
+
+        fs::copy("assets/tls/localhost.pem", &files.cert).unwrap();
+        fs::copy("assets/tls/localhost-key.pem", &files.key).unwrap();
+        tls::reload(&files).unwrap();
+        assert!(runtime.block_on(try_connect_tls(address, &[b"http/1.1"])).is_ok());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(feature = "oauth")]
+    #[test]
+    fn test_oauth() {
+        // A provider answering the token exchange and then who the token belongs to
+        let provider = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = provider.local_addr().unwrap().port();
+        let (asked, requests) = mpsc::channel();
+        thread::spawn(move || {
+            for (stream, answer) in provider.incoming().zip([r#"{"access_token": "t0k"}"#, r#"{"email": "luffy@example.com"}"#]) {
+                let mut stream = stream.unwrap();
+                let mut request = vec![0; 4096];
+                let read = stream.read(&mut request).unwrap();
+                asked.send(String::from_utf8_lossy(&request[..read]).into_owned()).unwrap();
+                let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{answer}", answer.len());
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        let oauth: oauth::OAuthConfig = serde_json::from_value(json!({
+            "client_id": "server", "client_secret": "s3cret", "redirect_uri": "http://localhost:7878/auth/callback",
+            "authorize_url": "https://provider.example/authorize",
+            "token_url": format!("http://127.0.0.1:{port}/token"),
+            "userinfo_url": format!("http://127.0.0.1:{port}/userinfo"),
+            "allowed_users": ["Luffy@example.com"],
+        }))
+        .unwrap();
+        let with_cookie = |cookie: &str| {
+            let mut headers = HeaderMap::new();
+            headers.append("Cookie", cookie);
+            headers
+        };
+        let cookie_value = |response: &Response, name: &str| {
+            let cookie = response.headers.get_all("Set-Cookie").find(|cookie| cookie.starts_with(name)).unwrap();
+            cookie[name.len() + 1..].split(';').next().unwrap().to_string()
+        };
+
+        // Login sends the browser to the provider with a state and a PKCE challenge
+        let login = oauth::login(&oauth, &Request::new("/auth/login?next=/ui", &HeaderMap::new(), b""));
+        assert_eq!(login.status, StatusCode::Found);
+        let location = login.headers.get("Location").unwrap();
+        assert!(location.starts_with("https://provider.example/authorize?response_type=code&client_id=server&"));
+        assert!(location.contains("redirect_uri=http%3A%2F%2Flocalhost%3A7878%2Fauth%2Fcallback"));
+        assert!(location.contains("&code_challenge_method=S256"));
+        let state = cookie_value(&login, "oauth_state");
+        assert!(location.contains(&format!("&state={state}&")));
+
+        // The state only counts in the browser it was given to
+        let callback = format!("/auth/callback?code=c0de&state={state}");
+        let response = oauth::callback(&oauth, &Request::new(&callback, &with_cookie("oauth_state=other"), b""));
+        assert_eq!(response.status, StatusCode::BadRequest);
+        let login = oauth::login(&oauth, &Request::new("/auth/login?next=//evil.example", &HeaderMap::new(), b""));
+        let state = cookie_value(&login, "oauth_state");
+        let callback = format!("/auth/callback?code=c0de&state={state}");
+        let response = oauth::callback(&oauth, &Request::new(&callback, &with_cookie(&format!("oauth_state={state}")), b""));
+        assert_eq!(response.status, StatusCode::SeeOther);
+        assert_eq!(response.headers.get("Location"), Some("/"));
+        let token_request = requests.recv().unwrap();
+        assert!(token_request.starts_with("POST /token HTTP/1.1\r\n"));
+        assert!(token_request.contains("grant_type=authorization_code&code=c0de&") && token_request.contains("&code_verifier="));
+        assert!(requests.recv().unwrap().contains("Authorization: Bearer t0k\r\n"));
+
+        // The session cookie signs the browser in until it logs out
+        let session = with_cookie(&format!("oauth_session={}", cookie_value(&response, "oauth_session")));
+        assert_eq!(oauth::user(&session), Some("luffy@example.com".to_string()));
+        let response = oauth::callback(&oauth, &Request::new(&callback, &with_cookie(&format!("oauth_state={state}")), b""));
+        assert_eq!(response.status, StatusCode::BadRequest);
+        assert_eq!(oauth::logout(&oauth, &Request::new("/auth/logout", &session, b"")).status, StatusCode::NoContent);
+        assert_eq!(oauth::user(&session), None);
+
+        // Unconfigured there is no sign-in, the admin routes only take the token
+        assert_eq!(new_client().get("/auth/login").status, 404);
+        assert_eq!(new_client().get("/admin/runtime").status, 401);
+        assert_eq!(admin_client().get("/admin/runtime").status, 200);
+    }
+
+    #[test]
+    fn test_rejected_request_response() {
+        start_server();
+        thread::sleep(Duration::from_secs(1));
+
+        // Smuggling attempts are answered with a status instead of a dropped connection
+        let request = "POST /submit HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Length: 4\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n";
+        let response = send_request(request);
+        assert!(response.starts_with("HTTP/1.1 501 NOT IMPLEMENTED"));
+        assert!(response.contains("Connection: close"));
+
+        let request = "GET / HTTP/1.1\r\nHost: 127.0.0.1\r\nBad Header: x\r\n\r\n";
+        assert!(send_request(request).starts_with("HTTP/1.1 400 BAD REQUEST"));
+    }
+
+    #[test]
+    fn test_multipart_parser() {
+        // A file part larger than one read, with near-misses of the delimiter inside
+        let file: Vec<u8> = (0..20_000u32)
+            .flat_map(|i| if i % 1000 == 0 { b"\r\n--XYZ".to_vec() } else { vec![(i % 251) as u8] })
+            .collect();
+        let mut body = b"preamble\r\n--XYZX\r\n".to_vec();
+        body.extend_from_slice(b"Content-Disposition: form-data; name=\"title\"\r\n\r\nhello\r\n--XYZX\r\n");
+        body.extend_from_slice(
+            b"Content-Disposition: form-data; name=\"file\"; filename=\"a;b.bin\"\r\nContent-Type: application/octet-stream\r\n\r\n",
+        );
+        body.extend_from_slice(&file);
+        body.extend_from_slice(b"\r\n--XYZX--\r\n");
+
+        let boundary = multipart::boundary("multipart/form-data; boundary=\"XYZX\"").unwrap();
+        let mut parts = multipart::Multipart::new(body.as_slice(), &boundary);
+
+        let part = parts.next_part().unwrap().unwrap();
+        assert_eq!(part.name, "title");
+        assert_eq!(part.filename, None);
+        let mut value = Vec::new();
+        parts.copy_body(&mut value).unwrap();
+        assert_eq!(value, b"hello");
+
+        let part = parts.next_part().unwrap().unwrap();
+        assert_eq!(part.filename.as_deref(), Some("a;b.bin"));
+        assert_eq!(part.content_type.as_deref(), Some("application/octet-stream"));
+        let mut contents = Vec::new();
+        assert_eq!(parts.copy_body(&mut contents).unwrap(), file.len() as u64);
+        assert_eq!(contents, file);
+
+        assert!(parts.next_part().unwrap().is_none());
+        assert_eq!(multipart::boundary("application/json"), None);
+
+        // A body cut off before the closing boundary is an error, not a short file
+        let mut parts = multipart::Multipart::new(&body[..body.len() - 12], &boundary);
+        parts.next_part().unwrap();
+        assert!(parts.next_part().unwrap().is_some());
+        assert!(parts.copy_body(&mut std::io::sink()).is_err());
+    }
+
+    #[test]
+    fn test_upload() {
+        start_server();
+        thread::sleep(Duration::from_secs(1));
+
+        let body = "--b0undary\r\nContent-Disposition: form-data; name=\"file\"; filename=\"../notes.txt\"\r\nContent-Type: text/plain\r\n\r\nstraw hat\r\n--b0undary--\r\n";
+        let request = format!(
+            "POST /upload HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Type: multipart/form-data; boundary=b0undary\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
         );
+        let response = send_request(&request);
+        assert!(response.starts_with("HTTP/1.1 201 CREATED"));
+
+        let json = response.split("\r\n\r\n").nth(1).unwrap();
+        let result: serde_json::Value = serde_json::from_str(json).unwrap();
+        let url = result["files"][0]["url"].as_str().unwrap();
+        assert!(url.starts_with("/uploads/") && url.ends_with("-notes.txt"));
+        assert_eq!(result["files"][0]["size"], 9);
+
+        let response = send_request(&format!("GET {} HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n", url));
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("\r\n\r\nstraw hat"));
+        std::fs::remove_file(format!("uploads/{}", &url["/uploads/".len()..])).unwrap();
+
+        // Anything but multipart is refused
+        let response = send_request("POST /upload HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Length: 2\r\nContent-Type: text/plain\r\n\r\nhi");
+        assert!(response.starts_with("HTTP/1.1 415 UNSUPPORTED MEDIA TYPE"));
+    }
+
+    #[test]
+    fn test_parse_request_compressed_body() {
+        let json = r#"{"id": 1, "name": "Romance Dawn"}"#;
+        let gzip_body = compression::compress(json.as_bytes(), compression::Encoding::Gzip);
+        let mut zlib = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        zlib.write_all(json.as_bytes()).unwrap();
+        let zlib_body = zlib.finish().unwrap();
+        let raw_deflate_body = compression::compress(json.as_bytes(), compression::Encoding::Deflate);
+
+        for (coding, encoded) in [("gzip", &gzip_body), ("deflate", &zlib_body), ("deflate", &raw_deflate_body)] {
+            let mut request = format!(
+                "POST /submit HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Type: application/json\r\nContent-Encoding: {}\r\nContent-Length: {}\r\n\r\n",
+                coding,
+                encoded.len()
+            )
+            .into_bytes();
+            request.extend_from_slice(encoded);
+            let mut buf_reader = BufReader::new(request.as_slice());
+            let (_, _, _, _, body) = parser::parse_request_with(&mut buf_reader, true).unwrap();
+            assert_eq!(body, json.as_bytes(), "{}", coding);
+        }
+
+        let request = "POST /submit HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Encoding: br\r\nContent-Length: 2\r\n\r\nhi";
+        let error = parser::parse_request_with(&mut BufReader::new(request.as_bytes()), true).unwrap_err();
+        assert_eq!(error.status(), StatusCode::UnsupportedMediaType);
+
+        let request = "POST /submit HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Encoding: gzip\r\nContent-Length: 2\r\n\r\nhi";
+        let error = parser::parse_request_with(&mut BufReader::new(request.as_bytes()), true).unwrap_err();
+        assert_eq!(error.status(), StatusCode::BadRequest);
+
+        // Decoding stops at the limit instead of inflating the whole body
+        let zeros = compression::compress(&[0; 4096], compression::Encoding::Gzip);
+        let error = compression::decompress(&zeros, compression::Encoding::Gzip, 1024).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::FileTooLarge);
+        assert_eq!(compression::decompress(&zeros, compression::Encoding::Gzip, 4096).unwrap().len(), 4096);
+    }
+
+    #[test]
+    fn test_loggable_headers() {
+        let config: config::Config = serde_json::from_str(r#"{"csrf": {"header": "X-Token"}}"#).unwrap();
+        let mut headers = HeaderMap::new();
+        headers.append("Host", "localhost");
+        headers.append("authorization", "Bearer s3cret");
+        headers.append("Cookie", "session=abc");
+        headers.append("X-Api-Key", "key");
+        headers.append("X-Token", "csrf");
+        let logged = format!("{:?}", loggable_headers(&config, &headers));
+        for secret in ["s3cret", "abc", "\"key\"", "\"csrf\""] {
+            assert!(!logged.contains(secret), "{logged}");
+        }
+        let loggable = loggable_headers(&config, &headers);
+        assert_eq!(loggable.get("Host"), Some("localhost"));
+        assert_eq!(loggable.get("Authorization"), Some("[redacted]"));
+        assert_eq!(loggable.iter().count(), 5);
+    }
+
+    #[test]
+    fn test_parse_cookies() {
+        let mut headers = HeaderMap::new();
+        headers.append("Cookie", "sessionId=abc123; userId=789; lang=en");
 
         // Parse cookies
         let cookies = parse_cookies(&headers);
@@ -578,10 +5598,63 @@ mod tests {
         assert_eq!(cookies.get("lang").unwrap(), "en");
     }
 
+    #[test]
+    fn test_parse_cookies_rfc6265() {
+        let mut headers = HeaderMap::new();
+        headers.append("Cookie", "quoted=\"a b\"; empty=; blank=\"\"; pair=x=y;  spaced = v ; flag; =nameless");
+        headers.append("Cookie", "theme=dark; quoted=second; theme=light");
+
+        let cookies = parse_cookies(&headers);
+        // Quoted values lose their quotes, empty ones are kept
+        assert_eq!(cookies["quoted"], "a b");
+        assert_eq!((cookies["empty"].as_str(), cookies["blank"].as_str()), ("", ""));
+        // The value runs to the next semicolon, around it whitespace doesn't count
+        assert_eq!(cookies["pair"], "x=y");
+        assert_eq!(cookies["spaced"], "v");
+        // Pairs without an = or a name are no cookies
+        assert!(!cookies.contains_key("flag") && !cookies.contains_key(""));
+        // The first of the same name wins, across headers too
+        assert_eq!(cookies["theme"], "dark");
+        assert_eq!(cookies.len(), 6);
+    }
+
+    #[test]
+    fn test_parse_cookies_case_insensitive_multiple() {
+        // Header names match in any case and every Cookie header is read
+        let mut headers = HeaderMap::new();
+        headers.append("cookie", "sessionId=abc123");
+        headers.append("COOKIE", "lang=en");
+
+        let cookies = parse_cookies(&headers);
+        assert_eq!(cookies.get("sessionId").unwrap(), "abc123");
+        assert_eq!(cookies.get("lang").unwrap(), "en");
+    }
+
+    #[test]
+    fn test_header_map() {
+        let mut headers = HeaderMap::new();
+        headers.append("Content-Length", "10");
+        headers.append("Set-Cookie", "a=1");
+        headers.append("set-cookie", "b=2");
+
+        assert_eq!(headers.get("content-length"), Some("10"));
+        assert_eq!(headers.get("CONTENT-LENGTH"), Some("10"));
+        let cookies: Vec<&str> = headers.get_all("Set-Cookie").collect();
+        assert_eq!(cookies, vec!["a=1", "b=2"]);
+
+        // insert replaces every earlier value
+        headers.insert("SET-COOKIE", "c=3");
+        let cookies: Vec<&str> = headers.get_all("set-cookie").collect();
+        assert_eq!(cookies, vec!["c=3"]);
+
+        headers.remove("content-length");
+        assert_eq!(headers.get("Content-Length"), None);
+    }
+
     #[test]
     fn test_parse_cookies_empty() {
         // No cookies in the headers
-        let headers = HashMap::new();
+        let headers = HeaderMap::new();
         let cookies = parse_cookies(&headers);
         assert!(cookies.is_empty());
     }
@@ -615,22 +5688,46 @@ mod tests {
         assert_eq!(cookies[0], "sessionId=abc123; Path=/; HttpOnly");
     }
 
+    #[cfg(feature = "oauth")]
     #[test]
-    fn test_is_cookie_expired() {
-        // Check if a past date is expired and a future date is not
-        let past_date = "Wed, 01 Jan 2020 00:00:00 GMT";
-        let future_date = "Wed, 01 Jan 2030 00:00:00 GMT";
+    fn test_session_expiry() {
+        let sessions = session::Store::new();
+        let id = sessions.start("octocat".to_string(), chrono::Duration::hours(1)).unwrap();
+        assert_eq!(sessions.get(&id).as_deref(), Some("octocat"));
+        assert_eq!(sessions.get("made-up"), None);
+        sessions.end(&id);
+        assert_eq!(sessions.get(&id), None);
 
-        // Check the expiration status
-        assert!(is_cookie_expired(past_date));
-        assert!(!is_cookie_expired(future_date));
+        // A session just past its time is still accepted, one beyond the leeway is not
+        let leeway = config::get().clock_skew_leeway_secs as i64;
+        let just_past = sessions.start("a".to_string(), chrono::Duration::seconds(-leeway / 2)).unwrap();
+        let long_past = sessions.start("b".to_string(), chrono::Duration::seconds(-leeway * 2)).unwrap();
+        assert!(sessions.get(&just_past).is_some());
+        assert_eq!(sessions.get(&long_past), None);
     }
 
     #[test]
-    fn test_is_cookie_expired_invalid_format() {
-        // Check an invalid date format
-        let invalid_date = "Invalid Date";
-        assert!(!is_cookie_expired(invalid_date));
+    fn test_clock_jump() {
+        let threshold = Duration::from_secs(5);
+        let start = SystemTime::now();
+
+        // Wall clock and monotonic clock agree
+        let steady = start + Duration::from_secs(10);
+        assert_eq!(clock::clock_jump(start, steady, Duration::from_secs(10), threshold), None);
+
+        // Wall clock moved a minute in ten seconds
+        let forward = start + Duration::from_secs(60);
+        assert_eq!(
+            clock::clock_jump(start, forward, Duration::from_secs(10), threshold),
+            Some(50_000)
+        );
+
+        // Wall clock went back in time
+        let backward = start - Duration::from_secs(30);
+        assert_eq!(
+            clock::clock_jump(start, backward, Duration::from_secs(10), threshold),
+            Some(-40_000)
+        );
     }
 
     #[test]
@@ -0,0 +1,97 @@
+// request metrics, enabled with the `metrics` cargo feature
+//
+// every request the router answers is counted under its method and the
+// pattern of the route it matched, "GET /entries/{id}", or "unmatched" for the
+// ones no route took: how long it took as a histogram, and how many of each
+// status it was answered with. GET /admin/metrics serves them, and how often
+// the response cache was used, in the Prometheus text format.
+
+use crate::http::{Request, Response, StatusCode};
+use crate::{connections, response_cache};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{LazyLock, Mutex, PoisonError};
+use std::time::Duration;
+
+pub(crate) const MEDIA_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+// upper bounds of the duration buckets, in seconds
+const BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+// the route label of requests no route matched
+pub(crate) const UNMATCHED: &str = "unmatched";
+
+#[derive(Default)]
+struct RouteMetrics {
+    // requests per bucket, not cumulative, the last one past every bound
+    buckets: [u64; BUCKETS.len() + 1],
+    seconds: f64,
+    statuses: BTreeMap<u16, u64>,
+}
+
+// by method and route pattern, sorted so the output is stable
+static ROUTES: LazyLock<Mutex<BTreeMap<(String, String), RouteMetrics>>> = LazyLock::new(Mutex::default);
+
+pub(crate) fn record(method: &str, route: &str, status: StatusCode, duration: Duration) {
+    let seconds = duration.as_secs_f64();
+    let mut routes = ROUTES.lock().unwrap_or_else(PoisonError::into_inner);
+    let metrics = routes.entry((method.to_string(), route.to_string())).or_default();
+    let bucket = BUCKETS.iter().position(|bound| seconds <= *bound).unwrap_or(BUCKETS.len());
+    metrics.buckets[bucket] += 1;
+    metrics.seconds += seconds;
+    *metrics.statuses.entry(status.code()).or_default() += 1;
+}
+
+// every metric in the Prometheus text exposition format
+pub(crate) fn render() -> String {
+    let routes = ROUTES.lock().unwrap_or_else(PoisonError::into_inner);
+    let mut out = String::new();
+    out.push_str("# HELP http_request_duration_seconds Time taken to answer requests.\n");
+    out.push_str("# TYPE http_request_duration_seconds histogram\n");
+    for ((method, route), metrics) in routes.iter() {
+        let labels = format!("method=\"{method}\",route=\"{}\"", escape(route));
+        let mut count = 0;
+        for (bound, requests) in BUCKETS.iter().zip(&metrics.buckets) {
+            count += requests;
+            let _ = writeln!(out, "http_request_duration_seconds_bucket{{{labels},le=\"{bound}\"}} {count}");
+        }
+        count += metrics.buckets[BUCKETS.len()];
+        let _ = writeln!(out, "http_request_duration_seconds_bucket{{{labels},le=\"+Inf\"}} {count}");
+        let _ = writeln!(out, "http_request_duration_seconds_sum{{{labels}}} {}", metrics.seconds);
+        let _ = writeln!(out, "http_request_duration_seconds_count{{{labels}}} {count}");
+    }
+    out.push_str("# HELP http_responses_total Responses sent, by status.\n");
+    out.push_str("# TYPE http_responses_total counter\n");
+    for ((method, route), metrics) in routes.iter() {
+        for (status, responses) in &metrics.statuses {
+            let _ = writeln!(
+                out,
+                "http_responses_total{{method=\"{method}\",route=\"{}\",status=\"{status}\"}} {responses}",
+                escape(route)
+            );
+        }
+    }
+    let (hits, misses) = response_cache::counts();
+    out.push_str("# HELP http_response_cache_hits_total Requests answered from the response cache.\n");
+    out.push_str("# TYPE http_response_cache_hits_total counter\n");
+    let _ = writeln!(out, "http_response_cache_hits_total {hits}");
+    out.push_str("# HELP http_response_cache_misses_total Cacheable requests the handler had to answer.\n");
+    out.push_str("# TYPE http_response_cache_misses_total counter\n");
+    let _ = writeln!(out, "http_response_cache_misses_total {misses}");
+    out.push_str("# HELP http_connections_open Connections being answered.\n");
+    out.push_str("# TYPE http_connections_open gauge\n");
+    let _ = writeln!(out, "http_connections_open {}", connections::open());
+    out.push_str("# HELP http_connections_rejected_total Connections answered 503 for being over connection_limit.\n");
+    out.push_str("# TYPE http_connections_rejected_total counter\n");
+    let _ = writeln!(out, "http_connections_rejected_total {}", connections::rejected());
+    out
+}
+
+// label values are quoted, backslashes and quotes in them escaped
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+pub(crate) fn endpoint(_request: &Request) -> Response {
+    Response::new(StatusCode::Ok).content_type(MEDIA_TYPE).body(render().into_bytes())
+}
@@ -0,0 +1,208 @@
+// streaming multipart/form-data parser (RFC 7578, RFC 2046 5.1)
+//
+// parts are handed out one at a time and their bodies copied straight into a
+// writer, so a large file never has to fit in memory. the delimiter is searched
+// across reads, only a delimiter's worth of bytes is held back between chunks.
+
+use std::io::{self, Read, Write};
+
+// longest header block accepted for a single part
+const MAX_PART_HEADER_BYTES: usize = 8 * 1024;
+
+// the boundary of a multipart/form-data Content-Type, None for anything else
+pub(crate) fn boundary(content_type: &str) -> Option<String> {
+    let (media_type, _) = content_type.split_once(';').unwrap_or((content_type, ""));
+    if !media_type.trim().eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    let boundary = parameter(content_type, "boundary")?;
+    (1..=70).contains(&boundary.len()).then_some(boundary)
+}
+
+// a `key=value` or `key="quoted value"` parameter of a header value
+fn parameter(header_value: &str, key: &str) -> Option<String> {
+    split_unquoted(header_value, ';').into_iter().skip(1).find_map(|item| {
+        let (name, value) = item.split_once('=')?;
+        if !name.trim().eq_ignore_ascii_case(key) {
+            return None;
+        }
+        let value = value.trim();
+        match value.strip_prefix('"').and_then(|value| value.strip_suffix('"')) {
+            Some(quoted) => Some(quoted.replace("\\\"", "\"").replace("\\\\", "\\")),
+            None => Some(value.to_string()),
+        }
+    })
+}
+
+// splits on `separator` except inside double quotes
+fn split_unquoted(value: &str, separator: char) -> Vec<&str> {
+    let mut items = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if c == separator && !quoted => {
+                items.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(&value[start..]);
+    items
+}
+
+// the headers of one part
+#[derive(Debug, PartialEq)]
+pub(crate) struct Part {
+    pub(crate) name: String,
+    pub(crate) filename: Option<String>,
+    pub(crate) content_type: Option<String>,
+}
+
+pub(crate) struct Multipart<R: Read> {
+    reader: R,
+    // CRLF "--" boundary, what ends every part body
+    delimiter: Vec<u8>,
+    buffer: Vec<u8>,
+    started: bool,
+    // a part was returned whose body hasn't been copied out yet
+    in_body: bool,
+    done: bool,
+}
+
+impl<R: Read> Multipart<R> {
+    pub(crate) fn new(reader: R, boundary: &str) -> Multipart<R> {
+        Multipart {
+            reader,
+            delimiter: format!("\r\n--{boundary}").into_bytes(),
+            // the first boundary has no CRLF in front of it, pretend it does so
+            // the preamble is skipped like any other part body
+            buffer: b"\r\n".to_vec(),
+            started: false,
+            in_body: false,
+            done: false,
+        }
+    }
+
+    // the headers of the next part, None once the closing boundary was read
+    pub(crate) fn next_part(&mut self) -> io::Result<Option<Part>> {
+        if self.done {
+            return Ok(None);
+        }
+        if !self.started || self.in_body {
+            // the preamble, or a body the caller didn't want
+            self.copy_body(&mut io::sink())?;
+        }
+        self.started = true;
+
+        // after a boundary comes "--" for the last one, or optional padding and CRLF
+        self.fill_to(2)?;
+        if self.buffer.starts_with(b"--") {
+            self.done = true;
+            return Ok(None);
+        }
+        if !self.read_line()?.trim().is_empty() {
+            return Err(invalid("unexpected data after boundary"));
+        }
+
+        let mut name = None;
+        let mut filename = None;
+        let mut content_type = None;
+        let mut header_bytes = 0;
+        loop {
+            let line = self.read_line()?;
+            header_bytes += line.len();
+            if header_bytes > MAX_PART_HEADER_BYTES {
+                return Err(invalid("part headers too large"));
+            }
+            if line.is_empty() {
+                break;
+            }
+            let (header, value) = line.split_once(':').ok_or_else(|| invalid("invalid part header"))?;
+            if header.trim().eq_ignore_ascii_case("Content-Disposition") {
+                name = parameter(value, "name");
+                filename = parameter(value, "filename");
+            } else if header.trim().eq_ignore_ascii_case("Content-Type") {
+                content_type = Some(value.trim().to_string());
+            }
+        }
+
+        let name = name.ok_or_else(|| invalid("part without a form-data name"))?;
+        self.in_body = true;
+        Ok(Some(Part { name, filename, content_type }))
+    }
+
+    // copies the body of the part last returned by next_part, returning its size
+    pub(crate) fn copy_body(&mut self, writer: &mut impl Write) -> io::Result<u64> {
+        let mut written = 0;
+        loop {
+            if let Some(at) = find(&self.buffer, &self.delimiter) {
+                writer.write_all(&self.buffer[..at])?;
+                self.buffer.drain(..at + self.delimiter.len());
+                self.in_body = false;
+                return Ok(written + at as u64);
+            }
+            // everything but a possible start of the delimiter is body
+            let keep = self.delimiter.len() - 1;
+            if self.buffer.len() > keep {
+                let flush = self.buffer.len() - keep;
+                writer.write_all(&self.buffer[..flush])?;
+                self.buffer.drain(..flush);
+                written += flush as u64;
+            }
+            if self.fill()? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "multipart body ended before the closing boundary",
+                ));
+            }
+        }
+    }
+
+    // reads a CRLF terminated line, without the CRLF
+    fn read_line(&mut self) -> io::Result<String> {
+        loop {
+            if let Some(end) = find(&self.buffer, b"\r\n") {
+                let line = String::from_utf8(self.buffer[..end].to_vec())
+                    .map_err(|_| invalid("part header is not valid UTF-8"))?;
+                self.buffer.drain(..end + 2);
+                return Ok(line);
+            }
+            if self.buffer.len() > MAX_PART_HEADER_BYTES {
+                return Err(invalid("part header line too long"));
+            }
+            if self.fill()? == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "multipart body ended in a header"));
+            }
+        }
+    }
+
+    fn fill_to(&mut self, length: usize) -> io::Result<()> {
+        while self.buffer.len() < length {
+            if self.fill()? == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "multipart body ended after a boundary"));
+            }
+        }
+        Ok(())
+    }
+
+    fn fill(&mut self) -> io::Result<usize> {
+        let mut chunk = [0u8; 8192];
+        let read = self.reader.read(&mut chunk)?;
+        self.buffer.extend_from_slice(&chunk[..read]);
+        Ok(read)
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
@@ -0,0 +1,387 @@
+// sign-in through an OAuth2 / OpenID Connect provider, enabled with the `oauth` cargo feature
+//
+// with `oauth` configured, the `protect`ed paths (the admin routes and /ui by
+// default) need a signed-in browser. one that isn't is sent to /auth/login,
+// which hands it to the provider with the authorization code flow and PKCE.
+// the provider sends it back to /auth/callback, where the code is exchanged
+// for an access token and the user is read from `userinfo_url`: the email,
+// else GitHub's login, else the OIDC subject. when `allowed_users` is empty
+// everyone who signs in is let in, otherwise only the users it lists. they
+// get a session cookie for `session_secs`, and POST /auth/logout ends it.
+// sessions are kept in memory, a restart signs everyone out. clients sending
+// the admin token are still let in to the admin routes without a session.
+//
+//   "oauth": { "provider": "github", "client_id": "...", "client_secret": "...",
+//              "redirect_uri": "https://example.com/auth/callback", "allowed_users": ["octocat"] }
+//
+// "google" and "github" fill in the provider's URLs and scopes, "generic"
+// (the default) takes them from `authorize_url`, `token_url` and `userinfo_url`.
+
+use crate::config::{self, Config};
+use crate::http::{HeaderMap, Request, Response, StatusCode};
+use crate::router::Next;
+use crate::session::Store;
+use crate::client::{self, Outgoing, Reply};
+use crate::log;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rustls::crypto::ring;
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::{self, BufReader, Write};
+use std::sync::{Arc, LazyLock, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+pub(crate) const LOGIN_PATH: &str = "/auth/login";
+const SESSION_COOKIE: &str = "oauth_session";
+const STATE_COOKIE: &str = "oauth_state";
+
+// how long a browser may take to come back from the provider
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Provider {
+    #[default]
+    Generic,
+    Google,
+    GitHub,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct OAuthConfig {
+    /// "google", "github" or "generic", whose URLs have to be given.
+    pub(crate) provider: Provider,
+    /// The client registered with the provider.
+    pub(crate) client_id: String,
+    pub(crate) client_secret: String,
+    /// Where the provider sends the browser back to, this server's /auth/callback.
+    pub(crate) redirect_uri: String,
+    /// The provider's authorization, token and userinfo endpoints, over its defaults.
+    pub(crate) authorize_url: Option<String>,
+    pub(crate) token_url: Option<String>,
+    pub(crate) userinfo_url: Option<String>,
+    /// Scopes asked for, the provider's defaults when unset.
+    pub(crate) scopes: Option<Vec<String>>,
+    /// Emails or logins let in, everyone who signs in when empty.
+    pub(crate) allowed_users: Vec<String>,
+    /// Seconds a session lasts.
+    pub(crate) session_secs: u64,
+    /// Paths ("/ui") and prefixes ("/admin/*") that need a session.
+    pub(crate) protect: Vec<String>,
+}
+
+impl Default for OAuthConfig {
+    fn default() -> Self {
+        OAuthConfig {
+            provider: Provider::Generic,
+            client_id: String::new(),
+            client_secret: String::new(),
+            redirect_uri: String::new(),
+            authorize_url: None,
+            token_url: None,
+            userinfo_url: None,
+            scopes: None,
+            allowed_users: Vec::new(),
+            session_secs: 8 * 60 * 60,
+            protect: vec!["/admin".to_string(), "/admin/*".to_string(), "/ui".to_string(), "/ui/*".to_string()],
+        }
+    }
+}
+
+// the authorization, token and userinfo endpoints and the default scopes
+struct Endpoints<'a> {
+    authorize: &'a str,
+    token: &'a str,
+    userinfo: &'a str,
+    scopes: String,
+}
+
+impl OAuthConfig {
+    fn endpoints(&self) -> Result<Endpoints<'_>, String> {
+        let (authorize, token, userinfo, scopes) = match self.provider {
+            Provider::Google => (
+                "https://accounts.google.com/o/oauth2/v2/auth",
+                "https://oauth2.googleapis.com/token",
+                "https://openidconnect.googleapis.com/v1/userinfo",
+                "openid email",
+            ),
+            Provider::GitHub => (
+                "https://github.com/login/oauth/authorize",
+                "https://github.com/login/oauth/access_token",
+                "https://api.github.com/user",
+                "read:user user:email",
+            ),
+            Provider::Generic => ("", "", "", "openid email profile"),
+        };
+        Ok(Endpoints {
+            authorize: url(&self.authorize_url, authorize, "authorize_url")?,
+            token: url(&self.token_url, token, "token_url")?,
+            userinfo: url(&self.userinfo_url, userinfo, "userinfo_url")?,
+            scopes: self.scopes.as_ref().map_or(scopes.to_string(), |scopes| scopes.join(" ")),
+        })
+    }
+
+    fn protects(&self, path: &str) -> bool {
+        self.protect.iter().any(|route| match route.strip_suffix('*') {
+            Some(prefix) => path.starts_with(prefix),
+            None => path == route,
+        })
+    }
+
+    // the cookies go back over TLS only when the server is reached over it
+    fn cookie(&self, name: &str, value: &str, max_age: u64) -> String {
+        let secure = if self.redirect_uri.starts_with("https://") { "; Secure" } else { "" };
+        format!("{name}={value}; Path=/; Max-Age={max_age}; HttpOnly; SameSite=Lax{secure}")
+    }
+}
+
+// the configured URL, else the provider's
+fn url<'a>(configured: &'a Option<String>, default: &'static str, name: &str) -> Result<&'a str, String> {
+    match configured {
+        Some(url) => Ok(url),
+        None if !default.is_empty() => Ok(default),
+        None => Err(format!("oauth needs {name} for a generic provider")),
+    }
+}
+
+// a browser sent to the provider, by the state it carries there and back
+struct Pending {
+    verifier: String,
+    next: String,
+    started: Instant,
+}
+
+static PENDING: LazyLock<Mutex<HashMap<String, Pending>>> = LazyLock::new(Mutex::default);
+// the users signed in, by the id in their session cookie
+static SESSIONS: Store<String> = Store::new();
+
+// the user a request is signed in as, in its extensions once the middleware ran
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct User(pub(crate) String);
+
+// the user signed in with the session cookie the request sends
+pub(crate) fn user(headers: &HeaderMap) -> Option<String> {
+    SESSIONS.get(&crate::parse_cookies(headers).remove(SESSION_COOKIE)?)
+}
+
+// the principal a request authenticated as with its session, for the audit log
+pub(crate) fn principal(config: &Config, headers: &HeaderMap) -> Option<String> {
+    config.oauth.as_ref()?;
+    user(headers).map(|user| format!("oauth:{user}"))
+}
+
+// router middleware keeping the protected paths to signed-in browsers and
+// clients sending the admin token, and telling the handlers who is signed in
+pub(crate) fn middleware(request: &mut Request, next: Next) -> Response {
+    let config = config::get();
+    let Some(oauth) = &config.oauth else {
+        return next(request);
+    };
+    let signed_in = user(&request.headers);
+    let admin = crate::admin_authorized(config, request.headers.get("Authorization"));
+    if let Some(user) = &signed_in {
+        request.extensions.insert(User(user.clone()));
+    }
+    if !oauth.protects(&request.path) || admin || signed_in.is_some() {
+        return next(request);
+    }
+    if crate::wants_html(&request.headers) {
+        let next = percent_encode(&request.uri);
+        return Response::redirect(StatusCode::SeeOther, format!("{LOGIN_PATH}?next={next}"));
+    }
+    Response::problem(StatusCode::Unauthorized, format!("Sign in at {LOGIN_PATH} first"))
+}
+
+// GET /auth/login, sends the browser to the provider
+pub(crate) fn login(oauth: &OAuthConfig, request: &Request) -> Response {
+    let endpoints = match oauth.endpoints() {
+        Ok(endpoints) => endpoints,
+        Err(e) => return Response::problem(StatusCode::InternalServerError, e),
+    };
+    let (state, verifier) = match (crate::random_hex(16), crate::random_hex(32)) {
+        (Ok(state), Ok(verifier)) => (state, verifier),
+        (Err(e), _) | (_, Err(e)) => {
+            return Response::problem(StatusCode::InternalServerError, format!("Failed to start signing in: {e}"))
+        }
+    };
+    // only back to a path of this server
+    let next = request.query.get("next").filter(|next| next.starts_with('/') && !next.starts_with("//"));
+    let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+    let location = format!(
+        "{}?response_type=code&client_id={}&redirect_uri={}&scope={}&state={state}&code_challenge={challenge}&code_challenge_method=S256",
+        endpoints.authorize,
+        percent_encode(&oauth.client_id),
+        percent_encode(&oauth.redirect_uri),
+        percent_encode(&endpoints.scopes),
+    );
+    let mut pending = PENDING.lock().unwrap_or_else(PoisonError::into_inner);
+    pending.retain(|_, login| login.started.elapsed() < LOGIN_TIMEOUT);
+    let next = next.cloned().unwrap_or_else(|| "/".to_string());
+    pending.insert(state.clone(), Pending { verifier, next, started: Instant::now() });
+    Response::redirect(StatusCode::Found, location)
+        .header("Set-Cookie", oauth.cookie(STATE_COOKIE, &state, LOGIN_TIMEOUT.as_secs()))
+        .header("Cache-Control", "no-store")
+}
+
+// GET /auth/callback, signs in the user the provider sent back with a code
+pub(crate) fn callback(oauth: &OAuthConfig, request: &Request) -> Response {
+    if let Some(error) = request.query.get("error") {
+        return Response::problem(StatusCode::Forbidden, format!("The provider refused to sign you in: {error}"));
+    }
+    let (Some(code), Some(state)) = (request.query.get("code"), request.query.get("state")) else {
+        return Response::problem(StatusCode::BadRequest, "The provider sent no code or state");
+    };
+    // the state has to come back to the browser it was given to
+    let cookie = crate::parse_cookies(&request.headers).remove(STATE_COOKIE);
+    let pending = PENDING.lock().unwrap_or_else(PoisonError::into_inner).remove(state);
+    let Some(pending) = pending.filter(|login| cookie.as_ref() == Some(state) && login.started.elapsed() < LOGIN_TIMEOUT)
+    else {
+        return Response::problem(StatusCode::BadRequest, "Unknown or expired sign-in, start again");
+    };
+    let user = match identify(oauth, code, &pending.verifier) {
+        Ok(user) => user,
+        Err(e) => {
+            log::warning!("Failed to sign in through the OAuth provider: {}", e);
+            return Response::problem(StatusCode::BadGateway, format!("Failed to sign in: {e}"));
+        }
+    };
+    if !oauth.allowed_users.is_empty() && !oauth.allowed_users.iter().any(|allowed| allowed.eq_ignore_ascii_case(&user)) {
+        log::warning!("{} signed in but is not an allowed user", user);
+        return Response::problem(StatusCode::Forbidden, format!("{user} is not allowed in"));
+    }
+    let id = match SESSIONS.start(user.clone(), chrono::Duration::seconds(oauth.session_secs as i64)) {
+        Ok(id) => id,
+        Err(e) => return Response::problem(StatusCode::InternalServerError, format!("Failed to start a session: {e}")),
+    };
+    log::info!("{} signed in", user);
+    Response::redirect(StatusCode::SeeOther, pending.next)
+        .header("Set-Cookie", oauth.cookie(SESSION_COOKIE, &id, oauth.session_secs))
+        .header("Set-Cookie", oauth.cookie(STATE_COOKIE, "", 0))
+        .header("Cache-Control", "no-store")
+}
+
+// POST /auth/logout, ends the session
+pub(crate) fn logout(oauth: &OAuthConfig, request: &Request) -> Response {
+    if let Some(id) = crate::parse_cookies(&request.headers).remove(SESSION_COOKIE) {
+        SESSIONS.end(&id);
+    }
+    Response::new(StatusCode::NoContent).header("Set-Cookie", oauth.cookie(SESSION_COOKIE, "", 0))
+}
+
+// the handlers of /auth, 404 while `oauth` isn't configured
+fn configured(request: &Request, handler: fn(&OAuthConfig, &Request) -> Response) -> Response {
+    match &config::get().oauth {
+        Some(oauth) => handler(oauth, request),
+        None => Response::problem(StatusCode::NotFound, "Signing in is not configured"),
+    }
+}
+
+pub(crate) fn login_page(request: &Request) -> Response {
+    configured(request, login)
+}
+
+pub(crate) fn callback_page(request: &Request) -> Response {
+    configured(request, callback)
+}
+
+pub(crate) fn logout_endpoint(request: &Request) -> Response {
+    configured(request, logout)
+}
+
+// exchanges the code for an access token, then asks who it belongs to
+fn identify(oauth: &OAuthConfig, code: &str, verifier: &str) -> Result<String, String> {
+    let endpoints = oauth.endpoints()?;
+    let form = format!(
+        "grant_type=authorization_code&code={}&redirect_uri={}&client_id={}&client_secret={}&code_verifier={verifier}",
+        percent_encode(code),
+        percent_encode(&oauth.redirect_uri),
+        percent_encode(&oauth.client_id),
+        percent_encode(&oauth.client_secret),
+    );
+    let token = fetch("POST", endpoints.token, &[("Content-Type", "application/x-www-form-urlencoded")], &form)?;
+    let Some(access_token) = token["access_token"].as_str() else {
+        let error = token["error"].as_str().unwrap_or("no access token in the answer");
+        return Err(format!("the token endpoint refused the code: {error}"));
+    };
+    let info = fetch("GET", endpoints.userinfo, &[("Authorization", &format!("Bearer {access_token}"))], "")?;
+    ["email", "login", "sub"]
+        .into_iter()
+        .find_map(|field| info[field].as_str().filter(|value| !value.is_empty()))
+        .map(str::to_string)
+        .ok_or_else(|| "the userinfo endpoint named no user".to_string())
+}
+
+// one request to the provider, over TLS for an https:// URL, its JSON answer
+fn fetch(method: &str, url: &str, headers: &[(&str, &str)], body: &str) -> Result<serde_json::Value, String> {
+    let (tls, rest) = match (url.strip_prefix("https://"), url.strip_prefix("http://")) {
+        (Some(rest), _) => (true, rest),
+        (None, Some(rest)) => (false, rest),
+        (None, None) => return Err(format!("{url} is not an http(s) URL")),
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => (&rest[..slash], &rest[slash..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().map_err(|_| format!("{url} has an invalid port"))?),
+        None => (authority, if tls { 443 } else { 80 }),
+    };
+
+    let mut request = Outgoing::new(method, path)
+        .header("Accept", "application/json")
+        // GitHub's API refuses requests without one
+        .header("User-Agent", config::get().server_name.clone())
+        .header("Connection", "close");
+    for (name, value) in headers {
+        request = request.header(name, *value);
+    }
+    let request = request.body(body).to_bytes(authority);
+
+    let exchange = || -> io::Result<Reply> {
+        let stream = client::connect((host, port), client::CONNECT_TIMEOUT, client::IO_TIMEOUT)?;
+        if !tls {
+            (&stream).write_all(&request)?;
+            return client::read_response(&mut BufReader::new(stream), false);
+        }
+        let name = ServerName::try_from(host.to_string()).map_err(io::Error::other)?;
+        let connection = ClientConnection::new(Arc::clone(&CLIENT), name).map_err(io::Error::other)?;
+        let mut stream = StreamOwned::new(connection, stream);
+        stream.write_all(&request)?;
+        client::read_response(&mut BufReader::new(stream), false)
+    };
+    let reply = exchange().map_err(|e| format!("{url}: {e}"))?;
+    let json = serde_json::from_slice(&reply.body).map_err(|e| format!("{url} answered {}: {e}", reply.status))?;
+    Ok(json)
+}
+
+// the rustls setup trusting the system's root certificates
+static CLIENT: LazyLock<Arc<ClientConfig>> = LazyLock::new(|| {
+    let mut roots = RootCertStore::empty();
+    let native = rustls_native_certs::load_native_certs();
+    for error in &native.errors {
+        log::warning!("Failed to load a root certificate: {}", error);
+    }
+    roots.add_parsable_certificates(native.certs);
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .expect("ring supports the default protocol versions")
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Arc::new(config)
+});
+
+// escapes everything but the unreserved characters (RFC 3986 2.3)
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
@@ -0,0 +1,130 @@
+// OpenAPI 3 description of the route table, served at /openapi.json
+//
+// a route documents itself in the route table with `.doc(Doc::new(..))`, the
+// schemas of the bodies come from the ApiSchema impls kept next to the serde
+// types they describe. routes without a Doc are still listed with their path
+// parameters, the document never leaves an endpoint out.
+
+use crate::router::Router;
+use serde_json::{json, Map, Value};
+
+// a JSON Schema for a type that is sent or received as JSON, matching what
+// its Serialize/Deserialize impls produce
+pub(crate) trait ApiSchema {
+    // the name under #/components/schemas
+    const NAME: &'static str;
+    fn schema() -> Value;
+}
+
+// a reference to the component schema of `T`
+pub(crate) fn reference<T: ApiSchema>() -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", T::NAME) })
+}
+
+pub(crate) fn array_of<T: ApiSchema>() -> Value {
+    json!({ "type": "array", "items": reference::<T>() })
+}
+
+// a media type and the schema of a body sent as it
+type Body = (&'static str, Value);
+
+// the documentation of one route
+#[derive(Debug, Default)]
+pub(crate) struct Doc {
+    summary: &'static str,
+    // name, JSON Schema type and description of each query parameter
+    query: Vec<(&'static str, &'static str, &'static str)>,
+    request: Option<Body>,
+    // status, description and, when there is one, the body's media type and schema
+    responses: Vec<(u16, &'static str, Option<Body>)>,
+}
+
+impl Doc {
+    pub(crate) fn new(summary: &'static str) -> Doc {
+        Doc { summary, ..Doc::default() }
+    }
+
+    pub(crate) fn query(mut self, name: &'static str, kind: &'static str, description: &'static str) -> Doc {
+        self.query.push((name, kind, description));
+        self
+    }
+
+    pub(crate) fn request(mut self, media_type: &'static str, schema: Value) -> Doc {
+        self.request = Some((media_type, schema));
+        self
+    }
+
+    // a response without a body
+    pub(crate) fn response(mut self, status: u16, description: &'static str) -> Doc {
+        self.responses.push((status, description, None));
+        self
+    }
+
+    pub(crate) fn response_body(
+        mut self,
+        status: u16,
+        description: &'static str,
+        media_type: &'static str,
+        schema: Value,
+    ) -> Doc {
+        self.responses.push((status, description, Some((media_type, schema))));
+        self
+    }
+}
+
+// the OpenAPI document for every route of `router`, `components` holding the
+// schemas the routes refer to
+pub(crate) fn document(router: &Router, components: Map<String, Value>) -> Value {
+    let mut paths = Map::new();
+    for (method, path, doc) in router.routes() {
+        let item = paths.entry(path).or_insert_with(|| json!({}));
+        item[method.to_ascii_lowercase()] = operation(path, doc);
+    }
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": env!("CARGO_PKG_NAME"),
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": paths,
+        "components": { "schemas": components },
+    })
+}
+
+fn operation(path: &str, doc: Option<&Doc>) -> Value {
+    let default_doc = Doc::default();
+    let doc = doc.unwrap_or(&default_doc);
+
+    let path_params = path
+        .split('/')
+        .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+        .map(|name| json!({ "name": name, "in": "path", "required": true, "schema": { "type": "string" } }));
+    let query_params = doc.query.iter().map(|(name, kind, description)| {
+        json!({ "name": name, "in": "query", "description": description, "schema": { "type": kind } })
+    });
+    let parameters: Vec<Value> = path_params.chain(query_params).collect();
+
+    let mut responses = Map::new();
+    for (status, description, body) in &doc.responses {
+        let mut response = json!({ "description": description });
+        if let Some((media_type, schema)) = body {
+            response["content"] = json!({ *media_type: { "schema": schema } });
+        }
+        responses.insert(status.to_string(), response);
+    }
+    if responses.is_empty() {
+        responses.insert("default".to_string(), json!({ "description": "Response" }));
+    }
+
+    let mut operation = json!({ "responses": responses });
+    if !doc.summary.is_empty() {
+        operation["summary"] = json!(doc.summary);
+    }
+    if !parameters.is_empty() {
+        operation["parameters"] = json!(parameters);
+    }
+    if let Some((media_type, schema)) = &doc.request {
+        operation["requestBody"] = json!({ "required": true, "content": { *media_type: { "schema": schema } } });
+    }
+    operation
+}
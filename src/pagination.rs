@@ -0,0 +1,113 @@
+// Link headers (RFC 8288, formerly 5988) for walking offset/limit pages, and
+// the cursors of ?after=<cursor>&limit= pages
+//
+// each link repeats the request's own query, filters included, with only
+// offset changed, so following "next" keeps the same search. parameters are
+// written in name order to keep the links stable between requests.
+//
+// a cursor is the place of the last entry of a page in the order it was
+// sorted in, its sort value and id, rather than its position, so the next
+// page starts right after it however many entries were added or removed in
+// the meantime. clients get it hex-encoded and are meant to hand it back
+// as it is.
+
+use crate::search::{SortKey, SortValue};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+// the first, prev, next and last links of the page at `offset` of `total` entries
+pub(crate) fn links(path: &str, query: &HashMap<String, String>, offset: usize, limit: usize, total: usize) -> String {
+    let last = total.saturating_sub(1) / limit * limit;
+    let mut pages = vec![("first", 0)];
+    if offset > 0 {
+        pages.push(("prev", offset.saturating_sub(limit).min(last)));
+    }
+    if offset.saturating_add(limit) < total {
+        pages.push(("next", offset + limit));
+    }
+    pages.push(("last", last));
+
+    pages
+        .into_iter()
+        .map(|(rel, offset)| format!("<{}>; rel=\"{rel}\"", page_uri(path, query, offset, limit)))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// the Link header of the page following the one ending at `cursor`
+pub(crate) fn next_link(path: &str, query: &HashMap<String, String>, cursor: &Cursor, limit: usize) -> String {
+    let mut params = params(query);
+    params.remove("offset");
+    params.insert("after", cursor.encode());
+    params.insert("limit", limit.to_string());
+    format!("<{}>; rel=\"next\"", uri(path, &params))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct Cursor {
+    // the order it was issued for, "name" or "-name" when descending
+    pub(crate) order: String,
+    pub(crate) value: SortValue,
+    pub(crate) id: usize,
+}
+
+impl Cursor {
+    pub(crate) fn order(key: SortKey, descending: bool) -> String {
+        format!("{}{}", if descending { "-" } else { "" }, key.name())
+    }
+
+    // ?after=<id>, the place of the entry with `id` in the default id order
+    pub(crate) fn at_id(id: usize) -> Cursor {
+        Cursor { order: Cursor::order(SortKey::Id, false), value: SortValue::Number(id as u64), id }
+    }
+
+    pub(crate) fn encode(&self) -> String {
+        let json = serde_json::to_vec(self).expect("a cursor serializes");
+        json.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    // an encoded cursor or a bare id, None for anything else
+    pub(crate) fn decode(text: &str) -> Option<Cursor> {
+        if let Ok(id) = text.parse() {
+            return Some(Cursor::at_id(id));
+        }
+        if !text.len().is_multiple_of(2) || !text.is_ascii() {
+            return None;
+        }
+        let bytes = (0..text.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        serde_json::from_slice(&bytes).ok()
+    }
+}
+
+fn page_uri(path: &str, query: &HashMap<String, String>, offset: usize, limit: usize) -> String {
+    let mut params = params(query);
+    params.insert("offset", offset.to_string());
+    params.insert("limit", limit.to_string());
+    uri(path, &params)
+}
+
+fn params(query: &HashMap<String, String>) -> BTreeMap<&str, String> {
+    query.iter().map(|(name, value)| (name.as_str(), value.clone())).collect()
+}
+
+fn uri(path: &str, params: &BTreeMap<&str, String>) -> String {
+    let query: Vec<String> = params
+        .iter()
+        .map(|(name, value)| format!("{}={}", percent_encode(name), percent_encode(value)))
+        .collect();
+    format!("{path}?{}", query.join("&"))
+}
+
+// escapes everything but unreserved characters (RFC 3986 2.3)
+pub(crate) fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
@@ -0,0 +1,384 @@
+// reads an HTTP/1.1 request off a buffered stream
+//
+// the head is parsed incrementally by HeadParser, which takes bytes in
+// whatever pieces they arrive and stops at the blank line, then the body is
+// read by its Content-Length. without hardening bare LF line endings are
+// accepted as well as CRLF.
+//
+// with `hardened` set in the config (the default) the parser refuses anything
+// two implementations could disagree about: ambiguous message framing, header
+// names with whitespace or separators, control characters, obsolete line
+// folding, bare LF line endings, oversized request lines or header blocks and
+// HTTP/1.1 requests without exactly one Host.
+// `--self-test http-hardening` runs the vectors in self_test.rs against it.
+
+use crate::compression::{self, Encoding};
+use crate::config;
+use crate::http::{HeaderMap, StatusCode, Version};
+use crate::multipart;
+use std::io::{self, BufRead, BufReader, Read};
+use thiserror::Error;
+
+// longest request line accepted in hardened mode, CRLF included
+pub(crate) const MAX_REQUEST_LINE_BYTES: usize = 8 * 1024;
+// longest single header line accepted in hardened mode, CRLF included
+pub(crate) const MAX_HEADER_LINE_BYTES: usize = 8 * 1024;
+// most header lines accepted in hardened mode
+pub(crate) const MAX_HEADERS: usize = 100;
+
+#[derive(Error, Debug)]
+pub(crate) enum RequestError {
+    #[error("Failed to read request line")]
+    ReadRequestLineError,
+    #[error("Invalid request line format")]
+    InvalidRequestLineFormat,
+    #[error("Unsupported HTTP version: {0}")]
+    UnsupportedVersion(String),
+    #[error("Request line is too long")]
+    RequestLineTooLong,
+    #[error("Failed to read header line")]
+    ReadHeaderLineError,
+    #[error("Invalid header line: {0}")]
+    InvalidHeaderLine(String),
+    #[error("Header line is too long")]
+    HeaderLineTooLong,
+    #[error("Too many header lines")]
+    TooManyHeaders,
+    #[error("Content-Length exceeds available data")]
+    ContentLengthExceedsData,
+    #[error("Failed to read body")]
+    ReadBodyError,
+    #[error("Invalid Content-Length value")]
+    InvalidContentLength,
+    #[error("Conflicting Content-Length values")]
+    ConflictingContentLength,
+    #[error("HTTP/1.1 requests need a Host header")]
+    MissingHost,
+    #[error("More than one Host header")]
+    DuplicateHost,
+    #[error("Transfer-Encoding is not supported")]
+    UnsupportedTransferEncoding,
+    #[error("Unsupported Content-Encoding: {0}")]
+    UnsupportedContentEncoding(String),
+    #[error("Body does not match its Content-Encoding")]
+    InvalidContentEncoding,
+    #[error("Decoded body is too large")]
+    DecodedBodyTooLarge,
+    #[error("Request body is too large")]
+    BodyTooLarge,
+}
+
+impl RequestError {
+    // the status the connection is answered with before it is closed
+    pub(crate) fn status(&self) -> StatusCode {
+        match self {
+            RequestError::RequestLineTooLong => StatusCode::UriTooLong,
+            RequestError::HeaderLineTooLong | RequestError::TooManyHeaders => {
+                StatusCode::RequestHeaderFieldsTooLarge
+            }
+            RequestError::UnsupportedTransferEncoding => StatusCode::NotImplemented,
+            RequestError::UnsupportedVersion(_) => StatusCode::HttpVersionNotSupported,
+            RequestError::UnsupportedContentEncoding(_) => StatusCode::UnsupportedMediaType,
+            RequestError::DecodedBodyTooLarge | RequestError::BodyTooLarge => StatusCode::PayloadTooLarge,
+            _ => StatusCode::BadRequest,
+        }
+    }
+}
+
+// the head and body of a request, with the hardening checks switched on or off
+pub(crate) fn parse_request_with<R: Read>(
+    buf_reader: &mut BufReader<R>,
+    hardened: bool,
+) -> Result<(String, String, Version, HeaderMap, Vec<u8>), RequestError> {
+    let head = read_head(buf_reader, hardened)?;
+    let body = read_body(buf_reader, &head.headers)?;
+    Ok((head.method, head.uri, head.version, head.headers, body))
+}
+
+// the request line and headers, everything before the body
+#[derive(Debug)]
+pub(crate) struct RequestHead {
+    pub(crate) method: String,
+    pub(crate) uri: String,
+    pub(crate) version: Version,
+    pub(crate) headers: HeaderMap,
+}
+
+// parses a request head from bytes as they arrive, in chunks of any size
+//
+// `advance` can be called again whenever more data comes in, a line split
+// across reads is kept until its end shows up. nothing past the blank line
+// closing the head is consumed, the body is framed separately from the
+// headers by read_body.
+#[derive(Debug, Default)]
+pub(crate) struct HeadParser {
+    hardened: bool,
+    // the part of the current line received so far
+    line: Vec<u8>,
+    // method, uri and version, once the request line is complete
+    request_line: Option<(String, String, Version)>,
+    headers: HeaderMap,
+    header_count: usize,
+}
+
+impl HeadParser {
+    pub(crate) fn new(hardened: bool) -> HeadParser {
+        HeadParser { hardened, ..HeadParser::default() }
+    }
+
+    // consumes bytes up to the end of the head, returning how many were used
+    // and, once the blank line after the headers has been seen, the head
+    pub(crate) fn advance(&mut self, input: &[u8]) -> Result<(usize, Option<RequestHead>), RequestError> {
+        let mut used = 0;
+        while used < input.len() {
+            let rest = &input[used..];
+            let Some(end) = rest.iter().position(|&b| b == b'\n') else {
+                self.line.extend_from_slice(rest);
+                self.check_line_length()?;
+                return Ok((input.len(), None));
+            };
+            self.line.extend_from_slice(&rest[..=end]);
+            used += end + 1;
+            self.check_line_length()?;
+            let line = std::mem::take(&mut self.line);
+            if let Some(head) = self.finish_line(line)? {
+                return Ok((used, Some(head)));
+            }
+        }
+        Ok((used, None))
+    }
+
+    // true until the request line has been read completely
+    fn at_request_line(&self) -> bool {
+        self.request_line.is_none()
+    }
+
+    // refuses a line as soon as it outgrows its limit, so an endless line
+    // can't grow the buffer without bound
+    fn check_line_length(&self) -> Result<(), RequestError> {
+        if !self.hardened {
+            return Ok(());
+        }
+        match self.request_line {
+            None if self.line.len() > MAX_REQUEST_LINE_BYTES => Err(RequestError::RequestLineTooLong),
+            Some(_) if self.line.len() > MAX_HEADER_LINE_BYTES => Err(RequestError::HeaderLineTooLong),
+            _ => Ok(()),
+        }
+    }
+
+    fn finish_line(&mut self, line: Vec<u8>) -> Result<Option<RequestHead>, RequestError> {
+        let Some((method, uri, version)) = self.request_line.take() else {
+            let line = String::from_utf8(line).map_err(|_| RequestError::InvalidRequestLineFormat)?;
+            if self.hardened {
+                check_request_line(&line)?;
+            }
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if parts.len() < 2 {
+                return Err(RequestError::InvalidRequestLineFormat);
+            }
+            let version = parse_version(parts.get(2).copied())?;
+            self.request_line = Some((parts[0].to_string(), parts[1].to_string(), version));
+            return Ok(None);
+        };
+
+        let line = String::from_utf8(line)
+            .map_err(|e| RequestError::InvalidHeaderLine(String::from_utf8_lossy(e.as_bytes()).into_owned()))?;
+        // without hardening a bare LF ends a line as well as CRLF does
+        if line == "\r\n" || (!self.hardened && line == "\n") {
+            let headers = std::mem::take(&mut self.headers);
+            if self.hardened {
+                check_framing(&headers)?;
+                check_host(version, &headers)?;
+            }
+            return Ok(Some(RequestHead { method, uri, version, headers }));
+        }
+        self.request_line = Some((method, uri, version));
+
+        if self.hardened {
+            self.header_count += 1;
+            if self.header_count > MAX_HEADERS {
+                return Err(RequestError::TooManyHeaders);
+            }
+            let (name, value) = check_header_line(&line)?;
+            self.headers.append(name, value);
+            return Ok(None);
+        }
+
+        let content = line.strip_suffix('\n').unwrap_or(&line);
+        let content = content.strip_suffix('\r').unwrap_or(content);
+        match content.split_once(": ") {
+            Some((name, value)) => self.headers.append(name, value.trim()),
+            None => return Err(RequestError::InvalidHeaderLine(line)),
+        }
+        Ok(None)
+    }
+}
+
+// feeds the reader's buffer to a HeadParser until the head is complete,
+// leaving the body unread
+pub(crate) fn read_head<R: Read>(buf_reader: &mut BufReader<R>, hardened: bool) -> Result<RequestHead, RequestError> {
+    let mut parser = HeadParser::new(hardened);
+    let read_error = |parser: &HeadParser| match parser.at_request_line() {
+        true => RequestError::ReadRequestLineError,
+        false => RequestError::ReadHeaderLineError,
+    };
+    loop {
+        let available = buf_reader.fill_buf().map_err(|_| read_error(&parser))?;
+        // an empty buffer is the end of the stream, it ended before the head did
+        if available.is_empty() {
+            return Err(read_error(&parser));
+        }
+        let (used, head) = parser.advance(available)?;
+        buf_reader.consume(used);
+        if let Some(head) = head {
+            return Ok(head);
+        }
+    }
+}
+
+// reads the Content-Length bytes of body following the head, however many
+// reads they take to arrive. the bytes are kept as they are, whatever their
+// type, for the handler to read as text or JSON
+pub(crate) fn read_body<R: Read>(reader: &mut R, headers: &HeaderMap) -> Result<Vec<u8>, RequestError> {
+    let streamed = streamed(headers);
+    let mut body = Vec::new();
+    if let Some(content_length) = headers.get("Content-Length").filter(|_| !streamed) {
+        let length = content_length
+            .parse::<u64>()
+            .map_err(|_| RequestError::InvalidContentLength)?;
+        if length > config::get().max_body_bytes {
+            return Err(RequestError::BodyTooLarge);
+        }
+        // grown as the data comes in rather than allocated up front, a
+        // Content-Length alone doesn't get to reserve memory
+        let mut buffer = Vec::new();
+        reader
+            .take(length)
+            .read_to_end(&mut buffer)
+            .map_err(|_| RequestError::ReadBodyError)?;
+        if (buffer.len() as u64) < length {
+            return Err(RequestError::ContentLengthExceedsData);
+        }
+        body = decode_body(headers, buffer)?;
+    }
+
+    Ok(body)
+}
+
+// multipart bodies can be large, they are left in the reader for the upload
+// endpoint to stream instead of being buffered with the request
+pub(crate) fn streamed(headers: &HeaderMap) -> bool {
+    headers.get("Content-Type").and_then(multipart::boundary).is_some()
+}
+
+// undoes the Content-Encoding of a body, codings are listed in the order they
+// were applied so they are removed last to first
+fn decode_body(headers: &HeaderMap, mut body: Vec<u8>) -> Result<Vec<u8>, RequestError> {
+    let codings: Vec<&str> = headers
+        .get_all("Content-Encoding")
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|coding| !coding.is_empty() && !coding.eq_ignore_ascii_case("identity"))
+        .collect();
+    let limit = config::get().max_decompressed_body_bytes;
+    for coding in codings.into_iter().rev() {
+        let encoding = Encoding::from_name(coding)
+            .ok_or_else(|| RequestError::UnsupportedContentEncoding(coding.to_string()))?;
+        body = compression::decompress(&body, encoding, limit).map_err(|e| match e.kind() {
+            io::ErrorKind::FileTooLarge => RequestError::DecodedBodyTooLarge,
+            _ => RequestError::InvalidContentEncoding,
+        })?;
+    }
+    Ok(body)
+}
+
+// a well formed version other than HTTP/1.x is refused with 505, anything
+// else in its place is a malformed request line. a lenient request line
+// without one is taken as HTTP/1.0
+fn parse_version(version: Option<&str>) -> Result<Version, RequestError> {
+    let Some(version) = version else {
+        return Ok(Version::Http10);
+    };
+    if let Some(version) = Version::parse(version) {
+        return Ok(version);
+    }
+    let number = version.strip_prefix("HTTP/").unwrap_or_default();
+    let (major, minor) = number.split_once('.').unwrap_or((number, "0"));
+    let digits = |part: &str| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit());
+    if digits(major) && digits(minor) {
+        Err(RequestError::UnsupportedVersion(version.to_string()))
+    } else {
+        Err(RequestError::InvalidRequestLineFormat)
+    }
+}
+
+// tchar from RFC 9110 5.6.2, the characters allowed in methods and header names
+fn is_token_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c)
+}
+
+fn is_token(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(is_token_char)
+}
+
+// method SP request-target SP version CRLF, with single spaces and no control characters
+fn check_request_line(request_line: &str) -> Result<(), RequestError> {
+    let line = request_line
+        .strip_suffix("\r\n")
+        .ok_or(RequestError::InvalidRequestLineFormat)?;
+    let parts: Vec<&str> = line.split(' ').collect();
+    let [method, target, version] = parts[..] else {
+        return Err(RequestError::InvalidRequestLineFormat);
+    };
+    let target_ok = !target.is_empty() && target.chars().all(|c| c.is_ascii_graphic());
+    if !is_token(method) || !target_ok || !version.starts_with("HTTP/") {
+        return Err(RequestError::InvalidRequestLineFormat);
+    }
+    Ok(())
+}
+
+// splits a header line into name and value, refusing bare LF endings,
+// obs-fold continuations, whitespace before the colon and control characters
+fn check_header_line(header_line: &str) -> Result<(&str, &str), RequestError> {
+    let invalid = || RequestError::InvalidHeaderLine(header_line.to_string());
+    let line = header_line.strip_suffix("\r\n").ok_or_else(invalid)?;
+    let (name, value) = line.split_once(':').ok_or_else(invalid)?;
+    if !is_token(name) {
+        return Err(invalid());
+    }
+    let value = value.trim_matches([' ', '\t']);
+    if value.chars().any(|c| c.is_control() && c != '\t') {
+        return Err(invalid());
+    }
+    Ok((name, value))
+}
+
+// an HTTP/1.1 request names exactly one Host (RFC 9112 3.2), HTTP/1.0
+// predates the header and may leave it out
+fn check_host(version: Version, headers: &HeaderMap) -> Result<(), RequestError> {
+    match headers.get_all("Host").count() {
+        0 if version == Version::Http11 => Err(RequestError::MissingHost),
+        0 | 1 => Ok(()),
+        _ => Err(RequestError::DuplicateHost),
+    }
+}
+
+// the body length must come from exactly one unambiguous source (RFC 9112 6.3),
+// chunked bodies aren't implemented so any Transfer-Encoding is refused
+fn check_framing(headers: &HeaderMap) -> Result<(), RequestError> {
+    if headers.get("Transfer-Encoding").is_some() {
+        return Err(RequestError::UnsupportedTransferEncoding);
+    }
+    let mut length: Option<&str> = None;
+    for value in headers.get_all("Content-Length") {
+        if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(RequestError::InvalidContentLength);
+        }
+        // repeats are tolerated only when they all agree
+        match length {
+            Some(previous) if previous != value => return Err(RequestError::ConflictingContentLength),
+            _ => length = Some(value),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,136 @@
+// problem details (RFC 7807), the body of every error response
+//
+// handlers only say what went wrong in `detail`. the type stays "about:blank",
+// which makes the title the status's reason phrase, and `instance` is the path
+// the request was for, added once routing knows it. a 422 also lists the
+// failing fields in an `errors` extension member.
+
+use crate::http::{self, IntoResponse, Response, StatusCode};
+use crate::openapi::{self, ApiSchema};
+use crate::validation::FieldError;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+pub(crate) const MEDIA_TYPE: &str = "application/problem+json";
+
+#[derive(Serialize, Debug)]
+pub(crate) struct Problem {
+    #[serde(rename = "type")]
+    problem_type: &'static str,
+    title: String,
+    status: u16,
+    detail: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    instance: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<FieldError>,
+}
+
+impl Problem {
+    pub(crate) fn new(status: StatusCode, detail: impl Into<String>) -> Problem {
+        Problem {
+            problem_type: "about:blank",
+            title: title(status),
+            status: status.code(),
+            detail: detail.into(),
+            instance: None,
+            errors: Vec::new(),
+        }
+    }
+
+    pub(crate) fn errors(mut self, errors: Vec<FieldError>) -> Problem {
+        self.errors = errors;
+        self
+    }
+
+    pub(crate) fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("problem details always serialize")
+    }
+}
+
+// a failed request as the endpoints report it, a status and a message that
+// becomes the problem's detail. a 422's message already is the problem
+// document listing the failing fields.
+#[derive(Debug)]
+pub(crate) struct ApiError {
+    pub(crate) status: StatusCode,
+    pub(crate) message: String,
+}
+
+impl ApiError {
+    // the (status, message) an endpoint answered, Err when the status is an error
+    pub(crate) fn check(outcome: (StatusCode, String)) -> Result<(StatusCode, String), ApiError> {
+        match outcome {
+            (status, message) if status.code() >= 400 => Err(ApiError { status, message }),
+            outcome => Ok(outcome),
+        }
+    }
+}
+
+impl From<(StatusCode, String)> for ApiError {
+    fn from((status, message): (StatusCode, String)) -> ApiError {
+        ApiError { status, message }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        match self.status {
+            StatusCode::UnprocessableEntity => Response::new(self.status)
+                .content_type(MEDIA_TYPE)
+                .body(self.message.into_bytes()),
+            status => Response::problem(status, self.message),
+        }
+    }
+}
+
+impl ApiSchema for Problem {
+    const NAME: &'static str = "Problem";
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["type", "title", "status", "detail"],
+            "properties": {
+                "type": { "type": "string" },
+                "title": { "type": "string" },
+                "status": { "type": "integer" },
+                "detail": { "type": "string" },
+                "instance": { "type": "string" },
+                "errors": { "type": "array", "items": openapi::reference::<FieldError>() },
+            },
+        })
+    }
+}
+
+// "REQUEST HEADER FIELDS TOO LARGE" -> "Request Header Fields Too Large"
+fn title(status: StatusCode) -> String {
+    status
+        .reason()
+        .split(' ')
+        .map(|word| {
+            let (first, rest) = word.split_at(1.min(word.len()));
+            format!("{first}{}", rest.to_ascii_lowercase())
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// sets `instance` on a problem response that doesn't name one yet
+pub(crate) fn with_instance(mut response: Response, path: &str) -> Response {
+    let is_problem = response
+        .headers
+        .get("Content-Type")
+        .is_some_and(|content_type| http::media_type(content_type) == MEDIA_TYPE);
+    if !is_problem {
+        return response;
+    }
+    let Ok(serde_json::Value::Object(mut members)) = serde_json::from_slice(&response.body) else {
+        return response;
+    };
+    if !members.contains_key("instance") {
+        members.insert("instance".to_string(), path.into());
+        response.body = serde_json::to_vec(&members).expect("problem details always serialize");
+    }
+    response
+}
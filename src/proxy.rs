@@ -0,0 +1,349 @@
+// reverse proxy routes, enabled with the `proxy` cargo feature
+//
+// a route registered with `Router::proxy` sends the request on to an upstream
+// server with the same method, headers and body, and answers with whatever
+// the upstream answered. X-Forwarded-For, X-Forwarded-Proto and
+// X-Forwarded-Host tell the upstream who the request came from. an upstream
+// written without a path gets the path as it was requested, one with a path
+// gets it followed by what the route's `*` matched: "/legacy/*" forwarding to
+// "http://127.0.0.1:9000/v1" sends "/legacy/users" to "/v1/users".
+// the upstream response is read whole before it is sent on, like every other
+// response of this server.
+//
+// a route can balance between several upstreams, taking turns or picking the
+// one with the fewest requests in flight. an upstream that fails `max_failures`
+// times in a row, by refusing the connection or not answering, is left out for
+// `eject_secs`. a request whose connection is refused is tried on the next
+// upstream, one that reached an upstream is never sent twice.
+
+use crate::http::{HeaderMap, Request, Response, StatusCode};
+use crate::client::{self, Reply};
+use crate::{log, request_id};
+use serde::{Deserialize, Deserializer};
+use std::fmt;
+use std::io::{self, BufReader, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+// headers describing one connection, never forwarded (RFC 9110 7.6.1)
+const HOP_BY_HOP: [&str; 8] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+// an http:// URL requests are forwarded to
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "String")]
+pub(crate) struct Upstream {
+    host: String,
+    port: u16,
+    // the path the forwarded paths go below, empty to keep them as they are
+    path: String,
+}
+
+// a route forwarded to an upstream, from `proxy_routes` in config.json
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct ProxyRoute {
+    pub(crate) path: String,
+    // one upstream or a list of them
+    #[serde(alias = "upstreams", deserialize_with = "one_or_many")]
+    pub(crate) upstream: Vec<Upstream>,
+    #[serde(flatten)]
+    pub(crate) options: PoolOptions,
+}
+
+// how a route picks between its upstreams
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Balance {
+    // each upstream in turn
+    #[default]
+    RoundRobin,
+    // the upstream with the fewest requests in flight
+    LeastConnections,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(default)]
+pub(crate) struct PoolOptions {
+    pub(crate) balance: Balance,
+    /// Failures in a row before an upstream is left out, 0 never leaves one out.
+    pub(crate) max_failures: u32,
+    /// Seconds a failing upstream is left out before it is tried again.
+    pub(crate) eject_secs: u64,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        PoolOptions { balance: Balance::RoundRobin, max_failures: 3, eject_secs: 30 }
+    }
+}
+
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Upstream>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(Upstream),
+        Many(Vec<Upstream>),
+    }
+    match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(upstream) => Ok(vec![upstream]),
+        OneOrMany::Many(upstreams) if upstreams.is_empty() => Err(serde::de::Error::custom("no upstream given")),
+        OneOrMany::Many(upstreams) => Ok(upstreams),
+    }
+}
+
+// the upstreams of one route and what is known about their health
+#[derive(Debug)]
+pub(crate) struct Pool {
+    targets: Vec<Target>,
+    options: PoolOptions,
+    // the turn of round robin
+    next: AtomicUsize,
+}
+
+#[derive(Debug)]
+struct Target {
+    upstream: Upstream,
+    in_flight: AtomicUsize,
+    // failures since the last answer
+    failures: AtomicU32,
+    // left out until then
+    ejected_until: Mutex<Option<Instant>>,
+}
+
+impl Pool {
+    pub(crate) fn new(upstreams: Vec<Upstream>, options: PoolOptions) -> Pool {
+        assert!(!upstreams.is_empty(), "a proxy pool needs an upstream");
+        let targets = upstreams
+            .into_iter()
+            .map(|upstream| Target {
+                upstream,
+                in_flight: AtomicUsize::new(0),
+                failures: AtomicU32::new(0),
+                ejected_until: Mutex::new(None),
+            })
+            .collect();
+        Pool { targets, options, next: AtomicUsize::new(0) }
+    }
+
+    // the healthy targets in the order to try them, the ejected ones when
+    // every target is, since a guess beats refusing the request
+    fn candidates(&self) -> Vec<&Target> {
+        let now = Instant::now();
+        let start = match self.options.balance {
+            Balance::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed),
+            Balance::LeastConnections => 0,
+        };
+        let mut order: Vec<&Target> = (0..self.targets.len())
+            .map(|i| &self.targets[(start + i) % self.targets.len()])
+            .collect();
+        if self.options.balance == Balance::LeastConnections {
+            // a stable sort keeps the registration order between equals
+            order.sort_by_key(|target| target.in_flight.load(Ordering::Relaxed));
+        }
+        let healthy: Vec<&Target> = order.iter().copied().filter(|target| !target.is_ejected(now)).collect();
+        if healthy.is_empty() {
+            order
+        } else {
+            healthy
+        }
+    }
+
+    // the upstream's answer, 502 once no upstream took the connection and 504
+    // when the one that did never answered
+    pub(crate) fn forward(&self, request: &Request) -> Response {
+        let mut last_error = None;
+        for target in self.candidates() {
+            let stream = match connect(&target.upstream) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    self.failed(target);
+                    last_error = Some((target, e));
+                    continue;
+                }
+            };
+            target.in_flight.fetch_add(1, Ordering::Relaxed);
+            let result = exchange(stream, &target.upstream, request);
+            target.in_flight.fetch_sub(1, Ordering::Relaxed);
+            match result {
+                Ok(response) => {
+                    target.failures.store(0, Ordering::Relaxed);
+                    return response;
+                }
+                Err(e) => {
+                    self.failed(target);
+                    return failure(&target.upstream, e);
+                }
+            }
+        }
+        match last_error {
+            Some((target, e)) => failure(&target.upstream, e),
+            None => Response::problem(StatusCode::BadGateway, "No upstream to forward to"),
+        }
+    }
+
+    fn failed(&self, target: &Target) {
+        let failures = target.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.options.max_failures == 0 || failures < self.options.max_failures {
+            return;
+        }
+        target.failures.store(0, Ordering::Relaxed);
+        let until = Instant::now() + Duration::from_secs(self.options.eject_secs);
+        *target.ejected_until.lock().unwrap_or_else(PoisonError::into_inner) = Some(until);
+        log::warning!("Leaving out {} for {}s after {} failures", target.upstream, self.options.eject_secs, failures);
+    }
+}
+
+impl Target {
+    fn is_ejected(&self, now: Instant) -> bool {
+        self.ejected_until
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .is_some_and(|until| now < until)
+    }
+}
+
+// a single upstream, `router.proxy("/legacy/*", "http://127.0.0.1:9000")`.
+// panics on an upstream that isn't an http:// URL
+impl From<&str> for Pool {
+    fn from(url: &str) -> Pool {
+        let upstream = Upstream::parse(url).unwrap_or_else(|e| panic!("{e}"));
+        Pool::new(vec![upstream], PoolOptions::default())
+    }
+}
+
+impl Upstream {
+    pub(crate) fn parse(url: &str) -> Result<Upstream, String> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("Upstream {url} is not an http:// URL"))?;
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], rest[slash..].trim_end_matches('/')),
+            None => (rest, ""),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !host.ends_with(':') => {
+                let port = port.parse().map_err(|_| format!("Upstream {url} has an invalid port"))?;
+                (host, port)
+            }
+            _ => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(format!("Upstream {url} has no host"));
+        }
+        Ok(Upstream { host: host.to_string(), port, path: path.to_string() })
+    }
+
+    // the path and query the upstream is asked for
+    fn target(&self, request: &Request) -> String {
+        let query = request.uri.find('?').map_or("", |at| &request.uri[at..]);
+        if self.path.is_empty() {
+            return format!("{}{query}", request.path);
+        }
+        let rest = request.params.get("*").map_or("", String::as_str);
+        format!("{}/{rest}{query}", self.path)
+    }
+}
+
+impl TryFrom<String> for Upstream {
+    type Error = String;
+
+    fn try_from(url: String) -> Result<Upstream, String> {
+        Upstream::parse(&url)
+    }
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "http://{}:{}{}", self.host, self.port, self.path)
+    }
+}
+
+fn failure(upstream: &Upstream, error: io::Error) -> Response {
+    match error.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => {
+            Response::problem(StatusCode::GatewayTimeout, format!("{upstream} did not answer in time"))
+        }
+        _ => Response::problem(StatusCode::BadGateway, format!("{upstream} could not be reached: {error}")),
+    }
+}
+
+fn connect(upstream: &Upstream) -> io::Result<TcpStream> {
+    client::connect((upstream.host.as_str(), upstream.port), client::CONNECT_TIMEOUT, client::IO_TIMEOUT)
+}
+
+fn exchange(mut stream: TcpStream, upstream: &Upstream, request: &Request) -> io::Result<Response> {
+    stream.write_all(&request_head(upstream, request))?;
+    stream.write_all(&request.body)?;
+    client::read_response(&mut BufReader::new(stream), request.method == "HEAD").map(relay)
+}
+
+fn request_head(upstream: &Upstream, request: &Request) -> Vec<u8> {
+    let mut headers = HeaderMap::new();
+    for (name, value) in request.headers.iter() {
+        let dropped = HOP_BY_HOP.iter().chain(&["Host", "Content-Length", "Content-Encoding"]);
+        if !dropped.into_iter().any(|hop| hop.eq_ignore_ascii_case(name)) {
+            headers.append(name, value);
+        }
+    }
+    headers.insert("Host", format!("{}:{}", upstream.host, upstream.port));
+    if let Some(peer) = request.connection.peer {
+        let forwarded_for = match request.headers.get("X-Forwarded-For") {
+            Some(earlier) => format!("{earlier}, {}", peer.ip()),
+            None => peer.ip().to_string(),
+        };
+        headers.insert("X-Forwarded-For", forwarded_for);
+    }
+    headers.insert("X-Forwarded-Proto", if request.connection.secure() { "https" } else { "http" });
+    if let Some(host) = request.headers.get("Host") {
+        headers.insert("X-Forwarded-Host", host);
+    }
+    // the upstream logs the request under the same id
+    if let Some(id) = request_id::current() {
+        headers.insert(request_id::HEADER, id);
+    }
+    // the body was decoded when it was read, it goes on as it is now
+    if !request.body.is_empty() {
+        headers.insert("Content-Length", request.body.len().to_string());
+    }
+    // one request per connection, the end of the response is where it closes
+    headers.insert("Connection", "close");
+
+    let mut head = format!("{} {} HTTP/1.1\r\n", request.method, upstream.target(request));
+    for (name, value) in headers.iter() {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str("\r\n");
+    head.into_bytes()
+}
+
+// the upstream's answer as this server sends it on
+fn relay(reply: Reply) -> Response {
+    let Some(status) = StatusCode::from_code(reply.status) else {
+        return Response::problem(
+            StatusCode::BadGateway,
+            format!("The upstream answered with status {}, which this server can't relay", reply.status),
+        );
+    };
+    let mut response = Response::new(status);
+    for (name, value) in reply.headers.iter() {
+        // Date and Server are this server's own, the length is counted again
+        let dropped = HOP_BY_HOP.iter().chain(&["Content-Length", "Date", "Server"]);
+        if !dropped.into_iter().any(|hop| hop.eq_ignore_ascii_case(name)) {
+            response.headers.append(name, value);
+        }
+    }
+    if status.allows_body() {
+        response.body = reply.body;
+    }
+    response
+}
@@ -0,0 +1,195 @@
+// daily quotas on the bytes each client sends and is sent
+//
+// with `quotas` configured every request is counted against its client: the
+// API key it sends, or else its address (the first hop of X-Forwarded-For
+// from a trusted proxy). what comes in is the request line, headers and body,
+// what goes out is the response as serialized, a streamed body counted before
+// chunking. a client that used up either quota is answered 429 until the day
+// ends at midnight UTC, with Retry-After and X-Quota-Remaining; every other
+// counted response says in X-Quota-Remaining how many bytes are left of the
+// quota closest to running out. a client may have quotas of its own in
+// `clients`, by address or key id. GET /admin/quotas lists today's usage.
+// the counts are kept in memory and start over when the server restarts.
+
+use crate::api_keys;
+use crate::audit;
+use crate::config::{self, Config};
+use crate::extract::Json;
+use crate::http::{HeaderMap, Request, Response, StatusCode};
+use crate::parser::RequestHead;
+use crate::problem::ApiError;
+use chrono::{NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{LazyLock, Mutex, PoisonError};
+
+pub(crate) const HEADER: &str = "X-Quota-Remaining";
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Limits {
+    /// Bytes a client may send a day, 0 for no limit.
+    pub(crate) daily_bytes_in: u64,
+    /// Bytes a client may be sent a day, 0 for no limit.
+    pub(crate) daily_bytes_out: u64,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct QuotaConfig {
+    #[serde(flatten)]
+    pub(crate) limits: Limits,
+    /// Address or API key id -> quotas replacing the ones above for that client.
+    pub(crate) clients: HashMap<String, Limits>,
+}
+
+impl QuotaConfig {
+    fn limits(&self, client: &Client) -> Limits {
+        let name = match client {
+            Client::Address(address) => address,
+            Client::Key(id) => id,
+        };
+        self.clients.get(name).copied().unwrap_or(self.limits)
+    }
+}
+
+// who a request is counted against
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum Client {
+    Key(String),
+    Address(String),
+}
+
+impl Client {
+    // "key:3f2a..." or "203.0.113.5", as GET /admin/quotas lists it
+    fn name(&self) -> String {
+        match self {
+            Client::Key(id) => format!("key:{id}"),
+            Client::Address(address) => address.clone(),
+        }
+    }
+}
+
+// a client's usage on `day`
+#[derive(Debug, Clone, Copy)]
+struct Counts {
+    day: NaiveDate,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+// a client's usage as GET /admin/quotas lists it
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub(crate) struct Usage {
+    pub(crate) client: String,
+    pub(crate) bytes_in: u64,
+    pub(crate) bytes_out: u64,
+    #[serde(flatten)]
+    pub(crate) limits: Limits,
+}
+
+static COUNTS: LazyLock<Mutex<HashMap<Client, Counts>>> = LazyLock::new(Mutex::default);
+
+// the client a request from `peer` with `headers` is counted against, None
+// without quotas configured or anything to tell the client by
+pub(crate) fn client(config: &Config, headers: &HeaderMap, peer: Option<SocketAddr>) -> Option<Client> {
+    config.quotas.as_ref()?;
+    if let Some(key) = api_keys::sent(config, headers) {
+        return Some(Client::Key(key.id));
+    }
+    audit::client_ip(headers, peer, config).map(|ip| Client::Address(ip.to_string()))
+}
+
+// the bytes of a request as it came in, its line, headers and body
+pub(crate) fn request_len(head: &RequestHead, body: usize) -> u64 {
+    // "GET / HTTP/1.1\r\n" and the blank line after the headers
+    let line = head.method.len() + 1 + head.uri.len() + " HTTP/1.1\r\n".len() + 2;
+    let headers: usize = head.headers.iter().map(|(name, value)| name.len() + ": ".len() + value.len() + 2).sum();
+    (line + headers + body) as u64
+}
+
+// runs `f` on today's counts of `client`, starting them over on a new day
+fn with_counts<T>(client: &Client, f: impl FnOnce(&mut Counts) -> T) -> T {
+    let today = Utc::now().date_naive();
+    let mut counts = COUNTS.lock().unwrap_or_else(PoisonError::into_inner);
+    // the clients not seen today are dropped once a day has passed
+    if counts.values().any(|counts| counts.day != today) {
+        counts.retain(|_, counts| counts.day == today);
+    }
+    let counts = counts.entry(client.clone()).or_insert(Counts { day: today, bytes_in: 0, bytes_out: 0 });
+    f(counts)
+}
+
+// bytes left of the quota closest to running out, None without quotas
+fn remaining(limits: Limits, counts: &Counts) -> Option<u64> {
+    let left = |limit: u64, used: u64| (limit > 0).then(|| limit.saturating_sub(used));
+    match (left(limits.daily_bytes_in, counts.bytes_in), left(limits.daily_bytes_out, counts.bytes_out)) {
+        (Some(incoming), Some(outgoing)) => Some(incoming.min(outgoing)),
+        (incoming, outgoing) => incoming.or(outgoing),
+    }
+}
+
+// counts `bytes` received from `client`, the 429 to answer with once it has
+// used up a quota
+pub(crate) fn receive(config: &Config, client: &Client, bytes: u64) -> Result<(), Response> {
+    let Some(quotas) = &config.quotas else {
+        return Ok(());
+    };
+    let limits = quotas.limits(client);
+    let counts = with_counts(client, |counts| {
+        counts.bytes_in += bytes;
+        *counts
+    });
+    let over_in = limits.daily_bytes_in > 0 && counts.bytes_in > limits.daily_bytes_in;
+    let over_out = limits.daily_bytes_out > 0 && counts.bytes_out >= limits.daily_bytes_out;
+    if !over_in && !over_out {
+        return Ok(());
+    }
+    let midnight = (counts.day + chrono::Days::new(1)).and_hms_opt(0, 0, 0).expect("midnight exists").and_utc();
+    let retry_after = (midnight - Utc::now()).num_seconds().max(1);
+    Err(Response::problem(StatusCode::TooManyRequests, "Daily quota used up, try again tomorrow")
+        .header("Retry-After", retry_after.to_string()))
+}
+
+// counts `response` sent to `client`, telling it how many bytes it has left
+pub(crate) fn send(config: &Config, client: &Client, response: Response, head_only: bool) -> Response {
+    let Some(quotas) = &config.quotas else {
+        return response;
+    };
+    let bytes = response.serialized_len(head_only);
+    let counts = with_counts(client, |counts| {
+        counts.bytes_out += bytes;
+        *counts
+    });
+    match remaining(quotas.limits(client), &counts) {
+        Some(left) => response.header(HEADER, left.to_string()),
+        None => response,
+    }
+}
+
+// GET /admin/quotas, today's usage of every client, 404 while `quotas` isn't configured
+pub(crate) fn index(_request: &Request) -> Result<Json<Vec<Usage>>, ApiError> {
+    let quotas = config::get()
+        .quotas
+        .as_ref()
+        .ok_or_else(|| ApiError::from((StatusCode::NotFound, "Quotas are not configured".to_string())))?;
+    Ok(Json(usage(quotas)))
+}
+
+pub(crate) fn usage(quotas: &QuotaConfig) -> Vec<Usage> {
+    let today = Utc::now().date_naive();
+    let counts = COUNTS.lock().unwrap_or_else(PoisonError::into_inner);
+    let mut usage: Vec<Usage> = counts
+        .iter()
+        .filter(|(_, counts)| counts.day == today)
+        .map(|(client, counts)| Usage {
+            client: client.name(),
+            bytes_in: counts.bytes_in,
+            bytes_out: counts.bytes_out,
+            limits: quotas.limits(client),
+        })
+        .collect();
+    usage.sort_by(|a, b| a.client.cmp(&b.client));
+    usage
+}
@@ -0,0 +1,100 @@
+// byte range requests (RFC 9110 14)
+//
+// a response opts in by carrying `Accept-Ranges: bytes`, the Range header is
+// then applied to its body once the handler is done. only single ranges are
+// served, a request for several gets the whole body, which the RFC allows.
+// ranges are taken from the identity body, ranged responses aren't compressed.
+
+use crate::http::{HeaderMap, Response, StatusCode};
+
+// the inclusive byte range a Range header selects from a body of `length` bytes,
+// None when the header should be ignored, Err when it can't be satisfied
+pub(crate) fn parse(range: &str, length: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = range.trim().strip_prefix("bytes=")?.trim();
+    if spec.contains(',') {
+        return None;
+    }
+    let (first, last) = spec.split_once('-')?;
+    let (first, last) = (first.trim(), last.trim());
+    let digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+
+    if first.is_empty() {
+        // a suffix range, the last n bytes
+        if !digits(last) {
+            return None;
+        }
+        let suffix: u64 = last.parse().ok()?;
+        if suffix == 0 || length == 0 {
+            return Some(Err(()));
+        }
+        return Some(Ok((length.saturating_sub(suffix), length - 1)));
+    }
+
+    if !digits(first) || !(last.is_empty() || digits(last)) {
+        return None;
+    }
+    let start: u64 = first.parse().ok()?;
+    let end: u64 = if last.is_empty() { u64::MAX } else { last.parse().ok()? };
+    if end < start {
+        return None;
+    }
+    if start >= length {
+        return Some(Err(()));
+    }
+    Some(Ok((start, end.min(length - 1))))
+}
+
+// whether the response will be cut down to a range, so it mustn't be compressed
+pub(crate) fn requested(method: &str, request_headers: &HeaderMap, response: &Response) -> bool {
+    method == "GET"
+        && response.status == StatusCode::Ok
+        && response
+            .headers
+            .get("Accept-Ranges")
+            .is_some_and(|units| units.eq_ignore_ascii_case("bytes"))
+        && request_headers.get("Range").is_some()
+}
+
+// answers a Range request with 206 Partial Content, or 416 when the range is
+// outside the body. an If-Range naming an older version gets the full body.
+pub(crate) fn apply(method: &str, request_headers: &HeaderMap, mut response: Response) -> Response {
+    if !requested(method, request_headers, &response) {
+        return response;
+    }
+    if let Some(if_range) = request_headers.get("If-Range") {
+        if !if_range_matches(if_range, &response) {
+            return response;
+        }
+    }
+
+    let length = response.body_len();
+    let range = request_headers.get("Range").unwrap_or_default();
+    match parse(range, length) {
+        None => response,
+        Some(Ok((start, end))) => {
+            response.status = StatusCode::PartialContent;
+            match &mut response.file {
+                Some(file) => file.narrow(start, end),
+                None => response.body = response.body[start as usize..=end as usize].to_vec(),
+            }
+            response.header("Content-Range", format!("bytes {start}-{end}/{length}"))
+        }
+        Some(Err(())) => Response::problem(StatusCode::RangeNotSatisfiable, format!("The range is outside the {length} byte body"))
+            .header("Content-Range", format!("bytes */{length}")),
+    }
+}
+
+// If-Range holds a strong ETag or a Last-Modified date (RFC 9110 13.1.5)
+fn if_range_matches(if_range: &str, response: &Response) -> bool {
+    let if_range = if_range.trim();
+    if if_range.starts_with('"') {
+        return response.headers.get("ETag").is_some_and(|etag| etag == if_range);
+    }
+    if if_range.starts_with("W/") {
+        return false;
+    }
+    response
+        .headers
+        .get("Last-Modified")
+        .is_some_and(|last_modified| last_modified == if_range)
+}
@@ -0,0 +1,76 @@
+// per client request limit, in fixed windows
+//
+// with `rate_limit` configured each client address may send `requests`
+// requests every `window_secs` seconds, the ones over it are answered 429
+// before they are read. an API key may carry a limit of its own, counted
+// over every client sending it. the limits can be switched off and on again
+// while the server runs through PATCH /admin/runtime.
+
+use crate::config::Config;
+use crate::http::{Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{LazyLock, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub(crate) struct RateLimit {
+    pub(crate) requests: u32,
+    pub(crate) window_secs: u64,
+}
+
+// clients tracked before windows that have ended are dropped
+const PRUNE_AT: usize = 1024;
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+// when each client's current window started and how many requests it sent in it
+static WINDOWS: LazyLock<Mutex<HashMap<IpAddr, (Instant, u32)>>> = LazyLock::new(Mutex::default);
+// the same for each API key with a limit, by key id
+static KEY_WINDOWS: LazyLock<Mutex<HashMap<String, (Instant, u32)>>> = LazyLock::new(Mutex::default);
+
+pub(crate) fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub(crate) fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+// counts a request from `client`, the 429 to answer it with when it is over the limit
+pub(crate) fn check(config: &Config, client: IpAddr) -> Result<(), Response> {
+    let Some(limit) = config.rate_limit.filter(|_| is_enabled()) else {
+        return Ok(());
+    };
+    count(&mut WINDOWS.lock().unwrap_or_else(PoisonError::into_inner), client, limit)
+}
+
+// counts a request made with the API key `id` against the key's own `limit`
+pub(crate) fn check_key(id: &str, limit: RateLimit) -> Result<(), Response> {
+    if !is_enabled() {
+        return Ok(());
+    }
+    count(&mut KEY_WINDOWS.lock().unwrap_or_else(PoisonError::into_inner), id.to_string(), limit)
+}
+
+fn count<K: Eq + Hash>(windows: &mut HashMap<K, (Instant, u32)>, key: K, limit: RateLimit) -> Result<(), Response> {
+    let window = Duration::from_secs(limit.window_secs);
+    let now = Instant::now();
+    if windows.len() >= PRUNE_AT {
+        windows.retain(|_, (start, _)| now.duration_since(*start) < window);
+    }
+    let (start, count) = windows.entry(key).or_insert((now, 0));
+    if now.duration_since(*start) >= window {
+        (*start, *count) = (now, 0);
+    }
+    if *count >= limit.requests {
+        let retry_after = window.saturating_sub(now.duration_since(*start)).as_secs().max(1);
+        return Err(Response::problem(StatusCode::TooManyRequests, "Too many requests, slow down")
+            .header("Retry-After", retry_after.to_string()));
+    }
+    *count += 1;
+    Ok(())
+}
@@ -0,0 +1,79 @@
+// config-defined redirects and path aliases, checked before routing
+//
+// a redirect answers with a Location pointing at the new path, an alias
+// serves the new path directly under the old one. both keep the query string.
+// redirects use 308/307 rather than 301/302 so clients replaying a POST or
+// PUT keep the method and body.
+//
+// a listener with `"routes": "https_redirect"` serves nothing in cleartext,
+// every request is answered 301 to the same host, path and query on the
+// first listener serving HTTPS, before its body is read.
+
+use crate::config::Config;
+use crate::http::{self, Response, StatusCode};
+use crate::listener::{self, Routes};
+use crate::parser::RequestHead;
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct Redirect {
+    pub(crate) from: String,
+    pub(crate) to: String,
+    #[serde(default)]
+    pub(crate) permanent: bool,
+}
+
+pub(crate) enum Rewrite {
+    // the uri to route, unchanged or aliased
+    Route(String),
+    // the redirect to send instead of routing
+    Redirect(Response),
+}
+
+pub(crate) fn resolve(config: &Config, uri: &str) -> Rewrite {
+    let (path, query) = match uri.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (uri, None),
+    };
+    let with_query = |target: &str| match query {
+        Some(query) => format!("{target}?{query}"),
+        None => target.to_string(),
+    };
+
+    if let Some(redirect) = config.redirects.iter().find(|redirect| redirect.from == path) {
+        let status = if redirect.permanent {
+            StatusCode::PermanentRedirect
+        } else {
+            StatusCode::TemporaryRedirect
+        };
+        return Rewrite::Redirect(Response::redirect(status, with_query(&redirect.to)));
+    }
+
+    match config.aliases.get(path) {
+        Some(target) => Rewrite::Route(with_query(target)),
+        None => Rewrite::Route(uri.to_string()),
+    }
+}
+
+// the redirect to HTTPS a request gets on an https_redirect listener, None on
+// the others. the ACME CA's challenges are still answered, it fetches them
+// over plain HTTP
+pub(crate) fn to_https(config: &Config, routes: Routes, head: &RequestHead) -> Option<Response> {
+    if routes != Routes::HttpsRedirect {
+        return None;
+    }
+    #[cfg(feature = "acme")]
+    if head.uri.starts_with(crate::acme::CHALLENGE_PATH) {
+        return None;
+    }
+    let Some(host) = head.headers.get("Host").map(http::host_name).filter(|host| !host.is_empty()) else {
+        return Some(Response::problem(StatusCode::BadRequest, "A Host header is needed to redirect to HTTPS"));
+    };
+    let port = match listener::https_port(config) {
+        None | Some(443) => String::new(),
+        Some(port) => format!(":{port}"),
+    };
+    // OPTIONS * and the like go to the root
+    let target = if head.uri.starts_with('/') { head.uri.as_str() } else { "/" };
+    Some(Response::redirect(StatusCode::MovedPermanently, format!("https://{host}{port}{target}")))
+}
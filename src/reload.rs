@@ -0,0 +1,225 @@
+// graceful shutdown and zero-downtime reload
+//
+// on SIGTERM the server stops accepting, waits up to `drain_timeout_secs` for
+// the connections it is answering, runs the shutdown hooks and exits. with
+// `socket.reuse_port` set a new server started with `--reload` binds the same
+// port next to the running one, takes over `pid_file` and sends the old
+// process that SIGTERM, so during a deployment the kernel always has a socket
+// to hand new connections to and none in flight is dropped. event streams
+// are open for good and not waited for.
+//
+// a shutdown hook is work that has to happen before the process is gone but
+// after nothing new comes in: syncing the data file, closing pools, telling a
+// webhook the server is leaving. hooks are registered with on_shutdown and run
+// one after the other in that order, on a thread of their own, so that
+// together they get `shutdown_timeout_secs` at most. a hook still running
+// then is abandoned with the ones after it and the process exits anyway.
+
+use crate::config::Config;
+use crate::listener::Bound;
+use crate::{log, store};
+use socket2::SockRef;
+use std::fs;
+use std::io;
+use std::net::{Shutdown, TcpListener};
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// set from the signal handler, which may do nothing else
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+type Hook = Box<dyn FnOnce() -> Result<(), String> + Send>;
+
+// the shutdown hooks with their names, in the order they run
+static HOOKS: Mutex<Vec<(String, Hook)>> = Mutex::new(Vec::new());
+
+// how often the flag and the connections left are looked at
+const POLL: Duration = Duration::from_millis(100);
+
+// counts a connection as in flight for as long as it is alive
+pub(crate) struct InFlight(());
+
+impl InFlight {
+    pub(crate) fn start() -> InFlight {
+        IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+        InFlight(())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub(crate) fn in_flight() -> usize {
+    IN_FLIGHT.load(Ordering::SeqCst)
+}
+
+// whether the listeners were shut down on purpose, so accept errors are expected
+pub(crate) fn stopping() -> bool {
+    STOPPING.load(Ordering::SeqCst)
+}
+
+// the process id written to the pid file, None when there is none
+pub(crate) fn read_pid(path: &Path) -> io::Result<Option<u32>> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(contents.trim().parse().ok()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// records this process in the pid file, and with `reload` tells the process
+// recorded there before to drain and exit
+pub(crate) fn take_over(config: &Config, reload: bool) -> io::Result<()> {
+    let Some(pid_file) = config.pid_file.as_deref().map(Path::new) else {
+        if reload {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "--reload needs pid_file in config.json"));
+        }
+        return Ok(());
+    };
+    let previous = read_pid(pid_file)?;
+    fs::write(pid_file, format!("{}\n", process::id()))?;
+    match previous {
+        Some(pid) if reload && pid != process::id() => {
+            log::info!("Taking over from process {pid}");
+            terminate(pid)
+        }
+        _ => Ok(()),
+    }
+}
+
+// handles SIGTERM from now on by shutting the listeners down, which ends the
+// accept loops and lets the server return into `finish`
+pub(crate) fn watch(listeners: &[Bound]) -> io::Result<()> {
+    let listeners = listeners
+        .iter()
+        // shutting down a socket from systemd would take it away from the next server too
+        .filter(|bound| !bound.inherited)
+        .map(|bound| bound.listener.try_clone())
+        .collect::<io::Result<Vec<TcpListener>>>()?;
+    install_handler();
+    thread::spawn(move || {
+        while !stopping() {
+            thread::sleep(POLL);
+        }
+        for listener in &listeners {
+            // wakes the accept loops, which see `stopping` and return
+            let _ = SockRef::from(listener).shutdown(Shutdown::Both);
+        }
+    });
+    Ok(())
+}
+
+// has `hook` run once the server stops, after the connections are drained;
+// an Err it returns is logged under `name`
+pub(crate) fn on_shutdown(name: &str, hook: impl FnOnce() -> Result<(), String> + Send + 'static) {
+    HOOKS.lock().unwrap_or_else(PoisonError::into_inner).push((name.to_string(), Box::new(hook)));
+}
+
+// the hooks every server has, registered before it starts accepting
+pub(crate) fn register_hooks() {
+    on_shutdown("sync the data file", || store::sync().map_err(|e| e.to_string()));
+}
+
+// what is left once nothing is accepted anymore: the connections in flight
+// get until the drain deadline, the shutdown hooks until theirs and the pid
+// file is let go
+pub(crate) fn finish(config: &Config) {
+    drain(config);
+    let abandoned = run_hooks(Duration::from_secs(config.shutdown_timeout_secs));
+    if !abandoned.is_empty() {
+        log::warning!("Exiting without waiting for the shutdown hooks {}", abandoned.join(", "));
+    }
+    if let Some(pid_file) = config.pid_file.as_deref().map(Path::new) {
+        // a server that took over has written its own pid already
+        if read_pid(pid_file).ok().flatten() == Some(process::id()) {
+            let _ = fs::remove_file(pid_file);
+        }
+    }
+    log::info!("Stopped");
+}
+
+// waits for the connections in flight, at most `drain_timeout_secs`
+pub(crate) fn drain(config: &Config) {
+    let deadline = Duration::from_secs(config.drain_timeout_secs);
+    let started = Instant::now();
+    if in_flight() > 0 {
+        log::info!("Draining {} connections", in_flight());
+    }
+    while in_flight() > 0 {
+        if started.elapsed() >= deadline {
+            log::warning!("Exiting with {} connections still open", in_flight());
+            return;
+        }
+        thread::sleep(POLL);
+    }
+}
+
+// runs the registered hooks in order, each only once, returning the names of
+// those that hadn't finished when `timeout` was up
+pub(crate) fn run_hooks(timeout: Duration) -> Vec<String> {
+    let hooks = std::mem::take(&mut *HOOKS.lock().unwrap_or_else(PoisonError::into_inner));
+    let mut pending: Vec<String> = hooks.iter().map(|(name, _)| name.clone()).collect();
+    let (done, finished) = mpsc::channel();
+    thread::spawn(move || {
+        for (name, hook) in hooks {
+            if let Err(e) = hook() {
+                eprintln!("Shutdown hook {name} failed: {e}");
+            }
+            if done.send(()).is_err() {
+                return;
+            }
+        }
+    });
+    let deadline = Instant::now() + timeout;
+    while !pending.is_empty() {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if finished.recv_timeout(left).is_err() {
+            break;
+        }
+        pending.remove(0);
+    }
+    pending
+}
+
+#[cfg(unix)]
+extern "C" fn on_terminate(_signal: libc::c_int) {
+    STOPPING.store(true, Ordering::SeqCst);
+}
+
+#[cfg(unix)]
+fn install_handler() {
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+    unsafe {
+        libc::signal(libc::SIGTERM, on_terminate as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+}
+
+#[cfg(unix)]
+fn terminate(pid: u32) -> io::Result<()> {
+    // SAFETY: kill only sends a signal, it touches no memory of this process
+    if unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } == 0 {
+        return Ok(());
+    }
+    match io::Error::last_os_error() {
+        // the old server is gone already, nothing to take over from
+        e if e.raw_os_error() == Some(libc::ESRCH) => Ok(()),
+        e => Err(e),
+    }
+}
+
+#[cfg(not(unix))]
+fn install_handler() {}
+
+#[cfg(not(unix))]
+fn terminate(_pid: u32) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "--reload needs a unix system"))
+}
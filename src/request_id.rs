@@ -0,0 +1,71 @@
+// an id for every request, to tell its log lines apart from the others
+//
+// each request gets a new id, or keeps the X-Request-Id it came with when it
+// was sent by one of the `trusted_proxies`, so the proxy's logs and these
+// share it. the id is answered in X-Request-Id and starts every log line
+// written while the request is handled. it is kept per thread, code handing a
+// request to another thread takes the id along with `enter`.
+//
+// ids are the milliseconds since the epoch, the process id and a counter in
+// hex, ordered by time and unique across the processes of a host.
+
+use crate::http::HeaderMap;
+use crate::ip_filter::Cidr;
+use std::cell::RefCell;
+use std::net::SocketAddr;
+use std::process;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub(crate) const HEADER: &str = "X-Request-Id";
+
+// longest incoming id taken over, longer ones are replaced
+const MAX_LENGTH: usize = 128;
+
+static COUNTER: AtomicU32 = AtomicU32::new(0);
+
+thread_local! {
+    static CURRENT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+pub(crate) fn generate() -> String {
+    let millis = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis());
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{millis:012x}-{:04x}-{:06x}", process::id() & 0xffff, count & 0xff_ffff)
+}
+
+// the incoming id when a trusted proxy sent a usable one, a new one otherwise
+pub(crate) fn assign(headers: &HeaderMap, peer: Option<SocketAddr>, trusted_proxies: &[Cidr]) -> String {
+    let trusted = peer.is_some_and(|peer| trusted_proxies.iter().any(|range| range.contains(peer.ip())));
+    headers
+        .get(HEADER)
+        .filter(|_| trusted)
+        .filter(|id| !id.is_empty() && id.len() <= MAX_LENGTH && id.bytes().all(|b| b.is_ascii_graphic()))
+        .map_or_else(generate, str::to_string)
+}
+
+// makes `id` the current one on this thread until the guard is dropped
+pub(crate) fn enter(id: String) -> Entered {
+    let previous = CURRENT.with(|current| current.replace(Some(id)));
+    Entered { previous }
+}
+
+pub(crate) struct Entered {
+    previous: Option<String>,
+}
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        CURRENT.with(|current| *current.borrow_mut() = self.previous.take());
+    }
+}
+
+// the id of the request this thread is handling
+pub(crate) fn current() -> Option<String> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+// "[id] " for log lines, empty outside of a request
+pub(crate) fn log_prefix() -> String {
+    current().map(|id| format!("[{id}] ")).unwrap_or_default()
+}
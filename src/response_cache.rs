@@ -0,0 +1,100 @@
+// in-memory cache of the responses to GET requests for the entries
+//
+// the routes wrapped in `middleware` keep their last `response_cache_entries`
+// 200 responses, keyed by path, query and Accept, and evict the least
+// recently used one when full. every entry is stamped with the store's
+// generation, so any write to the data, through the API or to the file by
+// another process, makes all of them stale at once. the handler's response is
+// kept as it left it, conditional requests, ranges and compression are still
+// worked out per request afterwards.
+
+use crate::config;
+use crate::http::{Request, Response, StatusCode};
+use crate::router::Next;
+use crate::store;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex, PoisonError};
+
+// path, query and Accept header
+type Key = (String, String, String);
+
+struct Cached {
+    response: Response,
+    generation: u64,
+    // the tick of the last time it was served, the lowest is evicted first
+    used: u64,
+}
+
+#[derive(Default)]
+struct Cache {
+    entries: HashMap<Key, Cached>,
+    tick: u64,
+}
+
+static CACHE: LazyLock<Mutex<Cache>> = LazyLock::new(Mutex::default);
+
+static HITS: AtomicU64 = AtomicU64::new(0);
+static MISSES: AtomicU64 = AtomicU64::new(0);
+
+// router middleware answering GET and HEAD requests from the cache when it can
+pub(crate) fn middleware(request: &mut Request, next: Next) -> Response {
+    let capacity = config::get().response_cache_entries;
+    if capacity == 0 || !matches!(request.method.as_str(), "GET" | "HEAD") {
+        return next(request);
+    }
+    let query = request.uri.split_once('?').map_or("", |(_, query)| query);
+    let accept = request.headers.get("Accept").unwrap_or("");
+    let key = (request.path.clone(), query.to_string(), accept.to_string());
+    // read before the handler runs, a write meanwhile leaves the entry stale
+    let generation = store::generation();
+    if let Some(response) = lookup(&key, generation) {
+        HITS.fetch_add(1, Ordering::Relaxed);
+        return response;
+    }
+    MISSES.fetch_add(1, Ordering::Relaxed);
+    let response = next(request);
+    if response.status == StatusCode::Ok {
+        insert(key, generation, response.clone(), capacity);
+    }
+    response
+}
+
+fn lookup(key: &Key, generation: u64) -> Option<Response> {
+    let mut cache = CACHE.lock().unwrap_or_else(PoisonError::into_inner);
+    cache.tick += 1;
+    let tick = cache.tick;
+    match cache.entries.get_mut(key) {
+        Some(cached) if cached.generation == generation => {
+            cached.used = tick;
+            Some(cached.response.clone())
+        }
+        _ => None,
+    }
+}
+
+fn insert(key: Key, generation: u64, response: Response, capacity: usize) {
+    let mut cache = CACHE.lock().unwrap_or_else(PoisonError::into_inner);
+    // stale entries go first, they can't be served again
+    let current = store::generation();
+    cache.entries.retain(|_, cached| cached.generation == current);
+    if generation != current {
+        return;
+    }
+    while cache.entries.len() >= capacity && !cache.entries.contains_key(&key) {
+        let oldest = cache.entries.iter().min_by_key(|(_, cached)| cached.used).map(|(key, _)| key.clone());
+        match oldest {
+            Some(oldest) => cache.entries.remove(&oldest),
+            None => break,
+        };
+    }
+    cache.tick += 1;
+    let used = cache.tick;
+    cache.entries.insert(key, Cached { response, generation, used });
+}
+
+// requests answered from the cache and requests that had to run the handler
+#[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+pub(crate) fn counts() -> (u64, u64) {
+    (HITS.load(Ordering::Relaxed), MISSES.load(Ordering::Relaxed))
+}
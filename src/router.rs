@@ -0,0 +1,345 @@
+// maps (method, path) pairs to handlers
+//
+// the route table is also what OPTIONS and 405 responses read their Allow
+// header from, so it is the single place that knows which methods a path takes.
+// a path segment written as `{name}` matches any one segment, handlers find
+// what it matched in `request.params`, a last segment written as `*` matches
+// the rest of the path, which is found under "*". routes may carry an OpenAPI
+// description.
+// a path differing from a route only by a trailing slash is a 404 unless the
+// router is told to route or redirect it with `trailing_slash`. the 404, 405,
+// 500 and 503 responses the router answers with itself can be replaced by
+// registering a handler for the status with `on_error`.
+// `scope` registers a group of routes under a shared path prefix, middleware
+// attached to the group with `middleware` wraps only its routes and runs
+// around the handler, outermost first, once a request has matched one.
+// middleware may put what it found out in `request.extensions` for the
+// middleware and handler after it.
+// handlers take the whole request or extractors, see extract.rs.
+
+use crate::caching::CachePolicy;
+use crate::extract::IntoHandler;
+use crate::http::{Request, Response, StatusCode};
+use crate::openapi::Doc;
+#[cfg(feature = "proxy")]
+use crate::proxy::Pool;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+pub(crate) type Handler = Arc<dyn Fn(&Request) -> Response + Send + Sync>;
+
+// where a client can go from a resource, and with which method
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct Link {
+    pub(crate) href: String,
+    pub(crate) method: &'static str,
+}
+
+// the rest of the chain a middleware hands the request on to
+pub(crate) type Next<'a> = &'a dyn Fn(&mut Request) -> Response;
+
+// code run around the handlers of a router or scope, it may answer the request
+// itself instead of calling `next`
+pub(crate) type Middleware = fn(&mut Request, Next) -> Response;
+
+// methods the server understands at all, anything else is answered with 405
+pub(crate) const KNOWN_METHODS: [&str; 7] =
+    ["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE", "OPTIONS"];
+
+struct Route {
+    method: &'static str,
+    path: String,
+    handler: Handler,
+    // the middleware of the scopes the route was registered in, outermost first
+    middleware: Vec<Middleware>,
+    cache: Option<CachePolicy>,
+    doc: Option<Doc>,
+}
+
+// a matched route's handler with the middleware wrapping it, owned so it can
+// be run on another thread
+pub(crate) struct Endpoint {
+    handler: Handler,
+    middleware: Vec<Middleware>,
+}
+
+impl Endpoint {
+    pub(crate) fn call(&self, request: &mut Request) -> Response {
+        run(&self.middleware, &self.handler, request)
+    }
+}
+
+fn run(middleware: &[Middleware], handler: &Handler, request: &mut Request) -> Response {
+    match middleware.split_first() {
+        Some((first, rest)) => first(request, &|request| run(rest, handler, request)),
+        None => handler(request),
+    }
+}
+
+// how a path that only matches a route once a trailing slash is added or
+// removed is treated
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum TrailingSlash {
+    // "/entries/" and "/entries" are different paths
+    #[default]
+    Strict,
+    // both reach the route
+    Ignore,
+    // the other spelling is redirected to the registered one
+    Redirect,
+}
+
+// what to do with a path that matches a route only with its trailing slash changed
+#[derive(Debug, PartialEq)]
+pub(crate) enum SlashMatch {
+    // route it as this path
+    Route(String),
+    // redirect to this path
+    Redirect(String),
+}
+
+#[derive(Default)]
+pub(crate) struct Router {
+    routes: Vec<Route>,
+    trailing_slash: TrailingSlash,
+    error_handlers: Vec<(StatusCode, Handler)>,
+    middleware: Vec<Middleware>,
+}
+
+impl Router {
+    pub(crate) fn new() -> Router {
+        Router::default()
+    }
+
+    pub(crate) fn trailing_slash(mut self, trailing_slash: TrailingSlash) -> Router {
+        self.trailing_slash = trailing_slash;
+        self
+    }
+
+    pub(crate) fn route<A>(mut self, method: &'static str, path: &str, handler: impl IntoHandler<A>) -> Router {
+        self.routes.push(Route {
+            method,
+            path: path.to_string(),
+            handler: handler.into_handler(),
+            middleware: Vec::new(),
+            cache: None,
+            doc: None,
+        });
+        self
+    }
+
+    // registers the routes `build` adds with `prefix` in front of their paths,
+    // "/admin" and "/compact" make "/admin/compact". only the routes and the
+    // middleware of the scope are kept, scopes can be nested.
+    pub(crate) fn scope(mut self, prefix: &str, build: impl FnOnce(Router) -> Router) -> Router {
+        let scope = build(Router::new());
+        for mut route in scope.routes {
+            route.path = format!("{prefix}{}", route.path);
+            route.middleware.splice(0..0, scope.middleware.iter().copied());
+            self.routes.push(route);
+        }
+        self
+    }
+
+    // wraps every route of this router or scope, whenever it was added, in
+    // `middleware`. middleware added first runs first.
+    pub(crate) fn middleware(mut self, middleware: Middleware) -> Router {
+        self.middleware.push(middleware);
+        self
+    }
+
+    pub(crate) fn get<A>(self, path: &str, handler: impl IntoHandler<A>) -> Router {
+        self.route("GET", path, handler)
+    }
+
+    pub(crate) fn post<A>(self, path: &str, handler: impl IntoHandler<A>) -> Router {
+        self.route("POST", path, handler)
+    }
+
+    pub(crate) fn put<A>(self, path: &str, handler: impl IntoHandler<A>) -> Router {
+        self.route("PUT", path, handler)
+    }
+
+    pub(crate) fn patch<A>(self, path: &str, handler: impl IntoHandler<A>) -> Router {
+        self.route("PATCH", path, handler)
+    }
+
+    pub(crate) fn delete<A>(self, path: &str, handler: impl IntoHandler<A>) -> Router {
+        self.route("DELETE", path, handler)
+    }
+
+    // forwards every request for `path` to an upstream server, a URL or a
+    // balanced Pool of them, see proxy.rs
+    #[cfg(feature = "proxy")]
+    pub(crate) fn proxy(self, path: &str, upstreams: impl Into<Pool>) -> Router {
+        let pool = Arc::new(upstreams.into());
+        ["GET", "POST", "PUT", "PATCH", "DELETE"].into_iter().fold(self, |router, method| {
+            let pool = Arc::clone(&pool);
+            router.route(method, path, move |request: &Request| pool.forward(request))
+        })
+    }
+
+    // answers the router's own `status` responses with `handler`: 404 for
+    // paths without a route, 405 for methods the server doesn't know, 500 for
+    // handlers that panicked and 503 for handlers that ran out of time
+    pub(crate) fn on_error<A>(mut self, status: StatusCode, handler: impl IntoHandler<A>) -> Router {
+        self.error_handlers.retain(|(registered, _)| *registered != status);
+        self.error_handlers.push((status, handler.into_handler()));
+        self
+    }
+
+    pub(crate) fn error_handler(&self, status: StatusCode) -> Option<&Handler> {
+        self.error_handlers
+            .iter()
+            .find(|(registered, _)| *registered == status)
+            .map(|(_, handler)| handler)
+    }
+
+    // declares the Cache-Control policy of the route added last
+    pub(crate) fn cache(mut self, policy: CachePolicy) -> Router {
+        if let Some(route) = self.routes.last_mut() {
+            route.cache = Some(policy);
+        }
+        self
+    }
+
+    // documents the route added last
+    pub(crate) fn doc(mut self, doc: Doc) -> Router {
+        if let Some(route) = self.routes.last_mut() {
+            route.doc = Some(doc);
+        }
+        self
+    }
+
+    // every route's method, path pattern and documentation, in registration order
+    pub(crate) fn routes(&self) -> impl Iterator<Item = (&'static str, &str, Option<&Doc>)> {
+        self.routes.iter().map(|route| (route.method, route.path.as_str(), route.doc.as_ref()))
+    }
+
+    // the endpoint registered for the method and path and the path parameters
+    // it matched, HEAD uses the GET handler
+    pub(crate) fn find(&self, method: &str, path: &str) -> Option<(Endpoint, HashMap<String, String>)> {
+        let method = if method == "HEAD" { "GET" } else { method };
+        self.routes
+            .iter()
+            .filter(|route| route.method == method)
+            .find_map(|route| {
+                let params = match_path(&route.path, path)?;
+                let middleware = self.middleware.iter().chain(&route.middleware).copied().collect();
+                Some((Endpoint { handler: Arc::clone(&route.handler), middleware }, params))
+            })
+    }
+
+    // the pattern of the route the method and path match, HEAD uses GET's
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    pub(crate) fn pattern(&self, method: &str, path: &str) -> Option<&str> {
+        let method = if method == "HEAD" { "GET" } else { method };
+        self.routes
+            .iter()
+            .find(|route| route.method == method && match_path(&route.path, path).is_some())
+            .map(|route| route.path.as_str())
+    }
+
+    // the Cache-Control policy declared for the method and path, HEAD uses GET's
+    pub(crate) fn cache_policy(&self, method: &str, path: &str) -> Option<CachePolicy> {
+        let method = if method == "HEAD" { "GET" } else { method };
+        self.routes
+            .iter()
+            .find(|route| route.method == method && match_path(&route.path, path).is_some())
+            .and_then(|route| route.cache)
+    }
+
+    // the registered spelling of a path that only matches a route with a
+    // trailing slash added or removed, None when it matches as it is, matches
+    // nothing either way or the router is strict
+    pub(crate) fn match_trailing_slash(&self, path: &str) -> Option<SlashMatch> {
+        if self.trailing_slash == TrailingSlash::Strict || self.is_registered(path) {
+            return None;
+        }
+        let other = match path.strip_suffix('/') {
+            Some(stripped) if !stripped.is_empty() => stripped.to_string(),
+            Some(_) => return None,
+            None => format!("{path}/"),
+        };
+        if !self.is_registered(&other) {
+            return None;
+        }
+        Some(match self.trailing_slash {
+            TrailingSlash::Redirect => SlashMatch::Redirect(other),
+            _ => SlashMatch::Route(other),
+        })
+    }
+
+    fn is_registered(&self, path: &str) -> bool {
+        self.routes.iter().any(|route| match_path(&route.path, path).is_some())
+    }
+
+    // the links of the resource at `path`, read off the routes registered for
+    // it: "self" for its GET route, "collection" for the GET route one
+    // segment up, "update" for its PUT route, or PATCH without one, and
+    // "delete" for its DELETE route, each left out when there is no such route
+    pub(crate) fn links(&self, path: &str) -> Vec<(&'static str, Link)> {
+        let takes = |method: &str, path: &str| {
+            self.routes.iter().any(|route| route.method == method && match_path(&route.path, path).is_some())
+        };
+        let link = |method: &'static str, href: &str| Link { href: href.to_string(), method };
+        let mut links = Vec::new();
+        if takes("GET", path) {
+            links.push(("self", link("GET", path)));
+        }
+        if let Some((collection, _)) = path.rsplit_once('/').filter(|(collection, _)| !collection.is_empty()) {
+            if takes("GET", collection) {
+                links.push(("collection", link("GET", collection)));
+            }
+        }
+        if let Some(method) = ["PUT", "PATCH"].into_iter().find(|method| takes(method, path)) {
+            links.push(("update", link(method, path)));
+        }
+        if takes("DELETE", path) {
+            links.push(("delete", link("DELETE", path)));
+        }
+        links
+    }
+
+    // the methods a path answers to, empty when the path isn't registered
+    pub(crate) fn allowed_methods(&self, path: &str) -> Vec<&'static str> {
+        let mut methods: Vec<&'static str> = Vec::new();
+        for route in self.routes.iter().filter(|route| match_path(&route.path, path).is_some()) {
+            if !methods.contains(&route.method) {
+                methods.push(route.method);
+            }
+            if route.method == "GET" && !methods.contains(&"HEAD") {
+                methods.push("HEAD");
+            }
+        }
+        if !methods.is_empty() {
+            methods.push("OPTIONS");
+        }
+        methods
+    }
+}
+
+// the parameters a path fills in for a route pattern, None if it doesn't match
+fn match_path(pattern: &str, path: &str) -> Option<HashMap<String, String>> {
+    let mut params = HashMap::new();
+    let mut segments = path.split('/');
+    for expected in pattern.split('/') {
+        let segment = segments.next()?;
+        if expected == "*" {
+            let rest: Vec<&str> = std::iter::once(segment).chain(segments).collect();
+            params.insert("*".to_string(), rest.join("/"));
+            return Some(params);
+        }
+        match expected.strip_prefix('{').and_then(|name| name.strip_suffix('}')) {
+            Some(name) if !segment.is_empty() => {
+                params.insert(name.to_string(), segment.to_string());
+            }
+            Some(_) => return None,
+            None if expected == segment => {}
+            None => return None,
+        }
+    }
+    segments.next().is_none().then_some(params)
+}
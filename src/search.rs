@@ -0,0 +1,130 @@
+// the filter and sort parameters of GET /entries
+//
+// `?season=1&min_rating=8&name_contains=Luffy&sort=average_rating&order=desc`
+// narrows and orders the collection before it is paginated, so offset and
+// limit page through the matches. parameters this module doesn't know, like
+// offset and limit themselves, are left to their own parsers.
+
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum SortKey {
+    Id,
+    Rank,
+    Season,
+    Episode,
+    Name,
+    Start,
+    TotalVotes,
+    AverageRating,
+}
+
+impl FromStr for SortKey {
+    type Err = ();
+
+    fn from_str(name: &str) -> Result<SortKey, ()> {
+        match name {
+            "id" => Ok(SortKey::Id),
+            "rank" => Ok(SortKey::Rank),
+            "season" => Ok(SortKey::Season),
+            "episode" => Ok(SortKey::Episode),
+            "name" => Ok(SortKey::Name),
+            "start" => Ok(SortKey::Start),
+            "total_votes" => Ok(SortKey::TotalVotes),
+            "average_rating" => Ok(SortKey::AverageRating),
+            _ => Err(()),
+        }
+    }
+}
+
+impl SortKey {
+    // the name ?sort= takes
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            SortKey::Id => "id",
+            SortKey::Rank => "rank",
+            SortKey::Season => "season",
+            SortKey::Episode => "episode",
+            SortKey::Name => "name",
+            SortKey::Start => "start",
+            SortKey::TotalVotes => "total_votes",
+            SortKey::AverageRating => "average_rating",
+        }
+    }
+}
+
+// what an entry is ordered by for a SortKey, counts like "32,043" as numbers
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum SortValue {
+    Number(u64),
+    Rating(f32),
+    Text(String),
+}
+
+impl SortValue {
+    pub(crate) fn compare(&self, other: &SortValue) -> Ordering {
+        match (self, other) {
+            (SortValue::Number(a), SortValue::Number(b)) => a.cmp(b),
+            (SortValue::Rating(a), SortValue::Rating(b)) => a.total_cmp(b),
+            (SortValue::Text(a), SortValue::Text(b)) => a.cmp(b),
+            // values of different keys, which a cursor checked against its order never meets
+            _ => Ordering::Equal,
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct EntryQuery {
+    pub(crate) season: Option<u32>,
+    pub(crate) episode: Option<u32>,
+    pub(crate) start: Option<u32>,
+    pub(crate) min_rating: Option<f32>,
+    pub(crate) max_rating: Option<f32>,
+    // matched case-insensitively anywhere in the name
+    pub(crate) name_contains: Option<String>,
+    pub(crate) sort: Option<SortKey>,
+    pub(crate) descending: bool,
+    // entries in the trash are left out unless asked for
+    pub(crate) include_deleted: bool,
+}
+
+impl EntryQuery {
+    // reads the parameters from a query string, naming the first invalid one
+    pub(crate) fn parse(params: &HashMap<String, String>) -> Result<EntryQuery, String> {
+        fn parsed<T: FromStr>(params: &HashMap<String, String>, name: &str) -> Result<Option<T>, String> {
+            match params.get(name) {
+                Some(value) => value
+                    .parse()
+                    .map(Some)
+                    .map_err(|_| format!("Invalid {name} parameter: {value}")),
+                None => Ok(None),
+            }
+        }
+
+        let descending = match params.get("order").map(String::as_str) {
+            None | Some("asc") => false,
+            Some("desc") => true,
+            Some(other) => return Err(format!("Invalid order parameter: {other}")),
+        };
+        Ok(EntryQuery {
+            season: parsed(params, "season")?,
+            episode: parsed(params, "episode")?,
+            start: parsed(params, "start")?,
+            min_rating: parsed(params, "min_rating")?,
+            max_rating: parsed(params, "max_rating")?,
+            name_contains: params.get("name_contains").map(|name| name.to_lowercase()),
+            sort: parsed(params, "sort")?,
+            descending,
+            include_deleted: parsed(params, "include_deleted")?.unwrap_or(false),
+        })
+    }
+}
+
+// "32,043" as 32043, so counts sort by value rather than as text
+pub(crate) fn grouped_number(value: &str) -> u64 {
+    value.replace(',', "").parse().unwrap_or(0)
+}
@@ -0,0 +1,90 @@
+// security headers sent with every response
+//
+// Strict-Transport-Security, X-Content-Type-Options, X-Frame-Options,
+// Referrer-Policy and Content-Security-Policy go out with the values in
+// `security_headers`, or the defaults below for the ones it doesn't set. a
+// handler may send its own instead, like the HTML pages whose inline scripts
+// the default policy would block. `route_security_headers` replaces them per
+// path ("/docs") or prefix ("/uploads/*"), over what the handler sent too.
+// an empty value leaves the header out. Strict-Transport-Security only goes
+// out over TLS, it mustn't be sent on a cleartext connection (RFC 6797 7.2).
+
+use crate::config::Config;
+use crate::http::Response;
+use serde::Deserialize;
+
+const STRICT_TRANSPORT_SECURITY: &str = "Strict-Transport-Security";
+
+// every header set, with its default
+const HEADERS: [(&str, &str); 5] = [
+    (STRICT_TRANSPORT_SECURITY, "max-age=31536000"),
+    ("X-Content-Type-Options", "nosniff"),
+    ("X-Frame-Options", "DENY"),
+    ("Referrer-Policy", "no-referrer"),
+    ("Content-Security-Policy", "default-src 'self'; frame-ancestors 'none'"),
+];
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct SecurityHeaders {
+    /// Strict-Transport-Security, sent over HTTPS only.
+    pub(crate) strict_transport_security: Option<String>,
+    /// X-Content-Type-Options.
+    pub(crate) content_type_options: Option<String>,
+    /// X-Frame-Options.
+    pub(crate) frame_options: Option<String>,
+    /// Referrer-Policy.
+    pub(crate) referrer_policy: Option<String>,
+    /// Content-Security-Policy.
+    pub(crate) content_security_policy: Option<String>,
+}
+
+impl SecurityHeaders {
+    // the value set for `header`, None when it is left to the default
+    fn get(&self, header: &str) -> Option<&str> {
+        match header {
+            STRICT_TRANSPORT_SECURITY => self.strict_transport_security.as_deref(),
+            "X-Content-Type-Options" => self.content_type_options.as_deref(),
+            "X-Frame-Options" => self.frame_options.as_deref(),
+            "Referrer-Policy" => self.referrer_policy.as_deref(),
+            "Content-Security-Policy" => self.content_security_policy.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+// the headers configured for a path, an exact entry winning over a prefix one
+fn for_route<'a>(config: &'a Config, path: &str) -> Option<&'a SecurityHeaders> {
+    if let Some(headers) = config.route_security_headers.get(path) {
+        return Some(headers);
+    }
+    config
+        .route_security_headers
+        .iter()
+        .filter_map(|(pattern, headers)| Some((pattern.strip_suffix('*')?, headers)))
+        .filter(|(prefix, _)| path.starts_with(prefix))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, headers)| headers)
+}
+
+// adds the security headers to the response to a request for `path`, sent
+// over TLS when `secure` is set
+pub(crate) fn apply(config: &Config, path: &str, secure: bool, mut response: Response) -> Response {
+    let route = for_route(config, path);
+    for (name, default) in HEADERS {
+        if name == STRICT_TRANSPORT_SECURITY && !secure {
+            continue;
+        }
+        let value = match route.and_then(|route| route.get(name)) {
+            Some(value) => value,
+            None if response.headers.get(name).is_some() => continue,
+            None => config.security_headers.get(name).unwrap_or(default),
+        };
+        if value.is_empty() {
+            response.headers.remove(name);
+        } else {
+            response.headers.insert(name, value);
+        }
+    }
+    response
+}
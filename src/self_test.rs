@@ -0,0 +1,181 @@
+// built-in conformance suites, run with `--self-test <suite>`
+//
+// `http-hardening` feeds request smuggling vectors, oversized fields and
+// malformed header lines through the hardened parser and checks each one is
+// accepted or refused as expected. the unit tests run the same suite, so a
+// parser change that loosens hardening fails `cargo test` too.
+
+use crate::parser::{self, RequestError, MAX_HEADERS, MAX_HEADER_LINE_BYTES, MAX_REQUEST_LINE_BYTES};
+use std::io::{BufReader, Cursor};
+
+// the suites `--self-test` knows about
+pub(crate) const SUITES: [&str; 1] = ["http-hardening"];
+
+// what the hardened parser must do with a vector
+enum Expect {
+    Accept,
+    // refused, with the given response status code
+    Reject(u16),
+}
+
+struct Vector {
+    name: &'static str,
+    request: Vec<u8>,
+    expect: Expect,
+}
+
+fn vector(name: &'static str, request: impl Into<Vec<u8>>, expect: Expect) -> Vector {
+    Vector { name, request: request.into(), expect }
+}
+
+fn http_hardening_vectors() -> Vec<Vector> {
+    let long_value = "a".repeat(MAX_HEADER_LINE_BYTES);
+    let long_target = format!("/{}", "a".repeat(MAX_REQUEST_LINE_BYTES));
+    let many_headers: String = (0..=MAX_HEADERS).map(|i| format!("X-Header-{i}: {i}\r\n")).collect();
+
+    vec![
+        vector("plain GET", "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n", Expect::Accept),
+        vector(
+            "POST with Content-Length",
+            "POST /submit HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhello",
+            Expect::Accept,
+        ),
+        vector(
+            "repeated identical Content-Length",
+            "POST /submit HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\nhello",
+            Expect::Accept,
+        ),
+        vector("no space after colon", "GET / HTTP/1.1\r\nHost:localhost\r\n\r\n", Expect::Accept),
+        // smuggling: the two framings disagree on where the body ends
+        vector(
+            "Content-Length with Transfer-Encoding (CL.TE)",
+            "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 6\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\nG",
+            Expect::Reject(501),
+        ),
+        vector(
+            "Transfer-Encoding with Content-Length (TE.CL)",
+            "POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\nContent-Length: 3\r\n\r\n1\r\nG\r\n0\r\n\r\n",
+            Expect::Reject(501),
+        ),
+        vector(
+            "obfuscated Transfer-Encoding",
+            "POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: xchunked\r\n\r\n",
+            Expect::Reject(501),
+        ),
+        vector(
+            "whitespace before colon",
+            "POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding : chunked\r\n\r\n",
+            Expect::Reject(400),
+        ),
+        vector(
+            "conflicting Content-Length",
+            "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\nhello!",
+            Expect::Reject(400),
+        ),
+        vector(
+            "Content-Length list",
+            "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5, 6\r\n\r\nhello!",
+            Expect::Reject(400),
+        ),
+        vector("signed Content-Length", "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: +5\r\n\r\nhello", Expect::Reject(400)),
+        vector("negative Content-Length", "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: -1\r\n\r\n", Expect::Reject(400)),
+        vector("hex Content-Length", "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0x5\r\n\r\nhello", Expect::Reject(400)),
+        // malformed header lines
+        vector(
+            "obs-fold continuation line",
+            "GET / HTTP/1.1\r\nHost: localhost\r\nX-Folded: a\r\n b\r\n\r\n",
+            Expect::Reject(400),
+        ),
+        vector("bare LF line ending", "GET / HTTP/1.1\r\nHost: localhost\n\r\n", Expect::Reject(400)),
+        vector("space in header name", "GET / HTTP/1.1\r\nHost: localhost\r\nBad Header: x\r\n\r\n", Expect::Reject(400)),
+        vector("empty header name", "GET / HTTP/1.1\r\nHost: localhost\r\n: x\r\n\r\n", Expect::Reject(400)),
+        vector("missing colon", "GET / HTTP/1.1\r\nHost: localhost\r\nNoColon\r\n\r\n", Expect::Reject(400)),
+        vector("NUL in header value", "GET / HTTP/1.1\r\nHost: localhost\r\nX-Null: a\0b\r\n\r\n", Expect::Reject(400)),
+        vector("bare CR in header value", "GET / HTTP/1.1\r\nHost: localhost\r\nX-Cr: a\rb\r\n\r\n", Expect::Reject(400)),
+        vector("invalid UTF-8 in header", &b"GET / HTTP/1.1\r\nHost: localhost\r\nX-Bytes: \xff\xfe\r\n\r\n"[..], Expect::Reject(400)),
+        // malformed request lines
+        vector("separator in method", "G(T / HTTP/1.1\r\nHost: localhost\r\n\r\n", Expect::Reject(400)),
+        vector("double space in request line", "GET  / HTTP/1.1\r\nHost: localhost\r\n\r\n", Expect::Reject(400)),
+        vector("missing version", "GET /\r\n\r\n", Expect::Reject(400)),
+        vector("bare LF request line", "GET / HTTP/1.1\n\r\n", Expect::Reject(400)),
+        // Host
+        vector("HTTP/1.1 without Host", "GET / HTTP/1.1\r\n\r\n", Expect::Reject(400)),
+        vector("HTTP/1.0 without Host", "GET / HTTP/1.0\r\n\r\n", Expect::Accept),
+        vector(
+            "two Host headers",
+            "GET / HTTP/1.1\r\nHost: localhost\r\nHost: evil.example\r\n\r\n",
+            Expect::Reject(400),
+        ),
+        // versions
+        vector("later HTTP/1.x", "GET / HTTP/1.2\r\nHost: localhost\r\n\r\n", Expect::Accept),
+        vector("HTTP/2.0 request line", "GET / HTTP/2.0\r\n\r\n", Expect::Reject(505)),
+        vector("HTTP/0.9 request line", "GET / HTTP/0.9\r\n\r\n", Expect::Reject(505)),
+        vector("malformed version", "GET / HTTP/one\r\n\r\n", Expect::Reject(400)),
+        // oversized fields
+        vector(
+            "oversized request line",
+            format!("GET {long_target} HTTP/1.1\r\nHost: localhost\r\n\r\n"),
+            Expect::Reject(414),
+        ),
+        vector(
+            "oversized header line",
+            format!("GET / HTTP/1.1\r\nHost: localhost\r\nX-Long: {long_value}\r\n\r\n"),
+            Expect::Reject(431),
+        ),
+        vector(
+            "too many headers",
+            format!("GET / HTTP/1.1\r\nHost: localhost\r\n{many_headers}\r\n"),
+            Expect::Reject(431),
+        ),
+    ]
+}
+
+// runs a suite and describes every vector that didn't behave as expected
+pub(crate) fn failures(suite: &str) -> Option<Vec<String>> {
+    let vectors = match suite {
+        "http-hardening" => http_hardening_vectors(),
+        _ => return None,
+    };
+    let mut failures = Vec::new();
+    for vector in vectors {
+        let outcome = parse(&vector.request);
+        let passed = match (&vector.expect, &outcome) {
+            (Expect::Accept, Ok(())) => true,
+            (Expect::Reject(status), Err(e)) => e.status().code() == *status,
+            _ => false,
+        };
+        let result = match &outcome {
+            Ok(()) => "accepted".to_string(),
+            Err(e) => format!("rejected with {} ({})", e.status().code(), e),
+        };
+        println!("{} {}: {}", if passed { "PASS" } else { "FAIL" }, vector.name, result);
+        if !passed {
+            failures.push(format!("{}: {}", vector.name, result));
+        }
+    }
+    Some(failures)
+}
+
+// runs a suite for `--self-test`, returning the process exit code
+pub(crate) fn run(suite: &str) -> i32 {
+    match failures(suite) {
+        Some(failures) if failures.is_empty() => {
+            println!("self-test {}: all vectors passed", suite);
+            0
+        }
+        Some(failures) => {
+            eprintln!("self-test {}: {} vector(s) failed", suite, failures.len());
+            1
+        }
+        None => {
+            eprintln!("Unknown self-test suite {:?}, available: {}", suite, SUITES.join(", "));
+            2
+        }
+    }
+}
+
+fn parse(request: &[u8]) -> Result<(), RequestError> {
+    // the whole request has to be buffered, as it would be off a socket
+    let mut buf_reader = BufReader::with_capacity(request.len().max(1), Cursor::new(request));
+    parser::parse_request_with(&mut buf_reader, true).map(|_| ())
+}
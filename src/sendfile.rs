@@ -0,0 +1,133 @@
+// response bodies sent straight from a file
+//
+// large static files aren't read into memory, the response carries the open
+// file and the part of it to send, written to the socket after the head. on
+// Linux the kernel copies it with sendfile(2) without passing it through the
+// server, elsewhere it is read and written in chunks. copy_file_range only
+// copies between files, so it can't serve a socket.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::net::TcpStream;
+use std::sync::Arc;
+
+// largest piece read or handed to the kernel at once
+const CHUNK: u64 = 1024 * 1024;
+
+// `length` bytes of `file` from `offset`
+#[derive(Debug, Clone)]
+pub(crate) struct FileBody {
+    file: Arc<File>,
+    offset: u64,
+    length: u64,
+}
+
+impl FileBody {
+    // the whole file, `length` bytes long
+    pub(crate) fn new(file: File, length: u64) -> FileBody {
+        FileBody { file: Arc::new(file), offset: 0, length }
+    }
+
+    pub(crate) fn length(&self) -> u64 {
+        self.length
+    }
+
+    // keeps the inclusive range `start..=end` of the bytes it stands for
+    pub(crate) fn narrow(&mut self, start: u64, end: u64) {
+        self.offset += start;
+        self.length = end + 1 - start;
+    }
+
+    // the bytes in memory, for writers that aren't a socket
+    pub(crate) fn read(&self) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        self.reader()?.read_to_end(&mut bytes)?;
+        if bytes.len() as u64 != self.length {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(bytes)
+    }
+
+    fn reader(&self) -> io::Result<impl Read + '_> {
+        let mut file = &*self.file;
+        file.seek(SeekFrom::Start(self.offset))?;
+        Ok(file.take(self.length))
+    }
+
+    #[cfg(target_os = "linux")]
+    pub(crate) fn send(&self, stream: &TcpStream) -> io::Result<()> {
+        use std::os::fd::AsRawFd;
+        let mut offset = self.offset as libc::off_t;
+        let mut remaining = self.length;
+        while remaining > 0 {
+            match send_chunk(stream.as_raw_fd(), self.file.as_raw_fd(), &mut offset, remaining) {
+                Ok(sent) => remaining -= sent,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn send(&self, mut stream: &TcpStream) -> io::Result<()> {
+        use std::io::{BufReader, Write};
+        let copied = io::copy(&mut BufReader::with_capacity(CHUNK as usize, self.reader()?), &mut stream)?;
+        if copied != self.length {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        stream.flush()
+    }
+
+    // send for a tokio stream, sendfile(2) is retried whenever the socket is writable again
+    #[cfg(all(feature = "tokio", target_os = "linux"))]
+    pub(crate) async fn send_async(&self, stream: &mut tokio::net::TcpStream) -> io::Result<()> {
+        use std::os::fd::AsRawFd;
+        use tokio::io::Interest;
+        let mut offset = self.offset as libc::off_t;
+        let mut remaining = self.length;
+        while remaining > 0 {
+            stream.writable().await?;
+            let sent = stream.try_io(Interest::WRITABLE, || {
+                send_chunk(stream.as_raw_fd(), self.file.as_raw_fd(), &mut offset, remaining)
+            });
+            match sent {
+                Ok(sent) => remaining -= sent,
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(all(feature = "tokio", not(target_os = "linux")))]
+    pub(crate) async fn send_async(&self, stream: &mut tokio::net::TcpStream) -> io::Result<()> {
+        use tokio::io::AsyncWriteExt;
+        let mut reader = self.reader()?;
+        let mut chunk = vec![0; CHUNK as usize];
+        let mut remaining = self.length;
+        while remaining > 0 {
+            let read = reader.read(&mut chunk)?;
+            if read == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            stream.write_all(&chunk[..read]).await?;
+            remaining -= read as u64;
+        }
+        Ok(())
+    }
+}
+
+// one sendfile(2) call, how many bytes went out
+#[cfg(target_os = "linux")]
+fn send_chunk(socket: i32, file: i32, offset: &mut libc::off_t, remaining: u64) -> io::Result<u64> {
+    let count = remaining.min(CHUNK) as usize;
+    // SAFETY: both descriptors are open for the duration of the call and
+    // `offset` points to a live off_t
+    match unsafe { libc::sendfile(socket, file, offset, count) } {
+        -1 => Err(io::Error::last_os_error()),
+        // the file got shorter since it was opened
+        0 => Err(io::ErrorKind::UnexpectedEof.into()),
+        sent => Ok(sent as u64),
+    }
+}
@@ -0,0 +1,62 @@
+// server-side sessions, kept in memory under a random id the client holds in a cookie
+//
+// the cookie carries the id only, what the session holds and when it ends
+// stay on the server, so a client can't stretch its session by sending an
+// old cookie or one it made up. an expired session is dropped the first time
+// it is looked up after its time, and starting a session sweeps out the
+// others that have expired. expiry goes through clock::is_expired, with the
+// skew leeway every other expiry check allows. sessions don't survive a
+// restart.
+
+use crate::clock;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex, PoisonError};
+
+struct Session<T> {
+    value: T,
+    expires: DateTime<Utc>,
+}
+
+pub(crate) struct Store<T> {
+    sessions: LazyLock<Mutex<HashMap<String, Session<T>>>>,
+}
+
+impl<T: Clone> Store<T> {
+    pub(crate) const fn new() -> Store<T> {
+        Store { sessions: LazyLock::new(Mutex::default) }
+    }
+
+    // starts a session holding `value` for `lifetime`, returning its id
+    pub(crate) fn start(&self, value: T, lifetime: chrono::Duration) -> Result<String, getrandom::Error> {
+        let id = crate::random_hex(32)?;
+        let mut sessions = self.sessions.lock().unwrap_or_else(PoisonError::into_inner);
+        sessions.retain(|_, session| !clock::is_expired(&session.expires));
+        sessions.insert(id.clone(), Session { value, expires: Utc::now() + lifetime });
+        Ok(id)
+    }
+
+    // what the session `id` holds, None once it expired or ended
+    #[cfg_attr(not(feature = "oauth"), allow(dead_code))]
+    pub(crate) fn get(&self, id: &str) -> Option<T> {
+        self.update(id, |value| value.clone())
+    }
+
+    // runs `f` on what the session `id` holds, None once it expired or ended
+    pub(crate) fn update<R>(&self, id: &str, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        let mut sessions = self.sessions.lock().unwrap_or_else(PoisonError::into_inner);
+        match sessions.get_mut(id) {
+            Some(session) if !clock::is_expired(&session.expires) => Some(f(&mut session.value)),
+            Some(_) => {
+                sessions.remove(id);
+                None
+            }
+            None => None,
+        }
+    }
+
+    #[cfg_attr(not(feature = "oauth"), allow(dead_code))]
+    pub(crate) fn end(&self, id: &str) {
+        self.sessions.lock().unwrap_or_else(PoisonError::into_inner).remove(id);
+    }
+}
@@ -0,0 +1,90 @@
+// warnings for requests that take longer than `slow_request_ms`
+//
+// a request goes through three phases: parse, from the moment the server
+// starts reading it until its body is in, handler, until the response is
+// serialized, and write, until it is sent. a slow one is logged at WARN with
+// its method, path, body sizes, how long each phase took and which of them
+// took the longest.
+
+use crate::config;
+use crate::http::split_uri;
+use crate::log;
+use crate::request_id;
+use std::time::{Duration, Instant};
+
+pub(crate) struct Timing {
+    started: Instant,
+    parsed: Option<Parsed>,
+    handled: Option<(Instant, usize)>,
+}
+
+// what is known about the request once it has been read
+struct Parsed {
+    at: Instant,
+    id: Option<String>,
+    method: String,
+    path: String,
+    body_bytes: usize,
+}
+
+impl Timing {
+    // call as the server starts reading a request
+    pub(crate) fn start() -> Timing {
+        Timing { started: Instant::now(), parsed: None, handled: None }
+    }
+
+    pub(crate) fn parsed(&mut self, method: &str, uri: &str, body_bytes: usize) {
+        self.parsed = Some(Parsed {
+            at: Instant::now(),
+            id: request_id::current(),
+            method: method.to_string(),
+            path: split_uri(uri).0.to_string(),
+            body_bytes,
+        });
+    }
+
+    pub(crate) fn handled(&mut self, response_bytes: usize) {
+        self.handled = Some((Instant::now(), response_bytes));
+    }
+
+    // call once the response is written, warns when the request was slow
+    pub(crate) fn finish(self) {
+        let threshold = config::get().slow_request_ms;
+        if threshold == 0 {
+            return;
+        }
+        if let Some(message) = self.report(Duration::from_millis(threshold), Instant::now()) {
+            let _id = self.parsed.as_ref().and_then(|parsed| parsed.id.clone()).map(request_id::enter);
+            log::warning!("{}", message);
+        }
+    }
+
+    // the warning for a request written at `now`, None when it took less than
+    // `threshold` or was never fully read
+    pub(crate) fn report(&self, threshold: Duration, now: Instant) -> Option<String> {
+        let parsed = self.parsed.as_ref()?;
+        let (handled, response_bytes) = self.handled.unwrap_or((now, 0));
+        let total = now.duration_since(self.started);
+        if total < threshold {
+            return None;
+        }
+        let phases = [
+            ("parse", parsed.at.duration_since(self.started)),
+            ("handler", handled.duration_since(parsed.at)),
+            ("write", now.duration_since(handled)),
+        ];
+        let slowest = phases.iter().max_by_key(|(_, took)| *took).map_or("handler", |(phase, _)| phase);
+        Some(format!(
+            "Slow request: {} {} took {:?} (parse {:?}, handler {:?}, write {:?}), mostly {}; request body {} bytes, response {} bytes",
+            parsed.method,
+            parsed.path,
+            total,
+            phases[0].1,
+            phases[1].1,
+            phases[2].1,
+            slowest,
+            parsed.body_bytes,
+            response_bytes
+        ))
+    }
+}
@@ -0,0 +1,253 @@
+// static files served from a directory, for virtual hosts mapped to one
+//
+// `virtual_hosts` in the config maps host names to a mount, for example
+// {"static.local": {"root": "public"}}. requests for those hosts are answered
+// from the mount's root instead of the API routes, other hosts and requests
+// without a Host still reach the routes. a path is resolved inside the root,
+// segments that could step out of it or name hidden files are answered 404
+// like a missing file, and a directory is served through its index.html.
+// with `spa` set a GET for a path without a file that doesn't look like a
+// file name either, "/settings/profile" but not "/logo.png", gets the root's
+// index.html, so a single-page app can route it on the client. with
+// `autoindex` a directory without an index.html is listed, see autoindex.rs.
+//
+// a file with a precompressed copy next to it, "app.js.br" or "app.js.gz", is
+// sent as that copy to clients accepting its encoding. the ETag is made from
+// the size and modification time of the file sent, which are kept in memory
+// for `metadata_secs` instead of looked up on every request, so a file changed
+// meanwhile may go out under its old tag until then. files larger than
+// IN_MEMORY_MAX aren't read, the connection sends them from the open file.
+
+use crate::autoindex;
+use crate::compression;
+use crate::config::Config;
+use crate::http::{http_date, HeaderMap, Response, StatusCode};
+use crate::sendfile::FileBody;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct StaticMount {
+    /// Directory the files are served from.
+    pub(crate) root: String,
+    /// Seconds the metadata of a file is trusted before it is read again, 0 to always read it.
+    #[serde(default = "default_metadata_secs")]
+    pub(crate) metadata_secs: u64,
+    /// Serve the root index.html for paths without a file, unless they have an extension.
+    #[serde(default)]
+    pub(crate) spa: bool,
+    /// List the contents of directories without an index.html.
+    #[serde(default)]
+    pub(crate) autoindex: bool,
+}
+
+fn default_metadata_secs() -> u64 {
+    2
+}
+
+// content codings looked for next to a file, with their suffix, preferred first
+const PRECOMPRESSED: [(&str, &str); 2] = [("br", "br"), ("gzip", "gz")];
+
+// files looked up before the metadata of all of them is dropped
+const MAX_CACHED: usize = 4096;
+
+// larger files are sent from the file instead of read into memory
+const IN_MEMORY_MAX: u64 = 64 * 1024;
+
+#[derive(Clone)]
+struct FileInfo {
+    path: PathBuf,
+    length: u64,
+    modified: Option<SystemTime>,
+}
+
+impl FileInfo {
+    fn read(path: PathBuf) -> Option<FileInfo> {
+        let metadata = fs::metadata(&path).ok().filter(|metadata| metadata.is_file())?;
+        Some(FileInfo { length: metadata.len(), modified: metadata.modified().ok(), path })
+    }
+
+    // a strong tag, the file's modification time and size, told apart by the coding
+    fn etag(&self, coding: Option<&str>) -> Option<String> {
+        let nanos = self.modified?.duration_since(UNIX_EPOCH).ok()?.as_nanos();
+        let suffix = coding.map(|coding| format!("-{coding}")).unwrap_or_default();
+        Some(format!("\"{nanos:x}-{:x}{suffix}\"", self.length))
+    }
+}
+
+// a request path resolved to a file and its precompressed copies
+#[derive(Clone)]
+struct Resolved {
+    file: FileInfo,
+    variants: Vec<(&'static str, FileInfo)>,
+    checked: Instant,
+}
+
+// by root and request path
+static METADATA: LazyLock<Mutex<HashMap<(String, String), Resolved>>> = LazyLock::new(Mutex::default);
+
+// the mount serving `host`, None for hosts the API routes answer
+pub(crate) fn for_host<'a>(config: &'a Config, host: Option<&str>) -> Option<&'a StaticMount> {
+    let host = host?;
+    config
+        .virtual_hosts
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(host))
+        .map(|(_, mount)| mount)
+}
+
+pub(crate) fn serve(mount: &StaticMount, method: &str, path: &str, headers: &HeaderMap) -> Response {
+    if !matches!(method, "GET" | "HEAD") {
+        return Response::problem(StatusCode::MethodNotAllowed, format!("{method} is not allowed on static files"))
+            .header("Allow", "GET, HEAD");
+    }
+    let mut key = (mount.root.clone(), path.to_string());
+    let mut resolved = lookup(mount, &key);
+    if resolved.is_none() && mount.autoindex {
+        let directory = locate(Path::new(&mount.root), path).filter(|directory| directory.is_dir());
+        if let Some(Ok(listing)) = directory.map(|directory| autoindex::listing(&directory, path, headers.get("Accept"))) {
+            return listing;
+        }
+    }
+    if resolved.is_none() && mount.spa && !has_extension(path) {
+        key.1 = "/".to_string();
+        resolved = lookup(mount, &key);
+    }
+    let Some(resolved) = resolved else {
+        return not_found();
+    };
+    let accept_encoding = headers.get("Accept-Encoding");
+    let variant = resolved
+        .variants
+        .iter()
+        .find(|(coding, _)| accept_encoding.is_some_and(|accepted| compression::accepts(accepted, coding)));
+    let (file, coding) = match variant {
+        Some((coding, file)) => (file, Some(*coding)),
+        None => (&resolved.file, None),
+    };
+    let mut response = Response::new(StatusCode::Ok)
+        .content_type(content_type(&resolved.file.path))
+        .header("Accept-Ranges", "bytes");
+    let body = if file.length > IN_MEMORY_MAX {
+        // sized from the open file, the metadata kept in memory may be out of date
+        File::open(&file.path)
+            .and_then(|opened| {
+                let length = opened.metadata()?.len();
+                Ok(response.file(FileBody::new(opened, length)))
+            })
+    } else {
+        fs::read(&file.path).map(|contents| response.body(contents))
+    };
+    response = match body {
+        Ok(response) => response,
+        Err(_) => {
+            // gone since it was looked up
+            forget(&key);
+            return not_found();
+        }
+    };
+    if let Some(coding) = coding {
+        response = response.header("Content-Encoding", coding);
+    }
+    if !resolved.variants.is_empty() {
+        response = response.header("Vary", "Accept-Encoding");
+    }
+    if let Some(etag) = file.etag(coding) {
+        response = response.header("ETag", etag);
+    }
+    if let Some(modified) = file.modified {
+        response = response.header("Last-Modified", http_date(modified.into()));
+    }
+    response
+}
+
+// the file a request path names and its copies, from memory while the
+// metadata is recent enough
+fn lookup(mount: &StaticMount, key: &(String, String)) -> Option<Resolved> {
+    let ttl = Duration::from_secs(mount.metadata_secs);
+    let cached = METADATA.lock().unwrap_or_else(PoisonError::into_inner).get(key).cloned();
+    if let Some(resolved) = cached.filter(|resolved| resolved.checked.elapsed() < ttl) {
+        return Some(resolved);
+    }
+    let file = FileInfo::read(resolve(Path::new(&mount.root), &key.1)?)?;
+    let variants = PRECOMPRESSED
+        .iter()
+        .filter_map(|(coding, suffix)| {
+            let mut path = file.path.clone().into_os_string();
+            path.push(format!(".{suffix}"));
+            Some((*coding, FileInfo::read(PathBuf::from(path))?))
+        })
+        .collect();
+    let resolved = Resolved { file, variants, checked: Instant::now() };
+    if !ttl.is_zero() {
+        let mut metadata = METADATA.lock().unwrap_or_else(PoisonError::into_inner);
+        if metadata.len() >= MAX_CACHED {
+            metadata.clear();
+        }
+        metadata.insert(key.clone(), resolved.clone());
+    }
+    Some(resolved)
+}
+
+fn forget(key: &(String, String)) {
+    METADATA.lock().unwrap_or_else(PoisonError::into_inner).remove(key);
+}
+
+// whether the last segment of a path looks like a file name, "app.js"
+fn has_extension(path: &str) -> bool {
+    path.rsplit('/').next().is_some_and(|name| name.contains('.'))
+}
+
+fn not_found() -> Response {
+    Response::problem(StatusCode::NotFound, "No file at this path")
+}
+
+// the file `path` names under `root`, None when it would leave the root or
+// there is no such file
+fn resolve(root: &Path, path: &str) -> Option<PathBuf> {
+    let mut file = locate(root, path)?;
+    if file.is_dir() {
+        file.push("index.html");
+    }
+    file.is_file().then_some(file)
+}
+
+// where `path` leads under `root`, None when it would leave the root or
+// names something hidden
+fn locate(root: &Path, path: &str) -> Option<PathBuf> {
+    let mut file = root.to_path_buf();
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        if segment.starts_with('.') || segment.contains('\\') {
+            return None;
+        }
+        file.push(segment);
+    }
+    Some(file)
+}
+
+// media type from the file extension
+fn content_type(file: &Path) -> &'static str {
+    let extension = file.extension().and_then(|extension| extension.to_str()).map(str::to_ascii_lowercase);
+    match extension.as_deref() {
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js" | "mjs") => "text/javascript; charset=utf-8",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("csv") => "text/csv; charset=utf-8",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("pdf") => "application/pdf",
+        Some("wasm") => "application/wasm",
+        Some("woff2") => "font/woff2",
+        _ => "application/octet-stream",
+    }
+}
@@ -0,0 +1,62 @@
+// counters behind GET /admin/stats
+//
+// plain atomics, bumped by the connection handlers as they go and read
+// without a lock, so a busy server pays one relaxed add per connection and
+// two per request. the counts are since the server started: connections
+// accepted, requests by method and answers by class of error. the endpoint
+// adds the uptime, the pool's utilization and the size of the store.
+
+use crate::http::StatusCode;
+use crate::router::KNOWN_METHODS;
+use serde_json::{json, Map, Value};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::LazyLock;
+use std::time::Instant;
+
+static STARTED: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+static CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+
+// by method in the order of KNOWN_METHODS, the last one for any other
+static REQUESTS: [AtomicU64; KNOWN_METHODS.len() + 1] = [const { AtomicU64::new(0) }; KNOWN_METHODS.len() + 1];
+
+static CLIENT_ERRORS: AtomicU64 = AtomicU64::new(0);
+static SERVER_ERRORS: AtomicU64 = AtomicU64::new(0);
+
+// starts the uptime clock, called as the server starts
+pub(crate) fn start() {
+    LazyLock::force(&STARTED);
+}
+
+pub(crate) fn connection_accepted() {
+    CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+}
+
+// counts a request answered with `status`
+pub(crate) fn request(method: &str, status: StatusCode) {
+    let index = KNOWN_METHODS.iter().position(|known| *known == method).unwrap_or(KNOWN_METHODS.len());
+    REQUESTS[index].fetch_add(1, Ordering::Relaxed);
+    match status.code() {
+        400..=499 => CLIENT_ERRORS.fetch_add(1, Ordering::Relaxed),
+        500..=599 => SERVER_ERRORS.fetch_add(1, Ordering::Relaxed),
+        _ => 0,
+    };
+}
+
+// the counters as GET /admin/stats shows them, without the pool and store
+pub(crate) fn snapshot() -> Value {
+    let mut by_method = Map::new();
+    for (method, count) in KNOWN_METHODS.iter().chain(&["other"]).zip(&REQUESTS) {
+        by_method.insert(method.to_string(), count.load(Ordering::Relaxed).into());
+    }
+    let total: u64 = REQUESTS.iter().map(|count| count.load(Ordering::Relaxed)).sum();
+    json!({
+        "uptime_secs": STARTED.elapsed().as_secs(),
+        "connections_accepted": CONNECTIONS.load(Ordering::Relaxed),
+        "requests": { "total": total, "by_method": by_method },
+        "errors": {
+            "client": CLIENT_ERRORS.load(Ordering::Relaxed),
+            "server": SERVER_ERRORS.load(Ordering::Relaxed),
+        },
+    })
+}
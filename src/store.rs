@@ -0,0 +1,345 @@
+// storage layer for the characters json file
+//
+// every read and write of the data file goes through here, so the on-disk
+// format (pretty or compact, always newline terminated) is decided in one place
+//
+// the parsed entries are kept in memory between requests. writes replace the
+// copy along with the file, and with `reload_data_file` a watcher reads the
+// file again when anything else changes it, keeping the old copy if it doesn't
+// parse.
+// an index of their positions by id is kept with them, so id lookups don't
+// scan. each write is recorded in a journal first, see journal.rs. the generation
+// counts the times the entries in memory were replaced or dropped, what is
+// derived from them is stale once it moved on.
+
+use crate::config::{self, DataFormat, IdStrategy};
+use crate::endpoints::Character;
+use crate::audit;
+use crate::journal::{self, Record};
+use crate::log;
+use chrono::{DateTime, Utc};
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+static WRITE_LOCK: Mutex<()> = Mutex::new(());
+
+static CACHE: Mutex<Option<Cached>> = Mutex::new(None);
+
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+struct Cached {
+    characters: Vec<Character>,
+    index: Index,
+    // the file's stamp when it was read or written, what a change is told by
+    stamp: Option<Stamp>,
+}
+
+// modification time and size
+type Stamp = (SystemTime, u64);
+
+// the position of each id in the entries it was built from, the first one
+// when an id is repeated
+pub(crate) type Index = Arc<HashMap<usize, usize>>;
+
+fn index(characters: &[Character]) -> Index {
+    let mut index = HashMap::with_capacity(characters.len());
+    for (position, character) in characters.iter().enumerate() {
+        index.entry(character.id()).or_insert(position);
+    }
+    Arc::new(index)
+}
+
+fn data_file() -> &'static Path {
+    Path::new(&config::get().data_file)
+}
+
+pub(crate) fn load() -> Vec<Character> {
+    load_indexed().0
+}
+
+// the entries along with the index of their positions, which stays right for
+// them while the caller holds the write lock and only changes them in place
+pub(crate) fn load_indexed() -> (Vec<Character>, Index) {
+    let mut cache = cache();
+    let cached = cached(&mut cache);
+    (cached.characters.clone(), cached.index.clone())
+}
+
+// the entry with `id`, without copying the others
+pub(crate) fn get(id: usize) -> Option<Character> {
+    let mut cache = cache();
+    let cached = cached(&mut cache);
+    cached.index.get(&id).map(|&position| cached.characters[position].clone())
+}
+
+// reads the data file into memory, the server does it before it starts so
+// that a file it can't read stops it there rather than failing requests
+pub(crate) fn open() -> io::Result<()> {
+    let mut cache = cache();
+    if cache.is_none() {
+        *cache = Some(read()?);
+    }
+    Ok(())
+}
+
+fn cached(cache: &mut Option<Cached>) -> &Cached {
+    if cache.is_none() {
+        let cached = read().unwrap_or_else(|e| panic!("Failed to read {}: {e}", data_file().display()));
+        *cache = Some(cached);
+    }
+    cache.as_ref().expect("filled above")
+}
+
+fn read() -> io::Result<Cached> {
+    // stamped before reading, a change made meanwhile is noticed next time
+    let stamp = stamp();
+    let file = File::open(data_file())?;
+    let characters: Vec<Character> = serde_json::from_reader(io::BufReader::new(file))?;
+    Ok(Cached { index: index(&characters), characters, stamp })
+}
+
+fn cache() -> MutexGuard<'static, Option<Cached>> {
+    CACHE.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// moves on after every change to the entries, once the new ones are in place
+pub(crate) fn generation() -> u64 {
+    GENERATION.load(Ordering::Acquire)
+}
+
+fn stamp() -> Option<Stamp> {
+    let metadata = fs::metadata(data_file()).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+// reads the file again if it isn't the one the entries kept in memory were
+// read from or written to, true when they were replaced. a file that doesn't
+// parse, an edit saved halfway or a typo, leaves the old entries in place
+pub(crate) fn reload_if_changed() -> bool {
+    let mut cache = cache();
+    let Some(cached) = cache.as_mut().filter(|cached| cached.stamp != stamp()) else {
+        return false;
+    };
+    match read() {
+        Ok(read) => {
+            *cached = read;
+            GENERATION.fetch_add(1, Ordering::Release);
+            true
+        }
+        Err(e) => {
+            log::warning!("Warning: the data file changed but can't be read, keeping the entries in memory: {e}");
+            false
+        }
+    }
+}
+
+// watches the data file's directory, the file itself is replaced on every
+// write, and reloads the data file when another process changes it
+pub(crate) fn spawn_watcher() {
+    let (sender, events) = mpsc::channel();
+    let mut watcher = match notify::recommended_watcher(sender) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            log::warning!("Warning: can't watch the data file, external changes are not seen: {e}");
+            return;
+        }
+    };
+    let file_name = data_file().file_name().map(|name| name.to_owned());
+    let directory = match data_file().parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    if let Err(e) = watcher.watch(directory, RecursiveMode::NonRecursive) {
+        log::warning!("Warning: can't watch the data file, external changes are not seen: {e}");
+        return;
+    }
+    thread::spawn(move || {
+        // the watcher stops when dropped
+        let _watcher = watcher;
+        for event in events.into_iter().flatten() {
+            let touches_file = event.paths.iter().any(|path| path.file_name() == file_name.as_deref());
+            if touches_file && !matches!(event.kind, EventKind::Access(_)) && reload_if_changed() {
+                log::info!("Data file changed on disk, reloading it");
+            }
+        }
+    });
+}
+
+// how often the purger looks for entries to remove, at most
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// removes the entries that were in the trash longer than `purge_deleted_after_secs`
+pub(crate) fn spawn_purger() {
+    let secs = config::get().purge_deleted_after_secs;
+    thread::spawn(move || loop {
+        // an age too large to subtract never comes
+        let cutoff = i64::try_from(secs)
+            .ok()
+            .and_then(chrono::Duration::try_seconds)
+            .and_then(|age| Utc::now().checked_sub_signed(age));
+        match cutoff.map(purge_deleted) {
+            None | Some(0) => {}
+            Some(count) => log::info!("Purged {count} entries from the trash"),
+        }
+        thread::sleep(Duration::from_secs(secs).min(PURGE_INTERVAL));
+    });
+}
+
+// removes the entries moved to the trash before `cutoff`, how many
+pub(crate) fn purge_deleted(cutoff: DateTime<Utc>) -> usize {
+    let _actor = audit::enter(audit::Actor::server("purge trash"));
+    let _lock = lock();
+    let mut characters = load();
+    let before = characters.len();
+    characters.retain(|c| c.deleted_at().is_none_or(|deleted_at| deleted_at >= cutoff));
+    let purged = before - characters.len();
+    if purged > 0 {
+        save(&characters);
+    }
+    purged
+}
+
+// held across a load, modify and save so concurrent writers can't undo each
+// other's changes, or change the data between a precondition check and the write
+pub(crate) fn lock() -> MutexGuard<'static, ()> {
+    WRITE_LOCK.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// the id for an entry about to be added to `characters`, callers hold the
+// write lock and store the entry before asking for another
+pub(crate) fn next_id(characters: &[Character]) -> io::Result<usize> {
+    let after_highest = characters.iter().map(Character::id).max().map_or(1, |id| id + 1);
+    match config::get().id_strategy {
+        IdStrategy::Sequential => Ok(after_highest),
+        IdStrategy::Counter => {
+            let counter = sibling(".next_id");
+            let stored = match fs::read_to_string(&counter) {
+                Ok(contents) => contents.trim().parse().unwrap_or(0),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+                Err(e) => return Err(e),
+            };
+            // an edited or stale counter never hands out an id that is taken
+            let id = after_highest.max(stored);
+            fs::write(&counter, format!("{}\n", id + 1))?;
+            Ok(id)
+        }
+    }
+}
+
+// a file next to the data file, "one_piece2.json.tmp" for ".tmp"
+fn sibling(suffix: &str) -> PathBuf {
+    let mut path = data_file().as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+// when the entries held in memory were last changed, the data file's mtime
+// as they were read or written, for Last-Modified. it is the time of what is
+// served, a change another process made is only seen once it is reloaded
+pub(crate) fn modified() -> Option<SystemTime> {
+    let mut cache = cache();
+    cached(&mut cache).stamp.map(|(modified, _)| modified)
+}
+
+// the size of the data file in bytes, None before it is first written
+pub(crate) fn size() -> Option<u64> {
+    fs::metadata(data_file()).map(|metadata| metadata.len()).ok()
+}
+
+// rewrites the data file in the configured format
+pub(crate) fn save(characters: &[Character]) {
+    let before = load();
+    let record = Record::diff(&before, characters);
+    let journal = sibling(".journal");
+    if !record.is_empty() {
+        journal::append(&journal, &record).expect("Failed to write journal");
+    }
+    write(characters, config::get().data_format).expect("Failed to write data file");
+    if !record.is_empty() {
+        journal::clear(&journal).expect("Failed to clear journal");
+    }
+    audit::record(&before, characters);
+}
+
+// applies the writes a crash left in the journal to the data file, how many
+pub(crate) fn recover() -> io::Result<usize> {
+    let journal = sibling(".journal");
+    let records = journal::read(&journal)?;
+    if records.is_empty() {
+        return Ok(0);
+    }
+    let count = records.len();
+    let mut characters = load();
+    for record in records {
+        record.apply(&mut characters);
+    }
+    write(&characters, config::get().data_format)?;
+    journal::clear(&journal)?;
+    Ok(count)
+}
+
+// copies the entries into a new file in `dir` named after the data file and
+// the time, "one_piece2-20241016T101500.123Z.json", returning the name
+pub(crate) fn backup(dir: &Path) -> io::Result<String> {
+    let stem = data_file().file_stem().and_then(|stem| stem.to_str()).unwrap_or("data");
+    let name = format!("{stem}-{}.json", Utc::now().format("%Y%m%dT%H%M%S%.3fZ"));
+    fs::create_dir_all(dir)?;
+    let file = OpenOptions::new().write(true).create_new(true).open(dir.join(&name))?;
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer_pretty(&mut writer, &load())?;
+    writer.write_all(b"\n")?;
+    writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    Ok(name)
+}
+
+// the entries of the backup `name` in `dir`
+pub(crate) fn read_backup(dir: &Path, name: &str) -> io::Result<Vec<Character>> {
+    let file = File::open(dir.join(name))?;
+    Ok(serde_json::from_reader(io::BufReader::new(file))?)
+}
+
+// rewrites the data file from the entries held in memory, returning its size
+pub(crate) fn flush() -> io::Result<u64> {
+    write(&load(), config::get().data_format)?;
+    Ok(fs::metadata(data_file())?.len())
+}
+
+// makes sure the data file is on disk before the process exits. every write
+// is synced as it happens, this only covers a file changed by someone else
+pub(crate) fn sync() -> io::Result<()> {
+    File::open(data_file())?.sync_all()
+}
+
+// rewrites the data file without any whitespace, whatever the configured format,
+// returning its size before and after
+pub(crate) fn compact() -> io::Result<(u64, u64)> {
+    let before = fs::metadata(data_file())?.len();
+    write(&load(), DataFormat::Compact)?;
+    let after = fs::metadata(data_file())?.len();
+    Ok((before, after))
+}
+
+// writes a temporary file next to the data file and renames it over it, so
+// readers and a crash mid-write only ever see the old or the new data
+fn write(characters: &[Character], format: DataFormat) -> io::Result<()> {
+    let temporary = sibling(".tmp");
+    let mut writer = BufWriter::new(File::create(&temporary)?);
+    match format {
+        DataFormat::Pretty => serde_json::to_writer_pretty(&mut writer, characters)?,
+        DataFormat::Compact => serde_json::to_writer(&mut writer, characters)?,
+    }
+    // keep the file newline terminated so it plays well with text tools
+    writer.write_all(b"\n")?;
+    writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    fs::rename(temporary, data_file())?;
+    *cache() = Some(Cached { characters: characters.to_vec(), index: index(characters), stamp: stamp() });
+    GENERATION.fetch_add(1, Ordering::Release);
+    Ok(())
+}
@@ -0,0 +1,68 @@
+// systemd socket activation
+//
+// a .socket unit makes systemd bind the listening sockets itself and pass
+// them to the server as file descriptors 3, 4, ... with LISTEN_FDS holding
+// how many there are and LISTEN_PID the process they are meant for. the
+// server then never binds anything: it can be restarted without refusing a
+// connection, since the socket stays open in systemd, and it can listen on a
+// privileged port without running as root.
+
+use std::env;
+use std::io;
+use std::net::TcpListener;
+use std::process;
+
+// the first descriptor systemd passes, after stdin, stdout and stderr
+const FIRST_FD: i32 = 3;
+
+// how many sockets were passed to the process with id `own_pid`, from the
+// values of LISTEN_PID and LISTEN_FDS
+pub(crate) fn passed_fds(listen_pid: Option<&str>, listen_fds: Option<&str>, own_pid: u32) -> usize {
+    // a child inherits the variables, only the process systemd started owns the sockets
+    if listen_pid.and_then(|pid| pid.trim().parse::<u32>().ok()) != Some(own_pid) {
+        return 0;
+    }
+    listen_fds.and_then(|fds| fds.trim().parse().ok()).unwrap_or(0)
+}
+
+// the listening sockets systemd passed, none when it didn't start the server
+pub(crate) fn listeners() -> io::Result<Vec<TcpListener>> {
+    let listen_pid = env::var("LISTEN_PID").ok();
+    let listen_fds = env::var("LISTEN_FDS").ok();
+    let count = passed_fds(listen_pid.as_deref(), listen_fds.as_deref(), process::id());
+    if count == 0 {
+        return Ok(Vec::new());
+    }
+    // taken once, nothing started from here must think the sockets are its own
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(name);
+    }
+    (0..count).map(|offset| from_fd(FIRST_FD + offset as i32)).collect()
+}
+
+#[cfg(unix)]
+fn from_fd(fd: i32) -> io::Result<TcpListener> {
+    use socket2::{Socket, Type};
+    use std::os::unix::io::FromRawFd;
+
+    // systemd hands each descriptor to this process alone, so owning it is sound
+    let socket = unsafe { Socket::from_raw_fd(fd) };
+    if socket.r#type()? != Type::STREAM {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("file descriptor {fd} is not a stream socket"),
+        ));
+    }
+    socket.set_cloexec(true)?;
+    // the accept loop expects a blocking socket, whatever NonBlocking= says
+    socket.set_nonblocking(false)?;
+    Ok(socket.into())
+}
+
+#[cfg(not(unix))]
+fn from_fd(fd: i32) -> io::Result<TcpListener> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("socket activation (file descriptor {fd}) needs a unix system"),
+    ))
+}
@@ -0,0 +1,259 @@
+// a small template engine for the HTML pages, enabled with the `templates` cargo feature
+//
+// templates are compiled into the binary from assets/templates and parsed
+// once, Response::render fills one in from any serializable context:
+//
+//   {{ character.name }}         the value at a dotted path, HTML-escaped
+//   {{ page.body | raw }}        the same without escaping
+//   {{ entry | json }}           the value as JSON, HTML-escaped for attributes
+//   {% if entries %}..{% else %}..{% endif %}
+//   {% for entry in entries %}..{{ loop.index }}..{% endfor %}
+//
+// path segments are object keys or array indexes, looked up in the loop
+// variables first, innermost out, then in the context. a missing value renders
+// as nothing and is false, like null, false, 0, "" and empty arrays and objects.
+
+use crate::formats::xml_escape;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::LazyLock;
+use thiserror::Error;
+
+// name -> source of the built-in templates
+const SOURCES: [(&str, &str); 2] = [
+    ("character.html", include_str!("../assets/templates/character.html")),
+    ("ui.html", include_str!("../assets/templates/ui.html")),
+];
+
+static TEMPLATES: LazyLock<HashMap<&str, Template>> = LazyLock::new(|| {
+    SOURCES
+        .iter()
+        .map(|(name, source)| {
+            let template = Template::parse(source).unwrap_or_else(|e| panic!("Invalid template {name}: {e}"));
+            (*name, template)
+        })
+        .collect()
+});
+
+#[derive(Debug, Error, PartialEq)]
+pub(crate) enum TemplateError {
+    #[error("unclosed {0} at byte {1}")]
+    Unclosed(&'static str, usize),
+    #[error("unexpected {{% {0} %}} at byte {1}")]
+    Unexpected(String, usize),
+    #[error("unknown filter {0}")]
+    UnknownFilter(String),
+}
+
+#[derive(Debug)]
+pub(crate) struct Template {
+    nodes: Vec<Node>,
+}
+
+#[derive(Debug)]
+enum Node {
+    Text(String),
+    Value { path: String, filter: Filter },
+    If { path: String, then: Vec<Node>, otherwise: Vec<Node> },
+    For { name: String, path: String, body: Vec<Node> },
+}
+
+// how a {{ value }} is written out
+#[derive(Debug, Clone, Copy)]
+enum Filter {
+    Escape,
+    Raw,
+    Json,
+}
+
+// a tag ending a block, "else", "endif" or "endfor", and where it was
+type BlockEnd = (String, usize);
+
+// a {% tag %} as the parser meets it
+enum Tag {
+    If(String),
+    Else,
+    EndIf,
+    For(String, String),
+    EndFor,
+}
+
+// the built-in template `name`
+pub(crate) fn get(name: &str) -> Option<&'static Template> {
+    TEMPLATES.get(name)
+}
+
+impl Template {
+    pub(crate) fn parse(source: &str) -> Result<Template, TemplateError> {
+        let mut parser = Parser { source, position: 0 };
+        let (nodes, end) = parser.nodes()?;
+        match end {
+            None => Ok(Template { nodes }),
+            Some((tag, at)) => Err(TemplateError::Unexpected(tag, at)),
+        }
+    }
+
+    pub(crate) fn render(&self, context: &Value) -> String {
+        let mut out = String::new();
+        render(&self.nodes, context, &mut Vec::new(), &mut out);
+        out
+    }
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    position: usize,
+}
+
+impl Parser<'_> {
+    // nodes up to the end of the source or a tag closing a block, which is
+    // returned with where it was for the caller to check
+    fn nodes(&mut self) -> Result<(Vec<Node>, Option<BlockEnd>), TemplateError> {
+        let mut nodes = Vec::new();
+        loop {
+            let rest = &self.source[self.position..];
+            let next = [rest.find("{{"), rest.find("{%")].into_iter().flatten().min();
+            let Some(offset) = next else {
+                if !rest.is_empty() {
+                    nodes.push(Node::Text(rest.to_string()));
+                }
+                self.position = self.source.len();
+                return Ok((nodes, None));
+            };
+            if offset > 0 {
+                nodes.push(Node::Text(rest[..offset].to_string()));
+            }
+            let start = self.position + offset;
+            if rest[offset..].starts_with("{{") {
+                let inner = self.inner(start, "}}", "{{")?;
+                let (path, filter) = match inner.split_once('|') {
+                    Some((path, filter)) => (path.trim(), Some(filter.trim())),
+                    None => (inner.trim(), None),
+                };
+                let filter = match filter {
+                    None => Filter::Escape,
+                    Some("raw") => Filter::Raw,
+                    Some("json") => Filter::Json,
+                    Some(other) => return Err(TemplateError::UnknownFilter(other.to_string())),
+                };
+                nodes.push(Node::Value { path: path.to_string(), filter });
+                continue;
+            }
+            let inner = self.inner(start, "%}", "{%")?.trim().to_string();
+            match parse_tag(&inner, start)? {
+                Tag::If(path) => {
+                    let (then, end) = self.nodes()?;
+                    let otherwise = match end {
+                        Some((tag, _)) if tag == "else" => match self.nodes()? {
+                            (otherwise, Some((tag, _))) if tag == "endif" => otherwise,
+                            (_, Some((tag, at))) => return Err(TemplateError::Unexpected(tag, at)),
+                            (_, None) => return Err(TemplateError::Unclosed("{% if %}", start)),
+                        },
+                        Some((tag, _)) if tag == "endif" => Vec::new(),
+                        Some((tag, at)) => return Err(TemplateError::Unexpected(tag, at)),
+                        None => return Err(TemplateError::Unclosed("{% if %}", start)),
+                    };
+                    nodes.push(Node::If { path, then, otherwise });
+                }
+                Tag::For(name, path) => {
+                    let body = match self.nodes()? {
+                        (body, Some((tag, _))) if tag == "endfor" => body,
+                        (_, Some((tag, at))) => return Err(TemplateError::Unexpected(tag, at)),
+                        (_, None) => return Err(TemplateError::Unclosed("{% for %}", start)),
+                    };
+                    nodes.push(Node::For { name, path, body });
+                }
+                Tag::Else | Tag::EndIf | Tag::EndFor => return Ok((nodes, Some((inner, start)))),
+            }
+        }
+    }
+
+    // the text between an opening delimiter at `start` and `close`, moving past it
+    fn inner(&mut self, start: usize, close: &str, open: &'static str) -> Result<&str, TemplateError> {
+        let from = start + 2;
+        let Some(length) = self.source[from..].find(close) else {
+            return Err(TemplateError::Unclosed(open, start));
+        };
+        self.position = from + length + close.len();
+        Ok(&self.source[from..from + length])
+    }
+}
+
+fn parse_tag(tag: &str, at: usize) -> Result<Tag, TemplateError> {
+    let words: Vec<&str> = tag.split_whitespace().collect();
+    match words.as_slice() {
+        ["if", path] => Ok(Tag::If(path.to_string())),
+        ["else"] => Ok(Tag::Else),
+        ["endif"] => Ok(Tag::EndIf),
+        ["for", name, "in", path] => Ok(Tag::For(name.to_string(), path.to_string())),
+        ["endfor"] => Ok(Tag::EndFor),
+        _ => Err(TemplateError::Unexpected(tag.to_string(), at)),
+    }
+}
+
+fn render(nodes: &[Node], context: &Value, scopes: &mut Vec<(String, Value)>, out: &mut String) {
+    for node in nodes {
+        match node {
+            Node::Text(text) => out.push_str(text),
+            Node::Value { path, filter } => {
+                let value = lookup(path, context, scopes);
+                match filter {
+                    Filter::Escape => out.push_str(&xml_escape(&value.map(to_text).unwrap_or_default())),
+                    Filter::Raw => out.push_str(&value.map(to_text).unwrap_or_default()),
+                    Filter::Json => out.push_str(&xml_escape(&value.unwrap_or(&Value::Null).to_string())),
+                }
+            }
+            Node::If { path, then, otherwise } => {
+                let branch = if lookup(path, context, scopes).is_some_and(truthy) { then } else { otherwise };
+                render(branch, context, scopes, out);
+            }
+            Node::For { name, path, body } => {
+                let items = match lookup(path, context, scopes) {
+                    Some(Value::Array(items)) => items.clone(),
+                    _ => Vec::new(),
+                };
+                for (index, item) in items.into_iter().enumerate() {
+                    scopes.push((name.clone(), item));
+                    scopes.push(("loop".to_string(), serde_json::json!({ "index": index + 1, "first": index == 0 })));
+                    render(body, context, scopes, out);
+                    scopes.truncate(scopes.len() - 2);
+                }
+            }
+        }
+    }
+}
+
+fn lookup<'a>(path: &str, context: &'a Value, scopes: &'a [(String, Value)]) -> Option<&'a Value> {
+    let mut segments = path.split('.');
+    let first = segments.next()?;
+    let mut value = match scopes.iter().rev().find(|(name, _)| name == first) {
+        Some((_, value)) => value,
+        None => context.get(first)?,
+    };
+    for segment in segments {
+        value = match value {
+            Value::Array(items) => items.get(segment.parse::<usize>().ok()?)?,
+            _ => value.get(segment)?,
+        };
+    }
+    Some(value)
+}
+
+fn truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(value) => *value,
+        Value::Number(number) => number.as_f64() != Some(0.0),
+        Value::String(text) => !text.is_empty(),
+        Value::Array(items) => !items.is_empty(),
+        Value::Object(fields) => !fields.is_empty(),
+    }
+}
+
+fn to_text(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(text) => text.clone(),
+        other => other.to_string(),
+    }
+}
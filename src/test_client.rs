@@ -0,0 +1,139 @@
+// an in-process client for tests, no socket or running server needed
+//
+// a request is written out as raw HTTP and handed to the closure the client
+// was made with, which returns the raw response. the library doesn't know how
+// the server answers, the binary's tests pass a closure running the bytes
+// through `answer` from a Cursor instead of a TcpStream, the same parsing,
+// routing and serialization a connection goes through. tests using it don't
+// wait for a listener or share port 7878, so they run in parallel and can't
+// be disturbed by a server another test started.
+
+// reads a raw request and returns the raw response, writing whatever interim
+// responses go out before it to the Vec
+type Answer = dyn Fn(&[u8], &mut Vec<u8>) -> Vec<u8>;
+
+/// Sends raw HTTP requests to a server's answering function, in memory.
+pub struct TestClient {
+    answer: Box<Answer>,
+    // sent with every request made through request()
+    headers: Vec<(String, String)>,
+}
+
+/// A response read back from the bytes the server wrote.
+#[derive(Debug)]
+pub struct TestResponse {
+    pub status: u16,
+    /// The header fields in the order they were sent, names as written.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// Whatever interim responses, like 100 Continue, went out before this one.
+    pub interim: Vec<u8>,
+}
+
+impl TestClient {
+    /// A client whose requests are answered by `answer`, which gets the raw
+    /// request and a buffer for interim responses and returns the raw response.
+    pub fn new(answer: impl Fn(&[u8], &mut Vec<u8>) -> Vec<u8> + 'static) -> TestClient {
+        TestClient { answer: Box::new(answer), headers: Vec::new() }
+    }
+
+    /// A client adding `name: value` to each of its requests.
+    pub fn with_header(mut self, name: &str, value: &str) -> TestClient {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn get(&self, uri: &str) -> TestResponse {
+        self.request("GET", uri, &[], "")
+    }
+
+    /// A request with a Content-Length added for `body` when there is one.
+    pub fn request(&self, method: &str, uri: &str, headers: &[(&str, &str)], body: &str) -> TestResponse {
+        let mut raw = format!("{method} {uri} HTTP/1.1\r\nHost: localhost\r\n");
+        let defaults = self.headers.iter().map(|(name, value)| (name.as_str(), value.as_str()));
+        for (name, value) in defaults.chain(headers.iter().copied()) {
+            raw.push_str(&format!("{name}: {value}\r\n"));
+        }
+        if !body.is_empty() {
+            raw.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        }
+        raw.push_str("\r\n");
+        raw.push_str(body);
+        self.send(raw.as_bytes())
+    }
+
+    /// Sends the bytes exactly as given, for requests the helpers can't build.
+    pub fn send(&self, raw: &[u8]) -> TestResponse {
+        let mut interim = Vec::new();
+        let response = (self.answer)(raw, &mut interim);
+        TestResponse::parse(&response, interim)
+    }
+}
+
+impl TestResponse {
+    fn parse(raw: &[u8], interim: Vec<u8>) -> TestResponse {
+        let split = raw
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .expect("response without a blank line after the headers");
+        let head = String::from_utf8_lossy(&raw[..split]);
+        let mut lines = head.split("\r\n");
+        let status = lines
+            .next()
+            .and_then(|line| line.split(' ').nth(1))
+            .and_then(|code| code.parse().ok())
+            .expect("response without a status line");
+        let headers: Vec<(String, String)> = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.to_string(), value.trim().to_string()))
+            .collect();
+        let mut response = TestResponse { status, headers, body: raw[split + 4..].to_vec(), interim };
+        if response.header("Transfer-Encoding") == Some("chunked") {
+            response.body = dechunk(&response.body);
+        }
+        response
+    }
+
+    /// The first value of the header `name`, whatever its case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(header, _)| header.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+
+    /// Every value of the header `name`, like each Set-Cookie.
+    pub fn header_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.headers
+            .iter()
+            .filter(move |(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    /// The body parsed as JSON.
+    ///
+    /// # Panics
+    ///
+    /// When the body is not JSON.
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_slice(&self.body).expect("response body is not JSON")
+    }
+}
+
+// the data of a chunked body, which must end with the last, empty chunk
+fn dechunk(mut raw: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    loop {
+        let line_end = raw.windows(2).position(|window| window == b"\r\n").expect("chunk without a size line");
+        let size = usize::from_str_radix(&String::from_utf8_lossy(&raw[..line_end]), 16).expect("invalid chunk size");
+        let data = &raw[line_end + 2..];
+        if size == 0 {
+            assert_eq!(data, b"\r\n", "trailing bytes after the last chunk");
+            return body;
+        }
+        body.extend_from_slice(&data[..size]);
+        assert_eq!(&data[size..size + 2], b"\r\n", "chunk without its CRLF");
+        raw = &data[size + 2..];
+    }
+}
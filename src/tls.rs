@@ -0,0 +1,179 @@
+// HTTPS listeners, enabled with the `tls` cargo feature
+//
+// a listener with `tls` set to the PEM files of its certificate chain and
+// private key is served over TLS 1.2 and 1.3:
+//
+//   "listeners": [{ "host": "0.0.0.0", "port": 8443, "tls": { "cert": "cert.pem", "key": "key.pem" } }]
+//
+// the tokio accept loop serves it, the feature brings that along. requests
+// are answered like on a cleartext listener, but sendfile(2) can't write
+// through TLS so file and streamed bodies are read into memory first. with
+// the `http2` feature ALPN offers h2 ahead of http/1.1, and the connections
+// of clients that take it are served by http2.
+//
+// the certificate is read into a resolver rather than the rustls config, so
+// reload can swap in the one the files hold now without a restart. handshakes
+// already done keep the old one.
+
+use crate::async_server::{self, Transport};
+use crate::connection::{ConnectionInfo, TlsInfo};
+use crate::http::Serialized;
+use crate::listener::Routes;
+use crate::{events, log};
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::ServerConfig;
+use serde::Deserialize;
+use std::io;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+// longest a client may take to finish the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct TlsFiles {
+    /// The certificate, followed by the intermediates chaining it to a root.
+    pub(crate) cert: String,
+    /// The certificate's private key.
+    pub(crate) key: String,
+}
+
+// the certificate a listener serves, None until its files hold one
+#[derive(Debug)]
+struct Certificate {
+    #[cfg_attr(not(feature = "acme"), allow(dead_code))]
+    files: TlsFiles,
+    current: RwLock<Option<Arc<CertifiedKey>>>,
+}
+
+impl ResolvesServerCert for Certificate {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.current.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+}
+
+// the certificates of every TLS listener, for reload to find the ones it swaps
+static CERTIFICATES: Mutex<Vec<Arc<Certificate>>> = Mutex::new(Vec::new());
+
+// the rustls setup serving the certificate in `files`. with `issued_later` set
+// files that don't hold one yet are no error, handshakes fail until a reload
+pub(crate) fn server_config(files: &TlsFiles, issued_later: bool) -> Result<Arc<ServerConfig>, String> {
+    let current = match load(files) {
+        Ok(key) => Some(Arc::new(key)),
+        Err(e) if issued_later => {
+            log::warning!("{e}, no certificate is served until one is issued");
+            None
+        }
+        Err(e) => return Err(e),
+    };
+    let certificate = Arc::new(Certificate { files: files.clone(), current: RwLock::new(current) });
+    CERTIFICATES.lock().unwrap_or_else(PoisonError::into_inner).push(Arc::clone(&certificate));
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .with_no_client_auth()
+        .with_cert_resolver(certificate);
+    #[cfg(feature = "http2")]
+    config.alpn_protocols.push(crate::http2::ALPN.to_vec());
+    config.alpn_protocols.push(b"http/1.1".to_vec());
+    Ok(Arc::new(config))
+}
+
+// reads the chain and key in `files`, checking they belong together
+fn load(files: &TlsFiles) -> Result<CertifiedKey, String> {
+    let certs = CertificateDer::pem_file_iter(&files.cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("{}: {e}", files.cert))?;
+    if certs.is_empty() {
+        return Err(format!("{}: no certificate found", files.cert));
+    }
+    let key = PrivateKeyDer::from_pem_file(&files.key).map_err(|e| format!("{}: {e}", files.key))?;
+    CertifiedKey::from_der(certs, key, &ring::default_provider()).map_err(|e| format!("{}: {e}", files.cert))
+}
+
+// serves the certificate now in `files` on every listener configured with
+// them, the old one is kept when they can't be read
+#[cfg_attr(not(feature = "acme"), allow(dead_code))]
+pub(crate) fn reload(files: &TlsFiles) -> Result<(), String> {
+    let key = Arc::new(load(files)?);
+    for certificate in CERTIFICATES.lock().unwrap_or_else(PoisonError::into_inner).iter() {
+        if certificate.files == *files {
+            *certificate.current.write().unwrap_or_else(PoisonError::into_inner) = Some(Arc::clone(&key));
+        }
+    }
+    log::info!("Serving the certificate in {}", files.cert);
+    Ok(())
+}
+
+// completes the handshake, None when the client fails it or takes too long
+pub(crate) async fn accept(stream: TcpStream, config: Arc<ServerConfig>) -> Option<TlsStream<TcpStream>> {
+    match tokio::time::timeout(HANDSHAKE_TIMEOUT, TlsAcceptor::from(config).accept(stream)).await {
+        Ok(Ok(stream)) => Some(stream),
+        Ok(Err(e)) => {
+            log::debug!("TLS handshake failed: {}", e);
+            None
+        }
+        Err(_) => {
+            log::debug!("TLS handshake timed out");
+            None
+        }
+    }
+}
+
+pub(crate) async fn handle_connection(stream: TcpStream, config: Arc<ServerConfig>, routes: Routes) {
+    let Some(stream) = accept(stream, config).await else {
+        return;
+    };
+    #[cfg(feature = "http2")]
+    if stream.get_ref().1.alpn_protocol() == Some(crate::http2::ALPN) {
+        return crate::http2::serve(stream, routes).await;
+    }
+    async_server::handle_connection(stream, routes).await;
+}
+
+impl Transport for TlsStream<TcpStream> {
+    fn connection(&self) -> ConnectionInfo {
+        let (stream, session) = self.get_ref();
+        let tls = TlsInfo {
+            version: session.protocol_version().map_or_else(String::new, |version| format!("{version:?}")),
+            cipher: session.negotiated_cipher_suite().map_or_else(String::new, |suite| format!("{:?}", suite.suite())),
+            sni: session.server_name().map(str::to_string),
+        };
+        ConnectionInfo {
+            peer: stream.peer_addr().ok(),
+            local: stream.local_addr().ok(),
+            tls: Some(tls),
+            alpn: session.alpn_protocol().map(|protocol| String::from_utf8_lossy(protocol).into_owned()),
+        }
+    }
+
+    async fn send_body(mut self, response: Serialized) -> io::Result<Self> {
+        if response.file.is_none() && response.stream.is_none() {
+            return Ok(self);
+        }
+        // the head already went out, what is left is read in on the blocking pool
+        let body = tokio::task::spawn_blocking(move || Serialized { bytes: Vec::new(), ..response }.into_bytes())
+            .await
+            .map_err(io::Error::other)??;
+        self.write_all(&body).await?;
+        Ok(self)
+    }
+
+    // the event stream's thread writes through rustls on the blocking socket
+    fn stream_events(self) -> io::Result<()> {
+        let (stream, connection) = self.into_inner();
+        let stream = stream.into_std()?;
+        stream.set_nonblocking(false)?;
+        std::thread::spawn(move || events::stream_events(rustls::StreamOwned::new(connection, stream)));
+        Ok(())
+    }
+}
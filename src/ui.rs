@@ -0,0 +1,57 @@
+// a small HTML interface to the entries at /ui, enabled with the `templates` cargo feature
+//
+// the page lists the entries server-side from assets/templates/ui.html and
+// its script makes every change through the JSON API, POST /submit,
+// PATCH /entries/{id} and DELETE /entries/{id}, so the same validation,
+// If-Match checks and journal apply as for any other client. entries in the
+// trash aren't listed. the delete buttons are plain forms posting to
+// /ui/entries/{id}/trash, answered with a redirect back to the page and the
+// outcome flashed on it. with `csrf` configured they carry the token in a
+// hidden field, the page setting the cookie when the browser has none yet.
+
+use crate::config;
+use crate::csrf;
+use crate::endpoints;
+use crate::flash::{self, Flash};
+use crate::http::{Request, Response, StatusCode};
+use crate::store;
+use serde_json::json;
+
+// the page's script and styles are inline
+const CONTENT_SECURITY_POLICY: &str = "default-src 'self'; script-src 'self' 'unsafe-inline'; style-src 'self' 'unsafe-inline'";
+
+pub(crate) fn page(request: &Request) -> Response {
+    // through the JSON text, turning the f32 ratings into a Value directly widens them to 7.800000190734863
+    let mut entries = store::load();
+    entries.retain(|entry| !entry.is_deleted());
+    let entries = serde_json::to_string(&entries).unwrap_or_default();
+    let entries: Vec<serde_json::Value> = serde_json::from_str(&entries).unwrap_or_default();
+    let flashes = flash::take(request);
+    let csrf = csrf::form_token(config::get(), &request.headers, request.connection.secure());
+    let context = json!({
+        "count": entries.len(),
+        "entries": entries,
+        "flashes": flashes,
+        "csrf": csrf.as_ref().map(|csrf| json!({ "field": csrf.field, "token": csrf.token })),
+    });
+    let response = Response::render("ui.html", context).header("Content-Security-Policy", CONTENT_SECURITY_POLICY);
+    match csrf.and_then(|csrf| csrf.set_cookie) {
+        Some(cookie) => response.header("Set-Cookie", cookie),
+        None => response,
+    }
+}
+
+// POST /ui/entries/{id}/trash, moves the entry to the trash and goes back to the page
+pub(crate) fn trash(request: &Request) -> Response {
+    let id = request.params.get("id").and_then(|id| id.parse::<usize>().ok());
+    let name = id.and_then(|id| store::load().into_iter().find(|entry| entry.id() == id && !entry.is_deleted()));
+    let name = name.map(|entry| entry.name().to_string());
+    let flash = match (id, name) {
+        (Some(id), Some(name)) => match endpoints::trash_entry(id, None) {
+            (StatusCode::NoContent, _) => Flash::notice(format!("Moved {name} to the trash")),
+            (_, message) => Flash::error(format!("Failed to move {name} to the trash: {message}")),
+        },
+        _ => Flash::error("No such entry"),
+    };
+    flash::set(request, Response::redirect(StatusCode::SeeOther, "/ui"), flash)
+}
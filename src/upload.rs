@@ -0,0 +1,198 @@
+// POST /upload, multipart/form-data file uploads
+//
+// file parts are streamed into `<upload_dir>/.tmp` and only moved next to the
+// other uploads once the part is complete, so a dropped connection never
+// leaves a truncated file behind a public url. stored files are served back
+// under /uploads/<name>, with names made unique and safe for the filesystem.
+
+use crate::caching::CachePolicy;
+use crate::config::{self, Config};
+use crate::http::{http_date, split_uri, HeaderMap, Response, StatusCode};
+use crate::{json, log, problem};
+use crate::multipart::{self, Multipart};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub(crate) const UPLOAD_PATH: &str = "/upload";
+pub(crate) const UPLOADS_URL_PREFIX: &str = "/uploads/";
+const ONE_YEAR_SECS: u32 = 365 * 24 * 60 * 60;
+
+#[derive(Serialize)]
+struct StoredFile {
+    field: String,
+    filename: String,
+    content_type: Option<String>,
+    size: u64,
+    url: String,
+}
+
+#[derive(Serialize, Default)]
+struct UploadResult {
+    files: Vec<StoredFile>,
+    fields: HashMap<String, String>,
+}
+
+// true for requests whose multipart body is left unread by the parser for `handle` to stream
+pub(crate) fn is_upload(method: &str, uri: &str, headers: &HeaderMap) -> bool {
+    method == "POST"
+        && split_uri(uri).0 == UPLOAD_PATH
+        && headers.get("Content-Type").and_then(multipart::boundary).is_some()
+}
+
+// reads the multipart body off `reader` and stores every file part
+pub(crate) fn handle<R: Read>(reader: R, headers: &HeaderMap) -> Response {
+    problem::with_instance(receive(reader, headers), UPLOAD_PATH)
+}
+
+fn receive<R: Read>(reader: R, headers: &HeaderMap) -> Response {
+    let config = config::get();
+    let Some(boundary) = headers.get("Content-Type").and_then(multipart::boundary) else {
+        return requires_multipart();
+    };
+    let Some(length) = headers.get("Content-Length") else {
+        return Response::problem(StatusCode::LengthRequired, "Uploads need a Content-Length");
+    };
+    let Ok(length) = length.parse::<u64>() else {
+        return Response::problem(StatusCode::BadRequest, "Invalid Content-Length");
+    };
+    if length > config.max_upload_bytes {
+        return Response::problem(
+            StatusCode::PayloadTooLarge,
+            format!("Uploads are limited to {} bytes", config.max_upload_bytes),
+        );
+    }
+
+    let mut parts = Multipart::new(reader.take(length), &boundary);
+    match store_parts(&mut parts, config) {
+        Ok(result) => match json::to_string(&result) {
+            Ok(body) => Response::json(StatusCode::Created, body),
+            Err(e) => Response::problem(StatusCode::InternalServerError, e.to_string()),
+        },
+        Err(e) if matches!(e.kind(), io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof) => {
+            Response::problem(StatusCode::BadRequest, format!("Invalid multipart body: {}", e))
+        }
+        Err(e) => {
+            log::error!("Failed to store upload: {}", e);
+            Response::problem(StatusCode::InternalServerError, "Failed to store upload")
+        }
+    }
+}
+
+// the answer to POST /upload with a body that isn't multipart/form-data
+pub(crate) fn requires_multipart() -> Response {
+    Response::problem(StatusCode::UnsupportedMediaType, "Expected multipart/form-data with a boundary")
+}
+
+fn store_parts<R: Read>(parts: &mut Multipart<R>, config: &Config) -> io::Result<UploadResult> {
+    let upload_dir = Path::new(&config.upload_dir);
+    let temp_dir = upload_dir.join(".tmp");
+    fs::create_dir_all(&temp_dir)?;
+
+    let mut result = UploadResult::default();
+    while let Some(part) = parts.next_part()? {
+        let Some(filename) = part.filename else {
+            // a plain form field, small enough to keep in memory
+            let mut value = Vec::new();
+            parts.copy_body(&mut value)?;
+            result.fields.insert(part.name, String::from_utf8_lossy(&value).to_string());
+            continue;
+        };
+
+        let name = stored_name(&filename);
+        let temp_path = temp_dir.join(&name);
+        let size = match write_temp(parts, &temp_path) {
+            Ok(size) => size,
+            Err(e) => {
+                let _ = fs::remove_file(&temp_path);
+                return Err(e);
+            }
+        };
+        fs::rename(&temp_path, upload_dir.join(&name))?;
+
+        result.files.push(StoredFile {
+            field: part.name,
+            filename,
+            content_type: part.content_type,
+            size,
+            url: format!("{UPLOADS_URL_PREFIX}{name}"),
+        });
+    }
+    Ok(result)
+}
+
+fn write_temp<R: Read>(parts: &mut Multipart<R>, path: &Path) -> io::Result<u64> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    let size = parts.copy_body(&mut writer)?;
+    writer.flush()?;
+    Ok(size)
+}
+
+// a unique name for an uploaded file, keeping only the safe characters of the
+// client's file name and dropping any directory part
+fn stored_name(filename: &str) -> String {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+
+    let base = filename.rsplit(['/', '\\']).next().unwrap_or("");
+    let mut safe: String = base
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || "._-".contains(c) { c } else { '_' })
+        .take(100)
+        .collect();
+    safe = safe.trim_start_matches('.').to_string();
+    if safe.is_empty() {
+        safe = "upload".to_string();
+    }
+
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis())
+        .unwrap_or(0);
+    let sequence = NEXT.fetch_add(1, Ordering::Relaxed);
+    format!("{millis:x}-{sequence}-{safe}")
+}
+
+// GET /uploads/<name>, None when the path isn't an existing upload
+pub(crate) fn serve(path: &str) -> Option<Response> {
+    let name = path.strip_prefix(UPLOADS_URL_PREFIX)?;
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || "._-".contains(c));
+    if !valid {
+        return None;
+    }
+    let path = Path::new(&config::get().upload_dir).join(name);
+    let contents = fs::read(&path).ok()?;
+    let mut response = Response::new(StatusCode::Ok)
+        .content_type(content_type(name))
+        .header("X-Content-Type-Options", "nosniff")
+        .header("Accept-Ranges", "bytes")
+        // stored names are never reused, so a cached copy can't go stale
+        .header("Cache-Control", CachePolicy::MaxAge(ONE_YEAR_SECS).header_value())
+        .body(contents);
+    if let Ok(modified) = fs::metadata(&path).and_then(|metadata| metadata.modified()) {
+        response = response.header("Last-Modified", http_date(modified.into()));
+    }
+    Some(response)
+}
+
+// media type from the file extension, files that could run script in a
+// browser are sent as opaque bytes
+fn content_type(name: &str) -> &'static str {
+    let extension = name.rsplit_once('.').map(|(_, extension)| extension.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("pdf") => "application/pdf",
+        Some("json") => "application/json",
+        Some("csv") => "text/csv; charset=utf-8",
+        Some("txt") => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
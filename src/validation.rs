@@ -0,0 +1,70 @@
+// field level validation of the entries clients write
+//
+// a write that deserializes fine can still hold values the dataset never
+// does, an empty name or a rating of 42. every failing field is reported at
+// once, as {"field", "message"} objects in a 422 problem details document.
+
+use crate::http::StatusCode;
+use crate::openapi::ApiSchema;
+use crate::problem::Problem;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+#[derive(Serialize, Debug, PartialEq)]
+pub(crate) struct FieldError {
+    pub(crate) field: String,
+    pub(crate) message: String,
+}
+
+impl FieldError {
+    pub(crate) fn new(field: impl Into<String>, message: impl Into<String>) -> FieldError {
+        FieldError { field: field.into(), message: message.into() }
+    }
+
+    // the same error for the entry at `index` of a bulk request, "[2].name"
+    pub(crate) fn at(self, index: usize) -> FieldError {
+        FieldError { field: format!("[{index}].{}", self.field), ..self }
+    }
+}
+
+impl ApiSchema for FieldError {
+    const NAME: &'static str = "FieldError";
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["field", "message"],
+            "properties": {
+                "field": { "type": "string" },
+                "message": { "type": "string" },
+            },
+        })
+    }
+}
+
+// records an error for `field` unless `valid`
+pub(crate) fn check(errors: &mut Vec<FieldError>, field: &str, valid: bool, message: &str) {
+    if !valid {
+        errors.push(FieldError::new(field, message));
+    }
+}
+
+// the body of a 422 response
+pub(crate) fn to_json(errors: Vec<FieldError>) -> String {
+    Problem::new(StatusCode::UnprocessableEntity, "One or more fields are invalid")
+        .errors(errors)
+        .to_json()
+}
+
+// a count written with thousands separators, like the dataset's "32,043"
+pub(crate) fn is_grouped_number(value: &str) -> bool {
+    let mut groups = value.split(',');
+    let first = groups.next().unwrap_or_default();
+    let digits = |group: &str| group.bytes().all(|b| b.is_ascii_digit());
+    (1..=3).contains(&first.len()) && digits(first) && groups.all(|group| group.len() == 3 && digits(group))
+}
+
+// a trend is "-" for an unranked episode, otherwise an integer
+pub(crate) fn is_trend(value: &str) -> bool {
+    value == "-" || value.parse::<i64>().is_ok()
+}